@@ -1,9 +1,13 @@
 //! A traditional DOM tree interface for navigating and manipulating
 //! XML documents.
 
-use std::{fmt, hash};
+use std::{error, fmt, hash, iter, ops::Range};
 
-use super::{raw, QName};
+use super::{
+    parser, raw,
+    str::{XmlChar, XmlStr},
+    NameTest, QName,
+};
 
 type SiblingFn<T> = unsafe fn(&raw::Connections, T) -> raw::SiblingIter<'_>;
 
@@ -32,6 +36,7 @@ impl<'d> Document<'d> {
     wrapper!(wrap_text, Text, raw::Text);
     wrapper!(wrap_comment, Comment, raw::Comment);
     wrapper!(wrap_pi, ProcessingInstruction, raw::ProcessingInstruction);
+    wrapper!(wrap_document_type, DocumentType, raw::DocumentType);
 
     #[doc(hidden)]
     pub fn new(storage: &'d raw::Storage, connections: &'d raw::Connections) -> Document<'d> {
@@ -73,6 +78,80 @@ impl<'d> Document<'d> {
         self.wrap_root(self.connections.root())
     }
 
+    /// The version named by the XML declaration, if any, such as
+    /// `1.0` or `1.1`. Any `1.x` version is accepted when parsing, but
+    /// the rules of XML 1.0 for names, characters and line endings are
+    /// always applied; a document declaring XML 1.1 may use them to
+    /// decide how to treat its content.
+    pub fn version(self) -> Option<&'d str> {
+        self.root().node().version()
+    }
+
+    pub fn set_version(self, version: Option<&str>) {
+        self.storage
+            .root_set_version(self.connections.root(), version);
+    }
+
+    /// The encoding named by the XML declaration, if any. This is
+    /// only a record of the declaration; no transcoding is performed.
+    pub fn encoding(self) -> Option<&'d str> {
+        self.root().node().encoding()
+    }
+
+    pub fn set_encoding(self, encoding: Option<&str>) {
+        self.storage
+            .root_set_encoding(self.connections.root(), encoding);
+    }
+
+    /// The value of the `standalone` pseudo-attribute of the XML
+    /// declaration, if any. `Some(true)` corresponds to `yes`.
+    pub fn standalone(self) -> Option<bool> {
+        self.root().node().standalone()
+    }
+
+    pub fn set_standalone(self, standalone: Option<bool>) {
+        self.storage
+            .root_set_standalone(self.connections.root(), standalone);
+    }
+
+    /// The document type declaration, if any. It is written where
+    /// `document_type_position` says.
+    pub fn document_type(self) -> Option<DocumentType<'d>> {
+        self.root()
+            .node()
+            .document_type()
+            .map(|n| self.wrap_document_type(n))
+    }
+
+    pub fn set_document_type(self, document_type: Option<DocumentType<'d>>) {
+        self.storage
+            .root_set_document_type(self.connections.root(), document_type.map(|d| d.node));
+    }
+
+    /// How many children of the root come before the document type
+    /// declaration; 0, the default, writes it just after the XML
+    /// declaration. The parser records where it was found. It is
+    /// never written after the root element.
+    pub fn document_type_position(self) -> usize {
+        self.root().node().document_type_position()
+    }
+
+    pub fn set_document_type_position(self, position: usize) {
+        self.storage
+            .root_set_document_type_position(self.connections.root(), position);
+    }
+
+    /// Creates a document type declaration. It has no effect until
+    /// given to `set_document_type`.
+    pub fn create_document_type(self, name: &str) -> DocumentType<'d> {
+        self.wrap_document_type(self.storage.create_document_type(name))
+    }
+
+    /// Creates an element. The name is not validated and this never
+    /// panics; a name that is not valid XML is only noticed when the
+    /// document is written, producing output that is not well-formed.
+    /// Use `try_create_element` or `create_element_ns` for names that
+    /// come from untrusted input.
     pub fn create_element<'n, N>(self, name: N) -> Element<'d>
     where
         N: Into<QName<'n>>,
@@ -80,10 +159,77 @@ impl<'d> Document<'d> {
         self.wrap_element(self.storage.create_element(name))
     }
 
+    /// Creates an element like `create_element`, but fails if the
+    /// name could not be written as XML: the local part must be an
+    /// [NCName](https://www.w3.org/TR/xml-names/#NT-NCName) and the
+    /// namespace URI, if any, must not be empty.
+    pub fn try_create_element<'n, N>(self, name: N) -> Result<Element<'d>, InvalidName>
+    where
+        N: Into<QName<'n>>,
+    {
+        let name = name.into();
+        validate_name(name)?;
+        Ok(self.create_element(name))
+    }
+
+    /// Creates a namespaced element that will be written with
+    /// `prefix`, failing if the result could not be written as XML.
+    ///
+    /// An empty `namespace_uri` puts the element in no namespace,
+    /// which is only allowed without a prefix. The local part and
+    /// prefix must be NCNames, `xmlns` may not be used as a prefix,
+    /// and `xml` only for its own namespace.
+    ///
+    /// ```
+    /// use sxd_document::{dom::InvalidName, Package};
+    ///
+    /// let package = Package::new();
+    /// let doc = package.as_document();
+    ///
+    /// let e = doc.create_element_ns("urn:x", Some("x"), "e").unwrap();
+    /// assert_eq!(e.preferred_prefix(), Some("x"));
+    ///
+    /// let e = doc.create_element_ns("", Some("x"), "e");
+    /// assert_eq!(e, Err(InvalidName::EmptyNamespaceUri));
+    /// ```
+    pub fn create_element_ns(
+        self,
+        namespace_uri: &str,
+        prefix: Option<&str>,
+        local_part: &str,
+    ) -> Result<Element<'d>, InvalidName> {
+        if !is_ncname(local_part) {
+            return Err(InvalidName::LocalPart(local_part.to_owned()));
+        }
+        validate_prefix(prefix)?;
+
+        let element = match (namespace_uri, prefix) {
+            ("", None) => self.create_element(local_part),
+            ("", Some(_)) => return Err(InvalidName::EmptyNamespaceUri),
+            (_, Some(p))
+                if p == "xmlns" || (p == "xml") != (namespace_uri == crate::XML_NS_URI) =>
+            {
+                return Err(InvalidName::Prefix(p.to_owned()));
+            }
+            (uri, _) => self.create_element((uri, local_part)),
+        };
+        element.set_preferred_prefix(prefix);
+        Ok(element)
+    }
+
     pub fn create_text(self, text: &str) -> Text<'d> {
         self.wrap_text(self.storage.create_text(text))
     }
 
+    /// Creates a text node that is written as a CDATA section. Any
+    /// `]]>` in the text is split across adjacent sections when
+    /// written, so the content needs no escaping.
+    pub fn create_cdata(self, text: &str) -> Text<'d> {
+        let text = self.create_text(text);
+        text.set_cdata(true);
+        text
+    }
+
     pub fn create_comment(self, text: &str) -> Comment<'d> {
         self.wrap_comment(self.storage.create_comment(text))
     }
@@ -96,6 +242,93 @@ impl<'d> Document<'d> {
         self.wrap_pi(self.storage.create_processing_instruction(target, value))
     }
 
+    /// The document element: the one element child of the root.
+    pub fn root_element(self) -> Option<Element<'d>> {
+        self.root()
+            .children()
+            .into_iter()
+            .find_map(ChildOfRoot::element)
+    }
+
+    /// Makes `element` the document element. Any existing document
+    /// element is detached, and the new one takes its place among the
+    /// comments and processing instructions of the root.
+    pub fn set_root_element(self, element: Element<'d>) {
+        let root = self.root();
+        let children = root.children();
+        let existing = children
+            .iter()
+            .position(|c| c.element().is_some())
+            .map(|index| (index, children[index]));
+
+        match existing {
+            Some((_, old)) if old == ChildOfRoot::Element(element) => {}
+            Some((index, old)) => {
+                root.remove_child(old);
+                root.insert_child_at(index, element);
+            }
+            None => root.append_child(element),
+        }
+    }
+
+    /// All nodes in the document, in document order.
+    pub fn descendants(self) -> Descendants<'d> {
+        Descendants::new(self.root().children())
+    }
+
+    /// All elements in the document, in document order.
+    pub fn descendant_elements(self) -> impl Iterator<Item = Element<'d>> {
+        self.descendants().filter_map(ChildOfElement::element)
+    }
+
+    /// Finds the first element, in document order, with an `id` or
+    /// `xml:id` attribute of the given value. Without a DTD the
+    /// attribute types are unknown, so only these two names count as
+    /// IDs; `id` must not be in a namespace.
+    ///
+    /// This searches the whole document on each call.
+    pub fn element_by_id(self, id: &str) -> Option<Element<'d>> {
+        self.descendant_elements().find(|e| {
+            e.attribute_value("id") == Some(id)
+                || e.attribute_value((crate::XML_NS_URI, "id")) == Some(id)
+        })
+    }
+
+    /// Normalizes every element in the document. See
+    /// `Element::normalize`.
+    pub fn normalize(self) {
+        for child in self.root().children() {
+            if let ChildOfRoot::Element(e) = child {
+                e.normalize();
+            }
+        }
+    }
+
+    /// Compares two documents by their logical content rather than by
+    /// identity. See `Element::canonical_eq` for what is compared.
+    /// Top-level comments and processing instructions must also match.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use sxd_document::parser;
+    ///
+    /// let a = parser::parse("<x:a xmlns:x='urn:a' one='1' two='2'/>").expect("Failed to parse");
+    /// let b = parser::parse("<y:a xmlns:y='urn:a' two='2' one='1'/>").expect("Failed to parse");
+    ///
+    /// assert!(a.as_document().canonical_eq(b.as_document(), false));
+    /// ```
+    pub fn canonical_eq(self, other: Document<'_>, ignore_whitespace: bool) -> bool {
+        let mut todo = Vec::new();
+
+        canonical_children_eq(
+            self.root().children(),
+            other.root().children(),
+            ignore_whitespace,
+            &mut todo,
+        ) && elements_canonical_eq(todo, ignore_whitespace)
+    }
+
     fn siblings<T>(self, f: SiblingFn<T>, node: T) -> Vec<ChildOfElement<'d>> {
         // This is safe because we don't allow the connection
         // information to leak outside of this method.
@@ -105,6 +338,71 @@ impl<'d> Document<'d> {
                 .collect()
         }
     }
+
+    fn nearest_preceding_sibling<T>(self, f: SiblingFn<T>, node: T) -> Option<ChildOfElement<'d>> {
+        // This is safe because we don't allow the connection
+        // information to leak outside of this method.
+        unsafe {
+            f(self.connections, node)
+                .next_back()
+                .map(|n| self.wrap_child_of_element(n))
+        }
+    }
+
+    fn nearest_following_sibling<T>(self, f: SiblingFn<T>, node: T) -> Option<ChildOfElement<'d>> {
+        // This is safe because we don't allow the connection
+        // information to leak outside of this method.
+        unsafe {
+            f(self.connections, node)
+                .next()
+                .map(|n| self.wrap_child_of_element(n))
+        }
+    }
+}
+
+/// A name rejected by the `try_` methods because it could not be
+/// written as XML.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidName {
+    /// The local part is not an NCName.
+    LocalPart(String),
+    /// The prefix is not an NCName.
+    Prefix(String),
+    /// The namespace URI is empty, which cannot be bound to a prefix.
+    EmptyNamespaceUri,
+}
+
+impl fmt::Display for InvalidName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            InvalidName::LocalPart(ref name) => write!(f, "invalid local name {:?}", name),
+            InvalidName::Prefix(ref prefix) => write!(f, "invalid prefix {:?}", prefix),
+            InvalidName::EmptyNamespaceUri => f.write_str("empty namespace URI"),
+        }
+    }
+}
+
+impl error::Error for InvalidName {}
+
+fn is_ncname(s: &str) -> bool {
+    s.end_of_ncname() == Some(s.len())
+}
+
+fn validate_name(name: QName<'_>) -> Result<(), InvalidName> {
+    if !is_ncname(name.local_part()) {
+        return Err(InvalidName::LocalPart(name.local_part().to_owned()));
+    }
+    if name.namespace_uri() == Some("") {
+        return Err(InvalidName::EmptyNamespaceUri);
+    }
+    Ok(())
+}
+
+fn validate_prefix(prefix: Option<&str>) -> Result<(), InvalidName> {
+    match prefix {
+        Some(prefix) if !is_ncname(prefix) => Err(InvalidName::Prefix(prefix.to_owned())),
+        _ => Ok(()),
+    }
 }
 
 impl<'d> PartialEq for Document<'d> {
@@ -128,6 +426,10 @@ impl<'d> fmt::Debug for Document<'d> {
 macro_rules! node(
     ($name:ident, $raw:ty, $doc:expr) => (
         #[doc = $doc]
+        ///
+        /// Handles are equal, and hash alike, only when they refer to
+        /// the same node; two nodes with the same content are not
+        /// equal.
         #[derive(Copy,Clone)]
         pub struct $name<'d> {
             document: Document<'d>,
@@ -166,6 +468,9 @@ node!(
 );
 
 impl<'d> Root<'d> {
+    /// Appends the child, detaching it from any previous parent first.
+    /// The root holds at most one element, so an element child
+    /// replaces the existing one, which is detached.
     pub fn append_child<C>(&self, child: C)
     where
         C: Into<ChildOfRoot<'d>>,
@@ -174,6 +479,69 @@ impl<'d> Root<'d> {
         self.document.connections.append_root_child(child.as_raw());
     }
 
+    /// Inserts the child so that it is at `index` among the children
+    /// of the root, detaching it from any previous parent first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is greater than the number of children, not
+    /// counting the child itself, or if the child is an element and
+    /// the root already has a different one. Use
+    /// `Document::set_root_element` to replace the element.
+    pub fn insert_child_at<C>(&self, index: usize, child: C)
+    where
+        C: Into<ChildOfRoot<'d>>,
+    {
+        let child = child.into();
+        self.document
+            .connections
+            .insert_root_child(index, child.as_raw());
+    }
+
+    /// Inserts the child immediately before `reference`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `reference` is not a child of the root.
+    pub fn insert_before<R, C>(&self, reference: R, child: C)
+    where
+        R: Into<ChildOfRoot<'d>>,
+        C: Into<ChildOfRoot<'d>>,
+    {
+        let child = child.into();
+        let index = self.sibling_index(reference.into(), child);
+        self.insert_child_at(index, child);
+    }
+
+    /// Inserts the child immediately after `reference`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `reference` is not a child of the root.
+    pub fn insert_after<R, C>(&self, reference: R, child: C)
+    where
+        R: Into<ChildOfRoot<'d>>,
+        C: Into<ChildOfRoot<'d>>,
+    {
+        let reference = reference.into();
+        let child = child.into();
+        if reference == child {
+            return;
+        }
+        let index = self.sibling_index(reference, child);
+        self.insert_child_at(index + 1, child);
+    }
+
+    /// The position of `reference` among the children once `child` has
+    /// been removed.
+    fn sibling_index(&self, reference: ChildOfRoot<'d>, child: ChildOfRoot<'d>) -> usize {
+        self.children()
+            .into_iter()
+            .filter(|&c| c != child || c == reference)
+            .position(|c| c == reference)
+            .expect("The reference node is not a child of the root")
+    }
+
     pub fn append_children<I>(&self, children: I)
     where
         I: IntoIterator,
@@ -193,6 +561,9 @@ impl<'d> Root<'d> {
         self.append_children(children);
     }
 
+    /// Detaches the child from the root. The child may later be added
+    /// elsewhere in the document. Does nothing if it is not a child of
+    /// the root.
     pub fn remove_child<C>(&self, child: C)
     where
         C: Into<ChildOfRoot<'d>>,
@@ -205,6 +576,8 @@ impl<'d> Root<'d> {
         self.document.connections.clear_root_children();
     }
 
+    /// The comments, processing instructions and element at the top
+    /// level of the document, in document order.
     pub fn children(&self) -> Vec<ChildOfRoot<'d>> {
         // This is safe because we copy of the children, and the
         // children are never deallocated.
@@ -252,13 +625,34 @@ impl<'d> Element<'d> {
         self.node().name()
     }
 
+    /// Renames the element, keeping its attributes and children. When
+    /// the namespace URI changes, the preferred prefix is cleared, as
+    /// it was chosen for the old namespace; the writer then uses a
+    /// prefix in scope for the new namespace or declares one. Set the
+    /// preferred prefix afterwards to choose it.
     pub fn set_name<'n, N>(&self, name: N)
     where
         N: Into<QName<'n>>,
     {
+        let name = name.into();
+        if name.namespace_uri() != self.name().namespace_uri() {
+            self.set_preferred_prefix(None);
+        }
         self.document.storage.element_set_name(self.node, name)
     }
 
+    /// Renames the element like `set_name`, but fails if the name
+    /// could not be written as XML. See `Document::try_create_element`.
+    pub fn try_set_name<'n, N>(&self, name: N) -> Result<(), InvalidName>
+    where
+        N: Into<QName<'n>>,
+    {
+        let name = name.into();
+        validate_name(name)?;
+        self.set_name(name);
+        Ok(())
+    }
+
     pub fn set_default_namespace_uri(&self, namespace_uri: Option<&str>) {
         self.document
             .storage
@@ -283,7 +677,9 @@ impl<'d> Element<'d> {
             .element_register_prefix(self.node, prefix, namespace_uri);
     }
 
-    /// Recursively resolve the prefix to a namespace URI.
+    /// Recursively resolve the prefix to a namespace URI, using the
+    /// nearest element that registered it. The `xml` prefix is always
+    /// bound, even if never registered.
     pub fn namespace_uri_for_prefix(&self, prefix: &str) -> Option<&'d str> {
         self.document
             .connections
@@ -293,6 +689,15 @@ impl<'d> Element<'d> {
     /// Recursively find a prefix for the namespace URI. Since
     /// multiple prefixes may map to the same URI, `preferred` can be
     /// provided to select a specific prefix, if it is valid.
+    /// Otherwise, the first prefix registered on the nearest element
+    /// is used.
+    ///
+    /// Only prefixes that resolve to the URI from this element are
+    /// considered; a prefix rebound by a closer element is skipped.
+    /// The `xml` prefix is found for its namespace even if never
+    /// registered.
+    ///
+    /// This is the reverse of `namespace_uri_for_prefix`.
     pub fn prefix_for_namespace_uri(
         &self,
         namespace_uri: &str,
@@ -305,6 +710,42 @@ impl<'d> Element<'d> {
         )
     }
 
+    /// The name as it would be written: `prefix:local` or just
+    /// `local`. Like the writer, an element in the default namespace
+    /// in scope is unprefixed; otherwise the preferred prefix is used
+    /// if it is bound to the element's namespace, then any other
+    /// prefix bound to it. When no prefix is bound, the preferred
+    /// prefix is used regardless, as the writer would declare it.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use sxd_document::parser;
+    ///
+    /// let package = parser::parse("<a xmlns:x='urn:x'><x:b/></a>").expect("Failed to parse");
+    /// let doc = package.as_document();
+    /// let a = doc.root().children()[0].element().unwrap();
+    /// let b = a.children()[0].element().unwrap();
+    ///
+    /// assert_eq!(b.qualified_name(), "x:b");
+    /// ```
+    pub fn qualified_name(&self) -> String {
+        let name = self.name();
+        let namespace_uri = match name.namespace_uri() {
+            Some(uri) if self.recursive_default_namespace_uri() != Some(uri) => uri,
+            _ => return name.local_part().to_owned(),
+        };
+
+        let preferred = self.preferred_prefix();
+        match self
+            .prefix_for_namespace_uri(namespace_uri, preferred)
+            .or(preferred)
+        {
+            Some(prefix) => format!("{}:{}", prefix, name.local_part()),
+            None => name.local_part().to_owned(),
+        }
+    }
+
     /// Retrieve all namespaces that are in scope, recursively walking
     /// up the document tree.
     pub fn namespaces_in_scope(&self) -> Vec<Namespace<'d>> {
@@ -315,6 +756,16 @@ impl<'d> Element<'d> {
             .collect()
     }
 
+    /// Retrieve the namespaces registered on this element itself,
+    /// ignoring those inherited from its ancestors, in the order they
+    /// were first registered.
+    pub fn prefixes_declared_here(&self) -> Vec<Namespace<'d>> {
+        self.node()
+            .registered_prefixes()
+            .map(|(prefix, uri)| Namespace { prefix, uri })
+            .collect()
+    }
+
     pub fn preferred_prefix(&self) -> Option<&'d str> {
         self.node().preferred_prefix()
     }
@@ -325,6 +776,42 @@ impl<'d> Element<'d> {
             .element_set_preferred_prefix(self.node, prefix);
     }
 
+    /// Sets the preferred prefix like `set_preferred_prefix`, but
+    /// fails if the prefix is not an NCName.
+    pub fn try_set_preferred_prefix(&self, prefix: Option<&str>) -> Result<(), InvalidName> {
+        validate_prefix(prefix)?;
+        self.set_preferred_prefix(prefix);
+        Ok(())
+    }
+
+    /// The byte offsets in the parsed input from the `<` of the start
+    /// tag to the end of the end tag. Only recorded when the parser
+    /// is asked to with `Parser::set_track_source_spans`.
+    pub fn source_span(&self) -> Option<Range<usize>> {
+        self.node().source_span()
+    }
+
+    pub fn set_source_span(&self, span: Option<Range<usize>>) {
+        self.document
+            .storage
+            .element_set_source_span(self.node, span);
+    }
+
+    /// Whether the element was written as an empty-element tag like
+    /// `<a/>` rather than with an end tag like `<a></a>` in the parsed
+    /// input. `None` for elements that were not parsed.
+    pub fn was_self_closing(&self) -> Option<bool> {
+        self.node().was_self_closing()
+    }
+
+    /// Records how the element was written in the input. The writer
+    /// consults this when asked to keep empty elements as parsed.
+    pub fn set_was_self_closing(&self, self_closing: Option<bool>) {
+        self.document
+            .storage
+            .element_set_was_self_closing(self.node, self_closing);
+    }
+
     pub fn parent(&self) -> Option<ParentOfChild<'d>> {
         self.document
             .connections
@@ -332,6 +819,54 @@ impl<'d> Element<'d> {
             .map(|n| self.document.wrap_parent_of_child(n))
     }
 
+    /// The elements containing this one, starting with its parent
+    /// and ending with the outermost element. The root node is not
+    /// included.
+    pub fn ancestors(&self) -> Ancestors<'d> {
+        Ancestors::new(self.parent())
+    }
+
+    /// The base URI in scope for this element, from the `xml:base`
+    /// attributes on it and its ancestors. Each relative value is
+    /// resolved against the one from further out; the result is still
+    /// relative if no absolute URI was given. Returns `None` if no
+    /// `xml:base` attribute is in scope.
+    ///
+    /// Only the common forms of reference are resolved, and no other
+    /// normalization is done.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use sxd_document::parser;
+    ///
+    /// let xml = "<a xml:base='http://example.com/docs/'><b xml:base='guide/intro.xml'/></a>";
+    /// let package = parser::parse(xml).expect("Failed to parse");
+    /// let doc = package.as_document();
+    /// let a = doc.root().children()[0].element().unwrap();
+    /// let b = a.children()[0].element().unwrap();
+    ///
+    /// assert_eq!(
+    ///     b.base_uri(),
+    ///     Some("http://example.com/docs/guide/intro.xml".to_owned())
+    /// );
+    /// ```
+    pub fn base_uri(&self) -> Option<String> {
+        let bases: Vec<_> = iter::once(*self)
+            .chain(self.ancestors())
+            .filter_map(|e| e.attribute_value((crate::XML_NS_URI, "base")))
+            .collect();
+
+        let (outermost, rest) = bases.split_last()?;
+        let base = rest
+            .iter()
+            .rev()
+            .fold((*outermost).to_owned(), |base, reference| {
+                resolve_uri(&base, reference)
+            });
+        Some(base)
+    }
+
     pub fn remove_from_parent(&self) {
         self.document
             .connections
@@ -348,6 +883,67 @@ impl<'d> Element<'d> {
             .append_element_child(self.node, child.as_raw());
     }
 
+    /// Inserts the child so that it is at `index` among the children
+    /// of this element, detaching it from any previous parent first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is greater than the number of children, not
+    /// counting the child itself.
+    pub fn insert_child_at<C>(&self, index: usize, child: C)
+    where
+        C: Into<ChildOfElement<'d>>,
+    {
+        let child = child.into();
+        self.document
+            .connections
+            .insert_element_child(self.node, index, child.as_raw());
+    }
+
+    /// Inserts the child immediately before `reference`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `reference` is not a child of this element.
+    pub fn insert_before<R, C>(&self, reference: R, child: C)
+    where
+        R: Into<ChildOfElement<'d>>,
+        C: Into<ChildOfElement<'d>>,
+    {
+        let child = child.into();
+        let index = self.sibling_index(reference.into(), child);
+        self.insert_child_at(index, child);
+    }
+
+    /// Inserts the child immediately after `reference`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `reference` is not a child of this element.
+    pub fn insert_after<R, C>(&self, reference: R, child: C)
+    where
+        R: Into<ChildOfElement<'d>>,
+        C: Into<ChildOfElement<'d>>,
+    {
+        let reference = reference.into();
+        let child = child.into();
+        if reference == child {
+            return;
+        }
+        let index = self.sibling_index(reference, child);
+        self.insert_child_at(index + 1, child);
+    }
+
+    /// The position of `reference` among the children once `child` has
+    /// been removed.
+    fn sibling_index(&self, reference: ChildOfElement<'d>, child: ChildOfElement<'d>) -> usize {
+        self.children()
+            .into_iter()
+            .filter(|&c| c != child || c == reference)
+            .position(|c| c == reference)
+            .expect("The reference node is not a child of this element")
+    }
+
     pub fn append_children<I>(&self, children: I)
     where
         I: IntoIterator,
@@ -367,6 +963,32 @@ impl<'d> Element<'d> {
         self.append_children(children);
     }
 
+    /// Parses a fragment of XML content with a default `Parser` and
+    /// appends the resulting nodes to this element. See
+    /// `Parser::append_fragment`; use it directly to parse with
+    /// limits, entities or other options.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use sxd_document::parser;
+    ///
+    /// let package = parser::parse("<list><item>one</item></list>").expect("Failed to parse");
+    /// let doc = package.as_document();
+    /// let list = doc.root().children()[0].element().unwrap();
+    ///
+    /// list.append_fragment_str("<item>two</item>").expect("Failed to append");
+    ///
+    /// assert_eq!(list.children().len(), 2);
+    /// assert_eq!(list.string_value(), "onetwo");
+    /// ```
+    pub fn append_fragment_str(&self, xml: &str) -> Result<(), parser::Error> {
+        parser::Parser::new().append_fragment(*self, xml)
+    }
+
+    /// Detaches the child from this element. The child may later be
+    /// added elsewhere in the document. Does nothing if it is not a
+    /// child of this element.
     pub fn remove_child<C>(&self, child: C)
     where
         C: Into<ChildOfElement<'d>>,
@@ -377,6 +999,7 @@ impl<'d> Element<'d> {
             .remove_element_child(self.node, child.as_raw());
     }
 
+    /// Detaches all children from this element.
     pub fn clear_children(&self) {
         self.document.connections.clear_element_children(self.node);
     }
@@ -404,6 +1027,18 @@ impl<'d> Element<'d> {
             .siblings(raw::Connections::element_following_siblings, self.node)
     }
 
+    /// The sibling node immediately before this one, if any.
+    pub fn preceding_sibling(&self) -> Option<ChildOfElement<'d>> {
+        self.document
+            .nearest_preceding_sibling(raw::Connections::element_preceding_siblings, self.node)
+    }
+
+    /// The sibling node immediately after this one, if any.
+    pub fn following_sibling(&self) -> Option<ChildOfElement<'d>> {
+        self.document
+            .nearest_following_sibling(raw::Connections::element_following_siblings, self.node)
+    }
+
     pub fn attribute<'n, N>(&self, name: N) -> Option<Attribute<'d>>
     where
         N: Into<QName<'n>>,
@@ -414,6 +1049,9 @@ impl<'d> Element<'d> {
             .map(|n| self.document.wrap_attribute(n))
     }
 
+    /// The attributes of this element, in the order they were set.
+    /// Replacing the value of an existing attribute moves it to the
+    /// end.
     pub fn attributes(&self) -> Vec<Attribute<'d>> {
         // This is safe because we make a copy of the children, and
         // the children are never deallocated.
@@ -427,6 +1065,12 @@ impl<'d> Element<'d> {
         }
     }
 
+    /// The number of attributes on this element.
+    pub fn attribute_count(&self) -> usize {
+        // This is safe because we only read the length.
+        unsafe { self.document.connections.attributes(self.node).len() }
+    }
+
     pub fn set_attribute_value<'n, N>(&self, name: N, value: &str) -> Attribute<'d>
     where
         N: Into<QName<'n>>,
@@ -436,6 +1080,22 @@ impl<'d> Element<'d> {
         self.document.wrap_attribute(attr)
     }
 
+    /// Sets an attribute like `set_attribute_value`, but fails if the
+    /// name could not be written as XML. See
+    /// `Document::try_create_element`.
+    pub fn try_set_attribute_value<'n, N>(
+        &self,
+        name: N,
+        value: &str,
+    ) -> Result<Attribute<'d>, InvalidName>
+    where
+        N: Into<QName<'n>>,
+    {
+        let name = name.into();
+        validate_name(name)?;
+        Ok(self.set_attribute_value(name, value))
+    }
+
     pub fn attribute_value<'n, N>(&self, name: N) -> Option<&'d str>
     where
         N: Into<QName<'n>>,
@@ -449,89 +1109,617 @@ impl<'d> Element<'d> {
             })
     }
 
-    pub fn remove_attribute<'n, N>(&self, name: N)
+    /// Removes the attribute with this name, leaving it without a
+    /// parent. Returns whether there was such an attribute.
+    ///
+    /// Namespace declarations are not attributes in this DOM, so this
+    /// never changes how prefixes resolve; see `register_prefix`.
+    pub fn remove_attribute<'n, N>(&self, name: N) -> bool
     where
         N: Into<QName<'n>>,
     {
-        self.document.connections.remove_attribute(self.node, name);
+        self.document.connections.remove_attribute(self.node, name)
     }
 
-    pub fn set_text(&self, text: &str) -> Text<'_> {
+    /// Replaces all children of this element with a single text
+    /// node containing `text`, which is returned. The previous
+    /// children are detached, not destroyed. The text is stored as
+    /// given; it is escaped when the document is written.
+    ///
+    /// The reverse is `string_value`.
+    pub fn set_text(&self, text: &str) -> Text<'d> {
         let text = self.document.create_text(text);
         self.clear_children();
         self.append_child(text);
         text
     }
-}
 
-impl<'d> fmt::Debug for Element<'d> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Element {{ name: {:?} }}", self.name())
+    /// All nodes below this element, in document order.
+    pub fn descendants(&self) -> Descendants<'d> {
+        Descendants::new(self.children())
     }
-}
 
-node!(
-    Attribute,
-    raw::Attribute,
-    "Metadata about the current element"
-);
+    /// All elements below this element, in document order.
+    pub fn descendant_elements(&self) -> impl Iterator<Item = Element<'d>> {
+        self.descendants().filter_map(ChildOfElement::element)
+    }
 
-impl<'d> Attribute<'d> {
-    pub fn name(&self) -> QName<'d> {
-        self.node().name()
+    /// The child elements whose names match `name`.
+    pub fn child_elements_named<'n, N>(&self, name: N) -> Vec<Element<'d>>
+    where
+        N: Into<NameTest<'n>>,
+    {
+        let name = name.into();
+        self.children()
+            .into_iter()
+            .filter_map(ChildOfElement::element)
+            .filter(|e| name.matches(e.name()))
+            .collect()
     }
-    pub fn value(&self) -> &'d str {
-        self.node().value()
+
+    /// All elements below this element whose names match `name`, in
+    /// document order.
+    pub fn find_descendants_named<'n, N>(&self, name: N) -> impl Iterator<Item = Element<'d>> + 'n
+    where
+        N: Into<NameTest<'n>>,
+        'd: 'n,
+    {
+        let name = name.into();
+        self.descendant_elements()
+            .filter(move |e| name.matches(e.name()))
     }
 
-    pub fn preferred_prefix(&self) -> Option<&'d str> {
-        self.node().preferred_prefix()
+    /// The text of all descendant text nodes concatenated in document
+    /// order; the XPath string-value of the element.
+    pub fn string_value(&self) -> String {
+        self.descendants()
+            .filter_map(ChildOfElement::text)
+            .map(|t| t.text())
+            .collect()
     }
 
-    pub fn set_preferred_prefix(&self, prefix: Option<&str>) {
-        self.document
-            .storage
-            .attribute_set_preferred_prefix(self.node, prefix);
+    /// Merges adjacent text children into a single text node and
+    /// removes empty text nodes, throughout this element and its
+    /// descendants. Text from CDATA sections is not merged with other
+    /// text.
+    pub fn normalize(&self) {
+        let mut todo = vec![*self];
+
+        while let Some(element) = todo.pop() {
+            // The first text node of the current run and the combined
+            // text of the run
+            let mut run: Option<(Text<'d>, String)> = None;
+
+            for child in element.children() {
+                match child {
+                    ChildOfElement::Text(t) if t.text().is_empty() => {
+                        element.remove_child(t);
+                        continue;
+                    }
+                    ChildOfElement::Text(t) if !t.is_cdata() => {
+                        if let Some((first, ref mut merged)) = run {
+                            merged.push_str(t.text());
+                            if !t.is_ignorable_whitespace() {
+                                first.set_ignorable_whitespace(false);
+                            }
+                            element.remove_child(t);
+                        } else {
+                            run = Some((t, t.text().to_owned()));
+                        }
+                        continue;
+                    }
+                    ChildOfElement::Element(e) => todo.push(e),
+                    _ => {}
+                }
+
+                finish_text_run(run.take());
+            }
+
+            finish_text_run(run);
+        }
     }
 
-    pub fn parent(&self) -> Option<Element<'d>> {
-        self.document
-            .connections
-            .attribute_parent(self.node)
-            .map(|n| self.document.wrap_element(n))
+    /// Walks the nodes below this element in document order, calling
+    /// `f` with each one and applying the `NodeAction` it returns. A
+    /// node is visited before its children, which are only visited
+    /// if it is kept. A replacement node is not itself visited.
+    ///
+    /// The children of each element are collected before any action
+    /// is applied, so nodes may be removed or replaced while walking.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use sxd_document::{dom::{ChildOfElement, NodeAction}, parser};
+    ///
+    /// let package = parser::parse("<a>one<!--c--><b>two<!--d--></b></a>").expect("Failed to parse");
+    /// let doc = package.as_document();
+    /// let a = doc.root().children()[0].element().unwrap();
+    ///
+    /// a.transform(&mut |node| match node {
+    ///     ChildOfElement::Comment(_) => NodeAction::Remove,
+    ///     _ => NodeAction::Keep,
+    /// });
+    ///
+    /// assert!(a.descendants().all(|n| n.comment().is_none()));
+    /// assert_eq!(a.string_value(), "onetwo");
+    /// ```
+    pub fn transform<F>(&self, f: &mut F)
+    where
+        F: FnMut(ChildOfElement<'d>) -> NodeAction<'d>,
+    {
+        let mut todo: Vec<_> = self.children().into_iter().map(|c| (*self, c)).collect();
+        todo.reverse();
+
+        while let Some((parent, child)) = todo.pop() {
+            match f(child) {
+                NodeAction::Keep => {
+                    if let ChildOfElement::Element(e) = child {
+                        todo.extend(e.children().into_iter().rev().map(|c| (e, c)));
+                    }
+                }
+                NodeAction::Remove => parent.remove_child(child),
+                NodeAction::ReplaceWith(replacement) => {
+                    if replacement != child {
+                        parent.insert_before(child, replacement);
+                        parent.remove_child(child);
+                    }
+                }
+            }
+        }
     }
 
-    pub fn remove_from_parent(&self) {
-        self.document
-            .connections
-            .remove_attribute_from_parent(self.node);
+    /// Compares this element and its descendants with another by
+    /// their logical content. Elements and attributes are compared by
+    /// expanded name, ignoring the prefixes used to write them, and
+    /// attributes are compared regardless of order. Adjacent text is
+    /// compared as a whole, whether or not it was written as CDATA.
+    /// When `ignore_whitespace` is set, text consisting only of
+    /// whitespace is skipped. Namespace registrations are ignored.
+    pub fn canonical_eq(&self, other: Element<'_>, ignore_whitespace: bool) -> bool {
+        elements_canonical_eq(vec![(*self, other)], ignore_whitespace)
     }
-}
 
-impl<'d> fmt::Debug for Attribute<'d> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "Attribute {{ name: {:?}, value: {:?} }}",
-            self.name(),
-            self.value()
-        )
+    /// Creates a new, detached element with the same name, namespace
+    /// registrations, preferred prefix, attributes and
+    /// `was_self_closing` as this one, but without any children.
+    pub fn shallow_clone(&self) -> Element<'d> {
+        let doc = self.document;
+        let element = doc.create_element(self.name());
+
+        element.set_default_namespace_uri(self.default_namespace_uri());
+        element.set_preferred_prefix(self.preferred_prefix());
+        element.set_was_self_closing(self.was_self_closing());
+
+        for (prefix, namespace_uri) in self.node().registered_prefixes() {
+            element.register_prefix(prefix, namespace_uri);
+        }
+
+        for attribute in self.attributes() {
+            let clone = element.set_attribute_value(attribute.name(), attribute.value());
+            clone.set_preferred_prefix(attribute.preferred_prefix());
+        }
+
+        element
+    }
+
+    /// Creates a new, detached copy of this element and all of its
+    /// descendants.
+    pub fn deep_clone(&self) -> Element<'d> {
+        let doc = self.document;
+        let clone = self.shallow_clone();
+        let mut todo = vec![(*self, clone)];
+
+        while let Some((original, clone)) = todo.pop() {
+            for child in original.children() {
+                match child {
+                    ChildOfElement::Element(e) => {
+                        let child_clone = e.shallow_clone();
+                        clone.append_child(child_clone);
+                        todo.push((e, child_clone));
+                    }
+                    ChildOfElement::Text(t) => {
+                        let text = doc.create_text(t.text());
+                        text.set_cdata(t.is_cdata());
+                        text.set_ignorable_whitespace(t.is_ignorable_whitespace());
+                        clone.append_child(text);
+                    }
+                    ChildOfElement::Comment(c) => {
+                        clone.append_child(doc.create_comment(c.text()));
+                    }
+                    ChildOfElement::ProcessingInstruction(pi) => {
+                        let pi = doc.create_processing_instruction(pi.target(), pi.value());
+                        clone.append_child(pi);
+                    }
+                }
+            }
+        }
+
+        clone
     }
 }
 
-node!(Text, raw::Text, "Textual data");
-
-impl<'d> Text<'d> {
-    pub fn text(&self) -> &'d str {
-        self.node().text()
+fn finish_text_run(run: Option<(Text<'_>, String)>) {
+    if let Some((first, merged)) = run {
+        if merged.len() != first.text().len() {
+            first.set_text(&merged);
+        }
     }
+}
 
-    pub fn set_text(&self, text: &str) {
-        self.document.storage.text_set_text(self.node, text)
+/// The length of the scheme, including the colon, if the URI has one.
+fn uri_scheme_len(uri: &str) -> Option<usize> {
+    let colon = uri.find(&[':', '/', '?', '#'][..])?;
+    let scheme = &uri[..colon];
+    let valid = uri[colon..].starts_with(':')
+        && scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.');
+    if valid {
+        Some(colon + 1)
+    } else {
+        None
     }
+}
 
-    pub fn parent(&self) -> Option<Element<'d>> {
-        self.document
+/// Splits the URI into the scheme and authority, the path, and the
+/// query and fragment.
+fn split_uri(uri: &str) -> (&str, &str, &str) {
+    let mut start = uri_scheme_len(uri).unwrap_or(0);
+    if uri[start..].starts_with("//") {
+        start += 2;
+        start += uri[start..]
+            .find(&['/', '?', '#'][..])
+            .unwrap_or(uri.len() - start);
+    }
+    let end = start
+        + uri[start..]
+            .find(&['?', '#'][..])
+            .unwrap_or(uri.len() - start);
+    (&uri[..start], &uri[start..end], &uri[end..])
+}
+
+/// Removes `.` and `..` segments from the path, as described in
+/// section 5.2.4 of RFC 3986. A relative path keeps the `..`
+/// segments that go above its start.
+fn remove_dot_segments(path: &str) -> String {
+    let (root, relative) = if path.starts_with('/') {
+        ("/", &path[1..])
+    } else {
+        ("", path)
+    };
+
+    let mut segments = Vec::new();
+    let mut trailing_slash = false;
+    for segment in relative.split('/') {
+        trailing_slash = segment == "." || segment == "..";
+        match segment {
+            "." => {}
+            ".." => match segments.last() {
+                Some(&last) if last != ".." => {
+                    segments.pop();
+                }
+                _ if root.is_empty() => segments.push(".."),
+                _ => {}
+            },
+            segment => segments.push(segment),
+        }
+    }
+    if trailing_slash {
+        segments.push("");
+    }
+
+    format!("{}{}", root, segments.join("/"))
+}
+
+/// Resolves the reference against the base URI, following section
+/// 5.2.2 of RFC 3986 for the common forms of reference.
+fn resolve_uri(base: &str, reference: &str) -> String {
+    if uri_scheme_len(reference).is_some() {
+        let (prefix, path, rest) = split_uri(reference);
+        return format!("{}{}{}", prefix, remove_dot_segments(path), rest);
+    }
+
+    let (prefix, path, _) = split_uri(base);
+    let without_fragment = &base[..base.find('#').unwrap_or(base.len())];
+
+    if reference.starts_with("//") {
+        let scheme = &base[..uri_scheme_len(base).unwrap_or(0)];
+        return format!("{}{}", scheme, reference);
+    }
+    if reference.is_empty() || reference.starts_with('#') {
+        return format!("{}{}", without_fragment, reference);
+    }
+    if reference.starts_with('?') {
+        return format!("{}{}{}", prefix, path, reference);
+    }
+
+    let (_, reference_path, reference_rest) = split_uri(reference);
+    let merged = if reference_path.starts_with('/') {
+        reference_path.to_owned()
+    } else if path.is_empty() && prefix.contains("//") {
+        format!("/{}", reference_path)
+    } else {
+        let directory = path.rfind('/').map_or("", |i| &path[..=i]);
+        format!("{}{}", directory, reference_path)
+    };
+
+    format!(
+        "{}{}{}",
+        prefix,
+        remove_dot_segments(&merged),
+        reference_rest
+    )
+}
+
+/// A child node as seen by `canonical_eq`, with adjacent text merged.
+#[derive(PartialEq)]
+enum CanonicalChild<'d> {
+    Element(QName<'d>),
+    Text(String),
+    Comment(&'d str),
+    ProcessingInstruction(&'d str, Option<&'d str>),
+}
+
+fn canonical_children<'d, C>(
+    children: Vec<C>,
+    ignore_whitespace: bool,
+    elements: &mut Vec<Element<'d>>,
+) -> Vec<CanonicalChild<'d>>
+where
+    C: Into<ChildOfElement<'d>>,
+{
+    let mut canonical = Vec::new();
+    let mut text = String::new();
+
+    let finish_text = |text: &mut String, canonical: &mut Vec<_>| {
+        let skip = ignore_whitespace && text.chars().all(|c| c.is_space_char());
+        if !text.is_empty() && !skip {
+            canonical.push(CanonicalChild::Text(text.clone()));
+        }
+        text.clear();
+    };
+
+    for child in children {
+        let child = match child.into() {
+            ChildOfElement::Text(t) => {
+                text.push_str(t.text());
+                continue;
+            }
+            ChildOfElement::Element(e) => {
+                elements.push(e);
+                CanonicalChild::Element(e.name())
+            }
+            ChildOfElement::Comment(c) => CanonicalChild::Comment(c.text()),
+            ChildOfElement::ProcessingInstruction(pi) => {
+                CanonicalChild::ProcessingInstruction(pi.target(), pi.value())
+            }
+        };
+
+        finish_text(&mut text, &mut canonical);
+        canonical.push(child);
+    }
+    finish_text(&mut text, &mut canonical);
+
+    canonical
+}
+
+/// Compares the children of two nodes, queueing their child elements
+/// to be compared in turn.
+fn canonical_children_eq<'a, 'b, A, B>(
+    a: Vec<A>,
+    b: Vec<B>,
+    ignore_whitespace: bool,
+    todo: &mut Vec<(Element<'a>, Element<'b>)>,
+) -> bool
+where
+    A: Into<ChildOfElement<'a>>,
+    B: Into<ChildOfElement<'b>>,
+{
+    let mut a_elements = Vec::new();
+    let mut b_elements = Vec::new();
+
+    let a = canonical_children(a, ignore_whitespace, &mut a_elements);
+    let b = canonical_children(b, ignore_whitespace, &mut b_elements);
+
+    todo.extend(a_elements.into_iter().zip(b_elements));
+    a == b
+}
+
+fn elements_canonical_eq<'a, 'b>(
+    mut todo: Vec<(Element<'a>, Element<'b>)>,
+    ignore_whitespace: bool,
+) -> bool {
+    fn sorted_attributes<'d>(element: Element<'d>) -> Vec<(Option<&'d str>, &'d str, &'d str)> {
+        let mut attributes: Vec<_> = element
+            .attributes()
+            .into_iter()
+            .map(|a| {
+                let name = a.name();
+                (name.namespace_uri(), name.local_part(), a.value())
+            })
+            .collect();
+        attributes.sort_unstable();
+        attributes
+    }
+
+    while let Some((a, b)) = todo.pop() {
+        if a.name() != b.name() || sorted_attributes(a) != sorted_attributes(b) {
+            return false;
+        }
+
+        if !canonical_children_eq(a.children(), b.children(), ignore_whitespace, &mut todo) {
+            return false;
+        }
+    }
+
+    true
+}
+
+impl<'d> fmt::Debug for Element<'d> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Element {{ name: {:?} }}", self.name())
+    }
+}
+
+node!(
+    Attribute,
+    raw::Attribute,
+    "Metadata about the current element"
+);
+
+impl<'d> Attribute<'d> {
+    pub fn name(&self) -> QName<'d> {
+        self.node().name()
+    }
+
+    /// Renames the attribute, keeping its value. Another attribute of
+    /// the same element with the new name is removed, as
+    /// `Element::set_attribute_value` would replace it. When the
+    /// namespace URI changes, the preferred prefix is cleared, as for
+    /// `Element::set_name`.
+    pub fn set_name<'n, N>(&self, name: N)
+    where
+        N: Into<QName<'n>>,
+    {
+        let name = name.into();
+        if let Some(parent) = self.parent() {
+            if let Some(other) = parent.attribute(name).filter(|a| a != self) {
+                other.remove_from_parent();
+            }
+        }
+        if name.namespace_uri() != self.name().namespace_uri() {
+            self.set_preferred_prefix(None);
+        }
+        self.document.storage.attribute_set_name(self.node, name)
+    }
+
+    /// Renames the attribute like `set_name`, but fails if the name
+    /// could not be written as XML. See `Document::try_create_element`.
+    pub fn try_set_name<'n, N>(&self, name: N) -> Result<(), InvalidName>
+    where
+        N: Into<QName<'n>>,
+    {
+        let name = name.into();
+        validate_name(name)?;
+        self.set_name(name);
+        Ok(())
+    }
+
+    /// The namespace URI of the attribute's name. Unprefixed
+    /// attributes are in no namespace, whatever the default namespace.
+    pub fn namespace_uri(&self) -> Option<&'d str> {
+        self.name().namespace_uri()
+    }
+
+    /// The local part of the attribute's name, without any prefix.
+    pub fn local_name(&self) -> &'d str {
+        self.name().local_part()
+    }
+
+    pub fn value(&self) -> &'d str {
+        self.node().value()
+    }
+
+    pub fn preferred_prefix(&self) -> Option<&'d str> {
+        self.node().preferred_prefix()
+    }
+
+    pub fn set_preferred_prefix(&self, prefix: Option<&str>) {
+        self.document
+            .storage
+            .attribute_set_preferred_prefix(self.node, prefix);
+    }
+
+    /// Sets the preferred prefix like `set_preferred_prefix`, but
+    /// fails if the prefix is not an NCName.
+    pub fn try_set_preferred_prefix(&self, prefix: Option<&str>) -> Result<(), InvalidName> {
+        validate_prefix(prefix)?;
+        self.set_preferred_prefix(prefix);
+        Ok(())
+    }
+
+    pub fn parent(&self) -> Option<Element<'d>> {
+        self.document
+            .connections
+            .attribute_parent(self.node)
+            .map(|n| self.document.wrap_element(n))
+    }
+
+    pub fn remove_from_parent(&self) {
+        self.document
+            .connections
+            .remove_attribute_from_parent(self.node);
+    }
+}
+
+impl<'d> fmt::Debug for Attribute<'d> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Attribute {{ name: {:?}, value: {:?} }}",
+            self.name(),
+            self.value()
+        )
+    }
+}
+
+node!(Text, raw::Text, "Textual data");
+
+impl<'d> Text<'d> {
+    pub fn text(&self) -> &'d str {
+        self.node().text()
+    }
+
+    /// Replaces the content of this text node in place. The node
+    /// keeps its identity, its position among its siblings and
+    /// whether it is written as a CDATA section. Any source span still
+    /// refers to the original input.
+    pub fn set_text(&self, text: &str) {
+        self.document.storage.text_set_text(self.node, text)
+    }
+
+    /// Whether the text came from, and will be written as, a CDATA
+    /// section.
+    pub fn is_cdata(&self) -> bool {
+        self.node().is_cdata()
+    }
+
+    pub fn set_cdata(&self, cdata: bool) {
+        self.document.storage.text_set_cdata(self.node, cdata)
+    }
+
+    /// Whether this text is whitespace between elements that is likely
+    /// insignificant, such as indentation. The parser only marks text
+    /// when asked to with `Parser::set_mark_whitespace_between_elements`.
+    /// The text is still written unchanged.
+    pub fn is_ignorable_whitespace(&self) -> bool {
+        self.node().is_ignorable_whitespace()
+    }
+
+    /// Marks or unmarks this text as ignorable whitespace. Nothing
+    /// checks that the text is actually whitespace.
+    pub fn set_ignorable_whitespace(&self, ignorable: bool) {
+        self.document
+            .storage
+            .text_set_ignorable_whitespace(self.node, ignorable)
+    }
+
+    /// The byte offsets in the parsed input of the character data,
+    /// CDATA section or reference this text came from. Only recorded
+    /// when the parser is asked to with
+    /// `Parser::set_track_source_spans`.
+    pub fn source_span(&self) -> Option<Range<usize>> {
+        self.node().source_span()
+    }
+
+    pub fn set_source_span(&self, span: Option<Range<usize>>) {
+        self.document.storage.text_set_source_span(self.node, span)
+    }
+
+    pub fn parent(&self) -> Option<Element<'d>> {
+        self.document
             .connections
             .text_parent(self.node)
             .map(|n| self.document.wrap_element(n))
@@ -550,6 +1738,18 @@ impl<'d> Text<'d> {
         self.document
             .siblings(raw::Connections::text_following_siblings, self.node)
     }
+
+    /// The sibling node immediately before this one, if any.
+    pub fn preceding_sibling(&self) -> Option<ChildOfElement<'d>> {
+        self.document
+            .nearest_preceding_sibling(raw::Connections::text_preceding_siblings, self.node)
+    }
+
+    /// The sibling node immediately after this one, if any.
+    pub fn following_sibling(&self) -> Option<ChildOfElement<'d>> {
+        self.document
+            .nearest_following_sibling(raw::Connections::text_following_siblings, self.node)
+    }
 }
 
 impl<'d> fmt::Debug for Text<'d> {
@@ -569,6 +1769,19 @@ impl<'d> Comment<'d> {
         self.document.storage.comment_set_text(self.node, new_text)
     }
 
+    /// The byte offsets of the whole comment in the parsed input. Only
+    /// recorded when the parser is asked to with
+    /// `Parser::set_track_source_spans`.
+    pub fn source_span(&self) -> Option<Range<usize>> {
+        self.node().source_span()
+    }
+
+    pub fn set_source_span(&self, span: Option<Range<usize>>) {
+        self.document
+            .storage
+            .comment_set_source_span(self.node, span)
+    }
+
     pub fn parent(&self) -> Option<ParentOfChild<'d>> {
         self.document
             .connections
@@ -591,6 +1804,18 @@ impl<'d> Comment<'d> {
         self.document
             .siblings(raw::Connections::comment_following_siblings, self.node)
     }
+
+    /// The sibling node immediately before this one, if any.
+    pub fn preceding_sibling(&self) -> Option<ChildOfElement<'d>> {
+        self.document
+            .nearest_preceding_sibling(raw::Connections::comment_preceding_siblings, self.node)
+    }
+
+    /// The sibling node immediately after this one, if any.
+    pub fn following_sibling(&self) -> Option<ChildOfElement<'d>> {
+        self.document
+            .nearest_following_sibling(raw::Connections::comment_following_siblings, self.node)
+    }
 }
 
 impl<'d> fmt::Debug for Comment<'d> {
@@ -625,6 +1850,19 @@ impl<'d> ProcessingInstruction<'d> {
             .processing_instruction_set_value(self.node, new_value);
     }
 
+    /// The byte offsets of the whole processing instruction in the
+    /// parsed input. Only recorded when the parser is asked to with
+    /// `Parser::set_track_source_spans`.
+    pub fn source_span(&self) -> Option<Range<usize>> {
+        self.node().source_span()
+    }
+
+    pub fn set_source_span(&self, span: Option<Range<usize>>) {
+        self.document
+            .storage
+            .processing_instruction_set_source_span(self.node, span);
+    }
+
     pub fn parent(&self) -> Option<ParentOfChild<'d>> {
         self.document
             .connections
@@ -651,6 +1889,22 @@ impl<'d> ProcessingInstruction<'d> {
             self.node,
         )
     }
+
+    /// The sibling node immediately before this one, if any.
+    pub fn preceding_sibling(&self) -> Option<ChildOfElement<'d>> {
+        self.document.nearest_preceding_sibling(
+            raw::Connections::processing_instruction_preceding_siblings,
+            self.node,
+        )
+    }
+
+    /// The sibling node immediately after this one, if any.
+    pub fn following_sibling(&self) -> Option<ChildOfElement<'d>> {
+        self.document.nearest_following_sibling(
+            raw::Connections::processing_instruction_following_siblings,
+            self.node,
+        )
+    }
 }
 
 impl<'d> fmt::Debug for ProcessingInstruction<'d> {
@@ -675,8 +1929,68 @@ macro_rules! unpack(
     )
 );
 
+node!(
+    DocumentType,
+    raw::DocumentType,
+    "The document type declaration. Only its name, external identifier
+    and the raw text of its internal subset are kept."
+);
+
+impl<'d> DocumentType<'d> {
+    pub fn name(&self) -> &'d str {
+        self.node().name()
+    }
+
+    pub fn set_name(&self, name: &str) {
+        self.document
+            .storage
+            .document_type_set_name(self.node, name);
+    }
+
+    /// The public identifier of the external subset. It is only
+    /// written along with a system identifier.
+    pub fn public_id(&self) -> Option<&'d str> {
+        self.node().public_id()
+    }
+
+    pub fn set_public_id(&self, id: Option<&str>) {
+        self.document
+            .storage
+            .document_type_set_public_id(self.node, id);
+    }
+
+    /// The system identifier, usually a URI, of the external subset.
+    pub fn system_id(&self) -> Option<&'d str> {
+        self.node().system_id()
+    }
+
+    pub fn set_system_id(&self, id: Option<&str>) {
+        self.document
+            .storage
+            .document_type_set_system_id(self.node, id);
+    }
+
+    /// The text between the `[` and `]` of the declaration, exactly
+    /// as it was parsed.
+    pub fn internal_subset(&self) -> Option<&'d str> {
+        self.node().internal_subset()
+    }
+
+    pub fn set_internal_subset(&self, subset: Option<&str>) {
+        self.document
+            .storage
+            .document_type_set_internal_subset(self.node, subset);
+    }
+}
+
+impl<'d> fmt::Debug for DocumentType<'d> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DocumentType {{ name: {:?} }}", self.name())
+    }
+}
+
 /// Nodes that may occur as a child of the root node
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum ChildOfRoot<'d> {
     Element(Element<'d>),
     Comment(Comment<'d>),
@@ -693,6 +2007,26 @@ impl<'d> ChildOfRoot<'d> {
         ProcessingInstruction
     );
 
+    /// Calls the method of the visitor matching this node's kind.
+    pub fn accept<V>(&self, visitor: &mut V)
+    where
+        V: Visitor<'d> + ?Sized,
+    {
+        match *self {
+            ChildOfRoot::Element(n) => visitor.visit_element(n),
+            ChildOfRoot::Comment(n) => visitor.visit_comment(n),
+            ChildOfRoot::ProcessingInstruction(n) => visitor.visit_processing_instruction(n),
+        }
+    }
+
+    pub fn parent(&self) -> Option<ParentOfChild<'d>> {
+        match *self {
+            ChildOfRoot::Element(n) => n.parent(),
+            ChildOfRoot::Comment(n) => n.parent(),
+            ChildOfRoot::ProcessingInstruction(n) => n.parent(),
+        }
+    }
+
     fn as_raw(&self) -> raw::ChildOfRoot {
         match *self {
             ChildOfRoot::Element(n) => raw::ChildOfRoot::Element(n.node),
@@ -705,7 +2039,7 @@ impl<'d> ChildOfRoot<'d> {
 }
 
 /// Nodes that may occur as a child of an element node
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum ChildOfElement<'d> {
     Element(Element<'d>),
     Text(Text<'d>),
@@ -724,6 +2058,35 @@ impl<'d> ChildOfElement<'d> {
         ProcessingInstruction
     );
 
+    /// Calls the method of the visitor matching this node's kind.
+    pub fn accept<V>(&self, visitor: &mut V)
+    where
+        V: Visitor<'d> + ?Sized,
+    {
+        match *self {
+            ChildOfElement::Element(n) => visitor.visit_element(n),
+            ChildOfElement::Text(n) => visitor.visit_text(n),
+            ChildOfElement::Comment(n) => visitor.visit_comment(n),
+            ChildOfElement::ProcessingInstruction(n) => visitor.visit_processing_instruction(n),
+        }
+    }
+
+    pub fn parent(&self) -> Option<ParentOfChild<'d>> {
+        match *self {
+            ChildOfElement::Element(n) => n.parent(),
+            ChildOfElement::Text(n) => n.parent().map(ParentOfChild::Element),
+            ChildOfElement::Comment(n) => n.parent(),
+            ChildOfElement::ProcessingInstruction(n) => n.parent(),
+        }
+    }
+
+    /// The elements containing this node, starting with its parent
+    /// and ending with the outermost element. The root node is not
+    /// included.
+    pub fn ancestors(&self) -> Ancestors<'d> {
+        Ancestors::new(self.parent())
+    }
+
     fn as_raw(&self) -> raw::ChildOfElement {
         match *self {
             ChildOfElement::Element(n) => raw::ChildOfElement::Element(n.node),
@@ -736,8 +2099,60 @@ impl<'d> ChildOfElement<'d> {
     }
 }
 
+/// Receives child nodes by kind from `ChildOfRoot::accept` and
+/// `ChildOfElement::accept`. Every method does nothing by default.
+///
+/// Visiting is not recursive; to walk a tree, visit the children of
+/// an element from `visit_element`.
+///
+/// ### Example
+///
+/// ```
+/// use sxd_document::{dom, parser};
+///
+/// #[derive(Default)]
+/// struct TextLength(usize);
+///
+/// impl<'d> dom::Visitor<'d> for TextLength {
+///     fn visit_element(&mut self, element: dom::Element<'d>) {
+///         for child in element.children() {
+///             child.accept(self);
+///         }
+///     }
+///
+///     fn visit_text(&mut self, text: dom::Text<'d>) {
+///         self.0 += text.text().len();
+///     }
+/// }
+///
+/// let package = parser::parse("<a>one<b>two</b></a>").expect("Failed to parse");
+/// let mut length = TextLength::default();
+/// for child in package.as_document().root().children() {
+///     child.accept(&mut length);
+/// }
+/// assert_eq!(length.0, 6);
+/// ```
+pub trait Visitor<'d> {
+    fn visit_element(&mut self, _element: Element<'d>) {}
+    fn visit_text(&mut self, _text: Text<'d>) {}
+    fn visit_comment(&mut self, _comment: Comment<'d>) {}
+    fn visit_processing_instruction(&mut self, _pi: ProcessingInstruction<'d>) {}
+}
+
+/// What `Element::transform` should do with the node it just
+/// visited.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NodeAction<'d> {
+    /// Leave the node in place and visit its children.
+    Keep,
+    /// Detach the node from its parent.
+    Remove,
+    /// Put the given node in place of this one, which is detached.
+    ReplaceWith(ChildOfElement<'d>),
+}
+
 /// Nodes that may occur as the parent of a child node
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum ParentOfChild<'d> {
     Root(Root<'d>),
     Element(Element<'d>),
@@ -793,16 +2208,78 @@ impl<'d> From<ChildOfRoot<'d>> for ChildOfElement<'d> {
     }
 }
 
-#[cfg(test)]
-mod test {
-    use super::{
-        super::{Package, QName},
-        ChildOfElement, ChildOfRoot, ParentOfChild,
-    };
-
-    macro_rules! assert_qname_eq(
-        ($l:expr, $r:expr) => (assert_eq!(Into::<QName<'_>>::into($l), $r.into()));
-    );
+/// An iterator over descendant nodes in document order, created by
+/// `Document::descendants` or `Element::descendants`.
+///
+/// The children of an element are only retrieved once the element
+/// itself has been visited.
+#[derive(Debug, Clone)]
+pub struct Descendants<'d> {
+    todo: Vec<ChildOfElement<'d>>,
+}
+
+impl<'d> Descendants<'d> {
+    fn new<I>(children: I) -> Descendants<'d>
+    where
+        I: IntoIterator,
+        I::Item: Into<ChildOfElement<'d>>,
+    {
+        let mut todo: Vec<_> = children.into_iter().map(Into::into).collect();
+        todo.reverse();
+        Descendants { todo }
+    }
+}
+
+impl<'d> Iterator for Descendants<'d> {
+    type Item = ChildOfElement<'d>;
+
+    fn next(&mut self) -> Option<ChildOfElement<'d>> {
+        let node = self.todo.pop()?;
+
+        if let ChildOfElement::Element(e) = node {
+            self.todo.extend(e.children().into_iter().rev());
+        }
+
+        Some(node)
+    }
+}
+
+/// An iterator over the elements containing a node, created by
+/// `Element::ancestors` or `ChildOfElement::ancestors`.
+#[derive(Debug, Clone)]
+pub struct Ancestors<'d> {
+    next: Option<Element<'d>>,
+}
+
+impl<'d> Ancestors<'d> {
+    fn new(parent: Option<ParentOfChild<'d>>) -> Ancestors<'d> {
+        Ancestors {
+            next: parent.and_then(ParentOfChild::element),
+        }
+    }
+}
+
+impl<'d> Iterator for Ancestors<'d> {
+    type Item = Element<'d>;
+
+    fn next(&mut self) -> Option<Element<'d>> {
+        let element = self.next?;
+        self.next = element.parent().and_then(ParentOfChild::element);
+        Some(element)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        super::{parser, NameTest, Package, QName},
+        ChildOfElement, ChildOfRoot, Comment, Element, InvalidName, NodeAction, ParentOfChild,
+        ProcessingInstruction, Text, Visitor,
+    };
+
+    macro_rules! assert_qname_eq(
+        ($l:expr, $r:expr) => (assert_eq!(Into::<QName<'_>>::into($l), $r.into()));
+    );
 
     #[test]
     fn the_root_belongs_to_a_document() {
@@ -844,6 +2321,53 @@ mod test {
         let children = root.children();
         assert_eq!(1, children.len());
         assert_eq!(children[0], ChildOfRoot::Element(beta));
+        assert_eq!(alpha.parent(), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn root_rejects_inserting_a_second_element_child() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let root = doc.root();
+        root.append_child(doc.create_comment("c"));
+        root.append_child(doc.create_element("alpha"));
+
+        root.insert_child_at(0, doc.create_element("beta"));
+    }
+
+    #[test]
+    fn root_can_move_its_element_child() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let root = doc.root();
+        let element = doc.create_element("alpha");
+        let comment = doc.create_comment("c");
+        root.append_child(element);
+        root.append_child(comment);
+
+        root.insert_child_at(1, element);
+
+        assert_eq!(root.children(), vec![comment.into(), element.into()]);
+    }
+
+    #[test]
+    fn root_can_add_a_prolog_before_a_parsed_element() {
+        let package = parser::parse("<a/><!--after-->").unwrap();
+        let doc = package.as_document();
+
+        let root = doc.root();
+        root.insert_child_at(0, doc.create_comment("before"));
+        root.insert_child_at(0, doc.create_processing_instruction("pi", None));
+        let after = root.children()[3];
+        root.remove_child(after);
+
+        assert_eq!(
+            doc.to_string(),
+            "<?xml version='1.0'?><?pi?><!--before--><a/>"
+        );
     }
 
     #[test]
@@ -938,145 +2462,1457 @@ mod test {
 
         root.clear_children();
 
-        assert!(root.children().is_empty());
-        assert!(element.parent().is_none());
+        assert!(root.children().is_empty());
+        assert!(element.parent().is_none());
+    }
+
+    #[test]
+    fn root_child_knows_its_parent() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let root = doc.root();
+        let alpha = doc.create_element("alpha");
+
+        root.append_child(alpha);
+
+        assert_eq!(Some(ParentOfChild::Root(root)), alpha.parent());
+    }
+
+    #[test]
+    fn documents_can_record_an_encoding() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        assert_eq!(None, doc.encoding());
+
+        doc.set_encoding(Some("ISO-8859-1"));
+        assert_eq!(Some("ISO-8859-1"), doc.encoding());
+
+        doc.set_encoding(None);
+        assert_eq!(None, doc.encoding());
+    }
+
+    #[test]
+    fn qualified_name_of_a_prefixed_element() {
+        let package = parser::parse("<a xmlns:p='urn:p'><p:b/></a>").unwrap();
+        let doc = package.as_document();
+        let a = doc.root().children()[0].element().unwrap();
+        let b = a.children()[0].element().unwrap();
+
+        assert_eq!(b.qualified_name(), "p:b");
+    }
+
+    #[test]
+    fn qualified_name_uses_an_in_scope_prefix_for_the_namespace() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let parent = doc.create_element("parent");
+        parent.register_prefix("p", "urn:p");
+        let child = doc.create_element(("urn:p", "child"));
+        parent.append_child(child);
+
+        assert_eq!(child.qualified_name(), "p:child");
+    }
+
+    #[test]
+    fn qualified_name_of_a_default_namespaced_element() {
+        let package = parser::parse("<a xmlns='urn:d'><b/></a>").unwrap();
+        let doc = package.as_document();
+        let a = doc.root().children()[0].element().unwrap();
+        let b = a.children()[0].element().unwrap();
+
+        assert_eq!(a.qualified_name(), "a");
+        assert_eq!(b.qualified_name(), "b");
+    }
+
+    #[test]
+    fn qualified_name_of_an_element_in_no_namespace() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let element = doc.create_element("plain");
+
+        assert_eq!(element.qualified_name(), "plain");
+    }
+
+    #[test]
+    fn documents_can_record_the_version() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        assert_eq!(None, doc.version());
+
+        doc.set_version(Some("1.1"));
+        assert_eq!(Some("1.1"), doc.version());
+    }
+
+    #[test]
+    fn documents_can_record_standalone() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        assert_eq!(None, doc.standalone());
+
+        doc.set_standalone(Some(false));
+        assert_eq!(Some(false), doc.standalone());
+    }
+
+    #[test]
+    fn elements_belong_to_a_document() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("alpha");
+
+        assert_eq!(doc, element.document());
+    }
+
+    #[test]
+    fn elements_can_have_element_children() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let beta = doc.create_element("beta");
+
+        alpha.append_child(beta);
+
+        let children = alpha.children();
+
+        assert_eq!(children[0], ChildOfElement::Element(beta));
+    }
+
+    #[test]
+    fn elements_can_append_multiple_children() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let beta = doc.create_element("beta");
+        let gamma = doc.create_element("gamma");
+
+        alpha.append_children(&[beta, gamma]);
+
+        let children = alpha.children();
+        assert_eq!(2, children.len());
+        assert_eq!(children[0], ChildOfElement::Element(beta));
+        assert_eq!(children[1], ChildOfElement::Element(gamma));
+    }
+
+    #[test]
+    fn elements_can_replace_children() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let beta = doc.create_element("beta");
+        let gamma = doc.create_element("gamma");
+        let zeta = doc.create_element("zeta");
+        alpha.append_child(zeta);
+
+        alpha.replace_children(&[beta, gamma]);
+
+        let children = alpha.children();
+        assert_eq!(2, children.len());
+        assert_eq!(children[0], ChildOfElement::Element(beta));
+        assert_eq!(children[1], ChildOfElement::Element(gamma));
+    }
+
+    #[test]
+    fn elements_can_remove_children() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let beta = doc.create_element("beta");
+        alpha.append_child(beta);
+
+        alpha.remove_child(beta);
+
+        assert!(alpha.children().is_empty());
+        assert!(beta.parent().is_none());
+    }
+
+    #[test]
+    fn elements_can_be_removed_from_parent() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let beta = doc.create_element("beta");
+        alpha.append_child(beta);
+
+        beta.remove_from_parent();
+
+        assert!(alpha.children().is_empty());
+        assert!(beta.parent().is_none());
+    }
+
+    #[test]
+    fn elements_can_clear_children() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let beta = doc.create_element("beta");
+        alpha.append_child(beta);
+
+        alpha.clear_children();
+
+        assert!(alpha.children().is_empty());
+        assert!(beta.parent().is_none());
+    }
+
+    #[test]
+    fn elements_can_insert_children_at_an_index() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let parent = doc.create_element("parent");
+        let a = doc.create_element("a");
+        let b = doc.create_element("b");
+        let c = doc.create_element("c");
+        let d = doc.create_element("d");
+        parent.append_child(b);
+
+        parent.insert_child_at(0, a);
+        parent.insert_child_at(2, d);
+        parent.insert_child_at(2, c);
+
+        assert_eq!(
+            parent.children(),
+            vec![a.into(), b.into(), c.into(), d.into()]
+        );
+    }
+
+    #[test]
+    fn inserting_an_existing_child_moves_it() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let parent = doc.create_element("parent");
+        let a = doc.create_element("a");
+        let b = doc.create_element("b");
+        let c = doc.create_element("c");
+        parent.append_children(vec![a, b, c]);
+
+        parent.insert_child_at(2, a);
+
+        assert_eq!(parent.children(), vec![b.into(), c.into(), a.into()]);
+    }
+
+    #[test]
+    fn inserting_a_child_from_another_parent_detaches_it() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let first = doc.create_element("first");
+        let second = doc.create_element("second");
+        let a = doc.create_element("a");
+        let text = doc.create_text("b");
+        first.append_child(a);
+        second.append_child(text);
+
+        second.insert_child_at(0, a);
+
+        assert!(first.children().is_empty());
+        assert_eq!(second.children(), vec![a.into(), text.into()]);
+        assert_eq!(a.parent(), Some(ParentOfChild::Element(second)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn inserting_past_the_end_panics() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let parent = doc.create_element("parent");
+        let a = doc.create_element("a");
+
+        parent.insert_child_at(1, a);
+    }
+
+    #[test]
+    fn elements_can_insert_children_before_and_after_siblings() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let parent = doc.create_element("parent");
+        let a = doc.create_element("a");
+        let b = doc.create_element("b");
+        let c = doc.create_element("c");
+        let d = doc.create_element("d");
+        parent.append_child(b);
+
+        parent.insert_before(b, a);
+        parent.insert_after(b, d);
+        parent.insert_after(b, c);
+
+        assert_eq!(
+            parent.children(),
+            vec![a.into(), b.into(), c.into(), d.into()]
+        );
+    }
+
+    #[test]
+    fn insert_before_a_later_sibling_moves_child() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let parent = doc.create_element("parent");
+        let a = doc.create_element("a");
+        let b = doc.create_element("b");
+        let c = doc.create_element("c");
+        parent.append_children(vec![a, b, c]);
+
+        parent.insert_before(c, a);
+
+        assert_eq!(parent.children(), vec![b.into(), a.into(), c.into()]);
+    }
+
+    #[test]
+    fn root_can_insert_children() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        let before = doc.create_comment("before");
+        let after = doc.create_processing_instruction("after", None);
+        doc.root().append_child(element);
+
+        doc.root().insert_child_at(0, before);
+        doc.root().insert_after(element, after);
+
+        assert_eq!(
+            doc.root().children(),
+            vec![before.into(), element.into(), after.into()]
+        );
+    }
+
+    #[test]
+    fn elements_can_be_shallow_cloned() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element(("urn:a", "alpha"));
+        alpha.set_preferred_prefix(Some("a"));
+        alpha.register_prefix("b", "urn:b");
+        alpha.set_default_namespace_uri(Some("urn:a"));
+        let attr = alpha.set_attribute_value(("urn:b", "attr"), "value");
+        attr.set_preferred_prefix(Some("b"));
+        alpha.set_was_self_closing(Some(false));
+        alpha.append_child(doc.create_element("beta"));
+        doc.root().append_child(alpha);
+
+        let clone = alpha.shallow_clone();
+
+        assert_ne!(clone, alpha);
+        assert_qname_eq!(("urn:a", "alpha"), clone.name());
+        assert_eq!(clone.preferred_prefix(), Some("a"));
+        assert_eq!(clone.default_namespace_uri(), Some("urn:a"));
+        assert_eq!(clone.namespace_uri_for_prefix("b"), Some("urn:b"));
+        let clone_attr = clone.attribute(("urn:b", "attr")).unwrap();
+        assert_eq!(clone_attr.value(), "value");
+        assert_eq!(clone_attr.preferred_prefix(), Some("b"));
+        assert_eq!(clone.was_self_closing(), Some(false));
+        assert!(clone.children().is_empty());
+        assert!(clone.parent().is_none());
+    }
+
+    #[test]
+    fn elements_can_be_deep_cloned() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let beta = doc.create_element("beta");
+        let text = doc.create_text("text");
+        text.set_cdata(true);
+        beta.append_child(text);
+        alpha.append_child(beta);
+        alpha.append_child(doc.create_comment("comment"));
+        alpha.append_child(doc.create_processing_instruction("pi", Some("value")));
+
+        let clone = alpha.deep_clone();
+        let children = clone.children();
+
+        assert_eq!(children.len(), 3);
+        let beta_clone = children[0].element().unwrap();
+        assert_ne!(beta_clone, beta);
+        assert_qname_eq!("beta", beta_clone.name());
+        let text_clone = beta_clone.children()[0].text().unwrap();
+        assert_eq!(text_clone.text(), "text");
+        assert!(text_clone.is_cdata());
+        assert_eq!(children[1].comment().unwrap().text(), "comment");
+        let pi = children[2].processing_instruction().unwrap();
+        assert_eq!(pi.target(), "pi");
+        assert_eq!(pi.value(), Some("value"));
+    }
+
+    #[test]
+    fn deep_clones_are_independent() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let beta = doc.create_element("beta");
+        alpha.set_attribute_value("attr", "original");
+        alpha.append_child(beta);
+
+        let clone = alpha.deep_clone();
+        clone.set_attribute_value("attr", "changed");
+        clone.children()[0].element().unwrap().set_name("gamma");
+        clone.append_child(doc.create_element("delta"));
+
+        assert_eq!(alpha.attribute_value("attr"), Some("original"));
+        assert_qname_eq!("beta", beta.name());
+        assert_eq!(alpha.children(), vec![beta.into()]);
+    }
+
+    #[test]
+    fn normalize_merges_adjacent_text() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let beta = doc.create_element("beta");
+        let first = doc.create_text("one ");
+        alpha.append_child(first);
+        alpha.append_child(doc.create_text("two "));
+        alpha.append_child(doc.create_text("three"));
+        alpha.append_child(beta);
+        alpha.append_child(doc.create_text("four"));
+
+        alpha.normalize();
+
+        let children = alpha.children();
+        assert_eq!(children.len(), 3);
+        assert_eq!(children[0], first.into());
+        assert_eq!(first.text(), "one two three");
+        assert_eq!(children[1], beta.into());
+        assert_eq!(children[2].text().unwrap().text(), "four");
+    }
+
+    #[test]
+    fn normalize_removes_empty_text() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let beta = doc.create_element("beta");
+        alpha.append_child(doc.create_text(""));
+        alpha.append_child(beta);
+
+        alpha.normalize();
+
+        assert_eq!(alpha.children(), vec![beta.into()]);
+    }
+
+    #[test]
+    fn normalize_keeps_ignorable_whitespace_only_if_all_merged_text_was() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let spaces = doc.create_text(" ");
+        spaces.set_ignorable_whitespace(true);
+        let more_spaces = doc.create_text(" ");
+        more_spaces.set_ignorable_whitespace(true);
+        alpha.append_child(spaces);
+        alpha.append_child(more_spaces);
+        alpha.append_child(doc.create_element("beta"));
+        let tabs = doc.create_text("\t");
+        tabs.set_ignorable_whitespace(true);
+        alpha.append_child(tabs);
+        alpha.append_child(doc.create_text("x"));
+
+        alpha.normalize();
+
+        assert_eq!(spaces.text(), "  ");
+        assert!(spaces.is_ignorable_whitespace());
+        assert_eq!(tabs.text(), "\tx");
+        assert!(!tabs.is_ignorable_whitespace());
+    }
+
+    #[test]
+    fn normalize_does_not_merge_cdata() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let cdata = doc.create_text("raw");
+        cdata.set_cdata(true);
+        alpha.append_child(doc.create_text("a"));
+        alpha.append_child(cdata);
+        alpha.append_child(doc.create_text("b"));
+
+        alpha.normalize();
+
+        assert_eq!(alpha.children().len(), 3);
+        assert_eq!(cdata.text(), "raw");
+    }
+
+    #[test]
+    fn normalize_recurses_through_the_document() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let beta = doc.create_element("beta");
+        beta.append_child(doc.create_text("a"));
+        beta.append_child(doc.create_text(""));
+        beta.append_child(doc.create_text("b"));
+        alpha.append_child(beta);
+        doc.root().append_child(alpha);
+
+        doc.normalize();
+
+        let children = beta.children();
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].text().unwrap().text(), "ab");
+    }
+
+    #[test]
+    fn try_create_element_accepts_valid_names() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let e = doc.try_create_element(("urn:x", "a-b.c_1")).unwrap();
+        assert_qname_eq!(e.name(), ("urn:x", "a-b.c_1"));
+    }
+
+    #[test]
+    fn try_create_element_rejects_invalid_names() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let invalid = |name: &str| Err(InvalidName::LocalPart(name.to_owned()));
+        assert_eq!(doc.try_create_element(""), invalid(""));
+        assert_eq!(doc.try_create_element("a b"), invalid("a b"));
+        assert_eq!(doc.try_create_element("1a"), invalid("1a"));
+        assert_eq!(doc.try_create_element("x:a"), invalid("x:a"));
+        assert_eq!(
+            doc.try_create_element(("", "a")),
+            Err(InvalidName::EmptyNamespaceUri)
+        );
+    }
+
+    #[test]
+    fn create_element_ns_uses_the_prefix() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let e = doc.create_element_ns("urn:x", Some("x"), "a").unwrap();
+        assert_qname_eq!(e.name(), ("urn:x", "a"));
+        assert_eq!(e.preferred_prefix(), Some("x"));
+
+        let e = doc.create_element_ns("urn:x", None, "a").unwrap();
+        assert_qname_eq!(e.name(), ("urn:x", "a"));
+        assert_eq!(e.preferred_prefix(), None);
+
+        let e = doc.create_element_ns("", None, "a").unwrap();
+        assert_qname_eq!(e.name(), "a");
+
+        let e = doc
+            .create_element_ns(crate::XML_NS_URI, Some("xml"), "a")
+            .unwrap();
+        assert_eq!(e.preferred_prefix(), Some("xml"));
+    }
+
+    #[test]
+    fn create_element_ns_rejects_invalid_combinations() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        assert_eq!(
+            doc.create_element_ns("", Some("x"), "a"),
+            Err(InvalidName::EmptyNamespaceUri)
+        );
+        assert_eq!(
+            doc.create_element_ns("urn:x", Some("x"), ""),
+            Err(InvalidName::LocalPart("".to_owned()))
+        );
+        assert_eq!(
+            doc.create_element_ns("urn:x", None, "x:a"),
+            Err(InvalidName::LocalPart("x:a".to_owned()))
+        );
+        assert_eq!(
+            doc.create_element_ns("urn:x", Some("1x"), "a"),
+            Err(InvalidName::Prefix("1x".to_owned()))
+        );
+        assert_eq!(
+            doc.create_element_ns("urn:x", Some("xmlns"), "a"),
+            Err(InvalidName::Prefix("xmlns".to_owned()))
+        );
+        assert_eq!(
+            doc.create_element_ns("urn:x", Some("xml"), "a"),
+            Err(InvalidName::Prefix("xml".to_owned()))
+        );
+        assert_eq!(
+            doc.create_element_ns(crate::XML_NS_URI, Some("x"), "a"),
+            Err(InvalidName::Prefix("x".to_owned()))
+        );
+        assert!(doc.root().children().is_empty());
+    }
+
+    #[test]
+    fn try_set_attribute_value_rejects_invalid_names() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let e = doc.create_element("e");
+
+        assert!(e.try_set_attribute_value("ok", "1").is_ok());
+        assert_eq!(
+            e.try_set_attribute_value("a b", "2"),
+            Err(InvalidName::LocalPart("a b".to_owned()))
+        );
+        assert_eq!(
+            e.try_set_attribute_value(("urn:x", "9"), "3"),
+            Err(InvalidName::LocalPart("9".to_owned()))
+        );
+        assert_eq!(e.attributes().len(), 1);
+    }
+
+    #[test]
+    fn try_set_preferred_prefix_rejects_invalid_prefixes() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let e = doc.create_element(("urn:x", "e"));
+        let a = e.set_attribute_value(("urn:x", "a"), "1");
+
+        assert!(e.try_set_preferred_prefix(Some("x")).is_ok());
+        assert!(e.try_set_preferred_prefix(None).is_ok());
+        assert_eq!(
+            e.try_set_preferred_prefix(Some("1x")),
+            Err(InvalidName::Prefix("1x".to_owned()))
+        );
+        assert_eq!(
+            a.try_set_preferred_prefix(Some("")),
+            Err(InvalidName::Prefix("".to_owned()))
+        );
+        assert_eq!(e.preferred_prefix(), None);
+        assert_eq!(a.preferred_prefix(), None);
+    }
+
+    #[derive(Default)]
+    struct CountingVisitor {
+        elements: usize,
+        texts: usize,
+        comments: usize,
+        processing_instructions: usize,
+    }
+
+    impl<'d> Visitor<'d> for CountingVisitor {
+        fn visit_element(&mut self, element: Element<'d>) {
+            self.elements += 1;
+            for child in element.children() {
+                child.accept(self);
+            }
+        }
+
+        fn visit_text(&mut self, _text: Text<'d>) {
+            self.texts += 1;
+        }
+
+        fn visit_comment(&mut self, _comment: Comment<'d>) {
+            self.comments += 1;
+        }
+
+        fn visit_processing_instruction(&mut self, _pi: ProcessingInstruction<'d>) {
+            self.processing_instructions += 1;
+        }
+    }
+
+    #[test]
+    fn visitor_receives_each_kind_of_child() {
+        let package = parser::parse("<!--c--><a>1<b>2<?pi?></b><!--c-->3<c/></a><?pi?>")
+            .expect("Failed to parse");
+        let doc = package.as_document();
+
+        let mut visitor = CountingVisitor::default();
+        for child in doc.root().children() {
+            child.accept(&mut visitor);
+        }
+
+        assert_eq!(visitor.elements, 3);
+        assert_eq!(visitor.texts, 3);
+        assert_eq!(visitor.comments, 2);
+        assert_eq!(visitor.processing_instructions, 2);
+    }
+
+    #[test]
+    fn visitor_methods_default_to_doing_nothing() {
+        struct Elements(usize);
+
+        impl<'d> Visitor<'d> for Elements {
+            fn visit_element(&mut self, _element: Element<'d>) {
+                self.0 += 1;
+            }
+        }
+
+        let package = parser::parse("<a>text<!--c--><?pi?><b/></a>").expect("Failed to parse");
+        let doc = package.as_document();
+
+        let a = doc.root_element().unwrap();
+
+        let mut visitor = Elements(0);
+        for child in a.children() {
+            child.accept(&mut visitor);
+        }
+
+        assert_eq!(visitor.0, 1);
+    }
+
+    #[test]
+    fn package_counts_nodes_by_kind() {
+        let package = parser::parse("<?pi?><a x='1' y='2'><!--c--><b>text</b>more</a>")
+            .expect("Failed to parse");
+
+        let counts = package.node_counts();
+        assert_eq!(counts.elements, 2);
+        assert_eq!(counts.attributes, 2);
+        assert_eq!(counts.texts, 2);
+        assert_eq!(counts.comments, 1);
+        assert_eq!(counts.processing_instructions, 1);
+        assert_eq!(package.node_count(), 8);
+    }
+
+    #[test]
+    fn package_counts_include_detached_nodes() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let a = doc.create_element("a");
+        doc.root().append_child(a);
+        doc.root().remove_child(a);
+        doc.create_text("unattached");
+
+        assert_eq!(package.node_count(), 2);
+    }
+
+    #[test]
+    fn package_allocated_bytes_grow_with_the_nodes() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let empty = package.allocated_bytes();
+
+        let a = doc.create_element("a");
+        doc.root().append_child(a);
+        let one = package.allocated_bytes();
+
+        a.append_child(doc.create_element("b"));
+        let two = package.allocated_bytes();
+
+        assert!(one > empty);
+        assert!(two > one);
+    }
+
+    #[test]
+    fn root_element_skips_the_prolog() {
+        let package = parser::parse("<!--prolog--><?pi?><a/>").expect("Failed to parse");
+        let doc = package.as_document();
+
+        let a = doc.root_element().unwrap();
+        assert_qname_eq!(a.name(), "a");
+    }
+
+    #[test]
+    fn a_new_document_has_no_root_element() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        assert!(doc.root_element().is_none());
+    }
+
+    #[test]
+    fn set_root_element_on_an_empty_document() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let a = doc.create_element("a");
+
+        doc.set_root_element(a);
+
+        assert_eq!(doc.root_element(), Some(a));
+        assert_eq!(a.parent(), Some(ParentOfChild::Root(doc.root())));
+    }
+
+    #[test]
+    fn set_root_element_replaces_the_existing_one_in_place() {
+        let package = parser::parse("<!--before--><a/><!--after-->").expect("Failed to parse");
+        let doc = package.as_document();
+        let a = doc.root_element().unwrap();
+        let b = doc.create_element("b");
+
+        doc.set_root_element(b);
+
+        let children = doc.root().children();
+        assert_eq!(children.len(), 3);
+        assert_eq!(children[1], ChildOfRoot::Element(b));
+        assert_eq!(doc.root_element(), Some(b));
+        assert!(a.parent().is_none());
+    }
+
+    #[test]
+    fn set_root_element_to_the_current_one_changes_nothing() {
+        let package = parser::parse("<!--before--><a/>").expect("Failed to parse");
+        let doc = package.as_document();
+        let a = doc.root_element().unwrap();
+
+        doc.set_root_element(a);
+
+        assert_eq!(doc.root().children().len(), 2);
+        assert_eq!(doc.root_element(), Some(a));
+    }
+
+    fn canonical_eq(a: &str, b: &str, ignore_whitespace: bool) -> bool {
+        let a = parser::parse(a).expect("Failed to parse");
+        let b = parser::parse(b).expect("Failed to parse");
+        a.as_document()
+            .canonical_eq(b.as_document(), ignore_whitespace)
+    }
+
+    #[test]
+    fn canonical_eq_ignores_prefixes() {
+        assert!(canonical_eq(
+            "<x:a xmlns:x='urn:a' xmlns:y='urn:b'><y:b y:c='1'/></x:a>",
+            "<a xmlns='urn:a'><q:b xmlns:q='urn:b' q:c='1'/></a>",
+            false
+        ));
+    }
+
+    #[test]
+    fn canonical_eq_ignores_attribute_order() {
+        assert!(canonical_eq(
+            "<a one='1' two='2'><b three='3' four='4'/></a>",
+            "<a two='2' one='1'><b four='4' three='3'/></a>",
+            false
+        ));
+    }
+
+    #[test]
+    fn canonical_eq_compares_names_attributes_and_text() {
+        assert!(!canonical_eq(
+            "<a xmlns='urn:a'/>",
+            "<a xmlns='urn:b'/>",
+            false
+        ));
+        assert!(!canonical_eq("<a one='1'/>", "<a one='2'/>", false));
+        assert!(!canonical_eq("<a one='1'/>", "<a one='1' two='2'/>", false));
+        assert!(!canonical_eq("<a>x</a>", "<a>y</a>", false));
+        assert!(!canonical_eq("<a><b/></a>", "<a><c/></a>", false));
+        assert!(!canonical_eq("<a/><!--x-->", "<a/><!--y-->", false));
+    }
+
+    #[test]
+    fn canonical_eq_merges_adjacent_text() {
+        assert!(canonical_eq(
+            "<a>x&amp;<![CDATA[y]]></a>",
+            "<a>x&amp;y</a>",
+            false
+        ));
+    }
+
+    #[test]
+    fn canonical_eq_optionally_ignores_whitespace_only_text() {
+        let a = "<a>\n  <b> x </b>\n</a>";
+        let b = "<a><b> x </b></a>";
+
+        assert!(!canonical_eq(a, b, false));
+        assert!(canonical_eq(a, b, true));
+        assert!(!canonical_eq("<a><b> x </b></a>", "<a><b>x</b></a>", true));
+    }
+
+    #[test]
+    fn element_canonical_eq_compares_subtrees() {
+        let package = parser::parse("<r><a k='v'><b/></a><c><a k='v'><b/></a></c></r>")
+            .expect("Failed to parse");
+        let doc = package.as_document();
+        let r = doc.root().children()[0].element().unwrap();
+        let first = r.children()[0].element().unwrap();
+        let c = r.children()[1].element().unwrap();
+        let second = c.children()[0].element().unwrap();
+
+        assert!(first.canonical_eq(second, false));
+        assert!(!first.canonical_eq(c, false));
+    }
+
+    #[test]
+    fn descendants_are_in_document_order() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let a = doc.create_element("a");
+        let b = doc.create_element("b");
+        let c = doc.create_element("c");
+        let d = doc.create_element("d");
+        let text = doc.create_text("text");
+        let comment = doc.create_comment("comment");
+        b.append_child(c);
+        b.append_child(text);
+        a.append_child(b);
+        a.append_child(comment);
+        a.append_child(d);
+        doc.root().append_child(a);
+
+        let descendants: Vec<_> = a.descendants().collect();
+        assert_eq!(
+            descendants,
+            vec![b.into(), c.into(), text.into(), comment.into(), d.into()]
+        );
+
+        let elements: Vec<_> = a.descendant_elements().collect();
+        assert_eq!(elements, vec![b, c, d]);
+    }
+
+    #[test]
+    fn document_descendants_include_top_level_nodes() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let comment = doc.create_comment("comment");
+        let a = doc.create_element("a");
+        let b = doc.create_element("b");
+        a.append_child(b);
+        doc.root().append_child(comment);
+        doc.root().append_child(a);
+
+        let descendants: Vec<_> = doc.descendants().collect();
+        assert_eq!(descendants, vec![comment.into(), a.into(), b.into()]);
+
+        let elements: Vec<_> = doc.descendant_elements().collect();
+        assert_eq!(elements, vec![a, b]);
+    }
+
+    #[test]
+    fn descendants_of_deeply_nested_elements() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let top = doc.create_element("top");
+        let mut parent = top;
+        for _ in 0..100_000 {
+            let child = doc.create_element("child");
+            parent.append_child(child);
+            parent = child;
+        }
+
+        assert_eq!(top.descendants().count(), 100_000);
+    }
+
+    #[test]
+    fn child_elements_can_be_found_by_name() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let parent = doc.create_element("parent");
+        let a1 = doc.create_element("a");
+        let b = doc.create_element("b");
+        let a2 = doc.create_element(("urn:x", "a"));
+        let grandchild = doc.create_element("a");
+        b.append_child(grandchild);
+        parent.append_child(a1);
+        parent.append_child(b);
+        parent.append_child(a2);
+
+        assert_eq!(parent.child_elements_named("a"), vec![a1]);
+        assert_eq!(parent.child_elements_named(("urn:x", "a")), vec![a2]);
+        assert_eq!(
+            parent.child_elements_named(NameTest::any_namespace("a")),
+            vec![a1, a2]
+        );
+        assert_eq!(
+            parent.child_elements_named(NameTest::any_local_part(None)),
+            vec![a1, b]
+        );
+        assert_eq!(
+            parent.child_elements_named(NameTest::any()),
+            vec![a1, b, a2]
+        );
+    }
+
+    #[test]
+    fn descendants_can_be_found_by_name() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let top = doc.create_element("top");
+        let a1 = doc.create_element(("urn:x", "a"));
+        let b = doc.create_element("b");
+        let a2 = doc.create_element(("urn:y", "a"));
+        let a3 = doc.create_element(("urn:x", "a"));
+        b.append_child(a2);
+        a1.append_child(b);
+        top.append_child(a1);
+        top.append_child(a3);
+
+        let found: Vec<_> = top.find_descendants_named(("urn:x", "a")).collect();
+        assert_eq!(found, vec![a1, a3]);
+
+        let found: Vec<_> = top
+            .find_descendants_named(NameTest::any_namespace("a"))
+            .collect();
+        assert_eq!(found, vec![a1, a2, a3]);
+
+        let found: Vec<_> = top
+            .find_descendants_named(NameTest::any_local_part(Some("urn:y")))
+            .collect();
+        assert_eq!(found, vec![a2]);
+    }
+
+    #[test]
+    fn transform_removes_comments_from_mixed_content() {
+        let package =
+            parser::parse("<a>one<!--x--><b><!--y-->two<!--z--></b><!--w-->three</a>").unwrap();
+        let doc = package.as_document();
+        let a = doc.root().children()[0].element().unwrap();
+
+        let mut visited = 0;
+        a.transform(&mut |node| {
+            visited += 1;
+            match node {
+                ChildOfElement::Comment(_) => NodeAction::Remove,
+                _ => NodeAction::Keep,
+            }
+        });
+
+        assert_eq!(visited, 8);
+        assert_eq!(a.descendants().count(), 4);
+        assert!(a.descendants().all(|n| n.comment().is_none()));
+        assert_eq!(a.string_value(), "onetwothree");
+    }
+
+    #[test]
+    fn transform_renames_elements() {
+        let package = parser::parse("<a><b><b/></b>text<c/></a>").unwrap();
+        let doc = package.as_document();
+        let a = doc.root().children()[0].element().unwrap();
+
+        a.transform(&mut |node| {
+            if let ChildOfElement::Element(e) = node {
+                if e.name().local_part() == "b" {
+                    e.set_name("renamed");
+                }
+            }
+            NodeAction::Keep
+        });
+
+        let names: Vec<_> = a
+            .descendant_elements()
+            .map(|e| e.name().local_part())
+            .collect();
+        assert_eq!(names, ["renamed", "renamed", "c"]);
+    }
+
+    #[test]
+    fn transform_replaces_nodes_without_visiting_the_replacement() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let top = doc.create_element("top");
+        let old = doc.create_element("old");
+        let after = doc.create_text("after");
+        old.append_child(doc.create_element("inner"));
+        top.append_child(old);
+        top.append_child(after);
+
+        let new = doc.create_element("new");
+        let mut visited = Vec::new();
+        top.transform(&mut |node| {
+            visited.push(node);
+            match node {
+                ChildOfElement::Element(e) if e == old => NodeAction::ReplaceWith(new.into()),
+                _ => NodeAction::Keep,
+            }
+        });
+
+        assert_eq!(visited, vec![old.into(), after.into()]);
+        assert_eq!(top.children(), vec![new.into(), after.into()]);
+        assert_eq!(old.parent(), None);
+    }
+
+    #[test]
+    fn name_tests_compare_namespace_uris_not_prefixes() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let parent = doc.create_element("parent");
+        let child = doc.create_element(("urn:x", "a"));
+        child.set_preferred_prefix(Some("x"));
+        parent.append_child(child);
+
+        assert_eq!(parent.child_elements_named(("urn:x", "a")), vec![child]);
+        assert!(parent.child_elements_named(("x", "a")).is_empty());
+    }
+
+    #[test]
+    fn string_value_concatenates_descendant_text() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let a = doc.create_element("a");
+        let b = doc.create_element("b");
+        a.append_child(doc.create_text("x"));
+        b.append_child(doc.create_text("y"));
+        a.append_child(b);
+        a.append_child(doc.create_cdata("<z>"));
+
+        assert_eq!(a.string_value(), "xy<z>");
+        assert_eq!(b.string_value(), "y");
+    }
+
+    #[test]
+    fn string_value_ignores_comments_and_processing_instructions() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let a = doc.create_element("a");
+        a.append_child(doc.create_comment("comment"));
+        a.append_child(doc.create_processing_instruction("pi", Some("value")));
+
+        assert_eq!(a.string_value(), "");
+    }
+
+    #[test]
+    fn nodes_know_their_nearest_siblings() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let parent = doc.create_element("parent");
+        let text = doc.create_text("text");
+        let element = doc.create_element("element");
+        let comment = doc.create_comment("comment");
+        let pi = doc.create_processing_instruction("pi", None);
+        parent.append_child(text);
+        parent.append_child(element);
+        parent.append_child(comment);
+        parent.append_child(pi);
+
+        assert_eq!(text.preceding_sibling(), None);
+        assert_eq!(text.following_sibling(), Some(element.into()));
+        assert_eq!(element.preceding_sibling(), Some(text.into()));
+        assert_eq!(element.following_sibling(), Some(comment.into()));
+        assert_eq!(comment.preceding_sibling(), Some(element.into()));
+        assert_eq!(comment.following_sibling(), Some(pi.into()));
+        assert_eq!(pi.preceding_sibling(), Some(comment.into()));
+        assert_eq!(pi.following_sibling(), None);
+    }
+
+    #[test]
+    fn top_level_nodes_know_their_nearest_siblings() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let comment = doc.create_comment("comment");
+        let element = doc.create_element("element");
+        doc.root().append_child(comment);
+        doc.root().append_child(element);
+
+        assert_eq!(comment.following_sibling(), Some(element.into()));
+        assert_eq!(element.preceding_sibling(), Some(comment.into()));
+        assert_eq!(element.following_sibling(), None);
+    }
+
+    #[test]
+    fn detached_nodes_have_no_siblings() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+
+        assert_eq!(element.preceding_sibling(), None);
+        assert_eq!(element.following_sibling(), None);
+    }
+
+    #[test]
+    fn elements_can_be_found_by_id() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let top = doc.create_element("top");
+        let first = doc.create_element("first");
+        let nested = doc.create_element("nested");
+        let second = doc.create_element("second");
+        let namespaced = doc.create_element("namespaced");
+        first.set_attribute_value("id", "one");
+        nested.set_attribute_value("id", "two");
+        second.set_attribute_value("id", "one");
+        namespaced.set_attribute_value((crate::XML_NS_URI, "id"), "three");
+        namespaced.set_attribute_value(("urn:other", "id"), "four");
+        first.append_child(nested);
+        top.append_child(first);
+        top.append_child(second);
+        top.append_child(namespaced);
+        doc.root().append_child(top);
+
+        assert_eq!(doc.element_by_id("one"), Some(first));
+        assert_eq!(doc.element_by_id("two"), Some(nested));
+        assert_eq!(doc.element_by_id("three"), Some(namespaced));
+        assert_eq!(doc.element_by_id("four"), None);
+        assert_eq!(doc.element_by_id("missing"), None);
+    }
+
+    #[test]
+    fn removing_a_non_child_does_nothing() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let beta = doc.create_element("beta");
+        let gamma = doc.create_element("gamma");
+        beta.append_child(gamma);
+
+        alpha.remove_child(gamma);
+        doc.root().remove_child(gamma);
+
+        assert_eq!(beta.children(), vec![gamma.into()]);
+        assert_eq!(gamma.parent(), Some(ParentOfChild::Element(beta)));
+    }
+
+    #[test]
+    fn removed_elements_can_be_appended_elsewhere() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let beta = doc.create_element("beta");
+        let gamma = doc.create_element("gamma");
+        let delta = doc.create_element("delta");
+        alpha.append_child(beta);
+        alpha.append_child(gamma);
+
+        alpha.remove_child(beta);
+        delta.append_child(beta);
+
+        assert_eq!(alpha.children(), vec![gamma.into()]);
+        assert_eq!(delta.children(), vec![beta.into()]);
+        assert_eq!(beta.parent(), Some(ParentOfChild::Element(delta)));
+    }
+
+    #[test]
+    // Nodes hash by identity, so changing them cannot change the hash
+    #[allow(clippy::mutable_key_type)]
+    fn nodes_are_hashed_by_identity() {
+        use std::collections::HashSet;
+
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let a = doc.create_element("same");
+        let b = doc.create_element("same");
+        a.append_child(b);
+
+        let mut visited = HashSet::new();
+        assert!(visited.insert(a));
+        assert!(!visited.insert(a));
+        assert!(visited.contains(&b.parent().unwrap().element().unwrap()));
+        assert!(!visited.contains(&b));
+        assert_ne!(a, b);
+
+        let children: HashSet<_> = a.children().into_iter().collect();
+        assert!(children.contains(&ChildOfElement::Element(b)));
+        assert!(!children.contains(&ChildOfElement::Element(a)));
+    }
+
+    #[test]
+    fn element_children_are_ordered() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let greek = doc.create_element("greek");
+        let alpha = doc.create_element("alpha");
+        let omega = doc.create_element("omega");
+
+        greek.append_child(alpha);
+        greek.append_child(omega);
+
+        let children = greek.children();
+
+        assert_eq!(children[0], ChildOfElement::Element(alpha));
+        assert_eq!(children[1], ChildOfElement::Element(omega));
+    }
+
+    #[test]
+    fn ancestors_are_ordered_from_the_parent_outwards() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let a = doc.create_element("a");
+        let b = doc.create_element("b");
+        let c = doc.create_element("c");
+        let text = doc.create_text("text");
+        doc.root().append_child(a);
+        a.append_child(b);
+        b.append_child(c);
+        c.append_child(text);
+
+        assert_eq!(c.ancestors().collect::<Vec<_>>(), vec![b, a]);
+        assert_eq!(
+            ChildOfElement::from(text).ancestors().collect::<Vec<_>>(),
+            vec![c, b, a]
+        );
+        assert_eq!(a.ancestors().count(), 0);
     }
 
     #[test]
-    fn root_child_knows_its_parent() {
+    fn ancestors_of_a_detached_element_end_at_the_detached_top() {
         let package = Package::new();
         let doc = package.as_document();
 
-        let root = doc.root();
-        let alpha = doc.create_element("alpha");
+        let a = doc.create_element("a");
+        let b = doc.create_element("b");
+        a.append_child(b);
 
-        root.append_child(alpha);
+        assert_eq!(b.ancestors().collect::<Vec<_>>(), vec![a]);
+    }
 
-        assert_eq!(Some(ParentOfChild::Root(root)), alpha.parent());
+    fn set_base(element: Element<'_>, uri: &str) {
+        element.set_attribute_value((crate::XML_NS_URI, "base"), uri);
     }
 
     #[test]
-    fn elements_belong_to_a_document() {
+    fn base_uri_combines_nested_bases() {
         let package = Package::new();
         let doc = package.as_document();
 
-        let element = doc.create_element("alpha");
+        let a = doc.create_element("a");
+        let b = doc.create_element("b");
+        let c = doc.create_element("c");
+        let d = doc.create_element("d");
+        doc.root().append_child(a);
+        a.append_child(b);
+        b.append_child(c);
+        c.append_child(d);
+        set_base(a, "http://example.com/docs/manual/index.xml");
+        set_base(b, "../guide/");
+        set_base(c, "./chapters/one.xml");
 
-        assert_eq!(doc, element.document());
+        assert_eq!(
+            a.base_uri(),
+            Some("http://example.com/docs/manual/index.xml".to_owned())
+        );
+        assert_eq!(
+            b.base_uri(),
+            Some("http://example.com/docs/guide/".to_owned())
+        );
+        assert_eq!(
+            d.base_uri(),
+            Some("http://example.com/docs/guide/chapters/one.xml".to_owned())
+        );
     }
 
     #[test]
-    fn elements_can_have_element_children() {
+    fn base_uri_absolute_reference_replaces_outer_base() {
         let package = Package::new();
         let doc = package.as_document();
 
-        let alpha = doc.create_element("alpha");
-        let beta = doc.create_element("beta");
-
-        alpha.append_child(beta);
-
-        let children = alpha.children();
+        let a = doc.create_element("a");
+        let b = doc.create_element("b");
+        a.append_child(b);
+        set_base(a, "http://example.com/docs/");
+        set_base(b, "file:///tmp/x/../y.xml");
 
-        assert_eq!(children[0], ChildOfElement::Element(beta));
+        assert_eq!(b.base_uri(), Some("file:///tmp/y.xml".to_owned()));
     }
 
     #[test]
-    fn elements_can_append_multiple_children() {
+    fn base_uri_resolves_absolute_paths_and_network_references() {
         let package = Package::new();
         let doc = package.as_document();
 
-        let alpha = doc.create_element("alpha");
-        let beta = doc.create_element("beta");
-        let gamma = doc.create_element("gamma");
-
-        alpha.append_children(&[beta, gamma]);
+        let a = doc.create_element("a");
+        let b = doc.create_element("b");
+        let c = doc.create_element("c");
+        a.append_child(b);
+        a.append_child(c);
+        set_base(a, "https://example.com/docs/index.xml?v=1#top");
+        set_base(b, "/images/");
+        set_base(c, "//cdn.example.com/lib/");
 
-        let children = alpha.children();
-        assert_eq!(2, children.len());
-        assert_eq!(children[0], ChildOfElement::Element(beta));
-        assert_eq!(children[1], ChildOfElement::Element(gamma));
+        assert_eq!(b.base_uri(), Some("https://example.com/images/".to_owned()));
+        assert_eq!(
+            c.base_uri(),
+            Some("https://cdn.example.com/lib/".to_owned())
+        );
     }
 
     #[test]
-    fn elements_can_replace_children() {
+    fn base_uri_against_an_authority_without_a_path() {
         let package = Package::new();
         let doc = package.as_document();
 
-        let alpha = doc.create_element("alpha");
-        let beta = doc.create_element("beta");
-        let gamma = doc.create_element("gamma");
-        let zeta = doc.create_element("zeta");
-        alpha.append_child(zeta);
-
-        alpha.replace_children(&[beta, gamma]);
+        let a = doc.create_element("a");
+        let b = doc.create_element("b");
+        a.append_child(b);
+        set_base(a, "http://example.com");
+        set_base(b, "docs/");
 
-        let children = alpha.children();
-        assert_eq!(2, children.len());
-        assert_eq!(children[0], ChildOfElement::Element(beta));
-        assert_eq!(children[1], ChildOfElement::Element(gamma));
+        assert_eq!(b.base_uri(), Some("http://example.com/docs/".to_owned()));
     }
 
     #[test]
-    fn elements_can_remove_children() {
+    fn base_uri_stays_relative_without_an_absolute_base() {
         let package = Package::new();
         let doc = package.as_document();
 
-        let alpha = doc.create_element("alpha");
-        let beta = doc.create_element("beta");
-        alpha.append_child(beta);
-
-        alpha.remove_child(beta);
+        let a = doc.create_element("a");
+        let b = doc.create_element("b");
+        a.append_child(b);
+        set_base(a, "../shared/");
+        set_base(b, "../common/x.xml");
 
-        assert!(alpha.children().is_empty());
-        assert!(beta.parent().is_none());
+        assert_eq!(b.base_uri(), Some("../common/x.xml".to_owned()));
     }
 
     #[test]
-    fn elements_can_be_removed_from_parent() {
+    fn base_uri_is_none_without_xml_base() {
         let package = Package::new();
         let doc = package.as_document();
 
-        let alpha = doc.create_element("alpha");
-        let beta = doc.create_element("beta");
-        alpha.append_child(beta);
-
-        beta.remove_from_parent();
+        let a = doc.create_element("a");
+        let b = doc.create_element("b");
+        a.append_child(b);
+        a.set_attribute_value("base", "http://example.com/");
 
-        assert!(alpha.children().is_empty());
-        assert!(beta.parent().is_none());
+        assert_eq!(b.base_uri(), None);
     }
 
     #[test]
-    fn elements_can_clear_children() {
-        let package = Package::new();
+    fn base_uri_from_parsed_document() {
+        let xml = "<a xml:base='http://example.com/a/b/'><x><y xml:base='../c'/></x></a>";
+        let package = parser::parse(xml).unwrap();
         let doc = package.as_document();
+        let a = doc.root().children()[0].element().unwrap();
+        let x = a.children()[0].element().unwrap();
+        let y = x.children()[0].element().unwrap();
 
-        let alpha = doc.create_element("alpha");
-        let beta = doc.create_element("beta");
-        alpha.append_child(beta);
-
-        alpha.clear_children();
-
-        assert!(alpha.children().is_empty());
-        assert!(beta.parent().is_none());
+        assert_eq!(x.base_uri(), Some("http://example.com/a/b/".to_owned()));
+        assert_eq!(y.base_uri(), Some("http://example.com/a/c".to_owned()));
     }
 
     #[test]
-    fn element_children_are_ordered() {
+    fn child_nodes_know_their_parent() {
         let package = Package::new();
         let doc = package.as_document();
 
-        let greek = doc.create_element("greek");
-        let alpha = doc.create_element("alpha");
-        let omega = doc.create_element("omega");
-
-        greek.append_child(alpha);
-        greek.append_child(omega);
-
-        let children = greek.children();
+        let a = doc.create_element("a");
+        let text = doc.create_text("text");
+        let comment = doc.create_comment("comment");
+        doc.root().append_child(a);
+        doc.root().append_child(comment);
+        a.append_child(text);
 
-        assert_eq!(children[0], ChildOfElement::Element(alpha));
-        assert_eq!(children[1], ChildOfElement::Element(omega));
+        assert_eq!(
+            ChildOfElement::from(text).parent(),
+            Some(ParentOfChild::Element(a))
+        );
+        assert_eq!(
+            ChildOfRoot::from(a).parent(),
+            Some(ParentOfChild::Root(doc.root()))
+        );
+        assert_eq!(
+            ChildOfRoot::from(comment).parent(),
+            Some(ParentOfChild::Root(doc.root()))
+        );
+        assert_eq!(ChildOfElement::from(doc.create_text("x")).parent(), None);
     }
 
     #[test]
@@ -1162,6 +3998,37 @@ mod test {
         assert_qname_eq!(alpha.name(), "beta");
     }
 
+    #[test]
+    fn prefixes_and_namespaces_resolve_across_redeclarations() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let outer = doc.create_element("outer");
+        outer.register_prefix("a", "uri1");
+        outer.register_prefix("b", "uri1");
+        let middle = doc.create_element("middle");
+        middle.register_prefix("a", "uri2");
+        let inner = doc.create_element("inner");
+        outer.append_child(middle);
+        middle.append_child(inner);
+
+        assert_eq!(outer.namespace_uri_for_prefix("a"), Some("uri1"));
+        assert_eq!(inner.namespace_uri_for_prefix("a"), Some("uri2"));
+        assert_eq!(inner.namespace_uri_for_prefix("b"), Some("uri1"));
+        assert_eq!(inner.namespace_uri_for_prefix("c"), None);
+
+        assert_eq!(outer.prefix_for_namespace_uri("uri1", None), Some("a"));
+        assert_eq!(outer.prefix_for_namespace_uri("uri1", Some("b")), Some("b"));
+        assert_eq!(inner.prefix_for_namespace_uri("uri1", None), Some("b"));
+        assert_eq!(inner.prefix_for_namespace_uri("uri1", Some("a")), Some("b"));
+        assert_eq!(inner.prefix_for_namespace_uri("uri2", None), Some("a"));
+        assert_eq!(inner.prefix_for_namespace_uri("uri3", None), None);
+        assert_eq!(
+            inner.prefix_for_namespace_uri(crate::XML_NS_URI, None),
+            Some("xml")
+        );
+    }
+
     #[test]
     fn elements_know_in_scope_namespaces() {
         let package = Package::new();
@@ -1180,6 +4047,19 @@ mod test {
         assert_eq!("uri", a_ns.uri());
     }
 
+    #[test]
+    fn the_xml_prefix_is_always_bound() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let element = doc.create_element("element");
+
+        assert_eq!(
+            element.namespace_uri_for_prefix("xml"),
+            Some("http://www.w3.org/XML/1998/namespace")
+        );
+        assert_eq!(element.namespace_uri_for_prefix("xmlns"), None);
+    }
+
     #[test]
     fn elements_in_scope_namespaces_override_parents_with_the_same_prefix() {
         let package = Package::new();
@@ -1200,6 +4080,57 @@ mod test {
         assert_eq!("uri2", ns.uri());
     }
 
+    #[test]
+    fn registered_prefixes_keep_their_order() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        element.register_prefix("z", "uri1");
+        element.register_prefix("b", "uri2");
+        element.register_prefix("m", "uri1");
+        element.register_prefix("z", "uri3");
+
+        let here: Vec<_> = element
+            .prefixes_declared_here()
+            .iter()
+            .map(|ns| (ns.prefix(), ns.uri()))
+            .collect();
+        assert_eq!(here, vec![("z", "uri3"), ("b", "uri2"), ("m", "uri1")]);
+    }
+
+    #[test]
+    fn elements_know_the_namespaces_declared_on_themselves() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let outer = doc.create_element("outer");
+        outer.register_prefix("a", "uri1");
+        outer.register_prefix("b", "uri2");
+
+        let middle = doc.create_element("middle");
+        let inner = doc.create_element("inner");
+        inner.register_prefix("a", "uri3");
+
+        outer.append_child(middle);
+        middle.append_child(inner);
+
+        assert_eq!(2, outer.prefixes_declared_here().len());
+        assert!(middle.prefixes_declared_here().is_empty());
+
+        let here = inner.prefixes_declared_here();
+        assert_eq!(1, here.len());
+        assert_eq!("a", here[0].prefix());
+        assert_eq!("uri3", here[0].uri());
+
+        let nses = inner.namespaces_in_scope();
+        assert_eq!(3, nses.len());
+        let a_ns = nses.iter().find(|ns| ns.prefix() == "a").unwrap();
+        assert_eq!("uri3", a_ns.uri());
+        let b_ns = nses.iter().find(|ns| ns.prefix() == "b").unwrap();
+        assert_eq!("uri2", b_ns.uri());
+    }
+
     #[test]
     fn attributes_belong_to_a_document() {
         let package = Package::new();
@@ -1211,6 +4142,27 @@ mod test {
         assert_eq!(doc, attr.document());
     }
 
+    #[test]
+    fn parsed_names_equal_constructed_names() {
+        let package =
+            parser::parse("<x:a xmlns:x='urn:x' x:b='1' c='2'/>").expect("Unable to parse");
+        let doc = package.as_document();
+        let a = doc.root_element().unwrap();
+
+        assert_eq!(a.name(), QName::with_namespace_uri(Some("urn:x"), "a"));
+        assert_ne!(a.name(), QName::new("a"));
+        assert_eq!(a.name().to_string(), "{urn:x}a");
+
+        let names: Vec<_> = a.attributes().iter().map(|attr| attr.name()).collect();
+        assert_eq!(
+            names,
+            vec![
+                QName::with_namespace_uri(Some("urn:x"), "b"),
+                QName::new("c")
+            ]
+        );
+    }
+
     #[test]
     fn elements_have_attributes() {
         let package = Package::new();
@@ -1255,12 +4207,43 @@ mod test {
         let element = doc.create_element("element");
         let attribute = element.set_attribute_value("hello", "world");
 
-        element.remove_attribute("hello");
+        assert!(element.remove_attribute("hello"));
 
         assert!(element.attribute("hello").is_none());
         assert!(attribute.parent().is_none());
     }
 
+    #[test]
+    fn namespaced_attributes_can_be_removed() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        element.set_attribute_value(("urn:a", "hello"), "world");
+        element.set_attribute_value("hello", "plain");
+
+        assert!(!element.remove_attribute("missing"));
+        assert!(!element.remove_attribute(("urn:b", "hello")));
+        assert!(element.remove_attribute(("urn:a", "hello")));
+        assert!(!element.remove_attribute(("urn:a", "hello")));
+
+        assert_eq!(element.attribute_value(("urn:a", "hello")), None);
+        assert_eq!(element.attribute_value("hello"), Some("plain"));
+    }
+
+    #[test]
+    fn removing_an_attribute_does_not_change_namespace_resolution() {
+        let package = parser::parse("<a xmlns:x='urn:x' x:b='1'/>").expect("Failed to parse");
+        let doc = package.as_document();
+        let a = doc.root_element().unwrap();
+
+        assert!(!a.remove_attribute("xmlns:x"));
+        assert!(a.remove_attribute(("urn:x", "b")));
+
+        assert_eq!(a.attribute_value(("urn:x", "b")), None);
+        assert_eq!(a.namespace_uri_for_prefix("x"), Some("urn:x"));
+    }
+
     #[test]
     fn attributes_can_be_removed_from_parent() {
         let package = Package::new();
@@ -1295,6 +4278,123 @@ mod test {
         assert_eq!("value2", attrs[1].value());
     }
 
+    #[test]
+    fn parsed_attributes_are_iterated_in_order() {
+        let package = parser::parse("<a x='1' y='2'/>").expect("Unable to parse");
+        let doc = package.as_document();
+        let a = doc.root_element().unwrap();
+
+        let pairs: Vec<_> = a
+            .attributes()
+            .into_iter()
+            .map(|attr| (attr.name().local_part(), attr.value()))
+            .collect();
+
+        assert_eq!(pairs, vec![("x", "1"), ("y", "2")]);
+        assert_eq!(2, a.attribute_count());
+    }
+
+    #[test]
+    fn renamed_element_keeps_its_content_and_is_written_with_the_new_name() {
+        let package = parser::parse("<a><b c='1'>text</b></a>").unwrap();
+        let doc = package.as_document();
+        let a = doc.root().children()[0].element().unwrap();
+        let b = a.children()[0].element().unwrap();
+
+        b.set_name(("urn:x", "renamed"));
+        b.set_preferred_prefix(Some("x"));
+
+        assert_qname_eq!(b.name(), ("urn:x", "renamed"));
+        assert_eq!(b.attribute_value("c"), Some("1"));
+        assert_eq!(
+            doc.to_string(),
+            "<?xml version='1.0'?><a><x:renamed c='1' xmlns:x='urn:x'>text</x:renamed></a>"
+        );
+    }
+
+    #[test]
+    fn renaming_into_another_namespace_clears_the_preferred_prefix() {
+        let package = parser::parse("<a xmlns:q='urn:q'><p:b xmlns:p='urn:p'/></a>").unwrap();
+        let doc = package.as_document();
+        let a = doc.root().children()[0].element().unwrap();
+        let b = a.children()[0].element().unwrap();
+
+        b.set_name(("urn:p", "same-namespace"));
+        assert_eq!(b.preferred_prefix(), Some("p"));
+
+        b.set_name(("urn:q", "other-namespace"));
+        assert_eq!(b.preferred_prefix(), None);
+        assert_eq!(b.qualified_name(), "q:other-namespace");
+    }
+
+    #[test]
+    fn renamed_attribute_replaces_one_with_the_same_name() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let e = doc.create_element("e");
+        let a = e.set_attribute_value("a", "1");
+        e.set_attribute_value(("urn:x", "b"), "2");
+
+        a.set_name(("urn:x", "b"));
+
+        assert_eq!(e.attribute_count(), 1);
+        assert_eq!(e.attribute_value(("urn:x", "b")), Some("1"));
+        assert_eq!(a.parent(), Some(e));
+    }
+
+    #[test]
+    fn renaming_validates_the_name() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let e = doc.create_element("e");
+        let a = e.set_attribute_value("a", "1");
+
+        assert_eq!(
+            e.try_set_name("not valid"),
+            Err(InvalidName::LocalPart("not valid".to_owned()))
+        );
+        assert_eq!(
+            a.try_set_name(("", "a")),
+            Err(InvalidName::EmptyNamespaceUri)
+        );
+        assert_qname_eq!(e.name(), "e");
+        assert_qname_eq!(a.name(), "a");
+
+        assert_eq!(a.try_set_name("b"), Ok(()));
+        assert_qname_eq!(a.name(), "b");
+    }
+
+    #[test]
+    fn attribute_namespace_uri_and_local_name() {
+        let package = parser::parse("<a p:x='1' y='2' xmlns:p='u' xmlns='d'/>").unwrap();
+        let doc = package.as_document();
+        let a = doc.root().children()[0].element().unwrap();
+        let x = a.attribute(("u", "x")).unwrap();
+        let y = a.attribute("y").unwrap();
+
+        assert_eq!(x.namespace_uri(), Some("u"));
+        assert_eq!(x.local_name(), "x");
+        assert_eq!(y.namespace_uri(), None);
+        assert_eq!(y.local_name(), "y");
+    }
+
+    #[test]
+    fn attribute_count_tracks_changes() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        assert_eq!(0, element.attribute_count());
+
+        element.set_attribute_value("name1", "value1");
+        element.set_attribute_value("name2", "value2");
+        element.set_attribute_value("name1", "value3");
+        assert_eq!(2, element.attribute_count());
+
+        element.remove_attribute("name2");
+        assert_eq!(1, element.attribute_count());
+    }
+
     #[test]
     fn text_belongs_to_a_document() {
         let package = Package::new();
@@ -1305,6 +4405,28 @@ mod test {
         assert_eq!(doc, text.document());
     }
 
+    #[test]
+    fn text_can_be_marked_as_cdata() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let text = doc.create_text("Now is the winter of our discontent.");
+        assert!(!text.is_cdata());
+
+        text.set_cdata(true);
+        assert!(text.is_cdata());
+    }
+
+    #[test]
+    fn cdata_can_be_created_directly() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let text = doc.create_cdata("<script>a && b</script>");
+        assert!(text.is_cdata());
+        assert_eq!(text.text(), "<script>a && b</script>");
+    }
+
     #[test]
     fn elements_can_have_text_children() {
         let package = Package::new();
@@ -1335,6 +4457,21 @@ mod test {
         assert_eq!(children[0].text().unwrap().text(), quote);
     }
 
+    #[test]
+    fn setting_text_replaces_mixed_children() {
+        let package = parser::parse("<p>one <b>two</b><!--c--><?pi?> three</p>").unwrap();
+        let doc = package.as_document();
+        let p = doc.root_element().unwrap();
+        let b = p.children()[1].element().unwrap();
+
+        let text = p.set_text("a < b");
+
+        assert_eq!(p.children(), vec![ChildOfElement::Text(text)]);
+        assert_eq!(p.string_value(), "a < b");
+        assert_eq!(b.parent(), None);
+        assert_eq!(b.string_value(), "two");
+    }
+
     #[test]
     fn text_knows_its_parent() {
         let package = Package::new();
@@ -1405,6 +4542,26 @@ mod test {
         assert_eq!(text.text(), "Made glorious summer by this sun of York");
     }
 
+    #[test]
+    fn changed_text_keeps_its_position_and_cdata_flag() {
+        let package = parser::parse("<a>one<b/><![CDATA[two]]></a>").unwrap();
+        let doc = package.as_document();
+        let a = doc.root_element().unwrap();
+        let children = a.children();
+        let one = children[0].text().unwrap();
+        let two = children[2].text().unwrap();
+
+        one.set_text("1 < 2");
+        two.set_text("3 > 2");
+
+        assert_eq!(a.children(), children);
+        assert!(two.is_cdata());
+        assert_eq!(
+            doc.to_string(),
+            "<?xml version='1.0'?><a>1 &lt; 2<b/><![CDATA[3 > 2]]></a>"
+        );
+    }
+
     #[test]
     fn comment_belongs_to_a_document() {
         let package = Package::new();
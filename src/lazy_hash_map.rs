@@ -1,8 +1,4 @@
-use std::{
-    borrow::Borrow,
-    collections::{hash_map, HashMap},
-    hash::Hash,
-};
+use std::{borrow::Borrow, collections::HashMap, hash::Hash};
 
 pub struct LazyHashMap<K, V> {
     map: Option<HashMap<K, V>>,
@@ -40,22 +36,4 @@ where
 
         self.map.as_mut().and_then(|m| m.insert(key, val))
     }
-
-    pub fn iter(&self) -> Iter<'_, K, V> {
-        Iter(self.map.as_ref().map(|m| m.iter()))
-    }
-}
-
-pub struct Iter<'a, K, V>(Option<hash_map::Iter<'a, K, V>>);
-
-impl<'a, K: 'a, V: 'a> Iterator for Iter<'a, K, V> {
-    type Item = (&'a K, &'a V);
-
-    fn next(&mut self) -> Option<Self::Item> {
-        self.0.as_mut().and_then(|i| i.next())
-    }
-
-    fn size_hint(&self) -> (usize, Option<usize>) {
-        self.0.as_ref().map_or((0, Some(0)), |i| i.size_hint())
-    }
 }
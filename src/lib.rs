@@ -19,7 +19,9 @@
 //! The `Package` struct is responsible for owning every node in the
 //! document. Strings are interned, allowing repeated text to consume
 //! less memory. This is very useful for documents containing lots of
-//! the same attributes and tag names.
+//! the same attributes and tag names. Parsed text is copied from the
+//! input once, when it is first seen, so a `Package` never borrows
+//! from the string it was parsed from.
 //!
 //! The flip side of this decision is that allocated nodes and strings
 //! are not deallocated until the entire `Package` is dropped. This is
@@ -55,7 +57,7 @@
 #[macro_use]
 extern crate peresil;
 
-use std::fmt;
+use std::{fmt, str::FromStr};
 
 mod lazy_hash_map;
 mod raw;
@@ -69,10 +71,11 @@ pub mod parser;
 pub mod thindom;
 pub mod writer;
 
-pub use crate::str::XmlChar;
+pub use crate::{raw::NodeCounts, str::XmlChar};
 
 static XML_NS_PREFIX: &str = "xml";
 static XML_NS_URI: &str = "http://www.w3.org/XML/1998/namespace";
+static XMLNS_NS_URI: &str = "http://www.w3.org/2000/xmlns/";
 
 /// A prefixed name. This represents what is found in the string form
 /// of an XML document, and does not apply any namespace mapping.
@@ -88,7 +91,7 @@ impl<'a> PrefixedName<'a> {
         PrefixedName::with_prefix(None, local_part)
     }
 
-    /// Create a `PrefixedName` without an optional prefix
+    /// Create a `PrefixedName` with an optional prefix
     pub fn with_prefix(prefix: Option<&'a str>, local_part: &'a str) -> PrefixedName<'a> {
         PrefixedName { prefix, local_part }
     }
@@ -101,6 +104,29 @@ impl<'a> PrefixedName<'a> {
     }
 }
 
+/// Renders the name as it would appear in a document, `prefix:local`
+/// or just `local`.
+///
+/// ### Example
+///
+/// ```
+/// use sxd_document::PrefixedName;
+///
+/// assert_eq!(PrefixedName::new("local").to_string(), "local");
+/// assert_eq!(
+///     PrefixedName::with_prefix(Some("x"), "local").to_string(),
+///     "x:local"
+/// );
+/// ```
+impl<'a> fmt::Display for PrefixedName<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.prefix {
+            Some(prefix) => write!(f, "{}:{}", prefix, self.local_part),
+            None => f.write_str(self.local_part),
+        }
+    }
+}
+
 /// A namespace-qualified name. This represents the name of an element
 /// or attribute *after* the prefix has been mapped to a specific
 /// namespace.
@@ -132,6 +158,29 @@ impl<'s> QName<'s> {
     }
 }
 
+/// Renders the name in Clark notation, `{namespace-uri}local` or just
+/// `local` when there is no namespace.
+///
+/// ### Example
+///
+/// ```
+/// use sxd_document::QName;
+///
+/// assert_eq!(QName::new("local").to_string(), "local");
+/// assert_eq!(
+///     QName::with_namespace_uri(Some("urn:x"), "local").to_string(),
+///     "{urn:x}local"
+/// );
+/// ```
+impl<'s> fmt::Display for QName<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.namespace_uri {
+            Some(uri) => write!(f, "{{{}}}{}", uri, self.local_part),
+            None => f.write_str(self.local_part),
+        }
+    }
+}
+
 impl<'s> From<(&'s str, &'s str)> for QName<'s> {
     fn from(v: (&'s str, &'s str)) -> QName<'s> {
         QName {
@@ -150,6 +199,75 @@ impl<'s> From<&'s str> for QName<'s> {
     }
 }
 
+/// A pattern that names are matched against. Either part of the
+/// name may be a wildcard; a specific namespace is compared by URI,
+/// never by prefix.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct NameTest<'s> {
+    namespace_uri: Option<Option<&'s str>>,
+    local_part: Option<&'s str>,
+}
+
+impl<'s> NameTest<'s> {
+    /// Matches every name
+    pub fn any() -> NameTest<'s> {
+        NameTest {
+            namespace_uri: None,
+            local_part: None,
+        }
+    }
+
+    /// Matches names with this local part, in any namespace or none
+    pub fn any_namespace(local_part: &'s str) -> NameTest<'s> {
+        NameTest {
+            namespace_uri: None,
+            local_part: Some(local_part),
+        }
+    }
+
+    /// Matches names in this namespace, whatever their local part
+    pub fn any_local_part(namespace_uri: Option<&'s str>) -> NameTest<'s> {
+        NameTest {
+            namespace_uri: Some(namespace_uri),
+            local_part: None,
+        }
+    }
+
+    pub fn matches(&self, name: QName<'_>) -> bool {
+        let namespace_matches = match self.namespace_uri {
+            Some(ns) => ns == name.namespace_uri(),
+            None => true,
+        };
+        let local_part_matches = match self.local_part {
+            Some(l) => l == name.local_part(),
+            None => true,
+        };
+
+        namespace_matches && local_part_matches
+    }
+}
+
+impl<'s> From<QName<'s>> for NameTest<'s> {
+    fn from(v: QName<'s>) -> NameTest<'s> {
+        NameTest {
+            namespace_uri: Some(v.namespace_uri),
+            local_part: Some(v.local_part),
+        }
+    }
+}
+
+impl<'s> From<(&'s str, &'s str)> for NameTest<'s> {
+    fn from(v: (&'s str, &'s str)) -> NameTest<'s> {
+        QName::from(v).into()
+    }
+}
+
+impl<'s> From<&'s str> for NameTest<'s> {
+    fn from(v: &'s str) -> NameTest<'s> {
+        QName::from(v).into()
+    }
+}
+
 /// The main entrypoint to an XML document
 ///
 /// This is an opaque structure that stores the internal details of
@@ -179,6 +297,34 @@ impl Package {
         dom::Document::new(&self.storage, &self.connections)
     }
 
+    /// The number of nodes allocated in this package, excluding the
+    /// root.
+    ///
+    /// Nodes are never deallocated before the package is dropped, so
+    /// this includes nodes that were created but never attached, or
+    /// were later removed from the tree.
+    pub fn node_count(&self) -> usize {
+        self.node_counts().total()
+    }
+
+    /// The number of nodes allocated in this package, by kind. See
+    /// `node_count`.
+    pub fn node_counts(&self) -> NodeCounts {
+        self.storage.node_counts()
+    }
+
+    /// Roughly how many bytes this package has allocated for its
+    /// nodes and strings. Like `node_count`, this includes everything
+    /// allocated since the package was created.
+    ///
+    /// Each node is counted at its size and the strings at the size of
+    /// the blocks holding them. The vectors of children and attributes
+    /// owned by nodes, and spare room in the arenas, are not included,
+    /// so the true figure is somewhat higher.
+    pub fn allocated_bytes(&self) -> usize {
+        self.storage.allocated_bytes()
+    }
+
     #[doc(hidden)]
     pub fn as_thin_document(&self) -> (thindom::Storage<'_>, thindom::Connections<'_>) {
         let s = thindom::Storage::new(&self.storage);
@@ -198,3 +344,26 @@ impl fmt::Debug for Package {
         write!(f, "Package")
     }
 }
+
+/// Parses a string into a DOM using the default `Parser`.
+///
+/// ### Example
+///
+/// ```
+/// use sxd_document::Package;
+///
+/// # fn main() -> Result<(), sxd_document::parser::Error> {
+/// let package: Package = "<hello>world</hello>".parse()?;
+/// let doc = package.as_document();
+/// let hello = doc.root().children()[0].element().unwrap();
+/// assert_eq!(hello.name().local_part(), "hello");
+/// # Ok(())
+/// # }
+/// ```
+impl FromStr for Package {
+    type Err = parser::Error;
+
+    fn from_str(xml: &str) -> Result<Package, parser::Error> {
+        parser::Parser::new().parse(xml)
+    }
+}
@@ -13,22 +13,49 @@
 //! </data>"#;
 //! let doc = parser::parse(xml).expect("Failed to parse");
 //! ```
+//!
+//! ### Markup characters in text
+//!
+//! A literal `<` may not appear in character data or attribute
+//! values, and every `&` must begin an entity or character reference.
+//! Otherwise parsing fails. A bare `&`, followed by whitespace or the
+//! end of the input, is reported at the `&` itself:
+//!
+//! ```
+//! use sxd_document::parser;
+//!
+//! let error = parser::parse("<a>fish & chips</a>").unwrap_err();
+//! assert_eq!(error.location(), 8);
+//! ```
+//!
+//! Any other malformed reference, such as `&;`, is reported just after
+//! the `&`, where the name was expected.
+//!
+//! `Parser::parse_lenient` instead keeps such an `&` as text.
 
 #[allow(unused, deprecated)] // rust-lang/rust#46510
 use std::ascii::AsciiExt;
 use std::{
+    borrow::Cow,
+    cell::Cell,
     char,
     collections::{BTreeSet, HashMap},
     error, fmt, iter,
     mem::replace,
-    ops::Deref,
+    ops::{Deref, Range},
+    str,
+    sync::Arc,
 };
 
 use peresil::{self, ParseMaster, Recoverable, StringPoint};
 
 use self::Reference::*;
 
-use super::{dom, str::XmlStr, PrefixedName, QName};
+use super::{
+    dom,
+    str::{XmlChar, XmlStr},
+    PrefixedName, QName,
+};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 enum SpecificError {
@@ -61,6 +88,8 @@ enum SpecificError {
     ExpectedDocumentTypeName,
     ExpectedIntSubset,
     ExpectedSystemLiteral,
+    ExpectedPublicLiteral,
+    ExpectedEntityValue,
 
     ExpectedClosingQuote(&'static str),
     ExpectedOpeningQuote(&'static str),
@@ -75,17 +104,31 @@ enum SpecificError {
 
     InvalidProcessingInstructionTarget,
     MismatchedElementEndName,
+    DoubleHyphenInComment,
+    UnterminatedComment,
+    UnterminatedProcessingInstruction,
 
     InvalidDecimalReference,
     InvalidHexReference,
     UnknownNamedReference,
+    RecursiveEntityReference,
 
     DuplicateAttribute,
     RedefinedNamespace,
     RedefinedDefaultNamespace,
     EmptyNamespace,
+    ReservedNamespace,
     UnknownNamespacePrefix,
     UnclosedElement,
+    MaximumDepthExceeded,
+    MaximumAttributesExceeded,
+    MaximumAttributeValueLengthExceeded,
+    MaximumElementsExceeded,
+    EntityExpansionLimitExceeded,
+
+    InvalidByteSequence,
+    UnsupportedEncoding,
+    TrailingText,
 }
 
 impl Recoverable for SpecificError {
@@ -97,34 +140,55 @@ impl Recoverable for SpecificError {
             | ExpectedYesNo
             | InvalidProcessingInstructionTarget
             | MismatchedElementEndName
+            | DoubleHyphenInComment
+            | UnterminatedComment
+            | UnterminatedProcessingInstruction
             | InvalidDecimalReference
             | InvalidHexReference
             | UnknownNamedReference
+            | RecursiveEntityReference
             | DuplicateAttribute
             | RedefinedNamespace
             | RedefinedDefaultNamespace
             | EmptyNamespace
+            | ReservedNamespace
             | UnknownNamespacePrefix
-            | UnclosedElement => false,
+            | UnclosedElement
+            | MaximumDepthExceeded
+            | MaximumAttributesExceeded
+            | MaximumAttributeValueLengthExceeded
+            | MaximumElementsExceeded
+            | EntityExpansionLimitExceeded
+            | InvalidByteSequence
+            | UnsupportedEncoding
+            | TrailingText => false,
             _ => true,
         }
     }
 }
 
-impl fmt::Display for SpecificError {
+impl SpecificError {
+    /// The description, along with any expected literal.
     #[allow(deprecated)]
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fn fmt_message(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use self::{error::Error, SpecificError::*};
 
         match *self {
             Expected(s) | ExpectedClosingQuote(s) | ExpectedOpeningQuote(s) => {
-                write!(f, "Parser error: {} {}", self.description(), s)
+                write!(f, "{} {:?}", self.description(), s)
             }
-            _ => write!(f, "Parser error: {}", self.description()),
+            _ => f.write_str(self.description()),
         }
     }
 }
 
+impl fmt::Display for SpecificError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Parser error: ")?;
+        self.fmt_message(f)
+    }
+}
+
 impl error::Error for SpecificError {
     fn description(&self) -> &str {
         use self::SpecificError::*;
@@ -151,6 +215,8 @@ impl error::Error for SpecificError {
             ExpectedDocumentTypeName => "expected document type name",
             ExpectedIntSubset => "expected int subset",
             ExpectedSystemLiteral => "expected system literal",
+            ExpectedPublicLiteral => "expected public identifier",
+            ExpectedEntityValue => "expected entity value",
             ExpectedClosingQuote(_) => "expected closing quote",
             ExpectedOpeningQuote(_) => "expected opening quote",
             ExpectedDecimalReferenceValue => "expected decimal reference value",
@@ -161,15 +227,28 @@ impl error::Error for SpecificError {
             ExpectedNamedReference => "expected named reference",
             InvalidProcessingInstructionTarget => "invalid processing instruction target",
             MismatchedElementEndName => "mismatched element end name",
+            DoubleHyphenInComment => "-- is not allowed inside a comment",
+            UnterminatedComment => "unterminated comment",
+            UnterminatedProcessingInstruction => "unterminated processing instruction",
             InvalidDecimalReference => "invalid decimal reference",
             InvalidHexReference => "invalid hex reference",
             UnknownNamedReference => "unknown named reference",
+            RecursiveEntityReference => "recursive entity reference",
             DuplicateAttribute => "duplicate attribute",
             RedefinedNamespace => "redefined namespace",
             RedefinedDefaultNamespace => "redefined default namespace",
             EmptyNamespace => "empty namespace",
+            ReservedNamespace => "the xml and xmlns prefixes and namespaces are reserved",
             UnknownNamespacePrefix => "unknown namespace prefix",
             UnclosedElement => "unclosed element",
+            MaximumDepthExceeded => "maximum element depth exceeded",
+            MaximumAttributesExceeded => "maximum attributes per element exceeded",
+            MaximumAttributeValueLengthExceeded => "maximum attribute value length exceeded",
+            MaximumElementsExceeded => "maximum element count exceeded",
+            EntityExpansionLimitExceeded => "entity expansion limit exceeded",
+            InvalidByteSequence => "invalid byte sequence",
+            UnsupportedEncoding => "unsupported encoding",
+            TrailingText => "unexpected text after the end",
         }
     }
 }
@@ -278,11 +357,14 @@ trait PrivateXmlParseExt<'a> {
     fn consume_hex_chars(&self) -> XmlProgress<'a, &'a str>;
     fn consume_char_data(&self) -> XmlProgress<'a, &'a str>;
     fn consume_cdata(&self) -> XmlProgress<'a, &'a str>;
-    fn consume_int_subset(&self) -> XmlProgress<'a, &'a str>;
+    fn consume_entity_value(&self, quote: &str) -> XmlProgress<'a, &'a str>;
+    fn consume_markup_declaration(&self) -> XmlProgress<'a, &'a str>;
     fn consume_comment(&self) -> XmlProgress<'a, &'a str>;
     fn consume_pi_value(&self) -> XmlProgress<'a, &'a str>;
     fn consume_start_tag(&self) -> XmlProgress<'a, &'a str>;
     fn consume_encoding(&self) -> XmlProgress<'a, &'a str>;
+    fn consume_system_literal(&self, quote: &str) -> XmlProgress<'a, &'a str>;
+    fn consume_pubid_literal(&self, quote: &str) -> XmlProgress<'a, &'a str>;
 }
 
 impl<'a> PrivateXmlParseExt<'a> for StringPoint<'a> {
@@ -310,8 +392,23 @@ impl<'a> PrivateXmlParseExt<'a> for StringPoint<'a> {
             .map_err(|_| SpecificError::ExpectedCData)
     }
 
-    fn consume_int_subset(&self) -> XmlProgress<'a, &'a str> {
-        self.consume_to(self.s.end_of_int_subset())
+    fn consume_entity_value(&self, quote: &str) -> XmlProgress<'a, &'a str> {
+        self.consume_to(self.s.end_of_entity_value(quote))
+            .map_err(|_| SpecificError::ExpectedEntityValue)
+    }
+
+    fn consume_system_literal(&self, quote: &str) -> XmlProgress<'a, &'a str> {
+        self.consume_to(self.s.end_of_system_literal(quote))
+            .map_err(|_| SpecificError::ExpectedSystemLiteral)
+    }
+
+    fn consume_pubid_literal(&self, quote: &str) -> XmlProgress<'a, &'a str> {
+        self.consume_to(self.s.end_of_pubid_literal(quote))
+            .map_err(|_| SpecificError::ExpectedPublicLiteral)
+    }
+
+    fn consume_markup_declaration(&self) -> XmlProgress<'a, &'a str> {
+        self.consume_to(self.s.end_of_markup_declaration())
             .map_err(|_| SpecificError::ExpectedIntSubset)
     }
 
@@ -355,10 +452,17 @@ impl<'a> X<'a> for StringPoint<'a> {
 
 #[derive(Debug, Copy, Clone)]
 enum Token<'a> {
-    XmlDeclaration,
-    DocumentTypeDeclaration,
+    XmlDeclaration(&'a str, Option<Span<&'a str>>, Option<bool>),
+    DocumentTypeDeclaration(Doctype<'a>),
+    InternalSubsetStart(Doctype<'a>),
+    InternalSubsetEnd(&'a str),
+    MarkupDeclaration,
+    EntityDeclarationStart(&'a str, &'static str),
+    EntityDeclarationEnd,
+    LiteralEntityValue(&'a str),
+    ReferenceEntityValue(Reference<'a>),
     Comment(&'a str),
-    ProcessingInstruction(&'a str, Option<&'a str>),
+    ProcessingInstruction(Span<&'a str>, Option<&'a str>),
     Whitespace(&'a str),
     ElementStart(Span<PrefixedName<'a>>),
     ElementStartClose,
@@ -373,14 +477,26 @@ enum Token<'a> {
     ContentReference(Reference<'a>),
 }
 
+/// The name and external identifier of a document type declaration.
+#[derive(Debug, Copy, Clone)]
+struct Doctype<'a> {
+    name: &'a str,
+    public_id: Option<&'a str>,
+    system_id: Option<&'a str>,
+}
+
 #[derive(Debug, Copy, Clone)]
 enum State {
     AtBeginning,
     AfterDeclaration,
+    InternalSubset,
+    AfterDocumentType,
+    AfterEntityDeclarationStart(&'static str),
     AfterElementStart(usize),
     AfterAttributeStart(usize, &'static str),
     Content(usize),
     AfterMainElement,
+    Fragment,
 }
 
 #[derive(Debug)]
@@ -388,23 +504,185 @@ struct PullParser<'a> {
     pm: XmlMaster<'a>,
     xml: StringPoint<'a>,
     state: State,
+    max_depth: Option<usize>,
+    max_attributes: Option<usize>,
+    max_attribute_value_length: Option<usize>,
+    max_elements: Option<usize>,
+    elements: usize,
+    attributes: usize,
+    attribute_value_start: usize,
+    token_start: usize,
+    internal_subset_start: StringPoint<'a>,
+    fragment: bool,
+    stop_at_next_document: bool,
 }
 
 impl<'a> PullParser<'a> {
     fn new(xml: &str) -> PullParser<'_> {
+        let start = skip_byte_order_mark(xml);
+
         PullParser {
             pm: ParseMaster::new(),
-            xml: StringPoint::new(xml),
+            xml: start,
             state: State::AtBeginning,
+            max_depth: None,
+            max_attributes: None,
+            max_attribute_value_length: None,
+            max_elements: None,
+            elements: 0,
+            attributes: 0,
+            attribute_value_start: 0,
+            token_start: start.offset,
+            internal_subset_start: start,
+            fragment: false,
+            stop_at_next_document: false,
+        }
+    }
+
+    /// Parses one of several documents that follow each other in
+    /// `xml`, starting at `offset`. The parser stops before anything
+    /// after the main element that starts another document.
+    fn new_document_at(xml: &str, offset: usize) -> PullParser<'_> {
+        let parser = PullParser::new(xml);
+        let start = if offset == 0 {
+            parser.xml
+        } else {
+            StringPoint {
+                s: &xml[offset..],
+                offset,
+            }
+        };
+
+        PullParser {
+            xml: start,
+            token_start: start.offset,
+            internal_subset_start: start,
+            stop_at_next_document: true,
+            ..parser
+        }
+    }
+
+    /// Parses text decoded from bytes, which already had any byte
+    /// order mark removed. Another one at the start is an error.
+    fn new_decoded(xml: &str) -> PullParser<'_> {
+        let start = StringPoint { s: xml, offset: 0 };
+
+        PullParser {
+            xml: start,
+            token_start: start.offset,
+            internal_subset_start: start,
+            ..PullParser::new(xml)
+        }
+    }
+
+    /// Parses content that may have any number of top-level elements
+    /// and text, but no prolog.
+    fn new_fragment(xml: &str) -> PullParser<'_> {
+        PullParser {
+            state: State::Fragment,
+            fragment: true,
+            ..PullParser::new(xml)
+        }
+    }
+
+    /// The state once no elements are open.
+    fn top_level(&self) -> State {
+        if self.fragment {
+            State::Fragment
+        } else {
+            State::AfterMainElement
         }
     }
+
+    fn with_limits(mut self, options: &Parser) -> Self {
+        self.max_depth = options.max_depth;
+        self.max_attributes = options.max_attributes;
+        self.max_attribute_value_length = options.max_attribute_value_length;
+        self.max_elements = options.max_elements;
+        self
+    }
+
+    /// Counts elements and attributes as they are read, failing once
+    /// one of the limits is exceeded.
+    fn check_limits(&mut self, token: &Token<'_>, end: usize) -> Option<SpecificError> {
+        let exceeds = |count: usize, limit: Option<usize>| limit.map_or(false, |l| count > l);
+
+        match *token {
+            Token::ElementStart(..) => {
+                self.elements += 1;
+                self.attributes = 0;
+                if exceeds(self.elements, self.max_elements) {
+                    return Some(SpecificError::MaximumElementsExceeded);
+                }
+            }
+            Token::AttributeStart(..) => {
+                self.attributes += 1;
+                self.attribute_value_start = end;
+                if exceeds(self.attributes, self.max_attributes) {
+                    return Some(SpecificError::MaximumAttributesExceeded);
+                }
+            }
+            Token::LiteralAttributeValue(..) | Token::ReferenceAttributeValue(..) => {
+                let length = end - self.attribute_value_start;
+                if exceeds(length, self.max_attribute_value_length) {
+                    return Some(SpecificError::MaximumAttributeValueLengthExceeded);
+                }
+            }
+            _ => {}
+        }
+
+        None
+    }
+
+    /// After a failure, treats a `&` that does not begin a reference
+    /// as literal text so that parsing can continue.
+    fn recover_stray_ampersand(&mut self) -> Option<Token<'a>> {
+        let (xml, amp) = self.xml.consume_literal("&").optional(self.xml);
+        let amp = amp?;
+
+        let token = match self.state {
+            State::Content(..) | State::Fragment => Token::CharData(amp),
+            State::AfterAttributeStart(..) => Token::LiteralAttributeValue(amp),
+            _ => return None,
+        };
+
+        self.token_start = self.xml.offset;
+        self.xml = xml;
+        Some(token)
+    }
+
+    /// The byte offset of the input not yet parsed.
+    fn offset(&self) -> usize {
+        self.xml.offset
+    }
+
+    /// The byte offsets of the most recently produced token.
+    fn token_range(&self) -> Range<usize> {
+        self.token_start..self.xml.offset
+    }
+
+    /// Replaces the depth tracked after an element close tag, for when
+    /// the close tag ended a different number of elements than one.
+    fn set_open_elements(&mut self, open: usize) {
+        self.state = match open {
+            0 => self.top_level(),
+            n => State::Content(n - 1),
+        };
+    }
 }
 
 fn parse_comment<'a>(xml: StringPoint<'a>) -> XmlProgress<'a, Token<'_>> {
+    let start = xml;
     let (xml, _) = try_parse!(xml
         .consume_literal("<!--")
         .map_err(|_| SpecificError::ExpectedComment));
+    if xml.s.end_of_comment().is_none() {
+        return peresil::Progress::failure(start, SpecificError::UnterminatedComment);
+    }
     let (xml, text) = try_parse!(xml.consume_comment());
+    if !xml.s.starts_with("-->") {
+        return peresil::Progress::failure(xml, SpecificError::DoubleHyphenInComment);
+    }
     let (xml, _) = try_parse!(xml.expect_literal("-->"));
 
     success(Token::Comment(text), xml)
@@ -478,12 +756,14 @@ fn parse_version_info<'a>(
 fn parse_encoding_declaration<'a>(
     pm: &mut XmlMaster<'a>,
     xml: StringPoint<'a>,
-) -> XmlProgress<'a, &'a str> {
+) -> XmlProgress<'a, Span<&'a str>> {
     let (xml, _) = try_parse!(xml.expect_space());
     let (xml, _) = try_parse!(xml.expect_literal("encoding"));
     let (xml, _) = try_parse!(parse_eq(xml));
-    let (xml, encoding) =
-        try_parse!(parse_quoted_value(pm, xml, |_, xml, _| xml.consume_encoding()));
+    let (xml, encoding) = try_parse!(parse_quoted_value(pm, xml, |_, xml, _| Span::parse(
+        xml,
+        |xml| xml.consume_encoding()
+    )));
 
     success(encoding, xml)
 }
@@ -491,7 +771,7 @@ fn parse_encoding_declaration<'a>(
 fn parse_standalone_declaration<'a>(
     pm: &mut XmlMaster<'a>,
     xml: StringPoint<'a>,
-) -> XmlProgress<'a, &'a str> {
+) -> XmlProgress<'a, bool> {
     let (xml, _) = try_parse!(xml.expect_space());
     let (xml, _) = try_parse!(xml.expect_literal("standalone"));
     let (xml, _) = try_parse!(parse_eq(xml));
@@ -503,7 +783,7 @@ fn parse_standalone_declaration<'a>(
             .map_err(|_| SpecificError::ExpectedYesNo)
     }));
 
-    success(standalone, xml)
+    success(standalone == "yes", xml)
 }
 
 fn parse_xml_declaration<'a>(
@@ -511,40 +791,59 @@ fn parse_xml_declaration<'a>(
     xml: StringPoint<'a>,
 ) -> XmlProgress<'a, Token<'a>> {
     let (xml, _) = try_parse!(xml.expect_literal("<?xml"));
-    let (xml, _version) = try_parse!(parse_version_info(pm, xml));
-    let (xml, _encoding) =
+    let (xml, version) = try_parse!(parse_version_info(pm, xml));
+    let (xml, encoding) =
         try_parse!(pm.optional(xml, |pm, xml| { parse_encoding_declaration(pm, xml) }));
-    let (xml, _standalone) =
+    let (xml, standalone) =
         try_parse!(pm.optional(xml, |pm, xml| { parse_standalone_declaration(pm, xml) }));
     let (xml, _) = xml.consume_space().optional(xml);
     let (xml, _) = try_parse!(xml.expect_literal("?>"));
 
-    success(Token::XmlDeclaration, xml)
+    success(Token::XmlDeclaration(version, encoding, standalone), xml)
 }
 
-/* only the SYSTEM variant */
-fn parse_external_id<'a>(pm: &mut XmlMaster<'a>, xml: StringPoint<'a>) -> XmlProgress<'a, &'a str> {
-    let (xml, _) = try_parse!(xml.expect_space());
+fn parse_system_literal<'a>(
+    pm: &mut XmlMaster<'a>,
+    xml: StringPoint<'a>,
+) -> XmlProgress<'a, &'a str> {
+    parse_quoted_value(pm, xml, |_, xml, quote| xml.consume_system_literal(quote))
+}
+
+fn parse_system_external_id<'a>(
+    pm: &mut XmlMaster<'a>,
+    xml: StringPoint<'a>,
+) -> XmlProgress<'a, (Option<&'a str>, &'a str)> {
     let (xml, _) = try_parse!(xml.expect_literal("SYSTEM"));
     let (xml, _) = try_parse!(xml.expect_space());
-    let (xml, external_id) = try_parse!(parse_quoted_value(pm, xml, |_, xml, quote| xml
-        .consume_attribute_value(quote)
-        .map_err(|_| SpecificError::ExpectedSystemLiteral)));
+    let (xml, system_id) = try_parse!(parse_system_literal(pm, xml));
 
-    success(external_id, xml)
+    success((None, system_id), xml)
 }
 
-fn parse_int_subset<'a>(_pm: &mut XmlMaster<'a>, xml: StringPoint<'a>) -> XmlProgress<'a, &'a str> {
-    let (xml, _) = try_parse!(xml.expect_literal("["));
-    let (xml, _) = xml.consume_space().optional(xml);
-    let (xml, elements) = try_parse!(xml
-        .consume_int_subset()
-        .map_err(|_| SpecificError::ExpectedIntSubset));
-    let (xml, _) = xml.consume_space().optional(xml);
-    let (xml, _) = try_parse!(xml.expect_literal("]"));
-    let (xml, _) = xml.consume_space().optional(xml);
+fn parse_public_external_id<'a>(
+    pm: &mut XmlMaster<'a>,
+    xml: StringPoint<'a>,
+) -> XmlProgress<'a, (Option<&'a str>, &'a str)> {
+    let (xml, _) = try_parse!(xml.expect_literal("PUBLIC"));
+    let (xml, _) = try_parse!(xml.expect_space());
+    let (xml, public_id) =
+        try_parse!(parse_quoted_value(pm, xml, |_, xml, quote| xml.consume_pubid_literal(quote)));
+    let (xml, _) = try_parse!(xml.expect_space());
+    let (xml, system_id) = try_parse!(parse_system_literal(pm, xml));
 
-    success(elements, xml)
+    success((Some(public_id), system_id), xml)
+}
+
+fn parse_external_id<'a>(
+    pm: &mut XmlMaster<'a>,
+    xml: StringPoint<'a>,
+) -> XmlProgress<'a, (Option<&'a str>, &'a str)> {
+    let (xml, _) = try_parse!(xml.expect_space());
+
+    pm.alternate()
+        .one(|pm| parse_system_external_id(pm, xml))
+        .one(|pm| parse_public_external_id(pm, xml))
+        .finish()
 }
 
 fn parse_document_type_declaration<'a>(
@@ -553,34 +852,154 @@ fn parse_document_type_declaration<'a>(
 ) -> XmlProgress<'a, Token<'a>> {
     let (xml, _) = try_parse!(xml.expect_literal("<!DOCTYPE"));
     let (xml, _) = try_parse!(xml.expect_space());
-    let (xml, _type_name) = try_parse!(xml
+    let (xml, name) = try_parse!(xml
         .consume_name()
         .map_err(|_| SpecificError::ExpectedDocumentTypeName));
-    let (xml, _id) = try_parse!(pm.optional(xml, |p, x| parse_external_id(p, x)));
+    // Unlike `optional`, this records why an external ID that was
+    // started could not be parsed
+    let (xml, id) = try_parse!(pm
+        .alternate()
+        .one(|pm| parse_external_id(pm, xml).map(Some))
+        .one(|_| success(None, xml))
+        .finish());
+    let (xml, _) = xml.consume_space().optional(xml);
+
+    let doctype = Doctype {
+        name,
+        public_id: id.and_then(|(public_id, _)| public_id),
+        system_id: id.map(|(_, system_id)| system_id),
+    };
+
+    pm.alternate()
+        .one(|_| {
+            xml.expect_literal(">")
+                .map(|_| Token::DocumentTypeDeclaration(doctype))
+        })
+        .one(|_| {
+            xml.expect_literal("[")
+                .map(|_| Token::InternalSubsetStart(doctype))
+        })
+        .finish()
+}
+
+/// `start` is the point just after the `[` that opened the internal
+/// subset, so that its raw text can be reported.
+fn parse_internal_subset_end<'a>(
+    start: StringPoint<'a>,
+    xml: StringPoint<'a>,
+) -> XmlProgress<'a, Token<'a>> {
+    let subset = start.to(xml);
+    let (xml, _) = try_parse!(xml.expect_literal("]"));
     let (xml, _) = xml.consume_space().optional(xml);
-    let (xml, _int_subset) = try_parse!(pm.optional(xml, |p, x| parse_int_subset(p, x)));
     let (xml, _) = try_parse!(xml.expect_literal(">"));
 
-    success(Token::DocumentTypeDeclaration, xml)
+    success(Token::InternalSubsetEnd(subset), xml)
 }
 
-fn parse_pi_value(xml: StringPoint<'_>) -> XmlProgress<'_, &str> {
+fn parse_entity_declaration_start<'a>(
+    pm: &mut XmlMaster<'a>,
+    xml: StringPoint<'a>,
+) -> XmlProgress<'a, Token<'a>> {
+    let (xml, _) = try_parse!(xml.expect_literal("<!ENTITY"));
     let (xml, _) = try_parse!(xml.expect_space());
+    let (xml, name) = try_parse!(xml
+        .consume_name()
+        .map_err(|_| SpecificError::ExpectedNamedReferenceValue));
+    let (xml, _) = try_parse!(xml.expect_space());
+
+    let (xml, q) = try_parse!(pm
+        .alternate()
+        .one(|_| xml
+            .expect_literal(QUOT)
+            .map_err(|_| SpecificError::ExpectedOpeningQuote(QUOT)))
+        .one(|_| xml
+            .expect_literal(APOS)
+            .map_err(|_| SpecificError::ExpectedOpeningQuote(APOS)))
+        .finish());
+
+    let q = if q == QUOT { QUOT } else { APOS };
+
+    success(Token::EntityDeclarationStart(name, q), xml)
+}
+
+fn parse_entity_declaration_end<'a>(
+    xml: StringPoint<'a>,
+    quote: &'static str,
+) -> XmlProgress<'a, Token<'a>> {
+    let (xml, _) = try_parse!(xml
+        .consume_literal(quote)
+        .map_err(|_| SpecificError::ExpectedClosingQuote(quote)));
+    let (xml, _) = xml.consume_space().optional(xml);
+    let (xml, _) = try_parse!(xml.expect_literal(">"));
+
+    success(Token::EntityDeclarationEnd, xml)
+}
+
+fn parse_entity_literal<'a>(xml: StringPoint<'a>, quote: &str) -> XmlProgress<'a, Token<'a>> {
+    let (xml, val) = try_parse!(xml.consume_entity_value(quote));
+
+    success(Token::LiteralEntityValue(val), xml)
+}
+
+fn parse_entity_reference<'a>(
+    pm: &mut XmlMaster<'a>,
+    xml: StringPoint<'a>,
+) -> XmlProgress<'a, Token<'a>> {
+    let (xml, val) = try_parse!(parse_reference(pm, xml));
+
+    success(Token::ReferenceEntityValue(val), xml)
+}
+
+/// Element type, attribute list, notation, parameter entity, and
+/// external entity declarations are checked only for balanced quotes.
+fn parse_markup_declaration(xml: StringPoint<'_>) -> XmlProgress<'_, Token<'_>> {
+    let (xml, _) = try_parse!(xml.expect_literal("<!"));
+    let (xml, _) = try_parse!(xml
+        .consume_name()
+        .map_err(|_| SpecificError::ExpectedIntSubset));
+    let (xml, _) = try_parse!(xml.consume_markup_declaration());
+    let (xml, _) = try_parse!(xml.expect_literal(">"));
+
+    success(Token::MarkupDeclaration, xml)
+}
+
+fn parse_parameter_entity_reference(xml: StringPoint<'_>) -> XmlProgress<'_, Token<'_>> {
+    let (xml, _) = try_parse!(xml.expect_literal("%"));
+    let (xml, _) = try_parse!(xml
+        .consume_name()
+        .map_err(|_| SpecificError::ExpectedNamedReferenceValue));
+    let (xml, _) = try_parse!(xml.expect_literal(";"));
+
+    success(Token::MarkupDeclaration, xml)
+}
+
+/// Only the single whitespace character separating the value from
+/// the target is skipped; any more whitespace is part of the value.
+/// This matches how values are written, so they round-trip exactly.
+fn parse_pi_value(xml: StringPoint<'_>) -> XmlProgress<'_, &str> {
+    let separator = xml.s.chars().next().filter(|c| c.is_space_char());
+    let (xml, _) = try_parse!(xml
+        .consume_to(separator.map(char::len_utf8))
+        .map_err(|_| SpecificError::ExpectedWhitespace));
     xml.consume_pi_value()
 }
 
 fn parse_pi<'a>(xml: StringPoint<'a>) -> XmlProgress<'a, Token<'_>> {
+    let start = xml;
     let (xml, _) = try_parse!(xml
         .consume_literal("<?")
         .map_err(|_| SpecificError::ExpectedProcessingInstruction));
+    if xml.s.end_of_pi_value().is_none() {
+        return peresil::Progress::failure(start, SpecificError::UnterminatedProcessingInstruction);
+    }
     let target_xml = xml;
-    let (xml, target) = try_parse!(xml
+    let (xml, target) = try_parse!(Span::parse(xml, |xml| xml
         .consume_name()
-        .map_err(|_| SpecificError::ExpectedProcessingInstructionTarget));
+        .map_err(|_| SpecificError::ExpectedProcessingInstructionTarget)));
     let (xml, value) = parse_pi_value(xml).optional(xml);
     let (xml, _) = try_parse!(xml.expect_literal("?>"));
 
-    if target.eq_ignore_ascii_case("xml") {
+    if target.value.eq_ignore_ascii_case("xml") {
         return peresil::Progress::failure(
             target_xml,
             SpecificError::InvalidProcessingInstructionTarget,
@@ -747,17 +1166,33 @@ fn parse_content_reference<'a>(
     success(Token::ContentReference(r), xml)
 }
 
+/// Whether the text begins with something only allowed before the
+/// main element: an XML or document type declaration, or an element.
+fn starts_document(xml: &str) -> bool {
+    let declaration = xml.starts_with("<?xml")
+        && xml["<?xml".len()..]
+            .chars()
+            .next()
+            .map_or(false, |c| c.is_space_char() || c == '?');
+
+    declaration
+        || xml.starts_with("<!DOCTYPE")
+        || (xml.starts_with('<') && !xml.starts_with("<!") && !xml.starts_with("<?"))
+}
+
 impl<'a> Iterator for PullParser<'a> {
     type Item = Result<Token<'a>, (usize, Vec<SpecificError>)>;
 
     fn next(&mut self) -> Option<Self::Item> {
         let pm = &mut self.pm;
         let xml = self.xml;
+        let internal_subset_start = self.internal_subset_start;
 
         let r = match self.state {
             State::AtBeginning => pm
                 .alternate()
                 .one(|pm| parse_xml_declaration(pm, xml))
+                .one(|pm| parse_document_type_declaration(pm, xml))
                 .one(|_| parse_element_start(xml))
                 .one(|_| xml.expect_space().map(Token::Whitespace))
                 .one(|_| parse_comment(xml))
@@ -773,6 +1208,33 @@ impl<'a> Iterator for PullParser<'a> {
                 .one(|_| parse_pi(xml))
                 .finish(),
 
+            // Only one document type declaration is allowed
+            State::AfterDocumentType => pm
+                .alternate()
+                .one(|_| parse_element_start(xml))
+                .one(|_| xml.expect_space().map(Token::Whitespace))
+                .one(|_| parse_comment(xml))
+                .one(|_| parse_pi(xml))
+                .finish(),
+
+            State::InternalSubset => pm
+                .alternate()
+                .one(|pm| parse_entity_declaration_start(pm, xml))
+                .one(|_| parse_markup_declaration(xml))
+                .one(|_| parse_parameter_entity_reference(xml))
+                .one(|_| parse_comment(xml).map(|_| Token::MarkupDeclaration))
+                .one(|_| parse_pi(xml).map(|_| Token::MarkupDeclaration))
+                .one(|_| xml.expect_space().map(Token::Whitespace))
+                .one(|_| parse_internal_subset_end(internal_subset_start, xml))
+                .finish(),
+
+            State::AfterEntityDeclarationStart(quote) => pm
+                .alternate()
+                .one(|_| parse_entity_literal(xml, quote))
+                .one(|pm| parse_entity_reference(pm, xml))
+                .one(|_| parse_entity_declaration_end(xml, quote))
+                .finish(),
+
             State::AfterElementStart(..) => pm
                 .alternate()
                 .one(|pm| parse_attribute_start(pm, xml))
@@ -799,7 +1261,7 @@ impl<'a> Iterator for PullParser<'a> {
                 .finish(),
 
             State::AfterMainElement => {
-                if xml.is_empty() {
+                if xml.is_empty() || (self.stop_at_next_document && starts_document(xml.s)) {
                     return None;
                 }
 
@@ -809,6 +1271,21 @@ impl<'a> Iterator for PullParser<'a> {
                     .one(|_| xml.expect_space().map(Token::Whitespace))
                     .finish()
             }
+
+            State::Fragment => {
+                if xml.is_empty() {
+                    return None;
+                }
+
+                pm.alternate()
+                    .one(|_| parse_element_start(xml))
+                    .one(|_| parse_char_data(xml))
+                    .one(|_| parse_cdata(xml))
+                    .one(|pm| parse_content_reference(pm, xml))
+                    .one(|_| parse_comment(xml))
+                    .one(|_| parse_pi(xml))
+                    .finish()
+            }
         };
 
         let (r, pt) = match pm.finish(r) {
@@ -820,7 +1297,13 @@ impl<'a> Iterator for PullParser<'a> {
                 status: peresil::Status::Failure(e),
                 point,
             } => {
-                return Some(Err((point.offset, e)));
+                // A bare `&` is reported where it is, not after it
+                let offset = if starts_with_bare_ampersand(xml.s) {
+                    xml.offset
+                } else {
+                    point.offset
+                };
+                return Some(Err((offset, e)));
             }
         };
 
@@ -829,23 +1312,48 @@ impl<'a> Iterator for PullParser<'a> {
         }
 
         let next_state = match (self.state, r) {
-            (State::AtBeginning, Token::XmlDeclaration)
+            (State::AtBeginning, Token::XmlDeclaration(..))
             | (State::AtBeginning, Token::ProcessingInstruction(..))
             | (State::AtBeginning, Token::Comment(..))
             | (State::AtBeginning, Token::Whitespace(..)) => State::AfterDeclaration,
+            (State::AtBeginning, Token::DocumentTypeDeclaration(..)) => State::AfterDocumentType,
+            (State::AtBeginning, Token::InternalSubsetStart(..)) => State::InternalSubset,
             (State::AtBeginning, Token::ElementStart(..)) => State::AfterElementStart(0),
 
             (State::AfterDeclaration, Token::ProcessingInstruction(..))
             | (State::AfterDeclaration, Token::Comment(..))
             | (State::AfterDeclaration, Token::Whitespace(..)) => State::AfterDeclaration,
-            (State::AfterDeclaration, Token::DocumentTypeDeclaration) => State::AfterDeclaration,
+            (State::AfterDeclaration, Token::DocumentTypeDeclaration(..)) => {
+                State::AfterDocumentType
+            }
+            (State::AfterDeclaration, Token::InternalSubsetStart(..)) => State::InternalSubset,
             (State::AfterDeclaration, Token::ElementStart(..)) => State::AfterElementStart(0),
 
+            (State::InternalSubset, Token::MarkupDeclaration)
+            | (State::InternalSubset, Token::Whitespace(..)) => State::InternalSubset,
+            (State::InternalSubset, Token::EntityDeclarationStart(_, q)) => {
+                State::AfterEntityDeclarationStart(q)
+            }
+            (State::InternalSubset, Token::InternalSubsetEnd(..)) => State::AfterDocumentType,
+
+            (State::AfterDocumentType, Token::ProcessingInstruction(..))
+            | (State::AfterDocumentType, Token::Comment(..))
+            | (State::AfterDocumentType, Token::Whitespace(..)) => State::AfterDocumentType,
+            (State::AfterDocumentType, Token::ElementStart(..)) => State::AfterElementStart(0),
+
+            (State::AfterEntityDeclarationStart(q), Token::LiteralEntityValue(..))
+            | (State::AfterEntityDeclarationStart(q), Token::ReferenceEntityValue(..)) => {
+                State::AfterEntityDeclarationStart(q)
+            }
+            (State::AfterEntityDeclarationStart(_), Token::EntityDeclarationEnd) => {
+                State::InternalSubset
+            }
+
             (State::AfterElementStart(d), Token::AttributeStart(_, q)) => {
                 State::AfterAttributeStart(d, q)
             }
             (State::AfterElementStart(d), Token::ElementStartClose) => State::Content(d),
-            (State::AfterElementStart(0), Token::ElementSelfClose) => State::AfterMainElement,
+            (State::AfterElementStart(0), Token::ElementSelfClose) => self.top_level(),
             (State::AfterElementStart(d), Token::ElementSelfClose) => State::Content(d - 1),
 
             (State::AfterAttributeStart(d, q), Token::LiteralAttributeValue(..))
@@ -860,89 +1368,391 @@ impl<'a> Iterator for PullParser<'a> {
             | (State::Content(d), Token::Comment(..))
             | (State::Content(d), Token::ProcessingInstruction(..)) => State::Content(d),
             (State::Content(d), Token::ElementStart(..)) => State::AfterElementStart(d + 1),
-            (State::Content(0), Token::ElementClose(..)) => State::AfterMainElement,
+            (State::Content(0), Token::ElementClose(..)) => self.top_level(),
             (State::Content(d), Token::ElementClose(..)) => State::Content(d - 1),
 
             (State::AfterMainElement, Token::Comment(..))
             | (State::AfterMainElement, Token::ProcessingInstruction(..))
             | (State::AfterMainElement, Token::Whitespace(..)) => State::AfterMainElement,
 
+            (State::Fragment, Token::CharData(..))
+            | (State::Fragment, Token::CData(..))
+            | (State::Fragment, Token::ContentReference(..))
+            | (State::Fragment, Token::Comment(..))
+            | (State::Fragment, Token::ProcessingInstruction(..)) => State::Fragment,
+            (State::Fragment, Token::ElementStart(..)) => State::AfterElementStart(0),
+
             (s, t) => {
                 unreachable!("Transitioning from {:?} to {:?} is impossible", s, t);
             }
         };
 
+        if let (State::AfterElementStart(d), Some(max_depth)) = (next_state, self.max_depth) {
+            // The state counts the elements enclosing the new one
+            if d >= max_depth {
+                return Some(Err((xml.offset, vec![SpecificError::MaximumDepthExceeded])));
+            }
+        }
+
+        if let Some(e) = self.check_limits(&r, pt.offset) {
+            return Some(Err((xml.offset, vec![e])));
+        }
+
+        if let Token::InternalSubsetStart(..) = r {
+            self.internal_subset_start = pt;
+        }
+
         self.state = next_state;
+        self.token_start = xml.offset;
         self.xml = pt;
 
         Some(Ok(r))
     }
 }
 
-struct DomBuilder<'d> {
-    doc: dom::Document<'d>,
-    elements: Vec<dom::Element<'d>>,
-    element_names: Vec<Span<PrefixedName<'d>>>,
-    attributes: Vec<DeferredAttribute<'d>>,
-    seen_top_element: bool,
+/// Whether the text starts with a `&` followed by whitespace or
+/// nothing at all, which cannot be the start of any reference.
+fn starts_with_bare_ampersand(xml: &str) -> bool {
+    let mut chars = xml.chars();
+    chars.next() == Some('&') && chars.next().map_or(true, |c| c.is_space_char())
 }
 
-impl<'d> DomBuilder<'d> {
-    fn new(doc: dom::Document<'d>) -> DomBuilder<'d> {
-        DomBuilder {
-            doc,
-            elements: vec![],
+/// The events read from a document, in document order and with where
+/// each one is in the input. `EventDriver` produces them from tokens;
+/// `DomBuilder` builds the DOM from them, while `SinkEvents` passes
+/// them on to a `ParserSink`.
+trait Events<'x> {
+    fn xml_declaration(
+        &mut self,
+        version: &'x str,
+        encoding: Option<&'x str>,
+        standalone: Option<bool>,
+    );
+
+    fn document_type(&mut self, _doctype: Doctype<'x>) {}
+
+    fn internal_subset(&mut self, _subset: &'x str) {}
+
+    /// A complete start tag. The values of the attributes are
+    /// expanded with `entities`. An empty element is followed directly
+    /// by its `element_end`.
+    fn start_tag(
+        &mut self,
+        name: Span<PrefixedName<'x>>,
+        attributes: Vec<DeferredAttribute<'x>>,
+        entities: &Entities<'x, '_>,
+        self_closing: bool,
+    ) -> DomBuilderResult<()>;
+
+    /// The end of the element started with `name`, which ends at `end`.
+    fn element_end(&mut self, name: Span<PrefixedName<'x>>, end: usize);
+
+    fn text(&mut self, text: &str, range: Range<usize>);
+
+    fn cdata(&mut self, text: &str, range: Range<usize>);
+
+    fn reference(&mut self, text: &str, range: Range<usize>);
+
+    fn comment(&mut self, text: &str, range: Range<usize>);
+
+    fn processing_instruction(
+        &mut self,
+        target: Span<&'x str>,
+        value: Option<&str>,
+        range: Range<usize>,
+    );
+}
+
+/// Turns tokens into `Events`, keeping track of the open elements,
+/// the attributes of the current start tag and the declared entities.
+/// Line endings are normalized and references expanded here.
+struct EventDriver<'x, 'r, E> {
+    events: E,
+    element_names: Vec<Span<PrefixedName<'x>>>,
+    attributes: Vec<DeferredAttribute<'x>>,
+    entities: Entities<'x, 'r>,
+    /// The open element a mismatched end tag should have closed.
+    mismatched_open: Option<usize>,
+}
+
+impl<'x, 'r, E> EventDriver<'x, 'r, E>
+where
+    E: Events<'x>,
+{
+    fn new(events: E, options: &'r Parser) -> Self {
+        EventDriver {
+            events,
             element_names: Vec::new(),
             attributes: Vec::new(),
-            seen_top_element: false,
+            entities: Entities::new(options),
+            mismatched_open: None,
         }
     }
 
-    fn append_to_either<T>(&self, child: T)
-    where
-        T: Into<dom::ChildOfRoot<'d>>,
-    {
-        match self.elements.last() {
-            None => self.doc.root().append_child(child),
-            Some(parent) => parent.append_child(child.into()),
+    fn error(&mut self, e: Span<SpecificError>) -> Error {
+        Error {
+            open_element: self.mismatched_open.take(),
+            ..e.into()
         }
     }
 
-    fn default_namespace_uri(&self) -> Option<&str> {
-        self.elements
-            .last()
-            .and_then(|e| e.recursive_default_namespace_uri())
+    fn open_elements(&self) -> usize {
+        self.element_names.len()
+    }
+
+    fn has_unclosed_elements(&self) -> bool {
+        !self.element_names.is_empty()
+    }
+
+    /// Closes the innermost open element, which ends at `end`.
+    fn close_element(&mut self, end: usize) -> Span<PrefixedName<'x>> {
+        let name = self.element_names.pop().expect("No open element");
+        self.events.element_end(name, end);
+        name
+    }
+
+    /// Closes the innermost open element with the given name, along
+    /// with any elements inside it that were never closed. A close tag
+    /// that matches no open element is ignored. Either way, a name
+    /// that differs from the innermost open element is an error.
+    fn close_element_leniently(
+        &mut self,
+        name: Span<PrefixedName<'x>>,
+        range: Range<usize>,
+    ) -> DomBuilderResult<()> {
+        let position = self
+            .element_names
+            .iter()
+            .rposition(|open| open.value == name.value);
+        let innermost = self.element_names.len().checked_sub(1);
+        self.mismatched_open = self.element_names.last().map(|open| open.offset);
+
+        if let Some(position) = position {
+            // Unclosed elements end where the close tag begins
+            while self.element_names.len() > position + 1 {
+                self.close_element(range.start);
+            }
+            self.close_element(range.end);
+        }
+
+        if position.is_some() && position == innermost {
+            Ok(())
+        } else {
+            Err(name.map(|_| SpecificError::MismatchedElementEndName))
+        }
+    }
+
+    fn finish_start_tag(&mut self, self_closing: bool) -> DomBuilderResult<()> {
+        let name = *self.element_names.last().expect("Unknown element name");
+        let attributes = replace(&mut self.attributes, Vec::new());
+        self.events
+            .start_tag(name, attributes, &self.entities, self_closing)
+    }
+
+    fn add_attribute_value(&mut self, v: AttributeValue<'x>) {
+        let a = self
+            .attributes
+            .last_mut()
+            .expect("Cannot add attribute value without an attribute");
+        a.values.push(v);
+    }
+
+    fn consume(&mut self, token: Token<'x>, range: Range<usize>) -> DomBuilderResult<()> {
+        use self::Token::*;
+
+        match token {
+            XmlDeclaration(version, encoding, standalone) => {
+                self.events
+                    .xml_declaration(version, encoding.map(|e| e.value), standalone);
+            }
+
+            DocumentTypeDeclaration(d) | InternalSubsetStart(d) => self.events.document_type(d),
+
+            InternalSubsetEnd(subset) => self.events.internal_subset(subset),
+
+            MarkupDeclaration => {}
+
+            EntityDeclarationStart(n, _) => self.entities.start_declaration(n),
+
+            LiteralEntityValue(v) => self.entities.add_literal(v),
+
+            ReferenceEntityValue(r) => self.entities.add_reference(r)?,
+
+            EntityDeclarationEnd => self.entities.finish_declaration(),
+
+            ElementStart(n) => {
+                self.element_names.push(n);
+            }
+
+            ElementStartClose => {
+                self.finish_start_tag(false)?;
+            }
+
+            ElementSelfClose => {
+                self.finish_start_tag(true)?;
+                self.close_element(range.end);
+            }
+
+            ElementClose(n) => {
+                let open_name = self.close_element(range.end);
+
+                if n.value != open_name.value {
+                    self.mismatched_open = Some(open_name.offset);
+                    return Err(n.map(|_| SpecificError::MismatchedElementEndName));
+                }
+            }
+
+            AttributeStart(n, _) => {
+                let attr = DeferredAttribute {
+                    name: n,
+                    values: Vec::new(),
+                };
+                self.attributes.push(attr);
+            }
+
+            LiteralAttributeValue(v) => {
+                self.add_attribute_value(AttributeValue::LiteralAttributeValue(v));
+            }
+
+            ReferenceAttributeValue(v) => {
+                self.add_attribute_value(AttributeValue::ReferenceAttributeValue(v));
+            }
+
+            AttributeEnd => {}
+
+            Whitespace(..) => {}
+
+            CharData(t) => self.events.text(&normalize_line_endings(t), range),
+
+            CData(t) => self.events.cdata(&normalize_line_endings(t), range),
+
+            ContentReference(t) => {
+                let events = &mut self.events;
+                expand_reference(t, &self.entities, |s| events.reference(s, range))?;
+            }
+
+            Comment(c) => self.events.comment(&normalize_line_endings(c), range),
+
+            ProcessingInstruction(t, v) => {
+                let v = v.map(normalize_line_endings);
+                self.events
+                    .processing_instruction(t, v.as_ref().map(|v| &v[..]), range);
+            }
+        };
+
+        Ok(())
+    }
+}
+
+struct DomBuilder<'d> {
+    doc: dom::Document<'d>,
+    elements: Vec<dom::Element<'d>>,
+    seen_top_element: bool,
+    warnings: Vec<Span<WarningKind>>,
+    track_source_spans: bool,
+}
+
+impl<'d> DomBuilder<'d> {
+    fn new(doc: dom::Document<'d>, options: &Parser) -> Self {
+        DomBuilder {
+            doc,
+            elements: vec![],
+            seen_top_element: false,
+            warnings: Vec::new(),
+            track_source_spans: options.track_source_spans,
+        }
+    }
+
+    fn append_to_either<T>(&self, child: T)
+    where
+        T: Into<dom::ChildOfRoot<'d>>,
+    {
+        match self.elements.last() {
+            None => self.doc.root().append_child(child),
+            Some(parent) => parent.append_child(child.into()),
+        }
+    }
+
+    fn default_namespace_uri(&self) -> Option<&str> {
+        self.elements
+            .last()
+            .and_then(|e| e.recursive_default_namespace_uri())
     }
 
     fn namespace_uri_for_prefix(&self, prefix: &str) -> Option<&str> {
+        if prefix == crate::XML_NS_PREFIX {
+            return Some(crate::XML_NS_URI);
+        }
+
         self.elements
             .last()
             .and_then(|e| e.namespace_uri_for_prefix(prefix))
     }
 
-    fn finish_opening_tag(&mut self) -> DomBuilderResult<()> {
-        let deferred_element = self.element_names.last().expect("Unknown element name");
-        let attributes = DeferredAttributes::new(replace(&mut self.attributes, Vec::new()));
+    fn finish_opening_tag(
+        &mut self,
+        deferred_element: Span<PrefixedName<'d>>,
+        attributes: Vec<DeferredAttribute<'d>>,
+        entities: &Entities<'d, '_>,
+    ) -> DomBuilderResult<()> {
+        let attributes = DeferredAttributes::new(attributes);
 
         attributes.check_duplicates()?;
-        let default_namespace = attributes.default_namespace()?;
+        let default_namespace = attributes.default_namespace(entities)?;
+
+        if let Some(ref ns_uri) = default_namespace {
+            let ns = &attributes.default_namespaces[0];
+            if is_reserved_namespace(ns_uri) {
+                return Err(ns.name.map(|_| SpecificError::ReservedNamespace));
+            }
+
+            if self.default_namespace_uri().unwrap_or("") == ns_uri {
+                self.warnings
+                    .push(ns.name.map(|_| WarningKind::RedundantNamespaceDeclaration));
+            }
+        }
 
-        let mut new_prefix_mappings = HashMap::new();
+        // Kept in declaration order so the prefixes are registered,
+        // and later enumerated, in a stable order
+        let mut new_prefix_mappings = Vec::new();
         for ns in attributes.namespaces() {
-            let value = AttributeValueBuilder::convert(&ns.values)?;
+            let value = AttributeValueBuilder::convert(&ns.values, entities)?;
 
             if value.is_empty() {
                 return Err(ns.name.map(|_| SpecificError::EmptyNamespace));
             }
 
-            new_prefix_mappings.insert(ns.name.value.local_part, value);
+            let prefix = ns.name.value.local_part;
+
+            // Only `xml` may be declared, and only to its own namespace
+            let reserved = match prefix {
+                "xml" => value != crate::XML_NS_URI,
+                "xmlns" => true,
+                _ => is_reserved_namespace(&value),
+            };
+            if reserved {
+                return Err(ns.name.map(|_| SpecificError::ReservedNamespace));
+            }
+
+            if self.namespace_uri_for_prefix(prefix) == Some(&value) {
+                self.warnings
+                    .push(ns.name.map(|_| WarningKind::RedundantNamespaceDeclaration));
+            }
+
+            new_prefix_mappings.push((ns.name.value.local_part, value));
         }
         let new_prefix_mappings = new_prefix_mappings;
+        let new_namespace_uri_for_prefix = |prefix: &str| {
+            new_prefix_mappings
+                .iter()
+                .find(|(p, _)| *p == prefix)
+                .map(|(_, uri)| &uri[..])
+        };
 
         let element_name = &deferred_element.value;
 
         let element = if let Some(prefix) = element_name.prefix {
-            let ns_uri = new_prefix_mappings.get(prefix).map(|p| &p[..]);
+            let ns_uri = new_namespace_uri_for_prefix(prefix);
             let ns_uri = ns_uri.or_else(|| self.namespace_uri_for_prefix(prefix));
 
             if let Some(ns_uri) = ns_uri {
@@ -983,18 +1793,31 @@ impl<'d> DomBuilder<'d> {
         self.elements.push(element);
 
         let mut builder = AttributeValueBuilder::new();
+        let mut seen_names = HashMap::new();
 
         for attribute in attributes.attributes() {
             let name = &attribute.name.value;
 
             builder.clear();
-            builder.ingest(&attribute.values)?;
+            builder.ingest(&attribute.values, entities)?;
 
             if let Some(prefix) = name.prefix {
-                let ns_uri = new_prefix_mappings.get(prefix).map(|p| &p[..]);
+                let ns_uri = new_namespace_uri_for_prefix(prefix);
                 let ns_uri = ns_uri.or_else(|| self.namespace_uri_for_prefix(prefix));
 
                 if let Some(ns_uri) = ns_uri {
+                    // Different prefixes may be bound to the same
+                    // namespace, so compare the expanded names.
+                    let expanded_name = (ns_uri, name.local_part);
+                    if let Some(other) = seen_names.insert(expanded_name, attribute.name) {
+                        let later = if other.offset > attribute.name.offset {
+                            other
+                        } else {
+                            attribute.name
+                        };
+                        return Err(later.map(|_| SpecificError::DuplicateAttribute));
+                    }
+
                     let attr = element.set_attribute_value((ns_uri, name.local_part), &builder);
                     attr.set_preferred_prefix(Some(prefix));
                 } else {
@@ -1010,123 +1833,355 @@ impl<'d> DomBuilder<'d> {
         Ok(())
     }
 
-    fn add_attribute_value(&mut self, v: AttributeValue<'d>) {
-        let a = self
-            .attributes
-            .last_mut()
-            .expect("Cannot add attribute value without an attribute");
-        a.values.push(v);
+    fn source_span(&self, range: Range<usize>) -> Option<Range<usize>> {
+        if self.track_source_spans {
+            Some(range)
+        } else {
+            None
+        }
+    }
+
+    fn mark_self_closing(&self, self_closing: bool) {
+        let element = self.elements.last().expect("No open element");
+        element.set_was_self_closing(Some(self_closing));
     }
 
-    fn add_text_data(&self, text: &str) {
+    fn add_text_data(&self, text: &str, range: Range<usize>) -> dom::Text<'d> {
         let e = self
             .elements
             .last()
             .expect("Cannot add text node without a parent");
         let t = self.doc.create_text(text);
+        t.set_source_span(self.source_span(range));
         e.append_child(t);
+        t
     }
+}
 
-    fn has_unclosed_elements(&self) -> bool {
-        !self.elements.is_empty()
+impl<'d> Events<'d> for DomBuilder<'d> {
+    fn xml_declaration(
+        &mut self,
+        version: &'d str,
+        encoding: Option<&'d str>,
+        standalone: Option<bool>,
+    ) {
+        self.doc.set_version(Some(version));
+        self.doc.set_encoding(encoding);
+        self.doc.set_standalone(standalone);
+    }
+
+    fn document_type(&mut self, d: Doctype<'d>) {
+        let document_type = self.doc.create_document_type(d.name);
+        document_type.set_public_id(d.public_id);
+        document_type.set_system_id(d.system_id);
+        self.doc.set_document_type(Some(document_type));
+        self.doc
+            .set_document_type_position(self.doc.root().children().len());
+    }
+
+    fn internal_subset(&mut self, subset: &'d str) {
+        if let Some(document_type) = self.doc.document_type() {
+            document_type.set_internal_subset(Some(subset));
+        }
     }
 
-    fn consume(&mut self, token: Token<'d>) -> DomBuilderResult<()> {
-        use self::Token::*;
-
-        match token {
-            XmlDeclaration => {}
+    fn start_tag(
+        &mut self,
+        name: Span<PrefixedName<'d>>,
+        attributes: Vec<DeferredAttribute<'d>>,
+        entities: &Entities<'d, '_>,
+        self_closing: bool,
+    ) -> DomBuilderResult<()> {
+        self.finish_opening_tag(name, attributes, entities)?;
+        self.mark_self_closing(self_closing);
+        Ok(())
+    }
 
-            DocumentTypeDeclaration => {}
+    fn element_end(&mut self, name: Span<PrefixedName<'d>>, end: usize) {
+        let element = self.elements.pop().expect("No open element");
 
-            ElementStart(n) => {
-                self.element_names.push(n);
-            }
+        // The name directly follows the `<` of the start tag
+        element.set_source_span(self.source_span(name.offset - 1..end));
+    }
 
-            ElementStartClose => {
-                self.finish_opening_tag()?;
-            }
+    fn text(&mut self, text: &str, range: Range<usize>) {
+        self.add_text_data(text, range);
+    }
 
-            ElementSelfClose => {
-                self.finish_opening_tag()?;
+    fn cdata(&mut self, text: &str, range: Range<usize>) {
+        self.add_text_data(text, range).set_cdata(true);
+    }
 
-                self.element_names.pop();
-                self.elements.pop();
-            }
+    fn reference(&mut self, text: &str, range: Range<usize>) {
+        self.add_text_data(text, range);
+    }
 
-            ElementClose(n) => {
-                let open_name = self.element_names.pop().expect("No open element");
-                self.elements.pop();
+    fn comment(&mut self, text: &str, range: Range<usize>) {
+        let c = self.doc.create_comment(text);
+        c.set_source_span(self.source_span(range));
+        self.append_to_either(c);
+    }
 
-                if n.value != open_name.value {
-                    return Err(n.map(|_| SpecificError::MismatchedElementEndName));
-                }
-            }
+    fn processing_instruction(
+        &mut self,
+        target: Span<&'d str>,
+        value: Option<&str>,
+        range: Range<usize>,
+    ) {
+        let prefix = target.value.as_bytes().get(..3);
+        if target.value.len() > 3 && prefix.map_or(false, |p| p.eq_ignore_ascii_case(b"xml")) {
+            self.warnings
+                .push(target.map(|_| WarningKind::ReservedProcessingInstructionTarget));
+        }
 
-            AttributeStart(n, _) => {
-                let attr = DeferredAttribute {
-                    name: n,
-                    values: Vec::new(),
-                };
-                self.attributes.push(attr);
-            }
+        let pi = self.doc.create_processing_instruction(target.value, value);
+        pi.set_source_span(self.source_span(range));
+        self.append_to_either(pi);
+    }
+}
 
-            LiteralAttributeValue(v) => {
-                self.add_attribute_value(AttributeValue::LiteralAttributeValue(v));
-            }
+/// Passes events on to a `ParserSink`, which has no use for where
+/// they are in the input.
+struct SinkEvents<'s, S>(&'s mut S);
 
-            ReferenceAttributeValue(v) => {
-                self.add_attribute_value(AttributeValue::ReferenceAttributeValue(v));
-            }
+impl<'s, 'x, S> Events<'x> for SinkEvents<'s, S>
+where
+    S: ParserSink<'x>,
+{
+    fn xml_declaration(
+        &mut self,
+        version: &'x str,
+        encoding: Option<&'x str>,
+        standalone: Option<bool>,
+    ) {
+        self.0.xml_declaration(version, encoding, standalone);
+    }
+
+    fn start_tag(
+        &mut self,
+        name: Span<PrefixedName<'x>>,
+        attributes: Vec<DeferredAttribute<'x>>,
+        entities: &Entities<'x, '_>,
+        _self_closing: bool,
+    ) -> DomBuilderResult<()> {
+        self.0.element_start(name.value);
+
+        let mut value = AttributeValueBuilder::new();
+        for attribute in &attributes {
+            value.clear();
+            value.ingest(&attribute.values, entities)?;
+            self.0.attribute(attribute.name.value, &value);
+        }
 
-            AttributeEnd => {}
+        self.0.attributes_end();
+        Ok(())
+    }
 
-            Whitespace(..) => {}
+    fn element_end(&mut self, name: Span<PrefixedName<'x>>, _end: usize) {
+        self.0.element_end(name.value);
+    }
 
-            CharData(t) | CData(t) => self.add_text_data(t),
+    fn text(&mut self, text: &str, _range: Range<usize>) {
+        self.0.text(text);
+    }
 
-            ContentReference(t) => {
-                decode_reference(t, |s| self.add_text_data(s))?;
-            }
+    fn cdata(&mut self, text: &str, _range: Range<usize>) {
+        self.0.cdata(text);
+    }
 
-            Comment(c) => {
-                let c = self.doc.create_comment(c);
-                self.append_to_either(c);
-            }
+    fn reference(&mut self, text: &str, _range: Range<usize>) {
+        self.0.reference(text);
+    }
 
-            ProcessingInstruction(t, v) => {
-                let pi = self.doc.create_processing_instruction(t, v);
-                self.append_to_either(pi);
-            }
-        };
+    fn comment(&mut self, text: &str, _range: Range<usize>) {
+        self.0.comment(text);
+    }
 
-        Ok(())
+    fn processing_instruction(
+        &mut self,
+        target: Span<&'x str>,
+        value: Option<&str>,
+        _range: Range<usize>,
+    ) {
+        self.0.processing_instruction(target.value, value);
     }
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Error {
     location: usize,
+    line: usize,
+    column: usize,
     errors: BTreeSet<SpecificError>,
+    kind: ErrorKind,
+    /// Where the name of the element a mismatched end tag should have
+    /// closed starts.
+    open_element: Option<usize>,
 }
 
 impl Error {
     fn new(location: usize, error: SpecificError) -> Self {
         let mut errors = BTreeSet::new();
         errors.insert(error);
-        Error { location, errors }
+        Error {
+            location,
+            line: 0,
+            column: 0,
+            errors,
+            kind: ErrorKind::Syntax,
+            open_element: None,
+        }
     }
 
+    /// Computes the line, column and kind of the error from the input
+    /// that was being parsed.
+    fn locate(mut self, xml: &str) -> Self {
+        let (line, column) = line_and_column(xml, self.location);
+        self.line = line;
+        self.column = column;
+        self.kind = ErrorKind::new(&self.errors, xml, self.location, self.open_element);
+        self
+    }
+
+    /// What went wrong, for callers that need to react to particular
+    /// problems.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use sxd_document::parser::{self, ErrorKind};
+    ///
+    /// let error = parser::parse("<a><b></a>").unwrap_err();
+    ///
+    /// assert_eq!(
+    ///     *error.kind(),
+    ///     ErrorKind::MismatchedEndTag {
+    ///         expected: "b".to_owned(),
+    ///         found: "a".to_owned(),
+    ///     }
+    /// );
+    /// ```
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// The byte offset into the input where the error occurred.
     pub fn location(&self) -> usize {
         self.location
     }
+
+    /// The 1-based line where the error occurred, or 0 if it is not
+    /// known because the error was not located in its input.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// The 1-based column where the error occurred, counted in
+    /// characters rather than bytes, or 0 if it is not known. See
+    /// `line`.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    /// Displays the error followed by the line of `xml` where it
+    /// occurred, with a caret beneath the position. `xml` must be the
+    /// string that was parsed; when parsing bytes, the snippet is
+    /// only shown for UTF-8 input.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use sxd_document::parser;
+    ///
+    /// let xml = "<a>\n  <b></c>\n</a>";
+    /// let error = parser::parse(xml).unwrap_err();
+    ///
+    /// assert_eq!(
+    ///     error.with_source(xml).to_string(),
+    ///     "XML parsing error at line 2, column 8: mismatched element end name\n  <b></c>\n       ^"
+    /// );
+    /// ```
+    pub fn with_source<'a>(&'a self, xml: &'a str) -> ErrorWithSource<'a> {
+        ErrorWithSource { error: self, xml }
+    }
+}
+
+/// An error displayed along with the input it occurred in. See
+/// `Error::with_source`.
+#[derive(Debug)]
+pub struct ErrorWithSource<'a> {
+    error: &'a Error,
+    xml: &'a str,
+}
+
+impl<'a> fmt::Display for ErrorWithSource<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)?;
+
+        let before = match self.xml.get(..self.error.location) {
+            Some(before) => before,
+            None => return Ok(()),
+        };
+        let line_start = before
+            .rfind('\n')
+            .map_or_else(|| skip_byte_order_mark(before).offset, |i| i + 1);
+        let line_end = self.xml[line_start..]
+            .find('\n')
+            .map_or(self.xml.len(), |i| line_start + i);
+        let line = self.xml[line_start..line_end].trim_end_matches('\r');
+
+        // Tabs are kept so that the caret lines up however they are shown
+        let padding: String = before[line_start..]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        write!(f, "\n{}\n{}^", line, padding)
+    }
+}
+
+/// A string read from a file may start with the byte order mark,
+/// which is not part of the document. Offsets still count from the
+/// start of the original string.
+fn skip_byte_order_mark(xml: &str) -> StringPoint<'_> {
+    let bom_len = if xml.starts_with(BYTE_ORDER_MARK) {
+        BYTE_ORDER_MARK.len_utf8()
+    } else {
+        0
+    };
+
+    StringPoint {
+        s: &xml[bom_len..],
+        offset: bom_len,
+    }
+}
+
+const BYTE_ORDER_MARK: char = '\u{FEFF}';
+
+fn line_and_column(xml: &str, location: usize) -> (usize, usize) {
+    let before = &xml[..location];
+    let line_start = before
+        .rfind('\n')
+        .map_or_else(|| skip_byte_order_mark(before).offset, |i| i + 1);
+
+    let line = before.matches('\n').count() + 1;
+    let column = before[line_start..].chars().count() + 1;
+
+    (line, column)
 }
 
 impl From<(usize, Vec<SpecificError>)> for Error {
     fn from(other: (usize, Vec<SpecificError>)) -> Self {
         let (location, errors) = other;
         let errors = errors.into_iter().collect();
-        Error { location, errors }
+        Error {
+            location,
+            line: 0,
+            column: 0,
+            errors,
+            kind: ErrorKind::Syntax,
+            open_element: None,
+        }
     }
 }
 
@@ -1136,13 +2191,28 @@ impl From<Span<SpecificError>> for Error {
     }
 }
 
+/// Lists every error that could have occurred at the location,
+/// separated by "or". The location is the byte offset when the line
+/// and column are not known.
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "XML parsing error at {}: {:?}",
-            self.location, self.errors
-        )
+        if self.line == 0 {
+            write!(f, "XML parsing error at byte {}: ", self.location)?;
+        } else {
+            write!(
+                f,
+                "XML parsing error at line {}, column {}: ",
+                self.line, self.column
+            )?;
+        }
+
+        for (i, error) in self.errors.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" or ")?;
+            }
+            error.fmt_message(f)?;
+        }
+        Ok(())
     }
 }
 
@@ -1152,1081 +2222,5079 @@ impl error::Error for Error {
     }
 }
 
-/// Parses a string into a DOM. On failure, the location of the
-/// parsing failure and all possible failures will be returned.
-pub fn parse(xml: &str) -> Result<super::Package, Error> {
-    let parser = PullParser::new(xml);
-    let package = super::Package::new();
+/// The kind of problem an `Error` reports. Names are given as they
+/// were written, with any prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The input ended while something, such as an element, was still
+    /// open.
+    UnexpectedEof,
+    /// An end tag does not match the innermost open element.
+    MismatchedEndTag { expected: String, found: String },
+    /// An entity reference names an entity that is not known.
+    UnknownEntity(String),
+    /// An entity refers to itself, directly or through others.
+    RecursiveEntity(String),
+    /// A name uses a namespace prefix that has not been declared.
+    UnknownPrefix(String),
+    /// A character reference is to a code point not allowed in XML.
+    InvalidCharReference,
+    /// An element has more than one attribute with the same name.
+    DuplicateAttribute(String),
+    /// A namespace declaration is empty, reserved or repeated.
+    InvalidNamespaceDeclaration,
+    /// Something other than a comment, processing instruction or
+    /// whitespace follows the main element, or follows a lone
+    /// reference.
+    TrailingContent,
+    /// One of the limits set on the `Parser` was exceeded.
+    LimitExceeded,
+    /// The bytes are not valid in their encoding, or the declared
+    /// encoding is not supported.
+    InvalidEncoding,
+    /// Any other malformed input. The `Error` lists what was expected.
+    Syntax,
+}
 
-    {
-        let doc = package.as_document();
-        let mut builder = DomBuilder::new(doc);
+impl ErrorKind {
+    fn new(
+        errors: &BTreeSet<SpecificError>,
+        xml: &str,
+        location: usize,
+        open_element: Option<usize>,
+    ) -> ErrorKind {
+        use self::SpecificError::*;
+
+        let rest = |offset: usize| xml.get(offset..).unwrap_or("");
+        let name_at = |offset: usize| {
+            let s = rest(offset);
+            s[..s.end_of_name().unwrap_or(0)].to_owned()
+        };
 
-        for token in parser {
-            let token = token?;
-            builder.consume(token)?;
+        for error in errors {
+            return match *error {
+                MismatchedElementEndName => ErrorKind::MismatchedEndTag {
+                    expected: open_element.map(name_at).unwrap_or_default(),
+                    found: name_at(location),
+                },
+                UnknownNamedReference => ErrorKind::UnknownEntity(name_at(location)),
+                RecursiveEntityReference => ErrorKind::RecursiveEntity(name_at(location)),
+                UnknownNamespacePrefix => {
+                    let s = rest(location);
+                    ErrorKind::UnknownPrefix(s[..s.end_of_ncname().unwrap_or(0)].to_owned())
+                }
+                InvalidDecimalReference | InvalidHexReference => ErrorKind::InvalidCharReference,
+                DuplicateAttribute => ErrorKind::DuplicateAttribute(name_at(location)),
+                RedefinedNamespace
+                | RedefinedDefaultNamespace
+                | EmptyNamespace
+                | ReservedNamespace => ErrorKind::InvalidNamespaceDeclaration,
+                UnclosedElement => ErrorKind::UnexpectedEof,
+                TrailingText => ErrorKind::TrailingContent,
+                MaximumDepthExceeded
+                | MaximumAttributesExceeded
+                | MaximumAttributeValueLengthExceeded
+                | MaximumElementsExceeded
+                | EntityExpansionLimitExceeded => ErrorKind::LimitExceeded,
+                InvalidByteSequence | UnsupportedEncoding => ErrorKind::InvalidEncoding,
+                _ => continue,
+            };
         }
 
-        if builder.has_unclosed_elements() {
-            return Err(Error::new(xml.len(), SpecificError::UnclosedElement));
+        // Only these are allowed after the main element
+        let after_main_element = [
+            ExpectedComment,
+            ExpectedProcessingInstruction,
+            ExpectedWhitespace,
+        ];
+        if errors.iter().eq(after_main_element.iter()) {
+            ErrorKind::TrailingContent
+        } else if location == xml.len() {
+            ErrorKind::UnexpectedEof
+        } else {
+            ErrorKind::Syntax
         }
     }
-
-    Ok(package)
 }
 
-type DomBuilderResult<T> = Result<T, Span<SpecificError>>;
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use self::ErrorKind::*;
 
-fn decode_reference<F>(ref_data: Reference<'_>, cb: F) -> DomBuilderResult<()>
-where
-    F: FnOnce(&str),
-{
-    match ref_data {
-        DecimalChar(span) => u32::from_str_radix(span.value, 10)
-            .ok()
-            .and_then(char::from_u32)
-            .ok_or_else(|| span.map(|_| SpecificError::InvalidDecimalReference))
-            .and_then(|c| {
-                let s: String = iter::repeat(c).take(1).collect();
-                cb(&s);
-                Ok(())
-            }),
-        HexChar(span) => u32::from_str_radix(span.value, 16)
-            .ok()
-            .and_then(char::from_u32)
-            .ok_or_else(|| span.map(|_| SpecificError::InvalidHexReference))
-            .and_then(|c| {
-                let s: String = iter::repeat(c).take(1).collect();
-                cb(&s);
-                Ok(())
-            }),
-        Entity(span) => {
-            let s = match span.value {
-                "amp" => "&",
-                "lt" => "<",
-                "gt" => ">",
-                "apos" => "'",
-                "quot" => "\"",
-                _ => return Err(span.map(|_| SpecificError::UnknownNamedReference)),
-            };
-            cb(s);
-            Ok(())
+        match self {
+            UnexpectedEof => f.write_str("unexpected end of input"),
+            MismatchedEndTag { expected, found } => {
+                write!(f, "expected </{}> but found </{}>", expected, found)
+            }
+            UnknownEntity(name) => write!(f, "unknown entity &{};", name),
+            RecursiveEntity(name) => write!(f, "recursive entity &{};", name),
+            UnknownPrefix(prefix) => write!(f, "unknown namespace prefix {}", prefix),
+            InvalidCharReference => f.write_str("invalid character reference"),
+            DuplicateAttribute(name) => write!(f, "duplicate attribute {}", name),
+            InvalidNamespaceDeclaration => f.write_str("invalid namespace declaration"),
+            TrailingContent => f.write_str("unexpected content after the end"),
+            LimitExceeded => f.write_str("parser limit exceeded"),
+            InvalidEncoding => f.write_str("invalid or unsupported encoding"),
+            Syntax => f.write_str("malformed XML"),
         }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-enum AttributeValue<'a> {
-    ReferenceAttributeValue(Reference<'a>),
-    LiteralAttributeValue(&'a str),
+/// A failure to parse one of several documents. See
+/// `Parser::parse_many`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DocumentError {
+    index: usize,
+    error: Error,
 }
 
-struct AttributeValueBuilder {
-    value: String,
+impl DocumentError {
+    /// The 0-based position of the document that failed among all
+    /// documents in the input.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// The failure, located within the whole input.
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
 }
 
-impl AttributeValueBuilder {
-    fn convert(values: &[AttributeValue<'_>]) -> DomBuilderResult<String> {
-        let mut builder = AttributeValueBuilder::new();
-        builder.ingest(values)?;
-        Ok(builder.implode())
+impl fmt::Display for DocumentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "in document {}: {}", self.index, self.error)
     }
+}
 
-    fn new() -> AttributeValueBuilder {
-        AttributeValueBuilder {
-            value: String::new(),
-        }
+impl error::Error for DocumentError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
     }
+}
 
-    fn ingest(&mut self, values: &[AttributeValue<'_>]) -> DomBuilderResult<()> {
-        use self::AttributeValue::*;
+/// Something unusual about a document that does not prevent it from
+/// being parsed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WarningKind {
+    /// A namespace prefix, or the default namespace, is declared
+    /// with the URI it is already bound to.
+    RedundantNamespaceDeclaration,
+    /// A processing instruction target begins with `xml`; such
+    /// targets are reserved for XML standards.
+    ReservedProcessingInstructionTarget,
+    /// The XML declaration names a different encoding than the one
+    /// detected from the bytes. See `Parser::parse_bytes_with_warnings`.
+    EncodingMismatch {
+        declared: Encoding,
+        detected: Encoding,
+    },
+}
 
-        for value in values.iter() {
-            match *value {
-                LiteralAttributeValue(v) => self.value.push_str(v),
-                ReferenceAttributeValue(r) => decode_reference(r, |s| self.value.push_str(s))?,
+impl fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use self::WarningKind::*;
+
+        match *self {
+            RedundantNamespaceDeclaration => f.write_str("redundant namespace declaration"),
+            ReservedProcessingInstructionTarget => {
+                f.write_str("reserved processing instruction target")
             }
+            EncodingMismatch { declared, detected } => write!(
+                f,
+                "declared encoding {} does not match detected encoding {}",
+                declared, detected
+            ),
+        }
+    }
+}
+
+/// A non-fatal problem found while parsing a document. See
+/// `Parser::parse_with_warnings`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    location: usize,
+    line: usize,
+    column: usize,
+    kind: WarningKind,
+}
+
+impl Warning {
+    fn new(xml: &str, span: Span<WarningKind>) -> Self {
+        let (line, column) = line_and_column(xml, span.offset);
+        Warning {
+            location: span.offset,
+            line,
+            column,
+            kind: span.value,
+        }
+    }
+
+    /// The byte offset into the input where the warning applies.
+    pub fn location(&self) -> usize {
+        self.location
+    }
+
+    /// The 1-based line where the warning applies.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// The 1-based column where the warning applies, counted in
+    /// characters rather than bytes.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    pub fn kind(&self) -> WarningKind {
+        self.kind
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "XML parsing warning at line {}, column {}: {}",
+            self.line, self.column, self.kind
+        )
+    }
+}
+
+/// Receives the events produced while parsing a document.
+///
+/// Every method has an empty default implementation, so a sink only
+/// needs to handle the events it is interested in. Names are reported
+/// as they appear in the document; namespace prefixes are not
+/// resolved.
+///
+/// ### Example
+///
+/// ```
+/// use sxd_document::{parser::{Parser, ParserSink}, PrefixedName};
+///
+/// #[derive(Default)]
+/// struct CountElements(usize);
+///
+/// impl<'x> ParserSink<'x> for CountElements {
+///     fn element_start(&mut self, _name: PrefixedName<'x>) {
+///         self.0 += 1;
+///     }
+/// }
+///
+/// let mut sink = CountElements::default();
+/// Parser::new().parse_with_sink("<a><b/><c/></a>", &mut sink).expect("Failed to parse");
+/// assert_eq!(sink.0, 3);
+/// ```
+pub trait ParserSink<'x> {
+    /// The XML declaration, if the document has one.
+    fn xml_declaration(
+        &mut self,
+        _version: &'x str,
+        _encoding: Option<&'x str>,
+        _standalone: Option<bool>,
+    ) {
+    }
+
+    /// The start of an element. Its attributes follow, terminated by
+    /// `attributes_end`.
+    fn element_start(&mut self, _name: PrefixedName<'x>) {}
+
+    /// An attribute of the most recently started element, including
+    /// namespace declarations. References in the value have already
+    /// been replaced and whitespace normalized.
+    fn attribute(&mut self, _name: PrefixedName<'x>, _value: &str) {}
+
+    /// All attributes of the most recently started element have been
+    /// reported.
+    fn attributes_end(&mut self) {}
+
+    /// The end of an element. Empty elements also produce this event.
+    fn element_end(&mut self, _name: PrefixedName<'x>) {}
+
+    /// Character data, with line endings normalized to `\n`.
+    fn text(&mut self, _text: &str) {}
+
+    /// The contents of a CDATA section, with line endings normalized
+    /// to `\n`. Defaults to treating it as text.
+    fn cdata(&mut self, text: &str) {
+        self.text(text)
+    }
+
+    /// The replacement text of a character or entity reference in
+    /// element content. Defaults to treating it as text.
+    fn reference(&mut self, text: &str) {
+        self.text(text)
+    }
+
+    /// A comment, with line endings normalized to `\n`.
+    fn comment(&mut self, _text: &str) {}
+
+    /// A processing instruction. Line endings in the value are
+    /// normalized to `\n`.
+    fn processing_instruction(&mut self, _target: &'x str, _value: Option<&str>) {}
+}
+
+/// The deepest nesting of elements accepted by a new `Parser`.
+pub const DEFAULT_MAX_DEPTH: usize = 1000;
+
+/// The most text, in bytes, that a new `Parser` allows entity
+/// references to produce in one document.
+pub const DEFAULT_MAX_ENTITY_EXPANSION: usize = 1024 * 1024;
+
+/// The most attributes, including namespace declarations, that a new
+/// `Parser` accepts on one element.
+pub const DEFAULT_MAX_ATTRIBUTES: usize = 1000;
+
+/// The longest attribute value, in bytes, that a new `Parser`
+/// accepts.
+pub const DEFAULT_MAX_ATTRIBUTE_VALUE_LENGTH: usize = 16 * 1024 * 1024;
+
+type EntityResolver = dyn Fn(&str) -> Option<String> + Send + Sync;
+
+/// Parses XML strings, either into a DOM or by sending events to a
+/// `ParserSink`.
+///
+/// Options are configured by chaining the `set_*` methods on a parser
+/// created with `Parser::new`. A configured parser can be reused for
+/// any number of documents.
+///
+/// ### Example
+///
+/// ```
+/// use sxd_document::parser::Parser;
+///
+/// let parser = Parser::new()
+///     .set_max_depth(Some(2))
+///     .set_ignore_whitespace_between_elements(true);
+///
+/// assert!(parser.parse("<a> <b/> </a>").is_ok());
+/// assert!(parser.parse("<a><b><c/></b></a>").is_err());
+/// ```
+pub struct Parser {
+    entities: Option<Arc<HashMap<String, String>>>,
+    entity_resolver: Option<Box<EntityResolver>>,
+    ignore_whitespace_between_elements: bool,
+    mark_whitespace_between_elements: bool,
+    max_depth: Option<usize>,
+    max_attributes: Option<usize>,
+    max_attribute_value_length: Option<usize>,
+    max_elements: Option<usize>,
+    max_entity_expansion: Option<usize>,
+    track_source_spans: bool,
+}
+
+impl Default for Parser {
+    fn default() -> Parser {
+        Parser {
+            entities: None,
+            entity_resolver: None,
+            ignore_whitespace_between_elements: false,
+            mark_whitespace_between_elements: false,
+            max_depth: Some(DEFAULT_MAX_DEPTH),
+            max_attributes: Some(DEFAULT_MAX_ATTRIBUTES),
+            max_attribute_value_length: Some(DEFAULT_MAX_ATTRIBUTE_VALUE_LENGTH),
+            max_elements: None,
+            max_entity_expansion: Some(DEFAULT_MAX_ENTITY_EXPANSION),
+            track_source_spans: false,
         }
+    }
+}
 
+impl Parser {
+    /// Creates a parser with the default options.
+    pub fn new() -> Parser {
+        Parser::default()
+    }
+
+    /// Limits how deeply elements may be nested, counting the top
+    /// element as depth 1. A document exceeding the limit fails to
+    /// parse. `None` allows any depth. Defaults to
+    /// `DEFAULT_MAX_DEPTH`.
+    pub fn set_max_depth(mut self, max_depth: Option<usize>) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Limits how many attributes, including namespace declarations,
+    /// an element may have. A document exceeding the limit fails to
+    /// parse. `None` allows any number. Defaults to
+    /// `DEFAULT_MAX_ATTRIBUTES`.
+    pub fn set_max_attributes(mut self, max_attributes: Option<usize>) -> Self {
+        self.max_attributes = max_attributes;
+        self
+    }
+
+    /// Limits the length, in bytes, of each attribute value as written
+    /// in the document, before references are replaced. A document
+    /// exceeding the limit fails to parse. `None` allows any length.
+    /// Defaults to `DEFAULT_MAX_ATTRIBUTE_VALUE_LENGTH`.
+    pub fn set_max_attribute_value_length(
+        mut self,
+        max_attribute_value_length: Option<usize>,
+    ) -> Self {
+        self.max_attribute_value_length = max_attribute_value_length;
+        self
+    }
+
+    /// Limits how many elements a document may contain in total. A
+    /// document exceeding the limit fails to parse. Defaults to
+    /// `None`, allowing any number, as a document sent to a
+    /// `ParserSink` is not held in memory.
+    pub fn set_max_elements(mut self, max_elements: Option<usize>) -> Self {
+        self.max_elements = max_elements;
+        self
+    }
+
+    /// Limits the total length, in bytes, of the replacement text
+    /// produced by references to declared or resolved entities in a
+    /// document. Predefined entities and character references do not
+    /// count. A document exceeding the limit fails to parse. `None`
+    /// allows any length. Defaults to `DEFAULT_MAX_ENTITY_EXPANSION`.
+    pub fn set_max_entity_expansion(mut self, max_entity_expansion: Option<usize>) -> Self {
+        self.max_entity_expansion = max_entity_expansion;
+        self
+    }
+
+    /// Supplies a table of replacement text for entity references
+    /// that are neither predefined by XML nor declared in the
+    /// document. The table is consulted before any entity resolver.
+    /// The replacement text is used as-is; references within it are
+    /// not expanded.
+    ///
+    /// The table may be passed in an `Arc` to share one large table,
+    /// such as the HTML named character references, between parsers.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use sxd_document::parser::Parser;
+    ///
+    /// let mut entities = HashMap::new();
+    /// entities.insert("nbsp".to_owned(), "\u{A0}".to_owned());
+    ///
+    /// let parser = Parser::new().set_entities(entities);
+    /// let package = parser.parse("<a>&nbsp;</a>").expect("Failed to parse");
+    /// ```
+    pub fn set_entities<E>(mut self, entities: E) -> Self
+    where
+        E: Into<Arc<HashMap<String, String>>>,
+    {
+        self.entities = Some(entities.into());
+        self
+    }
+
+    /// Supplies the replacement text for entity references that are
+    /// neither predefined by XML nor declared in the document. The
+    /// returned text is used as-is; references within it are not
+    /// expanded. Returning `None` reports the reference as unknown.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use sxd_document::parser::Parser;
+    ///
+    /// let parser = Parser::new().set_entity_resolver(|name| match name {
+    ///     "nbsp" => Some("\u{A0}".to_owned()),
+    ///     _ => None,
+    /// });
+    /// let package = parser.parse("<a>&nbsp;</a>").expect("Failed to parse");
+    /// ```
+    pub fn set_entity_resolver<F>(mut self, resolver: F) -> Self
+    where
+        F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+    {
+        self.entity_resolver = Some(Box::new(resolver));
+        self
+    }
+
+    /// Removes text consisting only of whitespace from elements that
+    /// have child elements but no other text, such as the indentation
+    /// of a pretty-printed document. Defaults to `false`.
+    ///
+    /// Without a DTD the parser cannot know which whitespace is
+    /// significant, so this is a heuristic: any element containing
+    /// text other than whitespace, including a CDATA section, keeps
+    /// all of its text. Whitespace produced by character references
+    /// is treated like literal whitespace.
+    ///
+    /// Nothing is removed from an element with `xml:space="preserve"`
+    /// or from its descendants, until one of them sets
+    /// `xml:space="default"`.
+    ///
+    /// This only applies when parsing into a DOM; a `ParserSink`
+    /// receives every piece of text.
+    pub fn set_ignore_whitespace_between_elements(mut self, ignore: bool) -> Self {
+        self.ignore_whitespace_between_elements = ignore;
+        self
+    }
+
+    /// Keeps the text that `set_ignore_whitespace_between_elements`
+    /// would remove, but marks it with `Text::is_ignorable_whitespace`
+    /// so it can be filtered later while still being written back
+    /// exactly. Defaults to `false`. Has no effect when whitespace is
+    /// being ignored, as the text is removed.
+    pub fn set_mark_whitespace_between_elements(mut self, mark: bool) -> Self {
+        self.mark_whitespace_between_elements = mark;
+        self
+    }
+
+    /// Records the byte offsets in the input of each element, text,
+    /// comment and processing instruction, available from their
+    /// `source_span` methods. Defaults to `false`.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use sxd_document::parser::Parser;
+    ///
+    /// let xml = "<a><b>text</b></a>";
+    /// let package = Parser::new()
+    ///     .set_track_source_spans(true)
+    ///     .parse(xml)
+    ///     .expect("Failed to parse");
+    /// let doc = package.as_document();
+    /// let a = doc.root().children()[0].element().unwrap();
+    /// let b = a.children()[0].element().unwrap();
+    ///
+    /// assert_eq!(&xml[b.source_span().unwrap()], "<b>text</b>");
+    /// ```
+    pub fn set_track_source_spans(mut self, track: bool) -> Self {
+        self.track_source_spans = track;
+        self
+    }
+
+    /// Parses a string into a DOM. On failure, the location of the
+    /// parsing failure and all possible failures will be returned.
+    pub fn parse(&self, xml: &str) -> Result<super::Package, Error> {
+        self.parse_with_warnings(xml).map(|(package, _)| package)
+    }
+
+    /// Parses a string into a DOM like `parse`, also returning
+    /// anything unusual about the document that did not prevent it
+    /// from being parsed. The warnings are in document order.
+    pub fn parse_with_warnings(&self, xml: &str) -> Result<(super::Package, Vec<Warning>), Error> {
+        self.parse_with_parser(xml, PullParser::new(xml))
+    }
+
+    fn parse_with_parser(
+        &self,
+        xml: &str,
+        parser: PullParser<'_>,
+    ) -> Result<(super::Package, Vec<Warning>), Error> {
+        let parser = parser.with_limits(self);
+        let (package, warnings) = parse_package(xml, parser, self).map_err(|e| e.locate(xml))?;
+        let warnings = warnings.into_iter().map(|w| Warning::new(xml, w)).collect();
+        Ok((package, warnings))
+    }
+
+    /// Parses a string containing several documents, one after
+    /// another, into a DOM for each. A document ends after its main
+    /// element and any comments, processing instructions and
+    /// whitespace that follow, up to the next XML declaration,
+    /// document type declaration or element. Each document must have
+    /// exactly one main element.
+    ///
+    /// On failure, the error is located within the whole string and
+    /// tells which document failed.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use sxd_document::parser::Parser;
+    ///
+    /// let xml = "<?xml version='1.0'?><a/>\n<?xml version='1.0'?><b/>";
+    /// let packages = Parser::new().parse_many(xml).expect("Failed to parse");
+    ///
+    /// assert_eq!(packages.len(), 2);
+    /// ```
+    pub fn parse_many(&self, xml: &str) -> Result<Vec<super::Package>, DocumentError> {
+        parse_many_packages(xml, self)
+    }
+
+    /// Parses a string into a DOM like `parse`, but on failure also
+    /// returns the nodes built before the error. Elements that were
+    /// still open when parsing stopped are kept, along with their
+    /// content so far.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use sxd_document::parser::Parser;
+    ///
+    /// let (package, error) = Parser::new().parse_partial("<a><b/>");
+    /// let doc = package.as_document();
+    /// let a = doc.root().children()[0].element().unwrap();
+    ///
+    /// assert_eq!(a.children().len(), 1);
+    /// assert!(error.is_some());
+    /// ```
+    pub fn parse_partial(&self, xml: &str) -> (super::Package, Option<Error>) {
+        let package = super::Package::new();
+        let error = build_package(&package, xml, self)
+            .err()
+            .map(|e| e.locate(xml));
+        (package, error)
+    }
+
+    /// Parses a string into a DOM, continuing past errors where a
+    /// reasonable recovery is possible. Returns whatever could be
+    /// built along with every error encountered, in document order.
+    ///
+    /// The recoveries are:
+    ///
+    /// - A `&` that does not begin a valid reference is treated as
+    ///   text.
+    /// - A reference to an unknown entity or an invalid character in
+    ///   content is dropped.
+    /// - A close tag for an enclosing element also closes the
+    ///   elements inside it; a close tag matching no open element is
+    ///   ignored.
+    /// - Elements still open at the end of the input are closed.
+    ///
+    /// Parsing stops at the first error with no recovery, keeping
+    /// the nodes built so far. Strict parsing with `parse` is not
+    /// affected.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use sxd_document::parser::Parser;
+    ///
+    /// let (package, errors) = Parser::new().parse_lenient("<a>fish & chips</a>");
+    /// let doc = package.as_document();
+    /// let a = doc.root().children()[0].element().unwrap();
+    ///
+    /// assert_eq!(a.string_value(), "fish & chips");
+    /// assert_eq!(errors.len(), 1);
+    /// ```
+    pub fn parse_lenient(&self, xml: &str) -> (super::Package, Vec<Error>) {
+        let (package, errors) = parse_package_lenient(xml, self);
+        let errors = errors.into_iter().map(|e| e.locate(xml)).collect();
+        (package, errors)
+    }
+
+    /// Parses a fragment of XML content into nodes of the document.
+    /// Unlike a document, the fragment may have any number of
+    /// top-level elements, text, comments and processing
+    /// instructions, but no XML or document type declaration.
+    ///
+    /// The nodes are returned in order, not attached to any parent,
+    /// ready to be appended where they belong. Any namespace prefixes
+    /// used must be declared within the fragment itself.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use sxd_document::{parser::Parser, Package};
+    ///
+    /// let package = Package::new();
+    /// let doc = package.as_document();
+    /// let list = doc.create_element("list");
+    ///
+    /// let items = Parser::new()
+    ///     .parse_fragment(doc, "<item>one</item><item>two</item>")
+    ///     .expect("Failed to parse");
+    /// list.append_children(items);
+    ///
+    /// assert_eq!(list.string_value(), "onetwo");
+    /// ```
+    pub fn parse_fragment<'d>(
+        &self,
+        doc: dom::Document<'d>,
+        xml: &str,
+    ) -> Result<Vec<dom::ChildOfElement<'d>>, Error> {
+        parse_fragment_into(doc, xml, None, self).map_err(|e| e.locate(xml))
+    }
+
+    /// Parses a fragment of XML content like `parse_fragment` and
+    /// appends the resulting nodes to `element`.
+    ///
+    /// Namespace prefixes declared by the element or its ancestors
+    /// may be used by the fragment, and unprefixed elements are in
+    /// the default namespace in scope there. If the fragment is
+    /// malformed, nothing is appended.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use sxd_document::parser::{self, Parser};
+    ///
+    /// let package = parser::parse("<list><item>one</item></list>").expect("Failed to parse");
+    /// let doc = package.as_document();
+    /// let list = doc.root().children()[0].element().unwrap();
+    ///
+    /// let result = Parser::new()
+    ///     .set_max_depth(Some(1))
+    ///     .append_fragment(list, "<item><b>two</b></item>");
+    ///
+    /// assert!(result.is_err());
+    /// assert_eq!(list.children().len(), 1);
+    /// ```
+    pub fn append_fragment(&self, element: dom::Element<'_>, xml: &str) -> Result<(), Error> {
+        let children = parse_fragment_into(element.document(), xml, Some(element), self)
+            .map_err(|e| e.locate(xml))?;
+        element.append_children(children);
         Ok(())
     }
 
-    fn clear(&mut self) {
-        self.value.clear();
+    /// Parses bytes into a DOM, first decoding them to a string.
+    ///
+    /// UTF-8 and UTF-16 are supported. A leading byte order mark is
+    /// used to detect UTF-16 and is otherwise removed. An encoding
+    /// named in the XML declaration must agree with the detected
+    /// one; any other encoding is reported as unsupported.
+    ///
+    /// Invalid bytes are reported at their offset in the input. All
+    /// other failures are reported at their offset in the decoded
+    /// string.
+    pub fn parse_bytes(&self, xml: &[u8]) -> Result<super::Package, Error> {
+        let (xml, encoding) = decode(xml)?;
+        encoding.require_declaration(&xml)?;
+        self.parse_with_parser(&xml, PullParser::new_decoded(&xml))
+            .map(|(package, _)| package)
+    }
+
+    /// Parses bytes into a DOM like `parse_bytes`, also returning
+    /// warnings like `parse_with_warnings`.
+    ///
+    /// An XML declaration naming a supported encoding other than the
+    /// detected one does not cause a failure. The document is decoded
+    /// using the detected encoding and an
+    /// `WarningKind::EncodingMismatch` warning is returned instead.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use sxd_document::parser::{Encoding, Parser, WarningKind};
+    ///
+    /// let mut xml = vec![0xFF, 0xFE];
+    /// let text = "<?xml version='1.0' encoding='UTF-8'?><a/>";
+    /// xml.extend(text.encode_utf16().flat_map(|u| vec![u as u8, (u >> 8) as u8]));
+    ///
+    /// let (_, warnings) = Parser::new().parse_bytes_with_warnings(&xml).unwrap();
+    ///
+    /// assert_eq!(
+    ///     warnings[0].kind(),
+    ///     WarningKind::EncodingMismatch {
+    ///         declared: Encoding::Utf8,
+    ///         detected: Encoding::Utf16Le,
+    ///     }
+    /// );
+    /// ```
+    pub fn parse_bytes_with_warnings(
+        &self,
+        xml: &[u8],
+    ) -> Result<(super::Package, Vec<Warning>), Error> {
+        let (xml, encoding) = decode(xml)?;
+        let mismatch = encoding.check_declaration(&xml)?;
+        let (package, mut warnings) =
+            self.parse_with_parser(&xml, PullParser::new_decoded(&xml))?;
+
+        if let Some(mismatch) = mismatch {
+            warnings.insert(0, Warning::new(&xml, mismatch));
+        }
+
+        Ok((package, warnings))
+    }
+
+    /// Parses bytes that are expected to be UTF-8 into a DOM, without
+    /// detecting other encodings like `parse_bytes` does. An encoding
+    /// named in the XML declaration must be UTF-8.
+    ///
+    /// All failures, including invalid bytes, are reported at their
+    /// offset in the input.
+    pub fn parse_utf8(&self, xml: &[u8]) -> Result<super::Package, Error> {
+        let xml = str::from_utf8(xml).map_err(|e| {
+            let valid = str::from_utf8(&xml[..e.valid_up_to()]).expect("Prefix must be valid");
+            invalid_bytes(e.valid_up_to(), valid)
+        })?;
+        Encoding::Utf8.require_declaration(xml)?;
+        self.parse(xml)
+    }
+
+    /// Parses bytes into a DOM like `parse_utf8`, without checking
+    /// that they are valid UTF-8.
+    ///
+    /// # Safety
+    ///
+    /// The bytes must be valid UTF-8, as required by
+    /// `str::from_utf8_unchecked`.
+    pub unsafe fn parse_utf8_unchecked(&self, xml: &[u8]) -> Result<super::Package, Error> {
+        let xml = str::from_utf8_unchecked(xml);
+        Encoding::Utf8.require_declaration(xml)?;
+        self.parse(xml)
+    }
+
+    /// Parses a string, reporting each piece of the document to the
+    /// sink as it is encountered. No DOM is created.
+    pub fn parse_with_sink<'x, S>(&self, xml: &'x str, sink: &mut S) -> Result<(), Error>
+    where
+        S: ParserSink<'x>,
+    {
+        parse_into_sink(xml, sink, self).map_err(|e| e.locate(xml))
     }
+}
 
-    fn implode(self) -> String {
-        self.value
+impl fmt::Debug for Parser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Parser")
+            .field("entities", &self.entities.as_ref().map(|e| e.len()))
+            .field("entity_resolver", &self.entity_resolver.is_some())
+            .field(
+                "ignore_whitespace_between_elements",
+                &self.ignore_whitespace_between_elements,
+            )
+            .field(
+                "mark_whitespace_between_elements",
+                &self.mark_whitespace_between_elements,
+            )
+            .field("max_depth", &self.max_depth)
+            .field("max_attributes", &self.max_attributes)
+            .field(
+                "max_attribute_value_length",
+                &self.max_attribute_value_length,
+            )
+            .field("max_elements", &self.max_elements)
+            .field("max_entity_expansion", &self.max_entity_expansion)
+            .field("track_source_spans", &self.track_source_spans)
+            .finish()
     }
 }
 
-impl Deref for AttributeValueBuilder {
-    type Target = str;
+/// A character encoding that `Parser::parse_bytes` understands.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    /// UTF-16 without a stated byte order. Only found in
+    /// declarations; detected UTF-16 always has a byte order.
+    Utf16,
+    Utf16Le,
+    Utf16Be,
+}
 
-    fn deref(&self) -> &str {
-        &self.value
+impl Encoding {
+    fn from_name(name: &str) -> Option<Encoding> {
+        let names = [
+            ("UTF-8", Encoding::Utf8),
+            ("UTF-16", Encoding::Utf16),
+            ("UTF-16LE", Encoding::Utf16Le),
+            ("UTF-16BE", Encoding::Utf16Be),
+        ];
+
+        names
+            .iter()
+            .find(|&&(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, e)| e)
+    }
+
+    fn accepts(self, declared: Encoding) -> bool {
+        match (self, declared) {
+            (Encoding::Utf16Le, Encoding::Utf16) | (Encoding::Utf16Be, Encoding::Utf16) => true,
+            _ => self == declared,
+        }
+    }
+
+    /// A declaration naming an encoding we do not support is an
+    /// error. One naming a supported encoding other than the
+    /// detected one is returned as a warning.
+    fn check_declaration(self, xml: &str) -> Result<Option<Span<WarningKind>>, Error> {
+        if let Some(Ok(Token::XmlDeclaration(_, Some(encoding), _))) = PullParser::new(xml).next() {
+            let declared = match Encoding::from_name(encoding.value) {
+                Some(declared) => declared,
+                None => {
+                    let e = Error::from(encoding.map(|_| SpecificError::UnsupportedEncoding));
+                    return Err(e.locate(xml));
+                }
+            };
+
+            if !self.accepts(declared) {
+                return Ok(Some(encoding.map(|_| WarningKind::EncodingMismatch {
+                    declared,
+                    detected: self,
+                })));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Like `check_declaration`, but a mismatch is also an error.
+    fn require_declaration(self, xml: &str) -> Result<(), Error> {
+        match self.check_declaration(xml)? {
+            Some(mismatch) => {
+                let e = Error::from(mismatch.map(|_| SpecificError::UnsupportedEncoding));
+                Err(e.locate(xml))
+            }
+            None => Ok(()),
+        }
     }
 }
 
-#[derive(Debug)]
-struct DeferredAttribute<'d> {
-    name: Span<PrefixedName<'d>>,
-    values: Vec<AttributeValue<'d>>,
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match *self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf16 => "UTF-16",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+        };
+        f.write_str(name)
+    }
 }
 
-struct DeferredAttributes<'a> {
-    attributes: Vec<DeferredAttribute<'a>>,
-    namespaces: Vec<DeferredAttribute<'a>>,
-    default_namespaces: Vec<DeferredAttribute<'a>>,
+fn decode(xml: &[u8]) -> Result<(Cow<'_, str>, Encoding), Error> {
+    const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+    // Without a byte order mark, UTF-16 is recognized by the start of
+    // the document, which must be a `<`.
+    let (encoding, bom_len) = if xml.starts_with(b"\xFF\xFE") {
+        (Encoding::Utf16Le, 2)
+    } else if xml.starts_with(b"\xFE\xFF") {
+        (Encoding::Utf16Be, 2)
+    } else if xml.starts_with(b"<\0") {
+        (Encoding::Utf16Le, 0)
+    } else if xml.starts_with(b"\0<") {
+        (Encoding::Utf16Be, 0)
+    } else if xml.starts_with(UTF8_BOM) {
+        (Encoding::Utf8, UTF8_BOM.len())
+    } else {
+        (Encoding::Utf8, 0)
+    };
+    let bytes = &xml[bom_len..];
+
+    let decoded = match encoding {
+        Encoding::Utf8 => str::from_utf8(bytes).map(Cow::Borrowed).map_err(|e| {
+            let valid = str::from_utf8(&bytes[..e.valid_up_to()]).expect("Prefix must be valid");
+            invalid_bytes(bom_len + e.valid_up_to(), valid)
+        })?,
+        // Never detected, but big-endian is the default without a
+        // byte order mark.
+        Encoding::Utf16 | Encoding::Utf16Le | Encoding::Utf16Be => {
+            let units = bytes.chunks_exact(2).map(|pair| {
+                let (low, high) = if encoding == Encoding::Utf16Le {
+                    (pair[0], pair[1])
+                } else {
+                    (pair[1], pair[0])
+                };
+                u16::from(high) << 8 | u16::from(low)
+            });
+
+            let mut decoded = String::with_capacity(bytes.len() / 2);
+            let mut offset = bom_len;
+            for unit in char::decode_utf16(units) {
+                match unit {
+                    Ok(c) => {
+                        decoded.push(c);
+                        offset += c.len_utf16() * 2;
+                    }
+                    Err(_) => return Err(invalid_bytes(offset, &decoded)),
+                }
+            }
+
+            // A trailing odd byte cannot be a complete code unit
+            if offset != xml.len() {
+                return Err(invalid_bytes(offset, &decoded));
+            }
+
+            Cow::Owned(decoded)
+        }
+    };
+
+    Ok((decoded, encoding))
 }
 
-impl<'a> DeferredAttributes<'a> {
-    fn new(attributes: Vec<DeferredAttribute<'a>>) -> DeferredAttributes<'a> {
-        let (mut namespaces, attributes): (Vec<_>, Vec<_>) = attributes
-            .into_iter()
-            .partition(|attr| attr.name.value.prefix == Some("xmlns"));
+/// The line and column are those of the end of the text that could
+/// be decoded before the invalid bytes.
+fn invalid_bytes(location: usize, decoded: &str) -> Error {
+    let (line, column) = line_and_column(decoded, decoded.len());
+    Error {
+        line,
+        column,
+        kind: ErrorKind::InvalidEncoding,
+        ..Error::new(location, SpecificError::InvalidByteSequence)
+    }
+}
 
-        let (mut default_namespaces, mut attributes): (Vec<_>, Vec<_>) = attributes
-            .into_iter()
-            .partition(|attr| attr.name.value.local_part == "xmlns");
+/// Parses a string into a DOM. On failure, the location of the
+/// parsing failure and all possible failures will be returned.
+pub fn parse(xml: &str) -> Result<super::Package, Error> {
+    Parser::new().parse(xml)
+}
+
+/// Replaces the predefined entity references and character
+/// references in a string, such as a value taken from an XML document
+/// by other means. Only allocates when a reference is present.
+///
+/// Any other entity reference is an error, as is an `&` that does not
+/// start a reference. The error's location is a byte offset into
+/// `text`.
+///
+/// ```
+/// use sxd_document::parser::unescape;
+///
+/// assert_eq!(unescape("&lt;a&gt; &amp;&#65;&#x42;").unwrap(), "<a> &AB");
+/// assert!(unescape("&nbsp;").is_err());
+/// ```
+pub fn unescape(text: &str) -> Result<Cow<'_, str>, Error> {
+    if !text.contains('&') {
+        return Ok(Cow::Borrowed(text));
+    }
+
+    let mut pm = ParseMaster::new();
+    let mut unescaped = String::with_capacity(text.len());
+    let mut xml = StringPoint::new(text);
+
+    while let Some(start) = xml.s.find('&') {
+        unescaped.push_str(&xml.s[..start]);
+        let reference_start = StringPoint {
+            s: &xml.s[start..],
+            offset: xml.offset + start,
+        };
+        xml = unescape_reference(&mut pm, reference_start, text, &mut unescaped)?;
+    }
 
-        fn sort_by_name(
-            a: &DeferredAttribute<'_>,
-            b: &DeferredAttribute<'_>,
-        ) -> ::std::cmp::Ordering {
-            a.name.value.cmp(&b.name.value)
+    unescaped.push_str(xml.s);
+    Ok(Cow::Owned(unescaped))
+}
+
+/// Decodes a single character reference or predefined entity
+/// reference, such as `&#65;`, `&#x42;` or `&amp;`, into the text it
+/// stands for.
+///
+/// The whole of `text` must be the reference. Any other entity
+/// reference is an error, as is a character reference to a code
+/// point that is not allowed in XML. The error's location is a byte
+/// offset into `text`.
+///
+/// ```
+/// use sxd_document::parser::decode_reference;
+///
+/// assert_eq!(decode_reference("&#x1F600;").unwrap(), "\u{1F600}");
+/// assert_eq!(decode_reference("&quot;").unwrap(), "\"");
+/// assert!(decode_reference("&#0;").is_err());
+/// ```
+pub fn decode_reference(text: &str) -> Result<String, Error> {
+    let mut pm = ParseMaster::new();
+    let mut decoded = String::new();
+
+    let after = unescape_reference(&mut pm, StringPoint::new(text), text, &mut decoded)?;
+    if !after.s.is_empty() {
+        return Err(Error::new(after.offset, SpecificError::TrailingText).locate(text));
+    }
+
+    Ok(decoded)
+}
+
+/// Parses the reference at the start of `xml`, appending what it
+/// stands for to `out`. Errors are located in `text`, which `xml` is
+/// a part of.
+fn unescape_reference<'a>(
+    pm: &mut XmlMaster<'a>,
+    xml: StringPoint<'a>,
+    text: &str,
+    out: &mut String,
+) -> Result<StringPoint<'a>, Error> {
+    let progress = parse_reference(pm, xml);
+    let (after, reference) = match pm.finish(progress) {
+        peresil::Progress {
+            status: peresil::Status::Success(reference),
+            point,
+        } => (point, reference),
+        peresil::Progress {
+            status: peresil::Status::Failure(e),
+            point,
+        } => return Err(Error::from((point.offset, e)).locate(text)),
+    };
+
+    let located = |e: Span<SpecificError>| Error::from(e).locate(text);
+    match reference {
+        DecimalChar(span) => {
+            let c = decode_char(span, 10, SpecificError::InvalidDecimalReference);
+            out.push(c.map_err(located)?);
         }
+        HexChar(span) => {
+            let c = decode_char(span, 16, SpecificError::InvalidHexReference);
+            out.push(c.map_err(located)?);
+        }
+        Entity(span) => {
+            let s = predefined_entity(span.value)
+                .ok_or_else(|| located(span.map(|_| SpecificError::UnknownNamedReference)))?;
+            out.push_str(s);
+        }
+    }
 
-        attributes.sort_by(sort_by_name);
-        namespaces.sort_by(sort_by_name);
-        default_namespaces.sort_by(sort_by_name);
+    Ok(after)
+}
 
-        DeferredAttributes {
-            attributes,
-            namespaces,
-            default_namespaces,
+fn parse_package(
+    xml: &str,
+    parser: PullParser<'_>,
+    options: &Parser,
+) -> Result<(super::Package, Vec<Span<WarningKind>>), Error> {
+    let package = super::Package::new();
+    let (warnings, _) = build_document(&package, xml, parser, options)?;
+    Ok((package, warnings))
+}
+
+/// Builds the document into the package, leaving the nodes built so
+/// far in place on failure.
+fn build_package(
+    package: &super::Package,
+    xml: &str,
+    options: &Parser,
+) -> Result<Vec<Span<WarningKind>>, Error> {
+    let parser = PullParser::new(xml).with_limits(options);
+    build_document(package, xml, parser, options).map(|(warnings, _)| warnings)
+}
+
+/// Returns the warnings and the offset where the parser stopped.
+fn build_document(
+    package: &super::Package,
+    xml: &str,
+    mut parser: PullParser<'_>,
+    options: &Parser,
+) -> Result<(Vec<Span<WarningKind>>, usize), Error> {
+    let doc = package.as_document();
+    let mut driver = EventDriver::new(DomBuilder::new(doc, options), options);
+
+    while let Some(token) = parser.next() {
+        let token = token?;
+        driver
+            .consume(token, parser.token_range())
+            .map_err(|e| driver.error(e))?;
+    }
+
+    if driver.has_unclosed_elements() {
+        return Err(Error::new(xml.len(), SpecificError::UnclosedElement));
+    }
+
+    handle_whitespace_between_elements(doc.root_element(), options);
+
+    Ok((driver.events.warnings, parser.offset()))
+}
+
+fn parse_many_packages(xml: &str, options: &Parser) -> Result<Vec<super::Package>, DocumentError> {
+    let mut packages = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let package = super::Package::new();
+        let parser = PullParser::new_document_at(xml, offset).with_limits(options);
+        let (_, end) =
+            build_document(&package, xml, parser, options).map_err(|e| DocumentError {
+                index: packages.len(),
+                error: e.locate(xml),
+            })?;
+        packages.push(package);
+
+        if end == xml.len() {
+            return Ok(packages);
         }
+        offset = end;
     }
+}
 
-    fn check_duplicates(&self) -> DomBuilderResult<()> {
-        for w in self.attributes.windows(2) {
-            if w[0].name.value == w[1].name.value {
-                return Err(w[1].name.map(|_| SpecificError::DuplicateAttribute));
+/// Prefixes used by the fragment may also be declared by the
+/// `context` element or its ancestors, and unprefixed elements are
+/// in its default namespace.
+fn parse_fragment_into<'d>(
+    doc: dom::Document<'d>,
+    xml: &str,
+    context: Option<dom::Element<'d>>,
+    options: &Parser,
+) -> Result<Vec<dom::ChildOfElement<'d>>, Error> {
+    let mut parser = PullParser::new_fragment(xml).with_limits(options);
+
+    // The top-level nodes are built inside an element that is never
+    // attached to the document, then detached from it
+    let holder = doc.create_element("fragment");
+    if let Some(context) = context {
+        for ns in context.namespaces_in_scope() {
+            holder.register_prefix(ns.prefix(), ns.uri());
+        }
+        holder.set_default_namespace_uri(context.recursive_default_namespace_uri());
+    }
+    let mut builder = DomBuilder::new(doc, options);
+    builder.elements.push(holder);
+    let mut driver = EventDriver::new(builder, options);
+
+    while let Some(token) = parser.next() {
+        let token = token?;
+        driver
+            .consume(token, parser.token_range())
+            .map_err(|e| driver.error(e))?;
+    }
+
+    if driver.has_unclosed_elements() {
+        return Err(Error::new(xml.len(), SpecificError::UnclosedElement));
+    }
+
+    handle_whitespace_between_elements(iter::once(holder), options);
+
+    let children = holder.children();
+    holder.clear_children();
+    Ok(children)
+}
+
+fn parse_package_lenient(xml: &str, options: &Parser) -> (super::Package, Vec<Error>) {
+    let mut parser = PullParser::new(xml).with_limits(options);
+    let package = super::Package::new();
+    let mut errors = Vec::new();
+
+    {
+        let doc = package.as_document();
+        let mut driver = EventDriver::new(DomBuilder::new(doc, options), options);
+        let mut stopped = false;
+
+        while let Some(token) = parser.next() {
+            let token = match token {
+                Ok(token) => token,
+                Err(e) => {
+                    // A limit that was exceeded is never recovered from
+                    let recoverable = e.1.iter().all(Recoverable::recoverable);
+                    errors.push(Error::from(e));
+                    match parser.recover_stray_ampersand().filter(|_| recoverable) {
+                        Some(token) => token,
+                        None => {
+                            stopped = true;
+                            break;
+                        }
+                    }
+                }
+            };
+
+            let range = parser.token_range();
+
+            match token {
+                Token::ElementClose(name) => {
+                    if let Err(e) = driver.close_element_leniently(name, range) {
+                        errors.push(driver.error(e));
+                    }
+                    parser.set_open_elements(driver.open_elements());
+                }
+                Token::ContentReference(..) => {
+                    if let Err(e) = driver.consume(token, range) {
+                        errors.push(driver.error(e));
+                    }
+                }
+                token => {
+                    if let Err(e) = driver.consume(token, range) {
+                        errors.push(driver.error(e));
+                        stopped = true;
+                        break;
+                    }
+                }
             }
         }
 
-        for w in self.namespaces.windows(2) {
-            if w[0].name.value == w[1].name.value {
-                return Err(w[1].name.map(|_| SpecificError::RedefinedNamespace));
+        if !stopped && driver.has_unclosed_elements() {
+            errors.push(Error::new(xml.len(), SpecificError::UnclosedElement));
+            while driver.has_unclosed_elements() {
+                driver.close_element(xml.len());
             }
         }
 
-        Ok(())
+        handle_whitespace_between_elements(doc.root_element(), options);
     }
 
-    fn attributes(&self) -> &[DeferredAttribute<'a>] {
-        &self.attributes
+    (package, errors)
+}
+
+fn is_reserved_namespace(uri: &str) -> bool {
+    uri == crate::XML_NS_URI || uri == crate::XMLNS_NS_URI
+}
+
+/// Removes or marks whitespace-only text in the given elements and
+/// their descendants, as the options ask.
+fn handle_whitespace_between_elements<'d, I>(elements: I, options: &Parser)
+where
+    I: IntoIterator<Item = dom::Element<'d>>,
+{
+    let remove = options.ignore_whitespace_between_elements;
+    if !remove && !options.mark_whitespace_between_elements {
+        return;
     }
 
-    fn namespaces(&self) -> &[DeferredAttribute<'a>] {
-        &self.namespaces
+    let is_whitespace =
+        |t: dom::Text<'_>| !t.is_cdata() && t.text().chars().all(|c| c.is_space_char());
+
+    // Each element is paired with whether its parent preserves space
+    let mut todo: Vec<_> = elements.into_iter().map(|e| (e, false)).collect();
+    while let Some((element, preserve)) = todo.pop() {
+        let preserve = match element.attribute_value((crate::XML_NS_URI, "space")) {
+            Some("preserve") => true,
+            Some("default") => false,
+            _ => preserve,
+        };
+
+        let children = element.children();
+        todo.extend(
+            children
+                .iter()
+                .filter_map(|c| c.element())
+                .map(|e| (e, preserve)),
+        );
+
+        if preserve {
+            continue;
+        }
+
+        let has_child_element = children.iter().any(|c| c.element().is_some());
+        let has_other_text = children
+            .iter()
+            .filter_map(|c| c.text())
+            .any(|t| !is_whitespace(t));
+
+        if has_child_element && !has_other_text {
+            for text in children.iter().filter_map(|c| c.text()) {
+                if remove {
+                    element.remove_child(text);
+                } else {
+                    text.set_ignorable_whitespace(true);
+                }
+            }
+        }
     }
+}
 
-    fn default_namespace(&self) -> DomBuilderResult<Option<String>> {
-        match self.default_namespaces.len() {
-            0 => Ok(None),
-            1 => {
-                let ns = &self.default_namespaces[0];
-                let value = AttributeValueBuilder::convert(&ns.values)?;
-                Ok(Some(value))
+fn parse_into_sink<'x, S>(xml: &'x str, sink: &mut S, options: &Parser) -> Result<(), Error>
+where
+    S: ParserSink<'x>,
+{
+    let mut parser = PullParser::new(xml).with_limits(options);
+    let mut driver = EventDriver::new(SinkEvents(sink), options);
+
+    while let Some(token) = parser.next() {
+        let token = token?;
+        driver
+            .consume(token, parser.token_range())
+            .map_err(|e| driver.error(e))?;
+    }
+
+    if driver.has_unclosed_elements() {
+        return Err(Error::new(xml.len(), SpecificError::UnclosedElement));
+    }
+
+    Ok(())
+}
+
+type DomBuilderResult<T> = Result<T, Span<SpecificError>>;
+
+fn decode_char(span: Span<&str>, radix: u32, error: SpecificError) -> DomBuilderResult<char> {
+    u32::from_str_radix(span.value, radix)
+        .ok()
+        .and_then(char::from_u32)
+        .filter(|c| c.is_char())
+        .ok_or_else(|| span.map(|_| error))
+}
+
+fn predefined_entity(name: &str) -> Option<&'static str> {
+    match name {
+        "amp" => Some("&"),
+        "lt" => Some("<"),
+        "gt" => Some(">"),
+        "apos" => Some("'"),
+        "quot" => Some("\""),
+        _ => None,
+    }
+}
+
+/// Replaces each `\r\n` and lone `\r` with `\n`, as the XML
+/// specification requires of all input. Only allocates when a
+/// carriage return is present.
+fn normalize_line_endings(text: &str) -> Cow<'_, str> {
+    if !text.contains('\r') {
+        return Cow::Borrowed(text);
+    }
+
+    let mut normalized = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\r' {
+            if chars.peek() == Some(&'\n') {
+                chars.next();
             }
-            _ => {
-                let last_namespace = self.default_namespaces.last().unwrap();
-                Err(last_namespace
-                    .name
-                    .map(|_| SpecificError::RedefinedDefaultNamespace))
+            normalized.push('\n');
+        } else {
+            normalized.push(c);
+        }
+    }
+    Cow::Owned(normalized)
+}
+
+fn expand_reference<F>(
+    ref_data: Reference<'_>,
+    entities: &Entities<'_, '_>,
+    cb: F,
+) -> DomBuilderResult<()>
+where
+    F: FnOnce(&str),
+{
+    match ref_data {
+        DecimalChar(span) => decode_char(span, 10, SpecificError::InvalidDecimalReference)
+            .map(|c| cb(c.encode_utf8(&mut [0; 4]))),
+        HexChar(span) => decode_char(span, 16, SpecificError::InvalidHexReference)
+            .map(|c| cb(c.encode_utf8(&mut [0; 4]))),
+        Entity(span) => match predefined_entity(span.value) {
+            Some(s) => {
+                cb(s);
+                Ok(())
             }
+            None => entities.expand(span).map(|s| cb(&s)),
+        },
+    }
+}
+
+/// The general entities declared in the internal subset of the
+/// document type declaration.
+///
+/// Character references in an entity value are replaced when the
+/// entity is declared, while entity references are kept and expanded
+/// each time the entity is used. The expanded text is always treated
+/// as character data; any markup it contains is not parsed.
+///
+/// Entities that have not been declared are looked up with the
+/// table and resolver supplied to the `Parser`, if any.
+///
+/// The text produced by all expansions in a document counts against
+/// the expansion limit of the `Parser`, so that a few small entities
+/// referring to each other many times cannot exhaust memory.
+struct Entities<'a, 'r> {
+    declared: HashMap<&'a str, String>,
+    pending: Option<(&'a str, String)>,
+    table: Option<&'r HashMap<String, String>>,
+    resolver: Option<&'r EntityResolver>,
+    max_expansion: Option<usize>,
+    expanded: Cell<usize>,
+}
+
+impl<'a, 'r> Entities<'a, 'r> {
+    fn new(options: &'r Parser) -> Self {
+        Entities {
+            declared: HashMap::new(),
+            pending: None,
+            table: options.entities.as_ref().map(|e| &**e),
+            resolver: options.entity_resolver.as_ref().map(|r| &**r),
+            max_expansion: options.max_entity_expansion,
+            expanded: Cell::new(0),
         }
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::{dom, Package, QName};
+    fn start_declaration(&mut self, name: &'a str) {
+        self.pending = Some((name, String::new()));
+    }
+
+    fn pending_value(&mut self) -> &mut String {
+        &mut self
+            .pending
+            .as_mut()
+            .expect("Cannot add entity value without a declaration")
+            .1
+    }
+
+    fn add_literal(&mut self, v: &str) {
+        self.pending_value().push_str(v);
+    }
+
+    fn add_reference(&mut self, r: Reference<'_>) -> DomBuilderResult<()> {
+        let value = self.pending_value();
+
+        match r {
+            DecimalChar(span) => value.push(decode_char(
+                span,
+                10,
+                SpecificError::InvalidDecimalReference,
+            )?),
+            HexChar(span) => value.push(decode_char(span, 16, SpecificError::InvalidHexReference)?),
+            Entity(span) => {
+                value.push('&');
+                value.push_str(span.value);
+                value.push(';');
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish_declaration(&mut self) {
+        let (name, value) = self.pending.take().expect("No entity declaration");
+        // The first declaration of an entity is binding
+        self.declared.entry(name).or_insert(value);
+    }
+
+    fn exceeds_limit(&self, additional: usize) -> bool {
+        match self.max_expansion {
+            Some(max) => self.expanded.get() + additional > max,
+            None => false,
+        }
+    }
+
+    /// Produces the replacement text of a reference to a declared
+    /// entity. Nested references are expanded without recursion, using
+    /// the stack of entities currently being expanded to detect an
+    /// entity that refers to itself.
+    fn expand(&self, reference: Span<&str>) -> DomBuilderResult<String> {
+        let error = |e| reference.map(|_| e);
+        let decode = |value, radix, e| {
+            let span = Span {
+                offset: reference.offset,
+                value,
+            };
+            decode_char(span, radix, e)
+        };
+
+        let mut expanded = String::new();
+        let mut open: Vec<(&str, &str)> = Vec::new();
+        let mut next = Some(reference.value);
+
+        loop {
+            if self.exceeds_limit(expanded.len()) {
+                return Err(error(SpecificError::EntityExpansionLimitExceeded));
+            }
+
+            if let Some(name) = next.take() {
+                if open.iter().any(|&(open_name, _)| open_name == name) {
+                    return Err(error(SpecificError::RecursiveEntityReference));
+                }
+                match self.declared.get(name) {
+                    Some(value) => open.push((name, value)),
+                    None => match self.table.and_then(|table| table.get(name)) {
+                        Some(value) => expanded.push_str(value),
+                        None => {
+                            let resolved = self.resolver.and_then(|resolver| resolver(name));
+                            let resolved = resolved
+                                .ok_or_else(|| error(SpecificError::UnknownNamedReference))?;
+                            expanded.push_str(&resolved);
+                        }
+                    },
+                }
+            }
+
+            let rest = match open.last_mut() {
+                Some((_, rest)) => rest,
+                None => break,
+            };
+
+            let start = match rest.find('&') {
+                Some(start) => start,
+                None => {
+                    expanded.push_str(rest);
+                    open.pop();
+                    continue;
+                }
+            };
+
+            expanded.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let end = after
+                .find(';')
+                .ok_or_else(|| error(SpecificError::UnknownNamedReference))?;
+            let name = &after[..end];
+            *rest = &after[end + 1..];
+
+            if name.starts_with("#x") {
+                expanded.push(decode(&name[2..], 16, SpecificError::InvalidHexReference)?);
+            } else if name.starts_with('#') {
+                expanded.push(decode(
+                    &name[1..],
+                    10,
+                    SpecificError::InvalidDecimalReference,
+                )?);
+            } else if let Some(s) = predefined_entity(name) {
+                expanded.push_str(s);
+            } else {
+                next = Some(name);
+            }
+        }
+
+        if self.exceeds_limit(expanded.len()) {
+            return Err(error(SpecificError::EntityExpansionLimitExceeded));
+        }
+        self.expanded.set(self.expanded.get() + expanded.len());
+
+        Ok(expanded)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum AttributeValue<'a> {
+    ReferenceAttributeValue(Reference<'a>),
+    LiteralAttributeValue(&'a str),
+}
+
+/// Builds an attribute value following the specification's
+/// attribute-value normalization. Literal tabs and line endings, both
+/// in the value itself and in the replacement text of entities, become
+/// spaces. Whitespace written as a character reference, such as
+/// `&#9;`, is kept. Without a DTD every attribute is treated as CDATA,
+/// so runs of spaces are not collapsed.
+struct AttributeValueBuilder {
+    value: String,
+}
+
+impl AttributeValueBuilder {
+    fn convert(
+        values: &[AttributeValue<'_>],
+        entities: &Entities<'_, '_>,
+    ) -> DomBuilderResult<String> {
+        let mut builder = AttributeValueBuilder::new();
+        builder.ingest(values, entities)?;
+        Ok(builder.implode())
+    }
+
+    fn new() -> AttributeValueBuilder {
+        AttributeValueBuilder {
+            value: String::new(),
+        }
+    }
+
+    fn ingest(
+        &mut self,
+        values: &[AttributeValue<'_>],
+        entities: &Entities<'_, '_>,
+    ) -> DomBuilderResult<()> {
+        use self::AttributeValue::*;
+
+        let literal_len: usize = values
+            .iter()
+            .map(|v| match *v {
+                LiteralAttributeValue(v) => v.len(),
+                ReferenceAttributeValue(_) => 0,
+            })
+            .sum();
+        self.value.reserve(literal_len);
+
+        for value in values.iter() {
+            match *value {
+                LiteralAttributeValue(v) => self.push_literal(v),
+                ReferenceAttributeValue(r @ Entity(..)) => {
+                    expand_reference(r, entities, |s| self.push_literal(s))?
+                }
+                ReferenceAttributeValue(r) => {
+                    expand_reference(r, entities, |s| self.value.push_str(s))?
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn push_literal(&mut self, text: &str) {
+        let text = normalize_line_endings(text);
+        if !text.contains(&['\t', '\n'][..]) {
+            self.value.push_str(&text);
+            return;
+        }
+
+        self.value.reserve(text.len());
+        self.value.extend(text.chars().map(|c| match c {
+            '\t' | '\n' => ' ',
+            c => c,
+        }));
+    }
+
+    fn clear(&mut self) {
+        self.value.clear();
+    }
+
+    fn implode(self) -> String {
+        self.value
+    }
+}
+
+impl Deref for AttributeValueBuilder {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.value
+    }
+}
+
+#[derive(Debug)]
+struct DeferredAttribute<'d> {
+    name: Span<PrefixedName<'d>>,
+    values: Vec<AttributeValue<'d>>,
+}
+
+/// The attributes of a start tag, split by kind. Each kind keeps the
+/// order the attributes appear in the document.
+struct DeferredAttributes<'a> {
+    attributes: Vec<DeferredAttribute<'a>>,
+    namespaces: Vec<DeferredAttribute<'a>>,
+    default_namespaces: Vec<DeferredAttribute<'a>>,
+}
+
+impl<'a> DeferredAttributes<'a> {
+    fn new(attributes: Vec<DeferredAttribute<'a>>) -> DeferredAttributes<'a> {
+        let (namespaces, attributes): (Vec<_>, Vec<_>) = attributes
+            .into_iter()
+            .partition(|attr| attr.name.value.prefix == Some("xmlns"));
+
+        let (default_namespaces, attributes): (Vec<_>, Vec<_>) = attributes
+            .into_iter()
+            .partition(|attr| attr.name.value.local_part == "xmlns");
+
+        DeferredAttributes {
+            attributes,
+            namespaces,
+            default_namespaces,
+        }
+    }
+
+    fn check_duplicates(&self) -> DomBuilderResult<()> {
+        fn first_repeat<'a, 'd>(
+            attributes: &'a [DeferredAttribute<'d>],
+        ) -> Option<&'a DeferredAttribute<'d>> {
+            let mut seen = BTreeSet::new();
+            attributes.iter().find(|attr| !seen.insert(attr.name.value))
+        }
+
+        if let Some(attr) = first_repeat(&self.attributes) {
+            return Err(attr.name.map(|_| SpecificError::DuplicateAttribute));
+        }
+
+        if let Some(attr) = first_repeat(&self.namespaces) {
+            return Err(attr.name.map(|_| SpecificError::RedefinedNamespace));
+        }
+
+        Ok(())
+    }
+
+    fn attributes(&self) -> &[DeferredAttribute<'a>] {
+        &self.attributes
+    }
+
+    fn namespaces(&self) -> &[DeferredAttribute<'a>] {
+        &self.namespaces
+    }
+
+    fn default_namespace(&self, entities: &Entities<'_, '_>) -> DomBuilderResult<Option<String>> {
+        match self.default_namespaces.len() {
+            0 => Ok(None),
+            1 => {
+                let ns = &self.default_namespaces[0];
+                let value = AttributeValueBuilder::convert(&ns.values, entities)?;
+                Ok(Some(value))
+            }
+            _ => {
+                let last_namespace = self.default_namespaces.last().unwrap();
+                Err(last_namespace
+                    .name
+                    .map(|_| SpecificError::RedefinedDefaultNamespace))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{dom, Package, QName};
+
+    macro_rules! assert_qname_eq(
+        ($l:expr, $r:expr) => (assert_eq!(Into::<QName<'_>>::into($l), $r.into()));
+    );
+
+    fn full_parse(xml: &str) -> Result<Package, Error> {
+        super::parse(xml)
+    }
+
+    fn quick_parse(xml: &str) -> Package {
+        full_parse(xml).expect("Failed to parse the XML string")
+    }
+
+    fn top<'d>(doc: &'d dom::Document<'d>) -> dom::Element<'d> {
+        doc.root().children()[0].element().unwrap()
+    }
+
+    #[test]
+    fn a_document_with_a_prolog() {
+        let package = quick_parse("<?xml version='1.0' ?><hello />");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_qname_eq!(top.name(), "hello");
+    }
+
+    #[test]
+    fn a_document_declaring_xml_1_1() {
+        let package = quick_parse(r#"<?xml version="1.1"?><a/>"#);
+        let doc = package.as_document();
+
+        assert_qname_eq!(top(&doc).name(), "a");
+        assert_eq!(doc.version(), Some("1.1"));
+    }
+
+    #[test]
+    fn a_document_without_a_declaration_has_no_version() {
+        let package = quick_parse("<a/>");
+        let doc = package.as_document();
+
+        assert_eq!(doc.version(), None);
+    }
+
+    #[test]
+    fn a_document_with_a_prolog_with_double_quotes() {
+        let package = quick_parse("<?xml version=\"1.0\" ?><hello />");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_qname_eq!(top.name(), "hello");
+    }
+
+    #[test]
+    fn a_prolog_with_an_encoding() {
+        let package = quick_parse("<?xml version='1.0' encoding='UTF-8' ?><hello />");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_qname_eq!(top.name(), "hello");
+    }
+
+    #[test]
+    fn a_prolog_with_an_encoding_records_it() {
+        let package = quick_parse("<?xml version='1.0' encoding='ISO-8859-1' ?><hello />");
+        let doc = package.as_document();
+
+        assert_eq!(doc.encoding(), Some("ISO-8859-1"));
+    }
+
+    #[test]
+    fn a_prolog_with_an_encoding_using_double_quotes() {
+        let package = quick_parse(r#"<?xml version="1.0" encoding="UTF-8"?><hello />"#);
+        let doc = package.as_document();
+
+        assert_eq!(doc.encoding(), Some("UTF-8"));
+    }
+
+    #[test]
+    fn a_prolog_without_an_encoding() {
+        let package = quick_parse("<?xml version='1.0'?><hello />");
+        let doc = package.as_document();
+
+        assert_eq!(doc.encoding(), None);
+    }
+
+    #[test]
+    fn a_non_standalone_prolog() {
+        let package = quick_parse("<?xml version='1.0' standalone='no'?><hello/>");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_qname_eq!(top.name(), "hello");
+        assert_eq!(doc.standalone(), Some(false));
+        assert_eq!(doc.encoding(), None);
+    }
+
+    #[test]
+    fn a_standalone_prolog_with_double_quotes() {
+        let package = quick_parse(r#"<?xml version="1.0" standalone="yes"?><hello/>"#);
+        let doc = package.as_document();
+
+        assert_eq!(doc.standalone(), Some(true));
+    }
+
+    #[test]
+    fn a_prolog_without_standalone() {
+        let package = quick_parse("<?xml version='1.0' encoding='UTF-8'?><hello/>");
+        let doc = package.as_document();
+
+        assert_eq!(doc.standalone(), None);
+    }
+
+    #[test]
+    fn a_complete_prolog() {
+        let package =
+            quick_parse("<?xml version='1.0' encoding='UTF-8' standalone='yes'?><hello/>");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_qname_eq!(top.name(), "hello");
+        assert_eq!(doc.encoding(), Some("UTF-8"));
+        assert_eq!(doc.standalone(), Some(true));
+    }
+
+    #[test]
+    fn a_prolog_with_a_doc_type_declaration_external_id() {
+        let package = quick_parse(
+            r#"<?xml version='1.0'?>
+        <!DOCTYPE doc SYSTEM "http://example.com/doc.dtd">
+        <hello/>"#,
+        );
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_qname_eq!(top.name(), "hello");
+    }
+
+    #[test]
+    fn a_doc_type_declaration_is_kept() {
+        let package = quick_parse(r#"<!DOCTYPE html SYSTEM "about:legacy-compat"><html/>"#);
+        let doc = package.as_document();
+        let document_type = doc.document_type().unwrap();
+
+        assert_eq!(document_type.name(), "html");
+        assert_eq!(document_type.public_id(), None);
+        assert_eq!(document_type.system_id(), Some("about:legacy-compat"));
+        assert_eq!(document_type.internal_subset(), None);
+    }
+
+    #[test]
+    fn a_doc_type_declaration_with_a_public_id() {
+        let package = quick_parse(
+            r#"<!DOCTYPE html PUBLIC '-//W3C//DTD XHTML 1.0 Strict//EN' "xhtml1-strict.dtd"><html/>"#,
+        );
+        let doc = package.as_document();
+        let document_type = doc.document_type().unwrap();
+
+        assert_eq!(
+            document_type.public_id(),
+            Some("-//W3C//DTD XHTML 1.0 Strict//EN")
+        );
+        assert_eq!(document_type.system_id(), Some("xhtml1-strict.dtd"));
+    }
+
+    #[test]
+    fn a_doc_type_declaration_with_unusual_system_literals() {
+        let package = quick_parse(r#"<!DOCTYPE a SYSTEM ""><a/>"#);
+        let doc = package.as_document();
+        assert_eq!(doc.document_type().unwrap().system_id(), Some(""));
+
+        let package = quick_parse(r#"<!DOCTYPE a SYSTEM 'a?b=1&c=<"2">'><a/>"#);
+        let doc = package.as_document();
+        assert_eq!(
+            doc.document_type().unwrap().system_id(),
+            Some(r#"a?b=1&c=<"2">"#)
+        );
+    }
+
+    #[test]
+    fn a_doc_type_declaration_keeps_the_raw_internal_subset() {
+        let subset = r#"
+            <!ELEMENT a (#PCDATA)>
+            <!ATTLIST a b CDATA "]>">
+            <!ENTITY e 'x]>y'>
+            <!-- ]> -->
+        "#;
+        let package = quick_parse(&format!("<!DOCTYPE a [{}] ><a>&e;</a>", subset));
+        let doc = package.as_document();
+        let document_type = doc.document_type().unwrap();
+
+        assert_eq!(document_type.name(), "a");
+        assert_eq!(document_type.system_id(), None);
+        assert_eq!(document_type.internal_subset(), Some(subset));
+        assert_eq!(top(&doc).string_value(), "x]>y");
+    }
+
+    #[test]
+    fn a_prolog_with_a_doc_type_declaration_int_subset() {
+        let package = quick_parse(
+            r#"<?xml version="1.0"?>
+            <!DOCTYPE note [
+            <!ELEMENT note (to,from,heading,body)>
+            <!ELEMENT to (#PCDATA)>
+            <!ELEMENT from (#PCDATA)>
+            <!ELEMENT heading (#PCDATA)>
+            <!ELEMENT body (#PCDATA)>
+            ]>
+            <note>
+            <to>Tove</to>
+            <from>Jani</from>
+            <heading>Reminder</heading>
+            <body>Don't forget me this weekend</body>
+            </note>
+        "#,
+        );
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_qname_eq!(top.name(), "note");
+    }
+
+    #[test]
+    fn a_prolog_with_a_doc_type_declaration_int_subset_trailing_ws() {
+        let package = quick_parse(
+            r#"<?xml version="1.0"?>
+            <!DOCTYPE note
+                [
+                    <!ELEMENT note (to,from,heading,body)>
+                    <!ELEMENT to (#PCDATA)>
+                    <!ELEMENT from (#PCDATA)>
+                    <!ELEMENT heading (#PCDATA)>
+                    <!ELEMENT body (#PCDATA)>
+                ]
+
+            >
+            <note>
+            <to>Tove</to>
+            <from>Jani</from>
+            <heading>Reminder</heading>
+            <body>Don't forget me this weekend</body>
+            </note>
+        "#,
+        );
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_qname_eq!(top.name(), "note");
+    }
+
+    #[test]
+    fn a_prolog_with_a_doc_type_declaration_zero_def() {
+        let package = quick_parse(
+            "<?xml version='1.0'?>
+        <!DOCTYPE doc>
+        <hello/>",
+        );
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_qname_eq!(top.name(), "hello");
+    }
+
+    #[test]
+    fn a_prolog_with_a_doc_type_declaration_zero_def_trailing_ws() {
+        let package = quick_parse(
+            "<?xml version='1.0'?>
+        <!DOCTYPE doc  >
+        <hello/>",
+        );
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_qname_eq!(top.name(), "hello");
+    }
+
+    #[test]
+    fn a_prolog_with_a_doc_type_declaration_both_int_subset_and_external_id() {
+        let package = quick_parse(
+            r#"<?xml version='1.0'?>
+        <!DOCTYPE doc SYSTEM "http://example.com/doc.dtd" [
+        <!ELEMENT hello (#PCDATA)>
+        ]>
+        <hello/>"#,
+        );
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_qname_eq!(top.name(), "hello");
+    }
+
+    #[test]
+    fn a_prolog_with_a_doc_type_declaration_both_int_subset_and_external_id_trailing_ws() {
+        let package = quick_parse(
+            r#"<?xml version='1.0'?>
+        <!DOCTYPE doc SYSTEM "http://example.com/doc.dtd" [
+        <!ELEMENT hello (#PCDATA)>
+         ]  >
+        <hello/>"#,
+        );
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_qname_eq!(top.name(), "hello");
+    }
+
+    #[test]
+    fn a_doc_type_declaration_with_an_internal_entity() {
+        let package = quick_parse(r#"<!DOCTYPE a [ <!ENTITY foo "bar"> ]><a>&foo;</a>"#);
+        let doc = package.as_document();
+        let a = top(&doc);
+        let text = a.children()[0].text().unwrap();
+
+        assert_eq!(text.text(), "bar");
+    }
+
+    #[test]
+    fn an_internal_subset_mixing_comments_and_entity_declarations() {
+        let package = quick_parse(
+            r#"<!DOCTYPE a [
+            <!-- a comment with > and ]> inside -->
+            <!ENTITY e "yes">
+            <?pi a value with > inside?>
+            %parameter;
+            <!ATTLIST a b CDATA "x>y">
+            ]><a>&e;</a>"#,
+        );
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        assert_eq!(a.string_value(), "yes");
+        assert_eq!(doc.document_type().unwrap().name(), "a");
+    }
+
+    #[test]
+    fn an_internal_entity_referring_to_another_entity() {
+        let package = quick_parse(
+            r#"<!DOCTYPE a [
+            <!ENTITY inner 'in&amp;ner'>
+            <!ENTITY outer "[&inner;&#33;]">
+            ]><a>&outer;</a>"#,
+        );
+        let doc = package.as_document();
+        let a = top(&doc);
+        let text = a.children()[0].text().unwrap();
+
+        assert_eq!(text.text(), "[in&ner!]");
+    }
+
+    #[test]
+    fn an_internal_entity_with_a_character_reference_to_an_ampersand() {
+        let package = quick_parse(r#"<!DOCTYPE a [ <!ENTITY e "&#38;lt;"> ]><a>&e;</a>"#);
+        let doc = package.as_document();
+        let a = top(&doc);
+        let text = a.children()[0].text().unwrap();
+
+        assert_eq!(text.text(), "<");
+    }
+
+    #[test]
+    fn an_internal_entity_in_an_attribute_value() {
+        let package = quick_parse(r#"<!DOCTYPE a [ <!ENTITY e "entity"> ]><a b="an &e;"/>"#);
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        assert_eq!(a.attribute_value("b"), Some("an entity"));
+    }
+
+    #[test]
+    fn the_first_declaration_of_an_internal_entity_is_binding() {
+        let package =
+            quick_parse(r#"<!DOCTYPE a [ <!ENTITY e "first"> <!ENTITY e "second"> ]><a>&e;</a>"#);
+        let doc = package.as_document();
+        let a = top(&doc);
+        let text = a.children()[0].text().unwrap();
+
+        assert_eq!(text.text(), "first");
+    }
+
+    #[test]
+    fn an_internal_subset_with_other_declarations() {
+        let package = quick_parse(
+            r#"<!DOCTYPE a [
+            <!-- a comment -->
+            <?pi in the subset?>
+            <!ELEMENT a (#PCDATA)>
+            <!ATTLIST a b CDATA "x>y">
+            <!ENTITY % param "<!ELEMENT b EMPTY>">
+            %param;
+            <!ENTITY external SYSTEM "external.xml">
+            <!NOTATION n SYSTEM "n">
+            <!ENTITY e "value">
+            ]><a>&e;</a>"#,
+        );
+        let doc = package.as_document();
+        let a = top(&doc);
+        let text = a.children()[0].text().unwrap();
+
+        assert_eq!(text.text(), "value");
+    }
+
+    fn html_entities(name: &str) -> Option<String> {
+        match name {
+            "nbsp" => Some("\u{A0}".to_owned()),
+            "amp" => Some("ampersand".to_owned()),
+            "e" => Some("resolved".to_owned()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn an_entity_supplied_by_the_resolver() {
+        let parser = Parser::new().set_entity_resolver(html_entities);
+        let package = parser.parse("<a>&nbsp;</a>").unwrap();
+        let doc = package.as_document();
+        let a = top(&doc);
+        let text = a.children()[0].text().unwrap();
+
+        assert_eq!(text.text(), "\u{A0}");
+    }
+
+    fn entity_table() -> HashMap<String, String> {
+        let mut entities = HashMap::new();
+        entities.insert("nbsp".to_owned(), "\u{A0}".to_owned());
+        entities.insert("copy".to_owned(), "\u{A9}".to_owned());
+        entities.insert("amp".to_owned(), "ampersand".to_owned());
+        entities
+    }
+
+    #[test]
+    fn entities_from_a_table() {
+        let parser = Parser::new().set_entities(entity_table());
+        let package = parser.parse("<a b='&copy;&amp;'>&nbsp;&amp;</a>").unwrap();
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        assert_eq!(a.attribute_value("b"), Some("\u{A9}&"));
+        assert_eq!(a.string_value(), "\u{A0}&");
+    }
+
+    #[test]
+    fn an_entity_table_is_consulted_before_the_resolver() {
+        let parser = Parser::new()
+            .set_entities(Arc::new(entity_table()))
+            .set_entity_resolver(html_entities);
+        let package = parser
+            .parse(r#"<!DOCTYPE a [ <!ENTITY copy "declared"> ]><a b="&copy;&nbsp;&e;"/>"#)
+            .unwrap();
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        assert_eq!(a.attribute_value("b"), Some("declared\u{A0}resolved"));
+    }
+
+    #[test]
+    fn predefined_and_declared_entities_take_precedence_over_the_resolver() {
+        let parser = Parser::new().set_entity_resolver(html_entities);
+        let package = parser
+            .parse(r#"<!DOCTYPE a [ <!ENTITY e "declared"> ]><a b="&amp;&e;&nbsp;"/>"#)
+            .unwrap();
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        assert_eq!(a.attribute_value("b"), Some("&declared\u{A0}"));
+    }
+
+    fn utf16_le(xml: &str) -> Vec<u8> {
+        let mut bytes = vec![0xFF, 0xFE];
+        bytes.extend(
+            xml.encode_utf16()
+                .flat_map(|u| vec![u as u8, (u >> 8) as u8]),
+        );
+        bytes
+    }
+
+    #[test]
+    fn bytes_in_utf16_le_with_a_byte_order_mark() {
+        let xml = utf16_le("<?xml version='1.0' encoding='UTF-16'?><a>h\u{E9}llo \u{1F600}</a>");
+        let package = Parser::new().parse_bytes(&xml).unwrap();
+        let doc = package.as_document();
+        let a = top(&doc);
+        let text = a.children()[0].text().unwrap();
+
+        assert_eq!(text.text(), "h\u{E9}llo \u{1F600}");
+        assert_eq!(doc.encoding(), Some("UTF-16"));
+    }
+
+    #[test]
+    fn bytes_in_utf16_be_with_a_byte_order_mark() {
+        let mut xml = vec![0xFE, 0xFF];
+        xml.extend(
+            "<a>b</a>"
+                .encode_utf16()
+                .flat_map(|u| vec![(u >> 8) as u8, u as u8]),
+        );
+        let package = Parser::new().parse_bytes(&xml).unwrap();
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        assert_qname_eq!(a.name(), "a");
+    }
+
+    #[test]
+    fn bytes_declaring_a_different_encoding_warn_about_the_mismatch() {
+        let xml = utf16_le("<?xml version='1.0' encoding='UTF-8'?><a>\u{E9}</a>");
+        let (package, warnings) = Parser::new().parse_bytes_with_warnings(&xml).unwrap();
+        let doc = package.as_document();
+
+        assert_eq!(top(&doc).string_value(), "\u{E9}");
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].location(), 30);
+        assert_eq!(
+            warnings[0].kind(),
+            WarningKind::EncodingMismatch {
+                declared: Encoding::Utf8,
+                detected: Encoding::Utf16Le,
+            }
+        );
+        assert_eq!(
+            warnings[0].to_string(),
+            "XML parsing warning at line 1, column 31: \
+             declared encoding UTF-8 does not match detected encoding UTF-16LE"
+        );
+    }
+
+    #[test]
+    fn bytes_declaring_the_detected_encoding_do_not_warn() {
+        let xml = utf16_le("<?xml version='1.0' encoding='utf-16'?><a/>");
+        let (_, warnings) = Parser::new().parse_bytes_with_warnings(&xml).unwrap();
+
+        assert_eq!(warnings, []);
+    }
+
+    #[test]
+    fn utf8_bytes_are_parsed_with_and_without_validation() {
+        let xml = b"<?xml version='1.0' encoding='UTF-8'?><a>h\xC3\xA9llo</a>";
+
+        let checked = Parser::new().parse_utf8(xml).unwrap();
+        let unchecked = unsafe { Parser::new().parse_utf8_unchecked(xml) }.unwrap();
+
+        for package in &[checked, unchecked] {
+            let doc = package.as_document();
+            let a = top(&doc);
+
+            assert_eq!(a.string_value(), "h\u{E9}llo");
+        }
+    }
+
+    #[test]
+    fn bytes_in_utf8_with_and_without_a_byte_order_mark() {
+        for xml in &[&b"\xEF\xBB\xBF<a>\xC3\xA9</a>"[..], &b"<a>\xC3\xA9</a>"[..]] {
+            let package = Parser::new().parse_bytes(xml).unwrap();
+            let doc = package.as_document();
+            let a = top(&doc);
+            let text = a.children()[0].text().unwrap();
+
+            assert_eq!(text.text(), "\u{E9}");
+        }
+    }
+
+    fn parse_ignoring_whitespace(xml: &str) -> Package {
+        Parser::new()
+            .set_ignore_whitespace_between_elements(true)
+            .parse(xml)
+            .expect("Failed to parse the XML string")
+    }
+
+    #[test]
+    fn whitespace_between_elements_is_kept_by_default() {
+        let package = quick_parse("<a>\n  <b/>\n</a>");
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        assert_eq!(a.children().len(), 3);
+    }
+
+    #[test]
+    fn whitespace_between_elements_can_be_ignored() {
+        let package = parse_ignoring_whitespace("<a>\n  <b>\n    <c/>\n  </b>\n</a>");
+        let doc = package.as_document();
+        let a = top(&doc);
+        let b = a.children()[0].element().unwrap();
+
+        assert_eq!(a.children().len(), 1);
+        assert_eq!(b.children().len(), 1);
+        assert!(b.children()[0].element().is_some());
+    }
+
+    #[test]
+    fn whitespace_in_mixed_content_is_kept_when_ignoring_whitespace() {
+        let package = parse_ignoring_whitespace("<a> x <b/> </a>");
+        let doc = package.as_document();
+        let a = top(&doc);
+        let children = a.children();
+
+        assert_eq!(children.len(), 3);
+        assert_eq!(children[0].text().unwrap().text(), " x ");
+        assert_eq!(children[2].text().unwrap().text(), " ");
+    }
+
+    #[test]
+    fn whitespace_only_content_is_kept_when_ignoring_whitespace() {
+        let package = parse_ignoring_whitespace("<a> <b>  </b> <![CDATA[ ]]><c/></a>");
+        let doc = package.as_document();
+        let a = top(&doc);
+        let b = a.children()[1].element().unwrap();
+
+        assert_eq!(a.children().len(), 5);
+        assert_eq!(b.children()[0].text().unwrap().text(), "  ");
+    }
+
+    #[test]
+    fn whitespace_between_elements_can_be_marked() {
+        let xml = "<a>\n  <b> x <c/> </b>\n  <d>  </d>\n</a>";
+        let package = Parser::new()
+            .set_mark_whitespace_between_elements(true)
+            .parse(xml)
+            .unwrap();
+        let doc = package.as_document();
+        let a = top(&doc);
+        let marked: Vec<_> = doc
+            .descendants()
+            .filter_map(|c| c.text())
+            .map(|t| (t.text(), t.is_ignorable_whitespace()))
+            .collect();
+
+        assert_eq!(a.children().len(), 5);
+        assert_eq!(
+            marked,
+            [
+                ("\n  ", true),
+                (" x ", false),
+                (" ", false),
+                ("\n  ", true),
+                ("  ", false),
+                ("\n", true),
+            ]
+        );
+        assert_eq!(doc.to_string(), format!("<?xml version='1.0'?>{}", xml));
+    }
+
+    #[test]
+    fn whitespace_is_not_marked_by_default() {
+        let package = quick_parse("<a>\n  <b/>\n</a>");
+        let doc = package.as_document();
+
+        assert!(top(&doc)
+            .children()
+            .iter()
+            .filter_map(|c| c.text())
+            .all(|t| !t.is_ignorable_whitespace()));
+    }
+
+    #[test]
+    fn whitespace_is_kept_in_elements_preserving_space() {
+        let package = parse_ignoring_whitespace(
+            "<a>\n <b xml:space='preserve'>\n <c>\n <d/>\n </c>\n</b>\n <e>\n <f/>\n</e>\n</a>",
+        );
+        let doc = package.as_document();
+        let a = top(&doc);
+        let b = a.children()[0].element().unwrap();
+        let c = b.children()[1].element().unwrap();
+        let e = a.children()[1].element().unwrap();
+
+        assert_eq!(a.children().len(), 2);
+        assert_eq!(b.children().len(), 3);
+        assert_eq!(c.children().len(), 3);
+        assert_eq!(e.children().len(), 1);
+    }
+
+    #[test]
+    fn preserving_space_can_be_overridden_by_a_descendant() {
+        let package = parse_ignoring_whitespace(
+            "<a xml:space='preserve'>\n <b xml:space='default'>\n <c/>\n</b>\n</a>",
+        );
+        let doc = package.as_document();
+        let a = top(&doc);
+        let b = a.children()[1].element().unwrap();
+
+        assert_eq!(a.children().len(), 3);
+        assert_eq!(b.children().len(), 1);
+    }
+
+    #[test]
+    fn whitespace_is_kept_in_fragments_preserving_space() {
+        let package = Package::new();
+        let nodes = Parser::new()
+            .set_ignore_whitespace_between_elements(true)
+            .parse_fragment(
+                package.as_document(),
+                "<a>\n<b/>\n</a>\n<a xml:space='preserve'>\n<b/>\n</a>",
+            )
+            .unwrap();
+
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].element().unwrap().children().len(), 1);
+        assert_eq!(nodes[1].element().unwrap().children().len(), 3);
+    }
+
+    #[test]
+    fn repeated_text_is_stored_once() {
+        let xml = format!("<a>{}</a>", "<b>\n  repeated\n</b>".repeat(1000));
+        let package = quick_parse(&xml);
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        let texts: Vec<_> = a
+            .children()
+            .into_iter()
+            .map(|b| b.element().unwrap().children()[0].text().unwrap().text())
+            .collect();
+
+        assert_eq!(texts.len(), 1000);
+        assert!(texts.iter().all(|t| *t == "\n  repeated\n"));
+        assert!(texts.iter().all(|t| t.as_ptr() == texts[0].as_ptr()));
+
+        // 1000 text nodes, but no more strings stored than for one
+        let once = quick_parse("<a><b>\n  repeated\n</b></a>");
+        assert_eq!(package.node_counts().texts, 1000);
+        assert_eq!(package.storage.string_bytes(), once.storage.string_bytes());
+    }
+
+    #[test]
+    fn multi_megabyte_text_is_a_single_node() {
+        let text = "Some text, with no markup.\n".repeat(256 * 1024);
+        let xml = format!("<a>{}</a>", text);
+        let package = quick_parse(&xml);
+        let doc = package.as_document();
+        let children = top(&doc).children();
+
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].text().unwrap().text(), text);
+    }
+
+    #[test]
+    fn multi_megabyte_attribute_value() {
+        let value = "a value\twith\r\nwhitespace ".repeat(128 * 1024);
+        let xml = format!("<a b='{}'/>", value);
+        let package = quick_parse(&xml);
+        let doc = package.as_document();
+
+        let expected = "a value with whitespace ".repeat(128 * 1024);
+        assert_eq!(top(&doc).attribute_value("b"), Some(&*expected));
+    }
+
+    fn nested(depth: usize) -> String {
+        "<a>".repeat(depth) + &"</a>".repeat(depth)
+    }
+
+    #[test]
+    fn elements_nested_to_the_maximum_depth() {
+        let package = Parser::new().set_max_depth(Some(3)).parse(&nested(3));
+
+        assert!(package.is_ok());
+    }
+
+    #[test]
+    fn deeply_nested_elements_without_a_maximum_depth() {
+        let depth = DEFAULT_MAX_DEPTH * 2;
+        let package = Parser::new()
+            .set_max_depth(None)
+            .parse(&nested(depth))
+            .unwrap();
+        let doc = package.as_document();
+
+        assert_eq!(doc.descendant_elements().count(), depth);
+    }
+
+    #[test]
+    fn entity_expansion_up_to_the_limit() {
+        let xml = r#"<!DOCTYPE a [<!ENTITY e "12345">]><a>&e;&e;&lt;&#65;</a>"#;
+        let parser = Parser::new().set_max_entity_expansion(Some(10));
+
+        assert!(parser.parse(xml).is_ok());
+    }
+
+    fn parse_warnings(xml: &str) -> Vec<(usize, WarningKind)> {
+        let (_, warnings) = Parser::new()
+            .parse_with_warnings(xml)
+            .expect("Failed to parse");
+        warnings.iter().map(|w| (w.location(), w.kind())).collect()
+    }
+
+    #[test]
+    fn an_ordinary_document_has_no_warnings() {
+        let warnings = parse_warnings("<a xmlns:x='urn:x'><b xmlns:x='urn:y'><?pi?></b></a>");
+        assert_eq!(warnings, vec![]);
+    }
+
+    #[test]
+    fn warning_for_processing_instruction_target_starting_with_xml() {
+        let (package, warnings) = Parser::new()
+            .parse_with_warnings("<hello/>\n<?xml-stylesheet href='a.xsl'?>")
+            .expect("Failed to parse");
+        let doc = package.as_document();
+
+        assert_eq!(doc.root().children().len(), 2);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            warnings[0].kind(),
+            WarningKind::ReservedProcessingInstructionTarget
+        );
+        assert_eq!(warnings[0].location(), 11);
+        assert_eq!(warnings[0].line(), 2);
+        assert_eq!(warnings[0].column(), 3);
+    }
+
+    #[test]
+    fn no_warning_for_processing_instruction_target_with_multibyte_characters() {
+        let warnings = parse_warnings("<?a\u{65E5}?><a/>");
+        assert_eq!(warnings, vec![]);
+    }
+
+    #[test]
+    fn warning_for_redeclared_namespace_prefix() {
+        let warnings = parse_warnings("<a xmlns:x='urn:x'><b xmlns:x='urn:x'/></a>");
+        assert_eq!(
+            warnings,
+            vec![(22, WarningKind::RedundantNamespaceDeclaration)]
+        );
+    }
+
+    #[test]
+    fn warning_for_redeclared_default_namespace() {
+        let warnings = parse_warnings("<a xmlns='urn:x'><b xmlns='urn:x'/></a>");
+        assert_eq!(
+            warnings,
+            vec![(20, WarningKind::RedundantNamespaceDeclaration)]
+        );
+    }
+
+    #[test]
+    fn warning_for_resetting_an_unset_default_namespace() {
+        let warnings = parse_warnings("<a xmlns=''/>");
+        assert_eq!(
+            warnings,
+            vec![(3, WarningKind::RedundantNamespaceDeclaration)]
+        );
+    }
+
+    fn parse_with_spans(xml: &str) -> Package {
+        Parser::new()
+            .set_track_source_spans(true)
+            .parse(xml)
+            .expect("Failed to parse")
+    }
+
+    #[test]
+    fn source_spans_are_not_tracked_by_default() {
+        let package = quick_parse("<a>text<!--c--></a>");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_eq!(top.source_span(), None);
+        assert_eq!(top.children()[0].text().unwrap().source_span(), None);
+    }
+
+    #[test]
+    fn source_span_of_a_nested_element() {
+        let xml = "<a>\n  <b x='1'><c/>text</b >\n</a>";
+        let package = parse_with_spans(xml);
+        let doc = package.as_document();
+        let top = top(&doc);
+        let b = top.children()[1].element().unwrap();
+        let c = b.children()[0].element().unwrap();
+
+        assert_eq!(&xml[top.source_span().unwrap()], xml);
+        assert_eq!(&xml[b.source_span().unwrap()], "<b x='1'><c/>text</b >");
+        assert_eq!(&xml[c.source_span().unwrap()], "<c/>");
+    }
+
+    #[test]
+    fn source_spans_of_text() {
+        let xml = "<a>one&amp;<![CDATA[two]]></a>";
+        let package = parse_with_spans(xml);
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        let spans: Vec<_> = top
+            .children()
+            .iter()
+            .map(|c| &xml[c.text().unwrap().source_span().unwrap()])
+            .collect();
+        assert_eq!(spans, ["one", "&amp;", "<![CDATA[two]]>"]);
+    }
+
+    #[test]
+    fn source_spans_of_comments_and_processing_instructions() {
+        let xml = "<!--before--><a><?pi value?></a>";
+        let package = parse_with_spans(xml);
+        let doc = package.as_document();
+        let children = doc.root().children();
+        let comment = children[0].comment().unwrap();
+        let top = children[1].element().unwrap();
+        let pi = top.children()[0].processing_instruction().unwrap();
+
+        assert_eq!(&xml[comment.source_span().unwrap()], "<!--before-->");
+        assert_eq!(&xml[pi.source_span().unwrap()], "<?pi value?>");
+    }
+
+    #[test]
+    fn source_spans_of_elements_closed_by_lenient_parsing() {
+        let xml = "<a><b><c>text</a>";
+        let (package, _) = Parser::new()
+            .set_track_source_spans(true)
+            .parse_lenient(xml);
+        let doc = package.as_document();
+        let top = top(&doc);
+        let b = top.children()[0].element().unwrap();
+
+        assert_eq!(&xml[top.source_span().unwrap()], xml);
+        assert_eq!(&xml[b.source_span().unwrap()], "<b><c>text");
+    }
+
+    #[test]
+    fn self_closing_elements_are_recorded() {
+        let package = quick_parse("<a><b/><c></c><d>x</d></a>");
+        let doc = package.as_document();
+        let a = top(&doc);
+        let children: Vec<_> = a
+            .children()
+            .into_iter()
+            .map(|c| c.element().unwrap().was_self_closing())
+            .collect();
+
+        assert_eq!(a.was_self_closing(), Some(false));
+        assert_eq!(children, [Some(true), Some(false), Some(false)]);
+    }
+
+    #[test]
+    fn created_elements_were_not_parsed_as_self_closing_or_not() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        assert_eq!(doc.create_element("a").was_self_closing(), None);
+    }
+
+    #[test]
+    fn fragment_with_several_top_level_elements() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let nodes = Parser::new().parse_fragment(doc, "<a/><b>x</b>").unwrap();
+
+        assert_eq!(nodes.len(), 2);
+        assert_qname_eq!(nodes[0].element().unwrap().name(), "a");
+        assert_qname_eq!(nodes[1].element().unwrap().name(), "b");
+        assert_eq!(nodes[1].element().unwrap().string_value(), "x");
+    }
+
+    #[test]
+    fn fragment_with_text_around_elements() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let nodes = Parser::new()
+            .parse_fragment(doc, "text <a/> more &amp;<!--c--><?pi?>")
+            .unwrap();
+
+        assert_eq!(nodes.len(), 6);
+        assert_eq!(nodes[0].text().unwrap().text(), "text ");
+        assert_qname_eq!(nodes[1].element().unwrap().name(), "a");
+        assert_eq!(nodes[2].text().unwrap().text(), " more ");
+        assert_eq!(nodes[3].text().unwrap().text(), "&");
+        assert_eq!(nodes[4].comment().unwrap().text(), "c");
+        assert_eq!(nodes[5].processing_instruction().unwrap().target(), "pi");
+    }
+
+    #[test]
+    fn fragment_nodes_are_detached() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let nodes = Parser::new().parse_fragment(doc, "<a/>b").unwrap();
+
+        assert!(doc.root().children().is_empty());
+        assert_eq!(nodes[0].element().unwrap().parent(), None);
+        assert_eq!(nodes[1].text().unwrap().parent(), None);
+    }
+
+    #[test]
+    fn empty_fragment() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let nodes = Parser::new().parse_fragment(doc, "").unwrap();
+
+        assert!(nodes.is_empty());
+    }
+
+    #[test]
+    fn many_documents_with_declarations() {
+        let xml =
+            "<?xml version='1.0'?>\n<a>1</a>\n<?xml version='1.0' encoding='UTF-8'?>\n<b>2</b>\n";
+        let packages = Parser::new().parse_many(xml).unwrap();
+
+        assert_eq!(packages.len(), 2);
+        let first = packages[0].as_document();
+        let second = packages[1].as_document();
+        assert_qname_eq!(top(&first).name(), "a");
+        assert_eq!(first.encoding(), None);
+        assert_qname_eq!(top(&second).name(), "b");
+        assert_eq!(second.encoding(), Some("UTF-8"));
+    }
+
+    #[test]
+    fn many_documents_keep_trailing_comments_with_the_previous_document() {
+        let xml = "<a/><!--after a--><?pi?> <b/>";
+        let packages = Parser::new().parse_many(xml).unwrap();
+
+        assert_eq!(packages.len(), 2);
+        let first = packages[0].as_document();
+        let second = packages[1].as_document();
+        assert_eq!(first.root().children().len(), 3);
+        assert_eq!(second.root().children().len(), 1);
+        assert_qname_eq!(top(&second).name(), "b");
+    }
+
+    #[test]
+    fn many_documents_failure_reports_the_document_and_offset() {
+        let xml = "<a/>\n<b><c></b>";
+        let error = Parser::new().parse_many(xml).unwrap_err();
+
+        assert_eq!(error.index(), 1);
+        assert_eq!(error.error().location(), 13);
+        assert_eq!(error.error().line(), 2);
+        assert_eq!(
+            error.to_string(),
+            "in document 1: XML parsing error at line 2, column 9: mismatched element end name"
+        );
+    }
+
+    #[test]
+    fn many_documents_failure_document_without_an_element() {
+        let xml = "<?xml version='1.0'?><!--c--><?xml version='1.0'?><b/>";
+        let error = Parser::new().parse_many(xml).unwrap_err();
+
+        assert_eq!(error.index(), 0);
+        assert_eq!(error.error().location(), 31);
+    }
+
+    #[test]
+    fn fragment_appended_to_an_element() {
+        let package = quick_parse("<a><c/></a>");
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        a.append_fragment_str("<b/>text").unwrap();
+
+        let children = a.children();
+        assert_eq!(children.len(), 3);
+        assert_qname_eq!(children[0].element().unwrap().name(), "c");
+        assert_qname_eq!(children[1].element().unwrap().name(), "b");
+        assert_eq!(
+            children[1].element().unwrap().parent(),
+            Some(dom::ParentOfChild::Element(a))
+        );
+        assert_eq!(children[2].text().unwrap().text(), "text");
+    }
+
+    #[test]
+    fn fragment_appended_to_an_element_uses_its_namespaces() {
+        let package = quick_parse("<a xmlns='default' xmlns:x='ex'><b/></a>");
+        let doc = package.as_document();
+        let b = top(&doc).children()[0].element().unwrap();
+
+        b.append_fragment_str("<x:c/><d x:attr='1'/>").unwrap();
+
+        let c = b.children()[0].element().unwrap();
+        let d = b.children()[1].element().unwrap();
+        assert_qname_eq!(c.name(), ("ex", "c"));
+        assert_eq!(c.preferred_prefix(), Some("x"));
+        assert_qname_eq!(d.name(), ("default", "d"));
+        assert_eq!(d.attribute_value(("ex", "attr")), Some("1"));
+    }
+
+    #[test]
+    fn malformed_fragment_appends_nothing() {
+        let package = quick_parse("<a/>");
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        let r = a.append_fragment_str("<b/><c>");
+
+        assert_eq!(r.unwrap_err().location(), 7);
+        assert!(a.children().is_empty());
+    }
+
+    #[test]
+    fn fragment_appended_with_a_configured_parser() {
+        let package = quick_parse("<a/>");
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        let mut entities = HashMap::new();
+        entities.insert("nbsp".to_owned(), "\u{A0}".to_owned());
+        let parser = Parser::new().set_entities(entities);
+
+        parser.append_fragment(a, "<b>&nbsp;</b>").unwrap();
+        assert_eq!(a.string_value(), "\u{A0}");
+
+        let r = a.append_fragment_str("<b>&nbsp;</b>");
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn partial_parsing_keeps_the_nodes_built_before_the_error() {
+        use super::SpecificError::*;
+
+        let (package, error) = Parser::new().parse_partial("<a><b/>");
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        assert_qname_eq!(a.name(), "a");
+        assert_eq!(a.children().len(), 1);
+        assert_qname_eq!(a.children()[0].element().unwrap().name(), "b");
+        assert_eq!(
+            lenient_errors(&[error.unwrap()]),
+            vec![(7, vec![UnclosedElement])]
+        );
+    }
+
+    #[test]
+    fn partial_parsing_stops_at_a_syntax_error() {
+        let (package, error) = Parser::new().parse_partial("<a>one<b>two</c>three</a>");
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        assert_eq!(a.string_value(), "onetwo");
+        assert_eq!(error.unwrap().location(), 14);
+    }
+
+    #[test]
+    fn partial_parsing_without_an_error() {
+        let (package, error) = Parser::new().parse_partial("<a/>");
+        let doc = package.as_document();
+
+        assert_qname_eq!(top(&doc).name(), "a");
+        assert!(error.is_none());
+    }
+
+    fn lenient_errors(errors: &[Error]) -> Vec<(usize, Vec<SpecificError>)> {
+        errors
+            .iter()
+            .map(|e| (e.location(), e.errors.iter().cloned().collect()))
+            .collect()
+    }
+
+    #[test]
+    fn lenient_parsing_treats_a_stray_ampersand_as_text() {
+        use super::SpecificError::*;
+
+        let (package, errors) = Parser::new().parse_lenient("<a b='x & y'>fish & chips</a>");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_eq!(top.attribute_value("b"), Some("x & y"));
+        assert_eq!(top.string_value(), "fish & chips");
+        assert_eq!(
+            lenient_errors(&errors),
+            vec![
+                (8, vec![ExpectedNamedReferenceValue]),
+                (18, vec![ExpectedNamedReferenceValue]),
+            ]
+        );
+    }
+
+    #[test]
+    fn lenient_parsing_closes_inner_elements_at_their_parent_end() {
+        use super::SpecificError::*;
+
+        let (package, errors) = Parser::new().parse_lenient("<a><b><c>text</a><!--after-->");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        let b = top.children()[0].element().unwrap();
+        let c = b.children()[0].element().unwrap();
+        assert_qname_eq!(c.name(), "c");
+        assert_eq!(c.string_value(), "text");
+        assert_eq!(doc.root().children().len(), 2);
+        assert_eq!(
+            lenient_errors(&errors),
+            vec![(15, vec![MismatchedElementEndName])]
+        );
+    }
+
+    #[test]
+    fn lenient_parsing_ignores_an_unmatched_end_tag() {
+        use super::SpecificError::*;
+
+        let (package, errors) = Parser::new().parse_lenient("<a>one</b>two</a>");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_eq!(top.string_value(), "onetwo");
+        assert_eq!(
+            lenient_errors(&errors),
+            vec![(8, vec![MismatchedElementEndName])]
+        );
+    }
+
+    #[test]
+    fn lenient_parsing_reports_elements_unclosed_at_the_end() {
+        use super::SpecificError::*;
+
+        let (package, errors) = Parser::new().parse_lenient("<a><b>text");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_eq!(top.string_value(), "text");
+        assert_eq!(lenient_errors(&errors), vec![(10, vec![UnclosedElement])]);
+    }
+
+    #[test]
+    fn lenient_parsing_stops_at_an_unrecoverable_error() {
+        use super::SpecificError::*;
+
+        let (package, errors) = Parser::new().parse_lenient("<a><b x='1' x='2'/><c/></a>");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert!(top.children().is_empty());
+        assert_eq!(
+            lenient_errors(&errors),
+            vec![(12, vec![DuplicateAttribute])]
+        );
+    }
+
+    #[test]
+    fn lenient_parsing_of_a_valid_document_has_no_errors() {
+        let (package, errors) = Parser::new().parse_lenient("<a>&amp;<b/></a>");
+        let doc = package.as_document();
+
+        assert_eq!(top(&doc).string_value(), "&");
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn a_document_with_a_single_element() {
+        let package = quick_parse("<hello />");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_qname_eq!(top.name(), "hello");
+    }
+
+    #[test]
+    fn an_element_with_a_namespace() {
+        let package = quick_parse("<ns:hello xmlns:ns='namespace'/>");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_eq!(top.preferred_prefix(), Some("ns"));
+        assert_qname_eq!(("namespace", "hello"), top.name());
+    }
+
+    #[test]
+    fn an_element_with_a_default_namespace() {
+        let package = quick_parse("<hello xmlns='namespace'/>");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_eq!(top.default_namespace_uri(), Some("namespace"));
+        assert_qname_eq!(("namespace", "hello"), top.name());
+    }
+
+    #[test]
+    fn an_element_with_a_non_ascii_name() {
+        let package = quick_parse("<\u{00E9}t\u{00E9}/>");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_qname_eq!(top.name(), "\u{00E9}t\u{00E9}");
+    }
+
+    #[test]
+    fn an_element_with_an_attribute() {
+        let package = quick_parse("<hello scope='world'/>");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_eq!(top.attribute_value("scope"), Some("world"));
+    }
+
+    #[test]
+    fn an_element_with_an_attribute_using_double_quotes() {
+        let package = quick_parse("<hello scope=\"world\"/>");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_eq!(top.attribute_value("scope"), Some("world"));
+    }
+
+    #[test]
+    fn an_attribute_in_single_quotes_may_contain_double_quotes() {
+        let package = quick_parse("<hello x='he said \"hi\"'/>");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_eq!(top.attribute_value("x"), Some("he said \"hi\""));
+    }
+
+    #[test]
+    fn an_attribute_in_double_quotes_may_contain_single_quotes() {
+        let package = quick_parse("<hello x=\"it's\"/>");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_eq!(top.attribute_value("x"), Some("it's"));
+    }
+
+    #[test]
+    fn an_element_with_multiple_attributes() {
+        let package = quick_parse("<hello scope='world' happy='true'/>");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_eq!(top.attribute_value("scope"), Some("world"));
+        assert_eq!(top.attribute_value("happy"), Some("true"));
+    }
+
+    #[test]
+    fn attributes_are_kept_in_document_order() {
+        let package = quick_parse("<a z='1' xmlns:ns='urn:ns' a='2' ns:q='4' m='3'/>");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        let names: Vec<_> = top
+            .attributes()
+            .iter()
+            .map(|a| a.name().local_part())
+            .collect();
+        assert_eq!(names, ["z", "a", "q", "m"]);
+    }
+
+    #[test]
+    fn an_attribute_with_a_namespace() {
+        let package = quick_parse("<hello ns:a='b' xmlns:ns='namespace'/>");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        let attr = top.attribute(("namespace", "a")).unwrap();
+
+        assert_eq!(attr.preferred_prefix(), Some("ns"));
+        assert_eq!(attr.value(), "b");
+    }
+
+    #[test]
+    fn an_attribute_with_a_namespace_declared_before_it() {
+        let package = quick_parse("<hello xmlns:ns='namespace' ns:a='b'/>");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        let attr = top.attribute(("namespace", "a")).unwrap();
+
+        assert_eq!(attr.preferred_prefix(), Some("ns"));
+        assert_eq!(attr.value(), "b");
+    }
+
+    #[test]
+    fn an_attribute_prefix_declared_after_it_overrides_an_ancestor() {
+        let package = quick_parse("<a xmlns:p='outer'><b p:x='1' xmlns:p='inner'/></a>");
+        let doc = package.as_document();
+        let b = top(&doc).children()[0].element().unwrap();
+
+        assert_eq!(b.attribute_value(("inner", "x")), Some("1"));
+        assert_eq!(b.attribute_value(("outer", "x")), None);
+    }
+
+    #[test]
+    fn the_xml_prefix_is_bound_without_a_declaration() {
+        let package = Package::new();
+        let nodes = Parser::new()
+            .parse_fragment(package.as_document(), "<a/><b xml:lang='en'/>")
+            .unwrap();
+        let b = nodes[1].element().unwrap();
+
+        assert_eq!(b.attribute_value((crate::XML_NS_URI, "lang")), Some("en"));
+        assert_eq!(
+            b.namespace_uri_for_prefix("xml"),
+            Some("http://www.w3.org/XML/1998/namespace")
+        );
+    }
+
+    #[test]
+    fn the_xml_prefix_may_be_declared_to_its_own_namespace() {
+        let package =
+            quick_parse("<a xmlns:xml='http://www.w3.org/XML/1998/namespace' xml:lang='en'/>");
+        let doc = package.as_document();
+
+        assert_eq!(
+            top(&doc).attribute_value((crate::XML_NS_URI, "lang")),
+            Some("en")
+        );
+    }
+
+    #[test]
+    fn an_attribute_with_xml_space_preserve() {
+        let package = quick_parse("<hello xml:space='preserve'> <a/> </hello>");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_eq!(
+            top.attribute((crate::XML_NS_URI, "space")).unwrap().value(),
+            "preserve"
+        );
+
+        let children = top.children();
+        assert_eq!(children.len(), 3);
+        assert_eq!(children[0].text().unwrap().text(), " ");
+        assert_qname_eq!(children[1].element().unwrap().name(), "a");
+        assert_eq!(children[2].text().unwrap().text(), " ");
+    }
+
+    #[test]
+    fn an_attribute_with_references() {
+        let package = quick_parse("<log msg='I &lt;3 math' />");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_eq!(top.attribute_value("msg"), Some("I <3 math"));
+    }
+
+    #[test]
+    fn an_element_that_is_not_self_closing() {
+        let package = quick_parse("<hello></hello>");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_qname_eq!(top.name(), "hello");
+    }
+
+    #[test]
+    fn nested_elements() {
+        let package = quick_parse("<hello><world/></hello>");
+        let doc = package.as_document();
+        let hello = top(&doc);
+        let world = hello.children()[0].element().unwrap();
+
+        assert_qname_eq!(world.name(), "world");
+    }
+
+    #[test]
+    fn multiply_nested_elements() {
+        let package = quick_parse("<hello><awesome><world/></awesome></hello>");
+        let doc = package.as_document();
+        let hello = top(&doc);
+        let awesome = hello.children()[0].element().unwrap();
+        let world = awesome.children()[0].element().unwrap();
+
+        assert_qname_eq!(world.name(), "world");
+    }
+
+    #[test]
+    fn nested_elements_with_namespaces() {
+        let package =
+            quick_parse("<ns1:hello xmlns:ns1='outer'><ns2:world xmlns:ns2='inner'/></ns1:hello>");
+        let doc = package.as_document();
+        let hello = top(&doc);
+        let world = hello.children()[0].element().unwrap();
+
+        assert_qname_eq!(world.name(), ("inner", "world"));
+    }
+
+    #[test]
+    fn nested_elements_with_inherited_namespaces() {
+        let package = quick_parse("<ns1:hello xmlns:ns1='outer'><ns1:world/></ns1:hello>");
+        let doc = package.as_document();
+        let hello = top(&doc);
+        let world = hello.children()[0].element().unwrap();
+
+        assert_eq!(world.preferred_prefix(), Some("ns1"));
+        assert_qname_eq!(world.name(), ("outer", "world"));
+    }
+
+    #[test]
+    fn unprefixed_attributes_do_not_inherit_default_namespace() {
+        let package = quick_parse("<a xmlns='urn:x'><b c='d'/></a>");
+        let doc = package.as_document();
+        let a = top(&doc);
+        let b = a.children()[0].element().unwrap();
+
+        assert_qname_eq!(a.name(), ("urn:x", "a"));
+        assert_qname_eq!(b.name(), ("urn:x", "b"));
+        assert_eq!(b.attribute_value("c"), Some("d"));
+        assert_eq!(b.attribute(("urn:x", "c")), None);
+    }
+
+    #[test]
+    fn nested_elements_with_inherited_default_namespace() {
+        let package = quick_parse("<hello xmlns='outer'><world/></hello>");
+        let doc = package.as_document();
+        let hello = top(&doc);
+        let world = hello.children()[0].element().unwrap();
+
+        assert_qname_eq!(world.name(), ("outer", "world"));
+    }
+
+    #[test]
+    fn nested_elements_with_reset_default_namespace() {
+        let package = quick_parse("<hello xmlns='outer'><world xmlns=''/></hello>");
+        let doc = package.as_document();
+        let hello = top(&doc);
+        let world = hello.children()[0].element().unwrap();
+
+        assert_qname_eq!(world.name(), "world");
+    }
+
+    #[test]
+    fn nested_elements_with_attributes() {
+        let package = quick_parse("<hello><world name='Earth'/></hello>");
+        let doc = package.as_document();
+        let hello = top(&doc);
+        let world = hello.children()[0].element().unwrap();
+
+        assert_eq!(world.attribute_value("name"), Some("Earth"));
+    }
+
+    #[test]
+    fn nested_elements_with_attributes_with_inherited_namespaces() {
+        let package = quick_parse("<hello xmlns:ns1='outer'><world ns1:name='Earth'/></hello>");
+        let doc = package.as_document();
+        let hello = top(&doc);
+        let world = hello.children()[0].element().unwrap();
+
+        let attr = world.attribute(("outer", "name")).unwrap();
+
+        assert_eq!(attr.preferred_prefix(), Some("ns1"));
+        assert_eq!(attr.value(), "Earth");
+    }
+
+    #[test]
+    fn element_with_text() {
+        let package = quick_parse("<hello>world</hello>");
+        let doc = package.as_document();
+        let hello = top(&doc);
+        let text = hello.children()[0].text().unwrap();
+
+        assert_eq!(text.text(), "world");
+    }
+
+    #[test]
+    fn element_with_cdata() {
+        let package = quick_parse("<words><![CDATA[I have & and < !]]></words>");
+        let doc = package.as_document();
+        let words = top(&doc);
+        let text = words.children()[0].text().unwrap();
+
+        assert_eq!(text.text(), "I have & and < !");
+        assert!(text.is_cdata());
+    }
+
+    #[test]
+    fn element_with_crlf_line_endings() {
+        let package = quick_parse("<hello>one\r\ntwo\r\n</hello>");
+        let doc = package.as_document();
+        let text = top(&doc).children()[0].text().unwrap();
+
+        assert_eq!(text.text(), "one\ntwo\n");
+    }
+
+    #[test]
+    fn element_with_lone_cr_line_endings() {
+        let package = quick_parse("<hello>one\rtwo\r\r\nthree</hello>");
+        let doc = package.as_document();
+        let text = top(&doc).children()[0].text().unwrap();
+
+        assert_eq!(text.text(), "one\ntwo\n\nthree");
+    }
+
+    #[test]
+    fn line_endings_are_normalized_in_cdata_comments_and_processing_instructions() {
+        let package = quick_parse("<a><![CDATA[x\r\ny]]><!--c\rd--><?pi e\r\nf?></a>");
+        let doc = package.as_document();
+        let children = top(&doc).children();
+
+        assert_eq!(children[0].text().unwrap().text(), "x\ny");
+        assert_eq!(children[1].comment().unwrap().text(), "c\nd");
+        assert_eq!(
+            children[2].processing_instruction().unwrap().value(),
+            Some("e\nf")
+        );
+    }
+
+    #[test]
+    fn attribute_with_crlf_line_endings() {
+        let package = quick_parse("<a b='one\r\ntwo\rthree'/>");
+        let doc = package.as_document();
+
+        assert_eq!(top(&doc).attribute_value("b"), Some("one two three"));
+    }
+
+    #[test]
+    fn attribute_with_literal_and_referenced_whitespace() {
+        let package = quick_parse("<a b='1\t2&#9;3\n4&#10;5&#13;6'/>");
+        let doc = package.as_document();
+
+        assert_eq!(top(&doc).attribute_value("b"), Some("1 2\t3 4\n5\r6"));
+    }
+
+    #[test]
+    fn attribute_with_whitespace_from_an_entity() {
+        let package = quick_parse("<!DOCTYPE a [<!ENTITY t '\t&#9;x'>]><a b='&t;'/>");
+        let doc = package.as_document();
+        let a = doc.root().children()[0].element().unwrap();
+
+        assert_eq!(a.attribute_value("b"), Some("  x"));
+    }
+
+    #[test]
+    fn attribute_runs_of_spaces_are_not_collapsed() {
+        let package = quick_parse("<a b='  1 \t 2  '/>");
+        let doc = package.as_document();
+
+        assert_eq!(top(&doc).attribute_value("b"), Some("  1   2  "));
+    }
+
+    #[test]
+    fn element_with_text_and_cdata() {
+        let package = quick_parse("<words>plain<![CDATA[raw]]></words>");
+        let doc = package.as_document();
+        let words = top(&doc);
+        let plain = words.children()[0].text().unwrap();
+        let raw = words.children()[1].text().unwrap();
+
+        assert!(!plain.is_cdata());
+        assert!(raw.is_cdata());
+    }
+
+    #[test]
+    fn element_with_comment() {
+        let package = quick_parse("<hello><!-- A comment --></hello>");
+        let doc = package.as_document();
+        let words = top(&doc);
+        let comment = words.children()[0].comment().unwrap();
+
+        assert_eq!(comment.text(), " A comment ");
+    }
+
+    #[test]
+    fn comment_before_top_element() {
+        let package = quick_parse("<!-- A comment --><hello />");
+        let doc = package.as_document();
+        let comment = doc.root().children()[0].comment().unwrap();
+
+        assert_eq!(comment.text(), " A comment ");
+    }
+
+    #[test]
+    fn multiple_comments_before_top_element() {
+        let xml = r"
+<!--Comment 1-->
+<!--Comment 2-->
+<hello />";
+        let package = quick_parse(xml);
+        let doc = package.as_document();
+        let comment1 = doc.root().children()[0].comment().unwrap();
+        let comment2 = doc.root().children()[1].comment().unwrap();
+
+        assert_eq!(comment1.text(), "Comment 1");
+        assert_eq!(comment2.text(), "Comment 2");
+    }
+
+    #[test]
+    fn misc_content_after_top_element() {
+        let package = quick_parse("<a/>\n<!--done--> <?pi value?>\n");
+        let doc = package.as_document();
+        let children = doc.root().children();
+
+        assert_eq!(children.len(), 3);
+        assert_eq!(children[1].comment().unwrap().text(), "done");
+        assert_eq!(children[2].processing_instruction().unwrap().target(), "pi");
+    }
+
+    #[test]
+    fn multiple_comments_after_top_element() {
+        let xml = r"
+<hello />
+<!--Comment 1-->
+<!--Comment 2-->";
+        let package = quick_parse(xml);
+        let doc = package.as_document();
+        let comment1 = doc.root().children()[1].comment().unwrap();
+        let comment2 = doc.root().children()[2].comment().unwrap();
+
+        assert_eq!(comment1.text(), "Comment 1");
+        assert_eq!(comment2.text(), "Comment 2");
+    }
+
+    #[test]
+    fn namespace_declarations_are_registered_in_order() {
+        let package = quick_parse("<a xmlns:z='urn:z' xmlns:b='urn:b' xmlns:m='urn:z'/>");
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        let prefixes: Vec<_> = a
+            .prefixes_declared_here()
+            .iter()
+            .map(|ns| ns.prefix())
+            .collect();
+        assert_eq!(prefixes, vec!["z", "b", "m", "xml"]);
+        assert_eq!(a.prefix_for_namespace_uri("urn:z", None), Some("z"));
+        assert_eq!(a.prefix_for_namespace_uri("urn:z", Some("m")), Some("m"));
+    }
+
+    #[test]
+    fn element_with_processing_instruction() {
+        let package = quick_parse("<hello><?device?></hello>");
+        let doc = package.as_document();
+        let hello = top(&doc);
+        let pi = hello.children()[0].processing_instruction().unwrap();
+
+        assert_eq!(pi.target(), "device");
+        assert_eq!(pi.value(), None);
+    }
+
+    #[test]
+    fn processing_instruction_target_starting_with_xml() {
+        let package = quick_parse("<?xml-stylesheet href='a.xsl'?><hello/>");
+        let doc = package.as_document();
+        let pi = doc.root().children()[0].processing_instruction().unwrap();
+
+        assert_eq!(pi.target(), "xml-stylesheet");
+        assert_eq!(pi.value(), Some("href='a.xsl'"));
+    }
+
+    #[test]
+    fn processing_instruction_value_keeps_whitespace_after_the_separator() {
+        let package = quick_parse("<hello><?t  a b  ?><?u\n\tc?><?v ?></hello>");
+        let doc = package.as_document();
+        let hello = top(&doc);
+        let values: Vec<_> = hello
+            .children()
+            .iter()
+            .map(|c| c.processing_instruction().unwrap().value())
+            .collect();
+
+        assert_eq!(values, vec![Some(" a b  "), Some("\tc"), Some("")]);
+    }
+
+    #[test]
+    fn top_level_processing_instructions() {
+        let xml = r"
+<?output printer?>
+<hello />
+<?validated?>";
+
+        let package = quick_parse(xml);
+        let doc = package.as_document();
+        let pi1 = doc.root().children()[0].processing_instruction().unwrap();
+        let pi2 = doc.root().children()[2].processing_instruction().unwrap();
+
+        assert_eq!(pi1.target(), "output");
+        assert_eq!(pi1.value(), Some("printer"));
+
+        assert_eq!(pi2.target(), "validated");
+        assert_eq!(pi2.value(), None);
+    }
+
+    #[test]
+    fn element_with_decimal_char_reference() {
+        let package = quick_parse("<math>2 &#62; 1</math>");
+        let doc = package.as_document();
+        let math = top(&doc);
+        let text1 = math.children()[0].text().unwrap();
+        let text2 = math.children()[1].text().unwrap();
+        let text3 = math.children()[2].text().unwrap();
+
+        assert_eq!(text1.text(), "2 ");
+        assert_eq!(text2.text(), ">");
+        assert_eq!(text3.text(), " 1");
+    }
+
+    #[test]
+    fn element_with_hexidecimal_char_reference() {
+        let package = quick_parse("<math>1 &#x3c; 2</math>");
+        let doc = package.as_document();
+        let math = top(&doc);
+        let text1 = math.children()[0].text().unwrap();
+        let text2 = math.children()[1].text().unwrap();
+        let text3 = math.children()[2].text().unwrap();
+
+        assert_eq!(text1.text(), "1 ");
+        assert_eq!(text2.text(), "<");
+        assert_eq!(text3.text(), " 2");
+    }
+
+    #[test]
+    fn element_with_astral_hexidecimal_char_reference() {
+        let package = quick_parse("<a>&#x1F600;</a>");
+        let doc = package.as_document();
+        let a = top(&doc);
+        let text = a.children()[0].text().unwrap();
+
+        assert_eq!(text.text(), "\u{1F600}");
+    }
+
+    #[test]
+    fn element_with_long_zero_padded_decimal_char_reference() {
+        let package = quick_parse("<a>&#00000000000000000065;</a>");
+        let doc = package.as_document();
+        let a = top(&doc);
+        let text = a.children()[0].text().unwrap();
+
+        assert_eq!(text.text(), "A");
+    }
+
+    #[test]
+    fn element_with_entity_reference() {
+        let package = quick_parse("<math>I &lt;3 math</math>");
+        let doc = package.as_document();
+        let math = top(&doc);
+        let text1 = math.children()[0].text().unwrap();
+        let text2 = math.children()[1].text().unwrap();
+        let text3 = math.children()[2].text().unwrap();
+
+        assert_eq!(text1.text(), "I ");
+        assert_eq!(text2.text(), "<");
+        assert_eq!(text3.text(), "3 math");
+    }
+
+    #[test]
+    fn element_with_mixed_children() {
+        let package = quick_parse("<hello>to <!--fixme--><a><![CDATA[the]]></a><?world?></hello>");
+        let doc = package.as_document();
+        let hello = top(&doc);
+
+        let text = hello.children()[0].text().unwrap();
+        let comment = hello.children()[1].comment().unwrap();
+        let element = hello.children()[2].element().unwrap();
+        let pi = hello.children()[3].processing_instruction().unwrap();
+
+        assert_eq!(text.text(), "to ");
+        assert_eq!(comment.text(), "fixme");
+        assert_qname_eq!(element.name(), "a");
+        assert_eq!(pi.target(), "world");
+    }
+
+    // TODO: untested errors
+    //
+    // versionnumber
+    // prefixedname
+    // decimal chars
+    // hex chars
+    // chardata
+    // cdata
+    // commentbody
+    // pinstructionvalue
+
+    macro_rules! assert_parse_failure {
+        ($actual:expr, $pos:expr, $($err:expr),+) => {
+            {
+                let errors: BTreeSet<_> = vec![$($err),+].into_iter().collect();
+                let actual = $actual.map_err(|e| (e.location(), e.errors));
+                let expected = Err(($pos, errors));
+                assert_eq!(actual, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn failure_fragment_with_an_unclosed_element() {
+        use super::SpecificError::*;
+
+        let package = Package::new();
+        let r = Parser::new().parse_fragment(package.as_document(), "<a/><b>");
+
+        assert_parse_failure!(r, 7, UnclosedElement);
+    }
+
+    #[test]
+    fn failure_invalid_encoding() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<?xml version='1.0' encoding='8BIT' ?><hi/>");
+
+        assert_parse_failure!(r, 30, ExpectedEncoding);
+    }
+
+    #[test]
+    fn failure_invalid_standalone() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<?xml version='1.0' standalone='invalid'?><hello/>");
+
+        assert_parse_failure!(r, 32, ExpectedYesNo);
+    }
+
+    #[test]
+    fn failure_invalid_public_id() {
+        use super::SpecificError::*;
+
+        let r = full_parse(r#"<!DOCTYPE a PUBLIC "a{b" "c"><a/>"#);
+
+        assert_parse_failure!(r, 20, ExpectedPublicLiteral);
+    }
+
+    #[test]
+    fn failure_standalone_before_encoding() {
+        let r = full_parse("<?xml version='1.0' standalone='yes' encoding='UTF-8'?><hello/>");
+
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn failure_no_open_brace() {
+        use super::SpecificError::*;
+
+        let r = full_parse("hi />");
+
+        assert_parse_failure!(
+            r,
+            0,
+            Expected("<?xml"),
+            Expected("<!DOCTYPE"),
+            ExpectedComment,
+            ExpectedProcessingInstruction,
+            ExpectedWhitespace,
+            ExpectedElement
+        );
+    }
+
+    #[test]
+    fn failure_unclosed_tag() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<hi");
+
+        assert_parse_failure!(
+            r,
+            3,
+            ExpectedWhitespace,
+            ExpectedElementSelfClosed,
+            ExpectedElementEnd
+        );
+    }
+
+    #[test]
+    fn failure_unexpected_space() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<hi / >");
+
+        assert_parse_failure!(
+            r,
+            4,
+            ExpectedAttribute,
+            ExpectedElementSelfClosed,
+            ExpectedElementEnd
+        );
+    }
+
+    #[test]
+    fn failure_attribute_without_open_quote() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<hi oops=value' />");
+
+        assert_parse_failure!(r, 9, ExpectedOpeningQuote("\'"), ExpectedOpeningQuote("\""));
+    }
+
+    #[test]
+    fn failure_attribute_without_close_quote() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<hi oops='value />");
+
+        assert_parse_failure!(
+            r,
+            18,
+            ExpectedNamedReference,
+            ExpectedDecimalReference,
+            ExpectedAttributeValue,
+            ExpectedHexReference,
+            ExpectedClosingQuote("\'")
+        );
+    }
+
+    #[test]
+    fn failure_less_than_in_attribute_value() {
+        use super::SpecificError::*;
+
+        for &(xml, quote) in &[("<a x='1<2'/>", "'"), ("<a x=\"1<2\"/>", "\"")] {
+            assert_parse_failure!(
+                full_parse(xml),
+                7,
+                ExpectedNamedReference,
+                ExpectedDecimalReference,
+                ExpectedAttributeValue,
+                ExpectedHexReference,
+                ExpectedClosingQuote(quote)
+            );
+        }
+    }
+
+    #[test]
+    fn failure_unclosed_attribute_and_tag() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<hi oops='value");
+
+        assert_parse_failure!(
+            r,
+            15,
+            ExpectedNamedReference,
+            ExpectedDecimalReference,
+            ExpectedAttributeValue,
+            ExpectedHexReference,
+            ExpectedClosingQuote("\'")
+        );
+    }
+
+    #[test]
+    fn failure_nested_unclosed_tag() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<hi><oops</hi>");
+
+        assert_parse_failure!(
+            r,
+            9,
+            ExpectedWhitespace,
+            ExpectedElementSelfClosed,
+            ExpectedElementEnd
+        );
+    }
+
+    #[test]
+    fn failure_missing_close_tag() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<hi>wow");
+
+        assert_parse_failure!(r, 7, UnclosedElement);
+    }
+
+    #[test]
+    fn failure_nested_unexpected_space() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<hi><oops / ></hi>");
+
+        assert_parse_failure!(
+            r,
+            10,
+            ExpectedAttribute,
+            ExpectedElementSelfClosed,
+            ExpectedElementEnd
+        );
+    }
+
+    #[test]
+    fn failure_malformed_entity_reference() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<hi>Entity: &;</hi>");
+
+        assert_parse_failure!(r, 13, ExpectedNamedReferenceValue);
+    }
+
+    #[test]
+    fn failure_bare_ampersand_in_text() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a>a & b</a>");
+
+        assert_parse_failure!(r, 5, ExpectedNamedReferenceValue);
+    }
+
+    #[test]
+    fn failure_unterminated_entity_reference_in_text() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a>a &b</a>");
+
+        assert_parse_failure!(r, 7, Expected(";"));
+    }
+
+    #[test]
+    fn failure_second_doc_type_declaration() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<!DOCTYPE a><!DOCTYPE b><a/>");
+        assert_parse_failure!(
+            r,
+            12,
+            ExpectedComment,
+            ExpectedElement,
+            ExpectedProcessingInstruction,
+            ExpectedWhitespace
+        );
+
+        let r = full_parse("<!DOCTYPE a [ ]><!DOCTYPE b><a/>");
+        assert_parse_failure!(
+            r,
+            16,
+            ExpectedComment,
+            ExpectedElement,
+            ExpectedProcessingInstruction,
+            ExpectedWhitespace
+        );
+    }
+
+    #[test]
+    fn failure_less_than_in_text() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a>a < b</a>");
+
+        assert_parse_failure!(r, 6, ExpectedElementName);
+    }
+
+    #[test]
+    fn failure_nested_malformed_entity_reference() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<hi><bye>Entity: &;</bye></hi>");
+
+        assert_parse_failure!(r, 18, ExpectedNamedReferenceValue);
+    }
+
+    #[test]
+    fn failure_nested_attribute_without_open_quote() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<hi><bye oops=value' /></hi>");
+
+        assert_parse_failure!(
+            r,
+            14,
+            ExpectedOpeningQuote("\'"),
+            ExpectedOpeningQuote("\"")
+        );
+    }
+
+    #[test]
+    fn failure_nested_attribute_without_close_quote() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<hi><bye oops='value /></hi>");
+
+        assert_parse_failure!(
+            r,
+            23,
+            ExpectedNamedReference,
+            ExpectedDecimalReference,
+            ExpectedAttributeValue,
+            ExpectedHexReference,
+            ExpectedClosingQuote("\'")
+        );
+    }
+
+    #[test]
+    fn failure_nested_unclosed_attribute_and_tag() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<hi><bye oops='value</hi>");
+
+        assert_parse_failure!(
+            r,
+            20,
+            ExpectedNamedReference,
+            ExpectedDecimalReference,
+            ExpectedAttributeValue,
+            ExpectedHexReference,
+            ExpectedClosingQuote("\'")
+        );
+    }
+
+    #[test]
+    fn failure_pi_target_as_xml() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a><?xml?></a>");
+
+        assert_parse_failure!(r, 5, InvalidProcessingInstructionTarget);
+    }
+
+    #[test]
+    fn failure_pi_target_as_mixed_case_xml() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a><?xMl?></a>");
+
+        assert_parse_failure!(r, 5, InvalidProcessingInstructionTarget);
+    }
+
+    #[test]
+    fn failure_pi_without_an_end() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a><?t value</a>");
+
+        assert_parse_failure!(r, 3, UnterminatedProcessingInstruction);
+    }
+
+    #[test]
+    fn failure_comment_containing_a_double_hyphen() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a><!-- a -- b --></a>");
+
+        assert_parse_failure!(r, 10, DoubleHyphenInComment);
+    }
+
+    #[test]
+    fn failure_comment_ending_with_a_hyphen() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a><!-- a ---></a>");
+
+        assert_parse_failure!(r, 10, DoubleHyphenInComment);
+    }
+
+    #[test]
+    fn failure_comment_without_an_end() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a/><!-- a -");
+
+        assert_parse_failure!(r, 4, UnterminatedComment);
+    }
+
+    #[test]
+    fn failure_end_tag_does_not_match() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a></b>");
+
+        assert_parse_failure!(r, 5, MismatchedElementEndName);
+    }
+
+    #[test]
+    fn failure_invalid_decimal_reference() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a>&#99999999;</a>");
+
+        assert_parse_failure!(r, 5, InvalidDecimalReference);
+    }
+
+    #[test]
+    fn failure_invalid_hex_reference() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a>&#x99999999;</a>");
+
+        assert_parse_failure!(r, 6, InvalidHexReference);
+    }
+
+    #[test]
+    fn failure_overflowing_decimal_reference() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a>&#99999999999999999999;</a>");
+
+        assert_parse_failure!(r, 5, InvalidDecimalReference);
+    }
+
+    #[test]
+    fn failure_overflowing_hex_reference() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a>&#xFFFFFFFFFFFFFFFFFFFFFFFF;</a>");
+
+        assert_parse_failure!(r, 6, InvalidHexReference);
+    }
+
+    #[test]
+    fn failure_empty_decimal_reference() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a>&#;</a>");
+
+        assert_parse_failure!(r, 5, ExpectedDecimalReferenceValue);
+    }
+
+    #[test]
+    fn failure_decimal_reference_to_null() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a>&#0;</a>");
+
+        assert_parse_failure!(r, 5, InvalidDecimalReference);
+    }
+
+    #[test]
+    fn failure_hex_reference_to_noncharacter() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a>&#xFFFF;</a>");
+
+        assert_parse_failure!(r, 6, InvalidHexReference);
+    }
+
+    #[test]
+    fn failure_hex_reference_to_surrogate() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a>&#xD800;</a>");
+
+        assert_parse_failure!(r, 6, InvalidHexReference);
+    }
+
+    #[test]
+    fn failure_unknown_named_reference() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a>&fake;</a>");
+
+        assert_parse_failure!(r, 4, UnknownNamedReference);
+    }
+
+    #[test]
+    fn failure_unknown_named_reference_in_attribute() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a b='x&nope;'/>");
+
+        assert_parse_failure!(r, 8, UnknownNamedReference);
+    }
+
+    #[test]
+    fn failure_unknown_named_reference_after_known_reference() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a>&amp;&nope;</a>");
+
+        assert_parse_failure!(r, 9, UnknownNamedReference);
+    }
+
+    #[test]
+    fn failure_recursive_entity_reference() {
+        use super::SpecificError::*;
+
+        let r = full_parse(r#"<!DOCTYPE a [ <!ENTITY x "&y;"> <!ENTITY y "a&x;"> ]><a>&x;</a>"#);
+
+        assert_parse_failure!(r, 57, RecursiveEntityReference);
+    }
+
+    #[test]
+    fn failure_unknown_named_reference_in_entity_value() {
+        use super::SpecificError::*;
+
+        let r = full_parse(r#"<!DOCTYPE a [ <!ENTITY x "&nope;"> ]><a>&x;</a>"#);
+
+        assert_parse_failure!(r, 41, UnknownNamedReference);
+    }
+
+    #[test]
+    fn failure_entity_declared_only_inside_a_comment() {
+        use super::SpecificError::*;
+
+        let r = full_parse(r#"<!DOCTYPE a [ <!-- <!ENTITY e "no"> --> ]><a>&e;</a>"#);
+
+        assert_parse_failure!(r, 46, UnknownNamedReference);
+    }
+
+    #[test]
+    fn failure_entity_unknown_to_the_resolver() {
+        use super::SpecificError::*;
+
+        let parser = Parser::new().set_entity_resolver(html_entities);
+        let r = parser.parse("<a>&copy;</a>");
+
+        assert_parse_failure!(r, 4, UnknownNamedReference);
+    }
+
+    #[test]
+    fn failure_bytes_with_an_unsupported_encoding() {
+        use super::SpecificError::*;
 
-    macro_rules! assert_qname_eq(
-        ($l:expr, $r:expr) => (assert_eq!(Into::<QName<'_>>::into($l), $r.into()));
-    );
+        let r = Parser::new().parse_bytes(b"<?xml version='1.0' encoding='ISO-8859-1'?><a/>");
 
-    fn full_parse(xml: &str) -> Result<Package, Error> {
-        super::parse(xml)
+        assert_parse_failure!(r, 30, UnsupportedEncoding);
     }
 
-    fn quick_parse(xml: &str) -> Package {
-        full_parse(xml).expect("Failed to parse the XML string")
-    }
+    #[test]
+    fn failure_bytes_declaring_a_different_encoding() {
+        use super::SpecificError::*;
 
-    fn top<'d>(doc: &'d dom::Document<'d>) -> dom::Element<'d> {
-        doc.root().children()[0].element().unwrap()
+        let xml = utf16_le("<?xml version='1.0' encoding='UTF-8'?><a/>");
+        let r = Parser::new().parse_bytes(&xml);
+
+        assert_parse_failure!(r, 30, UnsupportedEncoding);
     }
 
     #[test]
-    fn a_document_with_a_prolog() {
-        let package = quick_parse("<?xml version='1.0' ?><hello />");
-        let doc = package.as_document();
-        let top = top(&doc);
+    fn failure_bytes_with_warnings_and_an_unsupported_encoding() {
+        use super::SpecificError::*;
 
-        assert_qname_eq!(top.name(), "hello");
+        let xml = utf16_le("<?xml version='1.0' encoding='ISO-8859-1'?><a/>");
+        let r = Parser::new().parse_bytes_with_warnings(&xml);
+
+        assert_parse_failure!(r, 30, UnsupportedEncoding);
     }
 
     #[test]
-    fn a_document_with_a_prolog_with_double_quotes() {
-        let package = quick_parse("<?xml version=\"1.0\" ?><hello />");
-        let doc = package.as_document();
-        let top = top(&doc);
+    fn failure_bytes_with_invalid_utf8() {
+        use super::SpecificError::*;
 
-        assert_qname_eq!(top.name(), "hello");
+        let r = Parser::new().parse_bytes(b"\xEF\xBB\xBF<a>\n\xFF</a>");
+        let e = r.unwrap_err();
+
+        assert_eq!(e.line(), 2);
+        assert_eq!(e.column(), 1);
+        assert_parse_failure!(Err::<(), _>(e), 7, InvalidByteSequence);
     }
 
     #[test]
-    fn a_prolog_with_an_encoding() {
-        let package = quick_parse("<?xml version='1.0' encoding='UTF-8' ?><hello />");
-        let doc = package.as_document();
-        let top = top(&doc);
+    fn failure_utf8_bytes_that_are_invalid() {
+        use super::SpecificError::*;
 
-        assert_qname_eq!(top.name(), "hello");
+        let r = Parser::new().parse_utf8(b"<a>\n\xC3</a>");
+
+        assert_parse_failure!(r, 4, InvalidByteSequence);
     }
 
     #[test]
-    fn a_non_standalone_prolog() {
-        let package = quick_parse("<?xml version='1.0' standalone='no'?><hello/>");
-        let doc = package.as_document();
-        let top = top(&doc);
+    fn failure_utf8_bytes_are_located_in_the_input() {
+        use super::SpecificError::*;
 
-        assert_qname_eq!(top.name(), "hello");
+        let r = Parser::new().parse_utf8(b"\xEF\xBB\xBF<a></b>");
+
+        assert_parse_failure!(r, 8, MismatchedElementEndName);
     }
 
     #[test]
-    fn a_complete_prolog() {
-        let package =
-            quick_parse("<?xml version='1.0' encoding='UTF-8' standalone='yes'?><hello/>");
-        let doc = package.as_document();
-        let top = top(&doc);
+    fn failure_utf8_bytes_declaring_a_different_encoding() {
+        use super::SpecificError::*;
 
-        assert_qname_eq!(top.name(), "hello");
+        let r = Parser::new().parse_utf8(b"<?xml version='1.0' encoding='UTF-16'?><a/>");
+
+        assert_parse_failure!(r, 30, UnsupportedEncoding);
     }
 
     #[test]
-    fn a_prolog_with_a_doc_type_declaration_external_id() {
-        let package = quick_parse(
-            r#"<?xml version='1.0'?>
-        <!DOCTYPE doc SYSTEM "http://example.com/doc.dtd">
-        <hello/>"#,
-        );
-        let doc = package.as_document();
-        let top = top(&doc);
+    fn failure_bytes_with_an_unpaired_surrogate() {
+        use super::SpecificError::*;
 
-        assert_qname_eq!(top.name(), "hello");
+        let mut xml = utf16_le("<a>");
+        xml.extend(&[0x00, 0xD8]);
+        let r = Parser::new().parse_bytes(&xml);
+
+        assert_parse_failure!(r, 8, InvalidByteSequence);
     }
 
     #[test]
-    fn a_prolog_with_a_doc_type_declaration_int_subset() {
-        let package = quick_parse(
-            r#"<?xml version="1.0"?>
-            <!DOCTYPE note [
-            <!ELEMENT note (to,from,heading,body)>
-            <!ELEMENT to (#PCDATA)>
-            <!ELEMENT from (#PCDATA)>
-            <!ELEMENT heading (#PCDATA)>
-            <!ELEMENT body (#PCDATA)>
-            ]>
-            <note>
-            <to>Tove</to>
-            <from>Jani</from>
-            <heading>Reminder</heading>
-            <body>Don't forget me this weekend</body>
-            </note>
-        "#,
-        );
-        let doc = package.as_document();
-        let top = top(&doc);
+    fn failure_elements_nested_past_the_maximum_depth() {
+        use super::SpecificError::*;
 
-        assert_qname_eq!(top.name(), "note");
+        let r = Parser::new().set_max_depth(Some(3)).parse(&nested(4));
+
+        assert_parse_failure!(r, 9, MaximumDepthExceeded);
     }
 
     #[test]
-    fn a_prolog_with_a_doc_type_declaration_int_subset_trailing_ws() {
-        let package = quick_parse(
-            r#"<?xml version="1.0"?>
-            <!DOCTYPE note
-                [
-                    <!ELEMENT note (to,from,heading,body)>
-                    <!ELEMENT to (#PCDATA)>
-                    <!ELEMENT from (#PCDATA)>
-                    <!ELEMENT heading (#PCDATA)>
-                    <!ELEMENT body (#PCDATA)>
-                ]
+    fn failure_too_many_attributes() {
+        use super::SpecificError::*;
 
-            >
-            <note>
-            <to>Tove</to>
-            <from>Jani</from>
-            <heading>Reminder</heading>
-            <body>Don't forget me this weekend</body>
-            </note>
-        "#,
-        );
-        let doc = package.as_document();
-        let top = top(&doc);
+        let r = Parser::new()
+            .set_max_attributes(Some(2))
+            .parse("<a x='1' y='2' z='3'/>");
 
-        assert_qname_eq!(top.name(), "note");
+        assert_parse_failure!(r, 14, MaximumAttributesExceeded);
     }
 
     #[test]
-    fn a_prolog_with_a_doc_type_declaration_zero_def() {
-        let package = quick_parse(
-            "<?xml version='1.0'?>
-        <!DOCTYPE doc>
-        <hello/>",
-        );
-        let doc = package.as_document();
-        let top = top(&doc);
+    fn failure_too_many_attributes_counts_namespace_declarations() {
+        use super::SpecificError::*;
 
-        assert_qname_eq!(top.name(), "hello");
+        let r = Parser::new()
+            .set_max_attributes(Some(1))
+            .parse("<a xmlns:p='urn:p' p:x='1'/>");
+
+        assert_parse_failure!(r, 18, MaximumAttributesExceeded);
     }
 
     #[test]
-    fn a_prolog_with_a_doc_type_declaration_zero_def_trailing_ws() {
-        let package = quick_parse(
-            "<?xml version='1.0'?>
-        <!DOCTYPE doc  >
-        <hello/>",
-        );
-        let doc = package.as_document();
-        let top = top(&doc);
+    fn attributes_up_to_the_maximum_on_each_element() {
+        let package = Parser::new()
+            .set_max_attributes(Some(2))
+            .parse("<a x='1' y='2'><b x='1' y='2'/></a>");
 
-        assert_qname_eq!(top.name(), "hello");
+        assert!(package.is_ok());
     }
 
     #[test]
-    fn a_prolog_with_a_doc_type_declaration_both_int_subset_and_external_id() {
-        let package = quick_parse(
-            r#"<?xml version='1.0'?>
-        <!DOCTYPE doc SYSTEM "http://example.com/doc.dtd" [
-        <!ELEMENT hello (#PCDATA)>
-        ]>
-        <hello/>"#,
-        );
-        let doc = package.as_document();
-        let top = top(&doc);
+    fn failure_attribute_value_too_long() {
+        use super::SpecificError::*;
 
-        assert_qname_eq!(top.name(), "hello");
+        let parser = Parser::new().set_max_attribute_value_length(Some(4));
+
+        assert!(parser.parse("<a x='abcd'/>").is_ok());
+        assert_parse_failure!(
+            parser.parse("<a x='ab&amp;'/>"),
+            8,
+            MaximumAttributeValueLengthExceeded
+        );
     }
 
     #[test]
-    fn a_prolog_with_a_doc_type_declaration_both_int_subset_and_external_id_trailing_ws() {
-        let package = quick_parse(
-            r#"<?xml version='1.0'?>
-        <!DOCTYPE doc SYSTEM "http://example.com/doc.dtd" [
-        <!ELEMENT hello (#PCDATA)>
-         ]  >
-        <hello/>"#,
-        );
-        let doc = package.as_document();
-        let top = top(&doc);
+    fn failure_too_many_elements() {
+        use super::SpecificError::*;
 
-        assert_qname_eq!(top.name(), "hello");
+        let parser = Parser::new().set_max_elements(Some(3));
+
+        assert!(parser.parse("<a><b/><c/></a>").is_ok());
+        assert_parse_failure!(
+            parser.parse("<a><b/><c/><d/></a>"),
+            11,
+            MaximumElementsExceeded
+        );
     }
 
     #[test]
-    fn a_document_with_a_single_element() {
-        let package = quick_parse("<hello />");
-        let doc = package.as_document();
-        let top = top(&doc);
+    fn failure_sink_too_many_attributes() {
+        use super::SpecificError::*;
 
-        assert_qname_eq!(top.name(), "hello");
+        let mut sink = RecordingSink::default();
+        let r = Parser::new()
+            .set_max_attributes(Some(1))
+            .parse_with_sink("<a x='1' y='2'/>", &mut sink);
+
+        assert_parse_failure!(r, 8, MaximumAttributesExceeded);
     }
 
     #[test]
-    fn an_element_with_a_namespace() {
-        let package = quick_parse("<ns:hello xmlns:ns='namespace'/>");
-        let doc = package.as_document();
-        let top = top(&doc);
+    fn lenient_parsing_stops_at_an_exceeded_limit() {
+        let (_, errors) = Parser::new()
+            .set_max_attribute_value_length(Some(1))
+            .parse_lenient("<a x='a&amp;b'/>");
 
-        assert_eq!(top.preferred_prefix(), Some("ns"));
-        assert_qname_eq!(("namespace", "hello"), top.name());
+        let errors: Vec<_> = errors.iter().map(|e| e.location()).collect();
+        assert_eq!(errors, [7]);
     }
 
     #[test]
-    fn an_element_with_a_default_namespace() {
-        let package = quick_parse("<hello xmlns='namespace'/>");
-        let doc = package.as_document();
-        let top = top(&doc);
+    fn failure_elements_nested_past_the_default_maximum_depth() {
+        use super::SpecificError::*;
 
-        assert_eq!(top.default_namespace_uri(), Some("namespace"));
-        assert_qname_eq!(("namespace", "hello"), top.name());
+        let mut sink = RecordingSink::default();
+        let r = Parser::new().parse_with_sink(&nested(DEFAULT_MAX_DEPTH + 1), &mut sink);
+
+        assert_parse_failure!(r, DEFAULT_MAX_DEPTH * 3, MaximumDepthExceeded);
     }
 
     #[test]
-    fn an_element_with_an_attribute() {
-        let package = quick_parse("<hello scope='world'/>");
-        let doc = package.as_document();
-        let top = top(&doc);
+    fn failure_billion_laughs() {
+        use super::SpecificError::*;
 
-        assert_eq!(top.attribute_value("scope"), Some("world"));
+        let mut xml = String::from("<!DOCTYPE a [<!ENTITY lol0 'lol'>");
+        for i in 1..10 {
+            let refs = format!("&lol{};", i - 1).repeat(10);
+            xml += &format!("<!ENTITY lol{} '{}'>", i, refs);
+        }
+        xml += "]><a>&lol9;</a>";
+        let location = xml.len() - "lol9;</a>".len();
+
+        let r = full_parse(&xml);
+
+        assert_parse_failure!(r, location, EntityExpansionLimitExceeded);
     }
 
     #[test]
-    fn an_element_with_an_attribute_using_double_quotes() {
-        let package = quick_parse("<hello scope=\"world\"/>");
-        let doc = package.as_document();
-        let top = top(&doc);
+    fn failure_entity_expansion_limit_spans_the_document() {
+        use super::SpecificError::*;
 
-        assert_eq!(top.attribute_value("scope"), Some("world"));
+        let xml = r#"<!DOCTYPE a [<!ENTITY e "12345">]><a b="&e;">&e;&e;</a>"#;
+        let parser = Parser::new().set_max_entity_expansion(Some(10));
+
+        let r = parser.parse(xml);
+
+        assert_parse_failure!(r, 49, EntityExpansionLimitExceeded);
     }
 
     #[test]
-    fn an_element_with_multiple_attributes() {
-        let package = quick_parse("<hello scope='world' happy='true'/>");
-        let doc = package.as_document();
-        let top = top(&doc);
+    fn failure_parameter_entity_reference_in_entity_value() {
+        use super::SpecificError::*;
 
-        assert_eq!(top.attribute_value("scope"), Some("world"));
-        assert_eq!(top.attribute_value("happy"), Some("true"));
+        let r = full_parse(r#"<!DOCTYPE a [ <!ENTITY x "%p;"> ]><a/>"#);
+
+        assert_parse_failure!(
+            r,
+            26,
+            ExpectedEntityValue,
+            ExpectedNamedReference,
+            ExpectedDecimalReference,
+            ExpectedHexReference,
+            ExpectedClosingQuote("\"")
+        );
     }
 
     #[test]
-    fn an_attribute_with_a_namespace() {
-        let package = quick_parse("<hello ns:a='b' xmlns:ns='namespace'/>");
-        let doc = package.as_document();
-        let top = top(&doc);
+    fn failure_text_after_top_element() {
+        use super::SpecificError::*;
 
-        let attr = top.attribute(("namespace", "a")).unwrap();
+        let r = full_parse("<a/>garbage");
 
-        assert_eq!(attr.preferred_prefix(), Some("ns"));
-        assert_eq!(attr.value(), "b");
+        assert_parse_failure!(
+            r,
+            4,
+            ExpectedComment,
+            ExpectedProcessingInstruction,
+            ExpectedWhitespace
+        );
     }
 
     #[test]
-    fn an_attribute_with_xml_space_preserve() {
-        let package = quick_parse("<hello xml:space='preserve'> <a/> </hello>");
-        let doc = package.as_document();
-        let top = top(&doc);
+    fn failure_second_top_element() {
+        use super::SpecificError::*;
 
-        assert_eq!(
-            top.attribute((crate::XML_NS_URI, "space")).unwrap().value(),
-            "preserve"
+        let r = full_parse("<a/> <b/>");
+
+        assert_parse_failure!(
+            r,
+            5,
+            ExpectedComment,
+            ExpectedProcessingInstruction,
+            ExpectedWhitespace
         );
+    }
 
-        let children = top.children();
-        assert_eq!(children.len(), 3);
-        assert_eq!(children[0].text().unwrap().text(), " ");
-        assert_qname_eq!(children[1].element().unwrap().name(), "a");
-        assert_eq!(children[2].text().unwrap().text(), " ");
+    #[test]
+    fn failure_element_name_with_leading_digit() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<1abc/>");
+
+        assert_parse_failure!(r, 1, ExpectedElementName);
+    }
+
+    #[test]
+    fn failure_element_name_with_leading_hyphen() {
+        use super::SpecificError::*;
+
+        let r = full_parse("<a><-b/></a>");
+
+        assert_parse_failure!(r, 4, ExpectedElementName);
     }
 
     #[test]
-    fn an_attribute_with_references() {
-        let package = quick_parse("<log msg='I &lt;3 math' />");
-        let doc = package.as_document();
-        let top = top(&doc);
+    fn failure_duplicate_attribute() {
+        use super::SpecificError::*;
 
-        assert_eq!(top.attribute_value("msg"), Some("I <3 math"));
+        let r = full_parse("<a b='c' b='d'/>");
+
+        assert_parse_failure!(r, 9, DuplicateAttribute);
     }
 
     #[test]
-    fn an_element_that_is_not_self_closing() {
-        let package = quick_parse("<hello></hello>");
-        let doc = package.as_document();
-        let top = top(&doc);
+    fn failure_duplicate_namespaced_attribute() {
+        use super::SpecificError::*;
 
-        assert_qname_eq!(top.name(), "hello");
+        let r = full_parse("<a xmlns:p='urn:x' p:b='c' p:b='d'/>");
+
+        assert_parse_failure!(r, 27, DuplicateAttribute);
     }
 
     #[test]
-    fn nested_elements() {
-        let package = quick_parse("<hello><world/></hello>");
-        let doc = package.as_document();
-        let hello = top(&doc);
-        let world = hello.children()[0].element().unwrap();
+    fn failure_duplicate_attribute_through_different_prefixes() {
+        use super::SpecificError::*;
 
-        assert_qname_eq!(world.name(), "world");
+        let r = full_parse("<a xmlns:p='urn:x' xmlns:q='urn:x' q:b='c' p:b='d'/>");
+
+        assert_parse_failure!(r, 43, DuplicateAttribute);
     }
 
     #[test]
-    fn multiply_nested_elements() {
-        let package = quick_parse("<hello><awesome><world/></awesome></hello>");
+    fn attributes_with_same_local_name_in_different_namespaces() {
+        let package = quick_parse("<a xmlns:p='urn:x' b='c' p:b='d'/>");
         let doc = package.as_document();
-        let hello = top(&doc);
-        let awesome = hello.children()[0].element().unwrap();
-        let world = awesome.children()[0].element().unwrap();
+        let a = top(&doc);
 
-        assert_qname_eq!(world.name(), "world");
+        assert_eq!(a.attribute_value("b"), Some("c"));
+        assert_eq!(a.attribute_value(("urn:x", "b")), Some("d"));
     }
 
     #[test]
-    fn nested_elements_with_namespaces() {
-        let package =
-            quick_parse("<ns1:hello xmlns:ns1='outer'><ns2:world xmlns:ns2='inner'/></ns1:hello>");
-        let doc = package.as_document();
-        let hello = top(&doc);
-        let world = hello.children()[0].element().unwrap();
+    fn failure_redefined_namespace() {
+        use super::SpecificError::*;
 
-        assert_qname_eq!(world.name(), ("inner", "world"));
+        let r = full_parse("<a xmlns:b='c' xmlns:b='d'/>");
+
+        assert_parse_failure!(r, 15, RedefinedNamespace);
     }
 
     #[test]
-    fn nested_elements_with_inherited_namespaces() {
-        let package = quick_parse("<ns1:hello xmlns:ns1='outer'><ns1:world/></ns1:hello>");
-        let doc = package.as_document();
-        let hello = top(&doc);
-        let world = hello.children()[0].element().unwrap();
+    fn failure_redefined_default_namespace() {
+        use super::SpecificError::*;
 
-        assert_eq!(world.preferred_prefix(), Some("ns1"));
-        assert_qname_eq!(world.name(), ("outer", "world"));
+        let r = full_parse("<a xmlns='a' xmlns='b'/>");
+
+        assert_parse_failure!(r, 13, RedefinedDefaultNamespace);
     }
 
     #[test]
-    fn nested_elements_with_inherited_default_namespace() {
-        let package = quick_parse("<hello xmlns='outer'><world/></hello>");
-        let doc = package.as_document();
-        let hello = top(&doc);
-        let world = hello.children()[0].element().unwrap();
+    fn failure_xml_prefix_declared_to_another_namespace() {
+        use super::SpecificError::*;
 
-        assert_qname_eq!(world.name(), ("outer", "world"));
+        let r = full_parse("<a xmlns:xml='other'/>");
+
+        assert_parse_failure!(r, 3, ReservedNamespace);
     }
 
     #[test]
-    fn nested_elements_with_reset_default_namespace() {
-        let package = quick_parse("<hello xmlns='outer'><world xmlns=''/></hello>");
-        let doc = package.as_document();
-        let hello = top(&doc);
-        let world = hello.children()[0].element().unwrap();
+    fn failure_xmlns_prefix_declared() {
+        use super::SpecificError::*;
 
-        assert_qname_eq!(world.name(), "world");
+        let r = full_parse("<a xmlns:xmlns='http://www.w3.org/2000/xmlns/'/>");
+
+        assert_parse_failure!(r, 3, ReservedNamespace);
     }
 
     #[test]
-    fn nested_elements_with_attributes() {
-        let package = quick_parse("<hello><world name='Earth'/></hello>");
-        let doc = package.as_document();
-        let hello = top(&doc);
-        let world = hello.children()[0].element().unwrap();
+    fn failure_reserved_namespace_bound_to_another_prefix() {
+        use super::SpecificError::*;
 
-        assert_eq!(world.attribute_value("name"), Some("Earth"));
+        let r = full_parse("<a xmlns:x='http://www.w3.org/XML/1998/namespace'/>");
+        assert_parse_failure!(r, 3, ReservedNamespace);
+
+        let r = full_parse("<a xmlns='http://www.w3.org/2000/xmlns/'/>");
+        assert_parse_failure!(r, 3, ReservedNamespace);
     }
 
     #[test]
-    fn nested_elements_with_attributes_with_inherited_namespaces() {
-        let package = quick_parse("<hello xmlns:ns1='outer'><world ns1:name='Earth'/></hello>");
-        let doc = package.as_document();
-        let hello = top(&doc);
-        let world = hello.children()[0].element().unwrap();
+    fn failure_empty_namespace() {
+        use super::SpecificError::*;
 
-        let attr = world.attribute(("outer", "name")).unwrap();
+        let r = full_parse("<a xmlns:b=''/>");
 
-        assert_eq!(attr.preferred_prefix(), Some("ns1"));
-        assert_eq!(attr.value(), "Earth");
+        assert_parse_failure!(r, 3, EmptyNamespace);
     }
 
     #[test]
-    fn element_with_text() {
-        let package = quick_parse("<hello>world</hello>");
-        let doc = package.as_document();
-        let hello = top(&doc);
-        let text = hello.children()[0].text().unwrap();
+    fn failure_unknown_attribute_namespace_prefix() {
+        use super::SpecificError::*;
 
-        assert_eq!(text.text(), "world");
+        let r = full_parse("<a b:foo='a'/>");
+
+        assert_parse_failure!(r, 3, UnknownNamespacePrefix);
     }
 
     #[test]
-    fn element_with_cdata() {
-        let package = quick_parse("<words><![CDATA[I have & and < !]]></words>");
-        let doc = package.as_document();
-        let words = top(&doc);
-        let text = words.children()[0].text().unwrap();
+    fn failure_unknown_element_namespace_prefix() {
+        use super::SpecificError::*;
 
-        assert_eq!(text.text(), "I have & and < !");
+        let r = full_parse("<b:a/>");
+
+        assert_parse_failure!(r, 1, UnknownNamespacePrefix);
     }
 
     #[test]
-    fn element_with_comment() {
-        let package = quick_parse("<hello><!-- A comment --></hello>");
-        let doc = package.as_document();
-        let words = top(&doc);
-        let comment = words.children()[0].comment().unwrap();
+    fn failure_namespace_prefix_declared_on_sibling() {
+        use super::SpecificError::*;
 
-        assert_eq!(comment.text(), " A comment ");
+        let r = full_parse("<a><b xmlns:x='y'/><x:c/></a>");
+
+        assert_parse_failure!(r, 20, UnknownNamespacePrefix);
     }
 
     #[test]
-    fn comment_before_top_element() {
-        let package = quick_parse("<!-- A comment --><hello />");
-        let doc = package.as_document();
-        let comment = doc.root().children()[0].comment().unwrap();
+    fn failure_unknown_namespace_prefix_on_nested_element() {
+        use super::SpecificError::*;
 
-        assert_eq!(comment.text(), " A comment ");
+        let r = full_parse("<a xmlns:y='z'><x:a/></a>");
+
+        assert_parse_failure!(r, 16, UnknownNamespacePrefix);
     }
 
     #[test]
-    fn multiple_comments_before_top_element() {
-        let xml = r"
-<!--Comment 1-->
-<!--Comment 2-->
-<hello />";
-        let package = quick_parse(xml);
-        let doc = package.as_document();
-        let comment1 = doc.root().children()[0].comment().unwrap();
-        let comment2 = doc.root().children()[1].comment().unwrap();
+    fn failure_reports_line_and_column() {
+        let r = full_parse("<hi>\n  <oops / >\n</hi>");
+        let e = r.unwrap_err();
 
-        assert_eq!(comment1.text(), "Comment 1");
-        assert_eq!(comment2.text(), "Comment 2");
+        assert_eq!(e.location(), 13);
+        assert_eq!(e.line(), 2);
+        assert_eq!(e.column(), 9);
     }
 
     #[test]
-    fn multiple_comments_after_top_element() {
-        let xml = r"
-<hello />
-<!--Comment 1-->
-<!--Comment 2-->";
-        let package = quick_parse(xml);
-        let doc = package.as_document();
-        let comment1 = doc.root().children()[1].comment().unwrap();
-        let comment2 = doc.root().children()[2].comment().unwrap();
+    fn failure_column_counts_characters() {
+        let r = full_parse("<hi>\n<h\u{e9}llo / ></hi>");
+        let e = r.unwrap_err();
 
-        assert_eq!(comment1.text(), "Comment 1");
-        assert_eq!(comment2.text(), "Comment 2");
+        assert_eq!(e.location(), 13);
+        assert_eq!(e.line(), 2);
+        assert_eq!(e.column(), 8);
     }
 
     #[test]
-    fn element_with_processing_instruction() {
-        let package = quick_parse("<hello><?device?></hello>");
+    fn leading_byte_order_mark_is_skipped() {
+        let package = quick_parse("\u{FEFF}<a/>");
         let doc = package.as_document();
-        let hello = top(&doc);
-        let pi = hello.children()[0].processing_instruction().unwrap();
 
-        assert_eq!(pi.target(), "device");
-        assert_eq!(pi.value(), None);
+        assert_qname_eq!(top(&doc).name(), "a");
     }
 
     #[test]
-    fn top_level_processing_instructions() {
-        let xml = r"
-<?output printer?>
-<hello />
-<?validated?>";
+    fn byte_order_mark_does_not_shift_locations() {
+        let r = full_parse("\u{FEFF}<a></b>");
+        let e = r.unwrap_err();
 
-        let package = quick_parse(xml);
-        let doc = package.as_document();
-        let pi1 = doc.root().children()[0].processing_instruction().unwrap();
-        let pi2 = doc.root().children()[2].processing_instruction().unwrap();
+        assert_eq!(e.location(), 8);
+        assert_eq!(e.line(), 1);
+        assert_eq!(e.column(), 6);
+    }
 
-        assert_eq!(pi1.target(), "output");
-        assert_eq!(pi1.value(), Some("printer"));
+    #[test]
+    fn byte_order_mark_after_the_start_fails() {
+        use super::SpecificError::*;
 
-        assert_eq!(pi2.target(), "validated");
-        assert_eq!(pi2.value(), None);
+        let r = full_parse("<a/>\u{FEFF}");
+        assert_parse_failure!(
+            r,
+            4,
+            ExpectedWhitespace,
+            ExpectedComment,
+            ExpectedProcessingInstruction
+        );
+
+        let r = full_parse("\u{FEFF}\u{FEFF}<a/>");
+        assert_eq!(r.unwrap_err().location(), 3);
     }
 
     #[test]
-    fn element_with_decimal_char_reference() {
-        let package = quick_parse("<math>2 &#62; 1</math>");
-        let doc = package.as_document();
-        let math = top(&doc);
-        let text1 = math.children()[0].text().unwrap();
-        let text2 = math.children()[1].text().unwrap();
-        let text3 = math.children()[2].text().unwrap();
+    fn bytes_with_a_doubled_byte_order_mark_fail() {
+        let r = Parser::new().parse_bytes(b"\xEF\xBB\xBF\xEF\xBB\xBF<a/>");
+        assert_eq!(r.unwrap_err().location(), 0);
 
-        assert_eq!(text1.text(), "2 ");
-        assert_eq!(text2.text(), ">");
-        assert_eq!(text3.text(), " 1");
+        let xml = utf16_le("\u{FEFF}<a/>");
+        let r = Parser::new().parse_bytes(&xml);
+        assert_eq!(r.unwrap_err().location(), 0);
+
+        let r = Parser::new().parse_bytes_with_warnings(&xml);
+        assert_eq!(r.unwrap_err().location(), 0);
     }
 
     #[test]
-    fn element_with_hexidecimal_char_reference() {
-        let package = quick_parse("<math>1 &#x3c; 2</math>");
-        let doc = package.as_document();
-        let math = top(&doc);
-        let text1 = math.children()[0].text().unwrap();
-        let text2 = math.children()[1].text().unwrap();
-        let text3 = math.children()[2].text().unwrap();
+    fn failure_on_first_line() {
+        let r = full_parse("<a></b>");
+        let e = r.unwrap_err();
 
-        assert_eq!(text1.text(), "1 ");
-        assert_eq!(text2.text(), "<");
-        assert_eq!(text3.text(), " 2");
+        assert_eq!(e.line(), 1);
+        assert_eq!(e.column(), 6);
     }
 
     #[test]
-    fn element_with_entity_reference() {
-        let package = quick_parse("<math>I &lt;3 math</math>");
-        let doc = package.as_document();
-        let math = top(&doc);
-        let text1 = math.children()[0].text().unwrap();
-        let text2 = math.children()[1].text().unwrap();
-        let text3 = math.children()[2].text().unwrap();
+    fn failure_is_an_error() {
+        fn __assert_well_behaved_error()
+        where
+            Error: ::std::error::Error + Send + Sync + 'static,
+        {
+        }
+    }
 
-        assert_eq!(text1.text(), "I ");
-        assert_eq!(text2.text(), "<");
-        assert_eq!(text3.text(), "3 math");
+    #[derive(Default)]
+    struct RecordingSink {
+        events: Vec<String>,
     }
 
-    #[test]
-    fn element_with_mixed_children() {
-        let package = quick_parse("<hello>to <!--fixme--><a><![CDATA[the]]></a><?world?></hello>");
-        let doc = package.as_document();
-        let hello = top(&doc);
+    impl<'x> ParserSink<'x> for RecordingSink {
+        fn xml_declaration(
+            &mut self,
+            version: &'x str,
+            encoding: Option<&'x str>,
+            standalone: Option<bool>,
+        ) {
+            self.events.push(format!(
+                "declaration {} {:?} {:?}",
+                version, encoding, standalone
+            ));
+        }
 
-        let text = hello.children()[0].text().unwrap();
-        let comment = hello.children()[1].comment().unwrap();
-        let element = hello.children()[2].element().unwrap();
-        let pi = hello.children()[3].processing_instruction().unwrap();
+        fn element_start(&mut self, n: PrefixedName<'x>) {
+            self.events.push(format!("start {}", n));
+        }
 
-        assert_eq!(text.text(), "to ");
-        assert_eq!(comment.text(), "fixme");
-        assert_qname_eq!(element.name(), "a");
-        assert_eq!(pi.target(), "world");
-    }
+        fn attribute(&mut self, n: PrefixedName<'x>, value: &str) {
+            self.events.push(format!("attribute {}={}", n, value));
+        }
 
-    // TODO: untested errors
-    //
-    // versionnumber
-    // prefixedname
-    // decimal chars
-    // hex chars
-    // chardata
-    // cdata
-    // commentbody
-    // pinstructionvalue
+        fn attributes_end(&mut self) {
+            self.events.push("attributes end".to_string());
+        }
 
-    macro_rules! assert_parse_failure {
-        ($actual:expr, $pos:expr, $($err:expr),+) => {
-            {
-                let errors = vec![$($err),+];
-                let expected = Err(Error::from(($pos, errors)));
-                assert_eq!($actual, expected);
-            }
+        fn element_end(&mut self, n: PrefixedName<'x>) {
+            self.events.push(format!("end {}", n));
+        }
+
+        fn text(&mut self, text: &str) {
+            self.events.push(format!("text {}", text));
         }
-    }
 
-    #[test]
-    fn failure_invalid_encoding() {
-        use super::SpecificError::*;
+        fn comment(&mut self, text: &str) {
+            self.events.push(format!("comment {}", text));
+        }
 
-        let r = full_parse("<?xml version='1.0' encoding='8BIT' ?><hi/>");
+        fn processing_instruction(&mut self, target: &'x str, value: Option<&str>) {
+            self.events.push(format!("pi {} {:?}", target, value));
+        }
+    }
 
-        assert_parse_failure!(r, 30, ExpectedEncoding);
+    fn sink_parse(xml: &str) -> Result<Vec<String>, Error> {
+        let mut sink = RecordingSink::default();
+        Parser::new().parse_with_sink(xml, &mut sink)?;
+        Ok(sink.events)
     }
 
     #[test]
-    fn failure_invalid_standalone() {
-        use super::SpecificError::*;
-
-        let r = full_parse("<?xml version='1.0' standalone='invalid'?><hello/>");
+    fn sink_receives_normalized_text_and_attributes() {
+        let events = sink_parse("<a b='1\r\n2'>x\ry</a>").unwrap();
 
-        assert_parse_failure!(r, 32, ExpectedYesNo);
+        assert_eq!(
+            events,
+            vec![
+                "start a",
+                "attribute b=1 2",
+                "attributes end",
+                "text x\ny",
+                "end a",
+            ]
+        );
     }
 
     #[test]
-    fn failure_no_open_brace() {
-        use super::SpecificError::*;
-
-        let r = full_parse("hi />");
+    fn sink_receives_normalized_cdata_comments_and_processing_instructions() {
+        let events = sink_parse("<a><![CDATA[x\r\ny\rz]]><!--c\r\nd--><?pi v\r\nw?></a>").unwrap();
 
-        assert_parse_failure!(
-            r,
-            0,
-            Expected("<?xml"),
-            ExpectedComment,
-            ExpectedProcessingInstruction,
-            ExpectedWhitespace,
-            ExpectedElement
+        assert_eq!(
+            events,
+            vec![
+                "start a",
+                "attributes end",
+                "text x\ny\nz",
+                "comment c\nd",
+                "pi pi Some(\"v\\nw\")",
+                "end a",
+            ]
         );
     }
 
     #[test]
-    fn failure_unclosed_tag() {
-        use super::SpecificError::*;
-
-        let r = full_parse("<hi");
+    fn sink_receives_events_in_document_order() {
+        let events = sink_parse(
+            "<?xml version='1.0' encoding='UTF-8'?><!--c--><a x:b='1 &lt; 2' xmlns:x='y'>hi<c/><?pi v?></a>",
+        )
+        .unwrap();
 
-        assert_parse_failure!(
-            r,
-            3,
-            ExpectedWhitespace,
-            ExpectedElementSelfClosed,
-            ExpectedElementEnd
+        assert_eq!(
+            events,
+            vec![
+                "declaration 1.0 Some(\"UTF-8\") None",
+                "comment c",
+                "start a",
+                "attribute x:b=1 < 2",
+                "attribute xmlns:x=y",
+                "attributes end",
+                "text hi",
+                "start c",
+                "attributes end",
+                "end c",
+                "pi pi Some(\"v\")",
+                "end a",
+            ]
         );
     }
 
     #[test]
-    fn failure_unexpected_space() {
-        use super::SpecificError::*;
-
-        let r = full_parse("<hi / >");
+    fn sink_receives_references_as_text_by_default() {
+        let events = sink_parse("<a>1 &gt; <![CDATA[0]]></a>").unwrap();
 
-        assert_parse_failure!(
-            r,
-            4,
-            ExpectedAttribute,
-            ExpectedElementSelfClosed,
-            ExpectedElementEnd
+        assert_eq!(
+            events,
+            vec![
+                "start a",
+                "attributes end",
+                "text 1 ",
+                "text >",
+                "text  ",
+                "text 0",
+                "end a",
+            ]
         );
     }
 
     #[test]
-    fn failure_attribute_without_open_quote() {
-        use super::SpecificError::*;
-
-        let r = full_parse("<hi oops=value' />");
+    fn sink_does_not_resolve_namespace_prefixes() {
+        let events = sink_parse("<x:a/>").unwrap();
 
-        assert_parse_failure!(r, 9, ExpectedOpeningQuote("\'"), ExpectedOpeningQuote("\""));
+        assert_eq!(events, vec!["start x:a", "attributes end", "end x:a"]);
     }
 
     #[test]
-    fn failure_attribute_without_close_quote() {
+    fn sink_failure_end_tag_does_not_match() {
         use super::SpecificError::*;
 
-        let r = full_parse("<hi oops='value />");
+        let r = sink_parse("<a></b>");
 
-        assert_parse_failure!(
-            r,
-            18,
-            ExpectedNamedReference,
-            ExpectedDecimalReference,
-            ExpectedAttributeValue,
-            ExpectedHexReference,
-            ExpectedClosingQuote("\'")
-        );
+        assert_parse_failure!(r, 5, MismatchedElementEndName);
     }
 
     #[test]
-    fn failure_unclosed_attribute_and_tag() {
+    fn sink_failure_unclosed_element() {
         use super::SpecificError::*;
 
-        let r = full_parse("<hi oops='value");
+        let r = sink_parse("<a>");
 
-        assert_parse_failure!(
-            r,
-            15,
-            ExpectedNamedReference,
-            ExpectedDecimalReference,
-            ExpectedAttributeValue,
-            ExpectedHexReference,
-            ExpectedClosingQuote("\'")
-        );
+        assert_parse_failure!(r, 3, UnclosedElement);
     }
 
     #[test]
-    fn failure_nested_unclosed_tag() {
-        use super::SpecificError::*;
+    fn unescape_borrows_text_without_references() {
+        let text = "plain < text > \"'";
 
-        let r = full_parse("<hi><oops</hi>");
+        match super::unescape(text) {
+            Ok(Cow::Borrowed(t)) => assert_eq!(t, text),
+            other => panic!("Expected borrowed text, got {:?}", other),
+        }
+    }
 
-        assert_parse_failure!(
-            r,
-            9,
-            ExpectedWhitespace,
-            ExpectedElementSelfClosed,
-            ExpectedElementEnd
+    #[test]
+    fn unescape_replaces_references() {
+        assert_eq!(super::unescape("&amp;&#65;").unwrap(), "&A");
+        assert_eq!(
+            super::unescape("a &lt;&gt;&apos;&quot; &#x1F600; b").unwrap(),
+            "a <>'\" \u{1F600} b"
         );
     }
 
     #[test]
-    fn failure_missing_close_tag() {
+    fn unescape_failure_unknown_entity() {
         use super::SpecificError::*;
 
-        let r = full_parse("<hi>wow");
-
-        assert_parse_failure!(r, 7, UnclosedElement);
+        assert_parse_failure!(super::unescape("ab&nbsp;"), 3, UnknownNamedReference);
     }
 
     #[test]
-    fn failure_nested_unexpected_space() {
+    fn unescape_failure_invalid_character_reference() {
         use super::SpecificError::*;
 
-        let r = full_parse("<hi><oops / ></hi>");
-
-        assert_parse_failure!(
-            r,
-            10,
-            ExpectedAttribute,
-            ExpectedElementSelfClosed,
-            ExpectedElementEnd
-        );
+        assert_parse_failure!(super::unescape("&#0;"), 2, InvalidDecimalReference);
     }
 
     #[test]
-    fn failure_malformed_entity_reference() {
-        use super::SpecificError::*;
+    fn unescape_failure_bare_ampersand() {
+        let r = super::unescape("a & b");
 
-        let r = full_parse("<hi>Entity: &;</hi>");
+        assert_eq!(r.map_err(|e| e.location()), Err(3));
+    }
 
-        assert_parse_failure!(r, 13, ExpectedNamedReferenceValue);
+    #[test]
+    fn decode_reference_of_each_kind() {
+        assert_eq!(super::decode_reference("&#65;").unwrap(), "A");
+        assert_eq!(super::decode_reference("&#x1f600;").unwrap(), "\u{1F600}");
+
+        let named = ["&amp;", "&lt;", "&gt;", "&apos;", "&quot;"];
+        let decoded: Vec<_> = named
+            .iter()
+            .map(|r| super::decode_reference(r).unwrap())
+            .collect();
+        assert_eq!(decoded, ["&", "<", ">", "'", "\""]);
     }
 
     #[test]
-    fn failure_nested_malformed_entity_reference() {
+    fn decode_reference_failure_unknown_entity() {
         use super::SpecificError::*;
 
-        let r = full_parse("<hi><bye>Entity: &;</bye></hi>");
-
-        assert_parse_failure!(r, 18, ExpectedNamedReferenceValue);
+        assert_parse_failure!(super::decode_reference("&nbsp;"), 1, UnknownNamedReference);
     }
 
     #[test]
-    fn failure_nested_attribute_without_open_quote() {
+    fn decode_reference_failure_invalid_code_point() {
         use super::SpecificError::*;
 
-        let r = full_parse("<hi><bye oops=value' /></hi>");
-
+        assert_parse_failure!(super::decode_reference("&#xD800;"), 3, InvalidHexReference);
         assert_parse_failure!(
-            r,
-            14,
-            ExpectedOpeningQuote("\'"),
-            ExpectedOpeningQuote("\"")
+            super::decode_reference("&#1114112;"),
+            2,
+            InvalidDecimalReference
         );
     }
 
     #[test]
-    fn failure_nested_attribute_without_close_quote() {
+    fn decode_reference_failure_text_after_the_reference() {
         use super::SpecificError::*;
 
-        let r = full_parse("<hi><bye oops='value /></hi>");
-
-        assert_parse_failure!(
-            r,
-            23,
-            ExpectedNamedReference,
-            ExpectedDecimalReference,
-            ExpectedAttributeValue,
-            ExpectedHexReference,
-            ExpectedClosingQuote("\'")
-        );
+        assert_parse_failure!(super::decode_reference("&amp;x"), 5, TrailingText);
     }
 
     #[test]
-    fn failure_nested_unclosed_attribute_and_tag() {
-        use super::SpecificError::*;
+    fn decode_reference_failure_not_a_reference() {
+        let r = super::decode_reference("amp;");
 
-        let r = full_parse("<hi><bye oops='value</hi>");
+        assert_eq!(r.map_err(|e| e.location()), Err(0));
+    }
 
-        assert_parse_failure!(
-            r,
-            20,
-            ExpectedNamedReference,
-            ExpectedDecimalReference,
-            ExpectedAttributeValue,
-            ExpectedHexReference,
-            ExpectedClosingQuote("\'")
+    #[test]
+    fn errors_display_the_expected_literal() {
+        let error = super::parse("<a b></a>").unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "XML parsing error at line 1, column 5: expected \"=\""
         );
     }
 
     #[test]
-    fn failure_pi_target_as_xml() {
-        use super::SpecificError::*;
-
-        let r = full_parse("<a><?xml?></a>");
+    fn errors_display_alternatives_separated_by_or() {
+        let error = super::parse("<a").unwrap_err();
 
-        assert_parse_failure!(r, 5, InvalidProcessingInstructionTarget);
+        assert_eq!(
+            error.to_string(),
+            "XML parsing error at line 1, column 3: expected element end or expected element self closed or expected whitespace"
+        );
     }
 
     #[test]
-    fn failure_end_tag_does_not_match() {
-        use super::SpecificError::*;
+    fn errors_display_a_snippet_of_the_source() {
+        let xml = "<a>\r\n\t<b x='1' x='2'/>\r\n</a>";
+        let error = super::parse(xml).unwrap_err();
 
-        let r = full_parse("<a></b>");
-
-        assert_parse_failure!(r, 5, MismatchedElementEndName);
+        assert_eq!(
+            error.with_source(xml).to_string(),
+            "XML parsing error at line 2, column 11: duplicate attribute\n\t<b x='1' x='2'/>\n\t         ^"
+        );
     }
 
     #[test]
-    fn failure_invalid_decimal_reference() {
-        use super::SpecificError::*;
-
-        let r = full_parse("<a>&#99999999;</a>");
+    fn errors_not_located_display_the_byte_offset() {
+        let error = Error::new(4, SpecificError::Expected("="));
 
-        assert_parse_failure!(r, 5, InvalidDecimalReference);
+        assert_eq!(error.line(), 0);
+        assert_eq!(
+            error.to_string(),
+            "XML parsing error at byte 4: expected \"=\""
+        );
     }
 
     #[test]
-    fn failure_invalid_hex_reference() {
-        use super::SpecificError::*;
-
-        let r = full_parse("<a>&#x99999999;</a>");
+    fn errors_display_no_snippet_for_other_source() {
+        let error = super::parse("<a></b>").unwrap_err();
 
-        assert_parse_failure!(r, 6, InvalidHexReference);
+        assert_eq!(
+            error.with_source("").to_string(),
+            "XML parsing error at line 1, column 6: mismatched element end name"
+        );
     }
 
     #[test]
-    fn failure_unknown_named_reference() {
-        use super::SpecificError::*;
+    fn error_kind_mismatched_end_tag_names_both_elements() {
+        let error = super::parse("<a><p:b xmlns:p='urn:p'></a>").unwrap_err();
 
-        let r = full_parse("<a>&fake;</a>");
+        assert_eq!(
+            *error.kind(),
+            ErrorKind::MismatchedEndTag {
+                expected: "p:b".to_owned(),
+                found: "a".to_owned(),
+            }
+        );
+        assert_eq!(error.kind().to_string(), "expected </p:b> but found </a>");
+    }
 
-        assert_parse_failure!(r, 4, UnknownNamedReference);
+    #[test]
+    fn error_kind_mismatched_end_tag_from_sink() {
+        let error = sink_parse("<a><b></a>").unwrap_err();
+
+        assert_eq!(
+            *error.kind(),
+            ErrorKind::MismatchedEndTag {
+                expected: "b".to_owned(),
+                found: "a".to_owned(),
+            }
+        );
     }
 
     #[test]
-    fn failure_duplicate_attribute() {
-        use super::SpecificError::*;
+    fn error_kind_mismatched_end_tag_when_lenient() {
+        let (_, errors) = Parser::new().parse_lenient("<a><b></c></a>");
 
-        let r = full_parse("<a b='c' b='d'/>");
+        assert_eq!(
+            *errors[0].kind(),
+            ErrorKind::MismatchedEndTag {
+                expected: "b".to_owned(),
+                found: "c".to_owned(),
+            }
+        );
+    }
 
-        assert_parse_failure!(r, 9, DuplicateAttribute);
+    #[test]
+    fn error_kind_unexpected_eof() {
+        assert_eq!(
+            *super::parse("<a>").unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+        assert_eq!(
+            *super::parse("<a").unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
     }
 
     #[test]
-    fn failure_redefined_namespace() {
-        use super::SpecificError::*;
+    fn error_kind_unknown_entity() {
+        let error = super::parse("<a>&nbsp;</a>").unwrap_err();
 
-        let r = full_parse("<a xmlns:b='c' xmlns:b='d'/>");
+        assert_eq!(*error.kind(), ErrorKind::UnknownEntity("nbsp".to_owned()));
+        assert_eq!(error.kind().to_string(), "unknown entity &nbsp;");
+    }
 
-        assert_parse_failure!(r, 15, RedefinedNamespace);
+    #[test]
+    fn error_kind_unknown_prefix() {
+        let error = super::parse("<p:a/>").unwrap_err();
+
+        assert_eq!(*error.kind(), ErrorKind::UnknownPrefix("p".to_owned()));
     }
 
     #[test]
-    fn failure_redefined_default_namespace() {
-        use super::SpecificError::*;
+    fn error_kind_invalid_char_reference() {
+        assert_eq!(
+            *super::parse("<a>&#0;</a>").unwrap_err().kind(),
+            ErrorKind::InvalidCharReference
+        );
+        assert_eq!(
+            *super::parse("<a b='&#xD800;'/>").unwrap_err().kind(),
+            ErrorKind::InvalidCharReference
+        );
+    }
 
-        let r = full_parse("<a xmlns='a' xmlns='b'/>");
+    #[test]
+    fn error_kind_duplicate_attribute() {
+        let error = super::parse("<a b='1' b='2'/>").unwrap_err();
 
-        assert_parse_failure!(r, 13, RedefinedDefaultNamespace);
+        assert_eq!(*error.kind(), ErrorKind::DuplicateAttribute("b".to_owned()));
     }
 
     #[test]
-    fn failure_empty_namespace() {
-        use super::SpecificError::*;
+    fn error_kind_trailing_content() {
+        assert_eq!(
+            *super::parse("<a/><b/>").unwrap_err().kind(),
+            ErrorKind::TrailingContent
+        );
+        assert_eq!(
+            *super::decode_reference("&amp;x").unwrap_err().kind(),
+            ErrorKind::TrailingContent
+        );
+    }
 
-        let r = full_parse("<a xmlns:b=''/>");
+    #[test]
+    fn error_kind_invalid_encoding() {
+        let error = Parser::new().parse_bytes(b"<a>\xFF</a>").unwrap_err();
 
-        assert_parse_failure!(r, 3, EmptyNamespace);
+        assert_eq!(*error.kind(), ErrorKind::InvalidEncoding);
     }
 
     #[test]
-    fn failure_unknown_attribute_namespace_prefix() {
-        use super::SpecificError::*;
+    fn error_kind_limit_exceeded() {
+        let parser = Parser::new().set_max_depth(Some(1));
 
-        let r = full_parse("<a b:foo='a'/>");
+        let error = parser.parse("<a><b/></a>").unwrap_err();
 
-        assert_parse_failure!(r, 3, UnknownNamespacePrefix);
+        assert_eq!(*error.kind(), ErrorKind::LimitExceeded);
     }
 
     #[test]
-    fn failure_unknown_element_namespace_prefix() {
-        use super::SpecificError::*;
+    fn error_kind_syntax() {
+        let error = super::parse("<a b></a>").unwrap_err();
 
-        let r = full_parse("<b:a/>");
+        assert_eq!(*error.kind(), ErrorKind::Syntax);
+    }
+}
 
-        assert_parse_failure!(r, 1, UnknownNamespacePrefix);
+#[cfg(feature = "unstable")]
+mod bench {
+    extern crate test;
+
+    use self::test::Bencher;
+
+    use super::parse;
+
+    #[bench]
+    fn large_text(b: &mut Bencher) {
+        let xml = format!("<a>{}</a>", "Some text, with no markup.\n".repeat(40_000));
+        b.iter(|| parse(&xml).expect("Failed to parse"));
+        b.bytes = xml.len() as u64;
     }
 
-    #[test]
-    fn failure_is_an_error() {
-        fn __assert_well_behaved_error()
-        where
-            Error: ::std::error::Error + Send + Sync + 'static,
-        {
-        }
+    #[bench]
+    fn large_attribute_value(b: &mut Bencher) {
+        let xml = format!("<a b='{}'/>", "a value\twith\nwhitespace ".repeat(40_000));
+        b.iter(|| parse(&xml).expect("Failed to parse"));
+        b.bytes = xml.len() as u64;
+    }
+
+    #[bench]
+    fn repeated_text(b: &mut Bencher) {
+        let xml = format!("<a>{}</a>", "<b>\n  repeated\n</b>".repeat(10_000));
+        b.iter(|| parse(&xml).expect("Failed to parse"));
+        b.bytes = xml.len() as u64;
+    }
+
+    #[bench]
+    fn many_small_elements(b: &mut Bencher) {
+        let xml = format!("<a>{}</a>", "<b c='d'>text &amp; more</b>".repeat(10_000));
+        b.iter(|| parse(&xml).expect("Failed to parse"));
+        b.bytes = xml.len() as u64;
     }
 }
@@ -1,7 +1,10 @@
-use super::{lazy_hash_map::LazyHashMap, QName};
+use super::QName;
 
-use crate::string_pool::{InternedString, StringPool};
-use std::{marker::PhantomData, slice};
+use crate::{
+    lazy_hash_map::LazyHashMap,
+    string_pool::{InternedString, StringPool},
+};
+use std::{cell::Cell, marker::PhantomData, mem, ops::Range, slice};
 use typed_arena::Arena;
 
 struct InternedQName {
@@ -20,6 +23,51 @@ impl InternedQName {
 
 pub struct Root {
     children: Vec<ChildOfRoot>,
+    version: Option<InternedString>,
+    encoding: Option<InternedString>,
+    standalone: Option<bool>,
+    document_type: Option<*mut DocumentType>,
+    document_type_position: usize,
+}
+
+impl Root {
+    pub fn version(&self) -> Option<&str> {
+        self.version.map(|v| v.as_slice())
+    }
+    pub fn encoding(&self) -> Option<&str> {
+        self.encoding.map(|e| e.as_slice())
+    }
+    pub fn standalone(&self) -> Option<bool> {
+        self.standalone
+    }
+    pub fn document_type(&self) -> Option<*mut DocumentType> {
+        self.document_type
+    }
+    pub fn document_type_position(&self) -> usize {
+        self.document_type_position
+    }
+}
+
+pub struct DocumentType {
+    name: InternedString,
+    public_id: Option<InternedString>,
+    system_id: Option<InternedString>,
+    internal_subset: Option<InternedString>,
+}
+
+impl DocumentType {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn public_id(&self) -> Option<&str> {
+        self.public_id.map(|p| p.as_slice())
+    }
+    pub fn system_id(&self) -> Option<&str> {
+        self.system_id.map(|s| s.as_slice())
+    }
+    pub fn internal_subset(&self) -> Option<&str> {
+        self.internal_subset.map(|s| s.as_slice())
+    }
 }
 
 pub struct Element {
@@ -29,7 +77,13 @@ pub struct Element {
     children: Vec<ChildOfElement>,
     parent: Option<ParentOfChild>,
     attributes: Vec<*mut Attribute>,
-    prefix_to_namespace: LazyHashMap<InternedString, InternedString>,
+    /// In the order the prefixes were first registered, so that
+    /// enumerating them is deterministic.
+    prefix_to_namespace: Vec<(InternedString, InternedString)>,
+    /// Where each prefix is in `prefix_to_namespace`
+    prefix_positions: LazyHashMap<InternedString, usize>,
+    source_span: Option<Range<usize>>,
+    was_self_closing: Option<bool>,
 }
 
 impl Element {
@@ -42,6 +96,22 @@ impl Element {
     pub fn preferred_prefix(&self) -> Option<&str> {
         self.preferred_prefix.map(|p| p.as_slice())
     }
+    fn registered_namespace_uri(&self, prefix: &str) -> Option<InternedString> {
+        self.prefix_positions
+            .get(prefix)
+            .map(|&i| self.prefix_to_namespace[i].1)
+    }
+    pub fn registered_prefixes(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.prefix_to_namespace
+            .iter()
+            .map(|(prefix, uri)| (prefix.as_slice(), uri.as_slice()))
+    }
+    pub fn source_span(&self) -> Option<Range<usize>> {
+        self.source_span.clone()
+    }
+    pub fn was_self_closing(&self) -> Option<bool> {
+        self.was_self_closing
+    }
 }
 
 pub struct Attribute {
@@ -65,30 +135,47 @@ impl Attribute {
 
 pub struct Text {
     text: InternedString,
+    cdata: bool,
+    ignorable_whitespace: bool,
     parent: Option<*mut Element>,
+    source_span: Option<Range<usize>>,
 }
 
 impl Text {
     pub fn text(&self) -> &str {
         &self.text
     }
+    pub fn is_cdata(&self) -> bool {
+        self.cdata
+    }
+    pub fn is_ignorable_whitespace(&self) -> bool {
+        self.ignorable_whitespace
+    }
+    pub fn source_span(&self) -> Option<Range<usize>> {
+        self.source_span.clone()
+    }
 }
 
 pub struct Comment {
     text: InternedString,
     parent: Option<ParentOfChild>,
+    source_span: Option<Range<usize>>,
 }
 
 impl Comment {
     pub fn text(&self) -> &str {
         &self.text
     }
+    pub fn source_span(&self) -> Option<Range<usize>> {
+        self.source_span.clone()
+    }
 }
 
 pub struct ProcessingInstruction {
     target: InternedString,
     value: Option<InternedString>,
     parent: Option<ParentOfChild>,
+    source_span: Option<Range<usize>>,
 }
 
 impl ProcessingInstruction {
@@ -98,6 +185,9 @@ impl ProcessingInstruction {
     pub fn value(&self) -> Option<&str> {
         self.value.map(|v| v.as_slice())
     }
+    pub fn source_span(&self) -> Option<Range<usize>> {
+        self.source_span.clone()
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -118,9 +208,15 @@ impl ChildOfRoot {
     fn replace_parent(&self, parent: *mut Root) {
         match *self {
             ChildOfRoot::Element(n) => {
+                // The root has at most one element, which this replaces
                 let parent_r = unsafe { &mut *parent };
+                for old in &parent_r.children {
+                    if old.is_element() && old != self {
+                        old.remove_parent();
+                    }
+                }
+                parent_r.children.retain(|c| !c.is_element() || c == self);
                 let n = unsafe { &mut *n };
-                parent_r.children.retain(|c| !c.is_element());
                 replace_parent(*self, ParentOfChild::Root(parent), &mut n.parent);
             }
             ChildOfRoot::Comment(n) => {
@@ -289,6 +385,29 @@ impl From<ChildOfRoot> for ChildOfElement {
     }
 }
 
+/// The number of nodes of each kind allocated in a `Package`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct NodeCounts {
+    pub elements: usize,
+    pub attributes: usize,
+    pub texts: usize,
+    pub comments: usize,
+    pub processing_instructions: usize,
+    pub document_types: usize,
+}
+
+impl NodeCounts {
+    /// The number of nodes of every kind
+    pub fn total(&self) -> usize {
+        self.elements
+            + self.attributes
+            + self.texts
+            + self.comments
+            + self.processing_instructions
+            + self.document_types
+    }
+}
+
 pub struct Storage {
     strings: StringPool,
     roots: Arena<Root>,
@@ -297,6 +416,8 @@ pub struct Storage {
     texts: Arena<Text>,
     comments: Arena<Comment>,
     processing_instructions: Arena<ProcessingInstruction>,
+    document_types: Arena<DocumentType>,
+    node_counts: Cell<NodeCounts>,
 }
 
 impl Default for Storage {
@@ -309,6 +430,8 @@ impl Default for Storage {
             texts: Arena::new(),
             comments: Arena::new(),
             processing_instructions: Arena::new(),
+            document_types: Arena::new(),
+            node_counts: Cell::new(NodeCounts::default()),
         }
     }
 }
@@ -330,9 +453,39 @@ impl Storage {
         }
     }
 
+    pub fn string_bytes(&self) -> usize {
+        self.strings.interned_bytes()
+    }
+
+    pub fn node_counts(&self) -> NodeCounts {
+        self.node_counts.get()
+    }
+
+    fn count(&self, f: impl FnOnce(&mut NodeCounts)) {
+        let mut counts = self.node_counts.get();
+        f(&mut counts);
+        self.node_counts.set(counts);
+    }
+
+    pub fn allocated_bytes(&self) -> usize {
+        let counts = self.node_counts.get();
+        counts.elements * mem::size_of::<Element>()
+            + counts.attributes * mem::size_of::<Attribute>()
+            + counts.texts * mem::size_of::<Text>()
+            + counts.comments * mem::size_of::<Comment>()
+            + counts.processing_instructions * mem::size_of::<ProcessingInstruction>()
+            + counts.document_types * mem::size_of::<DocumentType>()
+            + self.strings.allocated_bytes()
+    }
+
     pub fn create_root(&self) -> *mut Root {
         self.roots.alloc(Root {
             children: Vec::new(),
+            version: None,
+            encoding: None,
+            standalone: None,
+            document_type: None,
+            document_type_position: 0,
         })
     }
 
@@ -343,6 +496,7 @@ impl Storage {
         let name = name.into();
         let name = self.intern_qname(name);
 
+        self.count(|c| c.elements += 1);
         self.elements.alloc(Element {
             name,
             default_namespace_uri: None,
@@ -350,7 +504,10 @@ impl Storage {
             children: Vec::new(),
             parent: None,
             attributes: Vec::new(),
-            prefix_to_namespace: LazyHashMap::new(),
+            prefix_to_namespace: Vec::new(),
+            prefix_positions: LazyHashMap::new(),
+            source_span: None,
+            was_self_closing: None,
         })
     }
 
@@ -362,6 +519,7 @@ impl Storage {
         let name = self.intern_qname(name);
         let value = self.intern(value);
 
+        self.count(|c| c.attributes += 1);
         self.attributes.alloc(Attribute {
             name,
             preferred_prefix: None,
@@ -373,13 +531,25 @@ impl Storage {
     pub fn create_text(&self, text: &str) -> *mut Text {
         let text = self.intern(text);
 
-        self.texts.alloc(Text { text, parent: None })
+        self.count(|c| c.texts += 1);
+        self.texts.alloc(Text {
+            text,
+            cdata: false,
+            ignorable_whitespace: false,
+            parent: None,
+            source_span: None,
+        })
     }
 
     pub fn create_comment(&self, text: &str) -> *mut Comment {
         let text = self.intern(text);
 
-        self.comments.alloc(Comment { text, parent: None })
+        self.count(|c| c.comments += 1);
+        self.comments.alloc(Comment {
+            text,
+            parent: None,
+            source_span: None,
+        })
     }
 
     pub fn create_processing_instruction(
@@ -390,13 +560,86 @@ impl Storage {
         let target = self.intern(target);
         let value = value.map(|v| self.intern(v));
 
+        self.count(|c| c.processing_instructions += 1);
         self.processing_instructions.alloc(ProcessingInstruction {
             target,
             value,
             parent: None,
+            source_span: None,
+        })
+    }
+
+    pub fn create_document_type(&self, name: &str) -> *mut DocumentType {
+        let name = self.intern(name);
+
+        self.count(|c| c.document_types += 1);
+        self.document_types.alloc(DocumentType {
+            name,
+            public_id: None,
+            system_id: None,
+            internal_subset: None,
         })
     }
 
+    pub fn root_set_version(&self, root: *mut Root, version: Option<&str>) {
+        let version = version.map(|v| self.intern(v));
+        let root_r = unsafe { &mut *root };
+        root_r.version = version;
+    }
+
+    pub fn root_set_encoding(&self, root: *mut Root, encoding: Option<&str>) {
+        let encoding = encoding.map(|e| self.intern(e));
+        let root_r = unsafe { &mut *root };
+        root_r.encoding = encoding;
+    }
+
+    pub fn root_set_standalone(&self, root: *mut Root, standalone: Option<bool>) {
+        let root_r = unsafe { &mut *root };
+        root_r.standalone = standalone;
+    }
+
+    pub fn root_set_document_type(
+        &self,
+        root: *mut Root,
+        document_type: Option<*mut DocumentType>,
+    ) {
+        let root_r = unsafe { &mut *root };
+        root_r.document_type = document_type;
+    }
+
+    pub fn root_set_document_type_position(&self, root: *mut Root, position: usize) {
+        let root_r = unsafe { &mut *root };
+        root_r.document_type_position = position;
+    }
+
+    pub fn document_type_set_name(&self, document_type: *mut DocumentType, name: &str) {
+        let name = self.intern(name);
+        let document_type_r = unsafe { &mut *document_type };
+        document_type_r.name = name;
+    }
+
+    pub fn document_type_set_public_id(&self, document_type: *mut DocumentType, id: Option<&str>) {
+        let id = id.map(|i| self.intern(i));
+        let document_type_r = unsafe { &mut *document_type };
+        document_type_r.public_id = id;
+    }
+
+    pub fn document_type_set_system_id(&self, document_type: *mut DocumentType, id: Option<&str>) {
+        let id = id.map(|i| self.intern(i));
+        let document_type_r = unsafe { &mut *document_type };
+        document_type_r.system_id = id;
+    }
+
+    pub fn document_type_set_internal_subset(
+        &self,
+        document_type: *mut DocumentType,
+        subset: Option<&str>,
+    ) {
+        let subset = subset.map(|s| self.intern(s));
+        let document_type_r = unsafe { &mut *document_type };
+        document_type_r.internal_subset = subset;
+    }
+
     pub fn element_set_name<'n, N>(&self, element: *mut Element, name: N)
     where
         N: Into<QName<'n>>,
@@ -416,7 +659,15 @@ impl Storage {
         let prefix = self.intern(prefix);
         let namespace_uri = self.intern(namespace_uri);
         let element_r = unsafe { &mut *element };
-        element_r.prefix_to_namespace.insert(prefix, namespace_uri);
+        let existing = element_r.prefix_positions.get(&prefix[..]).cloned();
+        match existing {
+            Some(i) => element_r.prefix_to_namespace[i].1 = namespace_uri,
+            None => {
+                let i = element_r.prefix_to_namespace.len();
+                element_r.prefix_to_namespace.push((prefix, namespace_uri));
+                element_r.prefix_positions.insert(prefix, i);
+            }
+        }
     }
 
     pub fn element_set_default_namespace_uri(
@@ -435,6 +686,26 @@ impl Storage {
         element_r.preferred_prefix = prefix;
     }
 
+    pub fn element_set_source_span(&self, element: *mut Element, span: Option<Range<usize>>) {
+        let element_r = unsafe { &mut *element };
+        element_r.source_span = span;
+    }
+
+    pub fn element_set_was_self_closing(&self, element: *mut Element, self_closing: Option<bool>) {
+        let element_r = unsafe { &mut *element };
+        element_r.was_self_closing = self_closing;
+    }
+
+    pub fn attribute_set_name<'n, N>(&self, attribute: *mut Attribute, name: N)
+    where
+        N: Into<QName<'n>>,
+    {
+        let name = name.into();
+        let name = self.intern_qname(name);
+        let attribute_r = unsafe { &mut *attribute };
+        attribute_r.name = name;
+    }
+
     pub fn attribute_set_preferred_prefix(&self, attribute: *mut Attribute, prefix: Option<&str>) {
         let prefix = prefix.map(|p| self.intern(p));
         let attribute_r = unsafe { &mut *attribute };
@@ -447,12 +718,32 @@ impl Storage {
         text_r.text = new_text;
     }
 
+    pub fn text_set_cdata(&self, text: *mut Text, cdata: bool) {
+        let text_r = unsafe { &mut *text };
+        text_r.cdata = cdata;
+    }
+
+    pub fn text_set_ignorable_whitespace(&self, text: *mut Text, ignorable: bool) {
+        let text_r = unsafe { &mut *text };
+        text_r.ignorable_whitespace = ignorable;
+    }
+
+    pub fn text_set_source_span(&self, text: *mut Text, span: Option<Range<usize>>) {
+        let text_r = unsafe { &mut *text };
+        text_r.source_span = span;
+    }
+
     pub fn comment_set_text(&self, comment: *mut Comment, new_text: &str) {
         let new_text = self.intern(new_text);
         let comment_r = unsafe { &mut *comment };
         comment_r.text = new_text;
     }
 
+    pub fn comment_set_source_span(&self, comment: *mut Comment, span: Option<Range<usize>>) {
+        let comment_r = unsafe { &mut *comment };
+        comment_r.source_span = span;
+    }
+
     pub fn processing_instruction_set_target(
         &self,
         pi: *mut ProcessingInstruction,
@@ -472,6 +763,15 @@ impl Storage {
         let pi_r = unsafe { &mut *pi };
         pi_r.value = new_value;
     }
+
+    pub fn processing_instruction_set_source_span(
+        &self,
+        pi: *mut ProcessingInstruction,
+        span: Option<Range<usize>>,
+    ) {
+        let pi_r = unsafe { &mut *pi };
+        pi_r.source_span = span;
+    }
 }
 
 pub struct Connections {
@@ -532,14 +832,62 @@ impl Connections {
         parent_r.children.push(child);
     }
 
+    pub fn insert_root_child<C>(&self, index: usize, child: C)
+    where
+        C: Into<ChildOfRoot>,
+    {
+        let child = child.into();
+        let parent_r = unsafe { &mut *self.root };
+
+        let len = parent_r.children.iter().filter(|&&x| x != child).count();
+        assert!(
+            index <= len,
+            "insertion index (is {}) should be <= number of children (is {})",
+            index,
+            len
+        );
+        assert!(
+            !child.is_element()
+                || parent_r
+                    .children
+                    .iter()
+                    .all(|&x| !x.is_element() || x == child),
+            "the root already has an element child"
+        );
+
+        child.replace_parent(self.root);
+        parent_r.children.insert(index, child);
+    }
+
+    pub fn insert_element_child<C>(&self, parent: *mut Element, index: usize, child: C)
+    where
+        C: Into<ChildOfElement>,
+    {
+        let child = child.into();
+        let parent_r = unsafe { &mut *parent };
+
+        let len = parent_r.children.iter().filter(|&&x| x != child).count();
+        assert!(
+            index <= len,
+            "insertion index (is {}) should be <= number of children (is {})",
+            index,
+            len
+        );
+
+        child.replace_parent(parent);
+        parent_r.children.insert(index, child);
+    }
+
     pub fn remove_root_child<C>(&self, child: C)
     where
         C: Into<ChildOfRoot>,
     {
         let parent_r = unsafe { &mut *self.root };
         let child = child.into();
-        child.remove_parent();
-        parent_r.children.retain(|&x| x != child);
+        if let Some(idx) = parent_r.children.iter().position(|&x| x == child) {
+            child.remove_parent();
+            parent_r.children.remove(idx);
+        }
     }
 
     pub fn remove_element_child<C>(&self, parent: *mut Element, child: C)
@@ -548,8 +896,10 @@ impl Connections {
     {
         let parent_r = unsafe { &mut *parent };
         let child = child.into();
-        child.remove_parent();
-        parent_r.children.retain(|&x| x != child);
+        if let Some(idx) = parent_r.children.iter().position(|&x| x == child) {
+            child.remove_parent();
+            parent_r.children.remove(idx);
+        }
     }
 
     pub fn clear_root_children(&self) {
@@ -793,7 +1143,7 @@ impl Connections {
             .cloned()
     }
 
-    pub fn remove_attribute<'n, N>(&self, element: *mut Element, name: N)
+    pub fn remove_attribute<'n, N>(&self, element: *mut Element, name: N) -> bool
     where
         N: Into<QName<'n>>,
     {
@@ -801,11 +1151,13 @@ impl Connections {
         self.remove_attribute_x(element, |a| a.name.as_qname() == name)
     }
 
-    pub fn remove_attribute_x<F>(&self, element: *mut Element, mut pred: F)
+    /// Returns whether any attribute was removed.
+    pub fn remove_attribute_x<F>(&self, element: *mut Element, mut pred: F) -> bool
     where
         F: FnMut(&mut Attribute) -> bool,
     {
         let element_r = unsafe { &mut *element };
+        let before = element_r.attributes.len();
 
         element_r.attributes.retain(|&a| {
             let a_r = unsafe { &mut *a };
@@ -814,7 +1166,9 @@ impl Connections {
                 a_r.parent = None;
             }
             !is_this_attr
-        })
+        });
+
+        element_r.attributes.len() != before
     }
 
     pub fn set_attribute(&self, parent: *mut Element, attribute: *mut Attribute) {
@@ -847,9 +1201,17 @@ impl Connections {
         prefix: &str,
     ) -> Option<&str> {
         self.element_parents(element)
-            .filter_map(|e| e.prefix_to_namespace.get(prefix))
+            .filter_map(|e| e.registered_namespace_uri(prefix))
             .next()
             .map(|s| s.as_slice())
+            .or_else(|| {
+                // The `xml` prefix is bound without being declared
+                if prefix == crate::XML_NS_PREFIX {
+                    Some(crate::XML_NS_URI)
+                } else {
+                    None
+                }
+            })
     }
 
     pub fn element_prefix_for_namespace_uri(
@@ -858,30 +1220,32 @@ impl Connections {
         namespace_uri: &str,
         preferred_prefix: Option<&str>,
     ) -> Option<&str> {
-        for element_r in self.element_parents(element) {
-            let prefixes: Vec<_> = element_r
-                .prefix_to_namespace
-                .iter()
-                .filter_map(|(&prefix, ns_uri)| {
-                    if ns_uri == namespace_uri {
-                        Some(prefix)
-                    } else {
-                        None
-                    }
-                })
-                .collect();
+        // A prefix registered on an ancestor may have been rebound
+        // to another namespace closer to the element
+        let prefixes: Vec<_> = self
+            .element_parents(element)
+            .flat_map(|e| e.prefix_to_namespace.iter())
+            .filter(|(_, ns_uri)| ns_uri == namespace_uri)
+            .map(|(prefix, _)| prefix.as_slice())
+            .filter(|prefix| {
+                self.element_namespace_uri_for_prefix(element, prefix) == Some(namespace_uri)
+            })
+            .collect();
 
-            if let Some(preferred_prefix) = preferred_prefix {
-                if let Some(prefix) = prefixes.iter().find(|&prefix| prefix == preferred_prefix) {
-                    return Some(prefix.as_slice());
-                }
+        if let Some(preferred_prefix) = preferred_prefix {
+            if let Some(prefix) = prefixes.iter().find(|&&prefix| prefix == preferred_prefix) {
+                return Some(prefix);
             }
+        }
 
-            if let Some(prefix) = prefixes.first() {
-                return Some(prefix.as_slice());
+        prefixes.first().cloned().or_else(|| {
+            // The `xml` prefix is bound without being declared
+            if namespace_uri == crate::XML_NS_URI {
+                Some(crate::XML_NS_PREFIX)
+            } else {
+                None
             }
-        }
-        None
+        })
     }
 
     pub fn element_namespaces_in_scope(&self, element: *mut Element) -> NamespacesInScope<'_> {
@@ -893,7 +1257,7 @@ impl Connections {
             .element_parents(element)
             .flat_map(|e| e.prefix_to_namespace.iter());
 
-        for (&prefix, &uri) in all_namespaces {
+        for &(prefix, uri) in all_namespaces {
             let namespace = (prefix.as_slice(), uri.as_slice());
             if !namespaces.iter().any(|ns| ns.0 == namespace.0) {
                 namespaces.push(namespace)
@@ -1021,3 +1385,13 @@ impl<'d> Iterator for SiblingIter<'d> {
         }
     }
 }
+
+impl<'d> DoubleEndedIterator for SiblingIter<'d> {
+    fn next_back(&mut self) -> Option<ChildOfElement> {
+        match self.data {
+            SiblingData::FromRoot(ref mut children) => children.next_back().map(|&sib| sib.into()),
+            SiblingData::FromElement(ref mut children) => children.next_back().cloned(),
+            SiblingData::Dead => None,
+        }
+    }
+}
@@ -51,8 +51,19 @@ pub trait XmlStr {
     /// Find the end of the starting tag
     fn end_of_start_tag(&self) -> Option<usize>;
     fn end_of_encoding(&self) -> Option<usize>;
-    /// Find the end of the internal doc type declaration, not including the ]
-    fn end_of_int_subset(&self) -> Option<usize>;
+    /// Find the end of the quoted entity value, not including the quote
+    fn end_of_entity_value(&self, quote: &str) -> Option<usize>;
+    /// Find the end of a markup declaration, not including the >. Any
+    /// > inside of a quoted literal is skipped.
+    fn end_of_markup_declaration(&self) -> Option<usize>;
+    /// Find the end of the quoted [system
+    /// literal](https://www.w3.org/TR/xml/#NT-SystemLiteral), not
+    /// including the quote
+    fn end_of_system_literal(&self, quote: &str) -> Option<usize>;
+    /// Find the end of the quoted [public
+    /// identifier](https://www.w3.org/TR/xml/#NT-PubidLiteral), not
+    /// including the quote
+    fn end_of_pubid_literal(&self, quote: &str) -> Option<usize>;
 }
 
 impl<'a> XmlStr for &'a str {
@@ -151,13 +162,62 @@ impl<'a> XmlStr for &'a str {
         )
     }
 
-    fn end_of_int_subset(&self) -> Option<usize> {
-        self.find(']')
+    fn end_of_entity_value(&self, quote: &str) -> Option<usize> {
+        if self.is_empty()
+            || self.starts_with('&')
+            || self.starts_with('%')
+            || self.starts_with(quote)
+        {
+            return None;
+        }
+
+        let quote_char = quote.chars().next().expect("Cant have null quote");
+
+        self.find(&['&', '%', quote_char][..])
+            .or_else(|| Some(self.len()))
+    }
+
+    fn end_of_markup_declaration(&self) -> Option<usize> {
+        let mut quote = None;
+
+        for (offset, c) in self.char_indices() {
+            match (quote, c) {
+                (None, '>') => return Some(offset),
+                (None, '"') | (None, '\'') => quote = Some(c),
+                (Some(q), c) if q == c => quote = None,
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    fn end_of_system_literal(&self, quote: &str) -> Option<usize> {
+        self.find(quote)
+    }
+
+    fn end_of_pubid_literal(&self, quote: &str) -> Option<usize> {
+        let quote_char = quote.chars().next().expect("Cant have null quote");
+
+        match self.find(|c| c == quote_char || !is_pubid_char(c)) {
+            Some(offset) if self[offset..].starts_with(quote) => Some(offset),
+            _ => None,
+        }
+    }
+}
+
+fn is_pubid_char(c: char) -> bool {
+    match c {
+        ' ' | '\r' | '\n' => true,
+        'a'..='z' | 'A'..='Z' | '0'..='9' => true,
+        _ => "-'()+,./:=?;!*#@$_%".contains(c),
     }
 }
 
 /// Predicates used when parsing an characters in an XML document.
 pub trait XmlChar {
+    /// Is this a [Char](http://www.w3.org/TR/xml/#NT-Char)?
+    fn is_char(self) -> bool;
     /// Is this a [NameStartChar](http://www.w3.org/TR/xml/#NT-NameStartChar)?
     fn is_name_start_char(self) -> bool;
     /// Is this a [NameChar](http://www.w3.org/TR/xml/#NT-NameChar)?
@@ -175,6 +235,18 @@ pub trait XmlChar {
 }
 
 impl XmlChar for char {
+    fn is_char(self) -> bool {
+        match self {
+            '\x09'
+            | '\x0A'
+            | '\x0D'
+            | '\u{000020}'..='\u{00D7FF}'
+            | '\u{00E000}'..='\u{00FFFD}'
+            | '\u{010000}'..='\u{10FFFF}' => true,
+            _ => false,
+        }
+    }
+
     fn is_name_start_char(self) -> bool {
         self == ':' || self.is_ncname_start_char()
     }
@@ -303,7 +375,63 @@ mod test {
     }
 
     #[test]
-    fn end_of_int_subset_excludes_right_square() {
-        assert_eq!("hello]>world".end_of_int_subset(), Some("hello".len()))
+    fn end_of_entity_value_stops_at_references() {
+        assert_eq!("ab&c;".end_of_entity_value("'"), Some("ab".len()));
+        assert_eq!("ab%c;".end_of_entity_value("'"), Some("ab".len()));
+    }
+
+    #[test]
+    fn end_of_markup_declaration_skips_quoted_greater_than() {
+        let decl = r#"ATTLIST a b CDATA "x>y""#;
+        assert_eq!(
+            (decl.to_owned() + "> rest")
+                .as_str()
+                .end_of_markup_declaration(),
+            Some(decl.len())
+        );
+    }
+
+    #[test]
+    fn end_of_system_literal_allows_any_character_but_the_quote() {
+        assert_eq!("a&<'b\"".end_of_system_literal("\""), Some("a&<'b".len()));
+        assert_eq!("\"".end_of_system_literal("\""), Some(0));
+    }
+
+    #[test]
+    fn end_of_pubid_literal_rejects_other_characters() {
+        assert_eq!(
+            "-//W3C//DTD XHTML 1.0//EN\"".end_of_pubid_literal("\""),
+            Some("-//W3C//DTD XHTML 1.0//EN".len())
+        );
+        assert_eq!("it's'".end_of_pubid_literal("'"), Some("it".len()));
+        assert_eq!("a<b\"".end_of_pubid_literal("\""), None);
+    }
+
+    #[test]
+    fn end_of_name_rejects_leading_digit() {
+        assert_eq!("1abc".end_of_name(), None);
+    }
+
+    #[test]
+    fn end_of_name_rejects_leading_hyphen() {
+        assert_eq!("-abc".end_of_name(), None);
+    }
+
+    #[test]
+    fn end_of_name_allows_digits_and_hyphens_after_start() {
+        assert_eq!("a-1 b".end_of_name(), Some("a-1".len()));
+    }
+
+    #[test]
+    fn end_of_name_allows_unicode_letter_start() {
+        assert_eq!(
+            "\u{00E9}t\u{00E9}/".end_of_name(),
+            Some("\u{00E9}t\u{00E9}".len())
+        );
+    }
+
+    #[test]
+    fn end_of_ncname_excludes_colon() {
+        assert_eq!("a:b".end_of_ncname(), Some("a".len()));
     }
 }
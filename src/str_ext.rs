@@ -101,8 +101,9 @@ where
 
         match tail.find(&self.chars) {
             Some(start) => {
+                let len = tail[start..].chars().next().map_or(1, char::len_utf8);
                 let start = self.start + start;
-                let end = start + 1; // Super dangerous! Assume we are only one byte long
+                let end = start + len;
                 if self.start == start {
                     let s = &self.haystack[start..end];
                     self.start = end;
@@ -168,4 +169,12 @@ mod test {
         let items: Vec<_> = ",;".split_keeping_delimiter(delims).collect();
         assert_eq!(&items, &[Delimiter(","), Delimiter(";")]);
     }
+
+    #[test]
+    fn split_with_multibyte_delimiter() {
+        use super::SplitType::*;
+        let delims = |b| b == '\u{e9}';
+        let items: Vec<_> = "caf\u{e9}s".split_keeping_delimiter(delims).collect();
+        assert_eq!(&items, &[Match("caf"), Delimiter("\u{e9}"), Match("s")]);
+    }
 }
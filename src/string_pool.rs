@@ -158,6 +158,16 @@ impl StringPool {
         unsafe { mem::transmute(interned_str) }
     }
 
+    /// The total length of the distinct strings interned so far
+    pub fn interned_bytes(&self) -> usize {
+        self.index.borrow().iter().map(|s| s.len).sum()
+    }
+
+    /// The total capacity of the chunks holding the strings
+    pub fn allocated_bytes(&self) -> usize {
+        self.chunks.borrow().iter().map(|c| c.capacity).sum()
+    }
+
     fn do_intern(&self, s: &str) -> InternedString {
         self.ensure_capacity(s.len());
         self.store(s)
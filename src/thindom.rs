@@ -394,6 +394,10 @@ impl<'d> Text<'d> {
     pub fn text(&self) -> &str {
         self.node().text()
     }
+
+    pub fn is_cdata(&self) -> bool {
+        self.node().is_cdata()
+    }
 }
 
 impl<'d> fmt::Debug for Text<'d> {
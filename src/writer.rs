@@ -16,21 +16,37 @@
 //! format_document(&doc, &mut output).expect("unable to output XML");
 //! ```
 //!
+//! Documents and elements can also be displayed directly, using the
+//! default `Writer`:
+//!
+//! ```
+//! use sxd_document::parser;
+//!
+//! let package = parser::parse("<hello planet='Earth'/>").expect("Failed to parse");
+//! println!("{}", package.as_document());
+//!
+//! assert_eq!(
+//!     package.as_document().to_string(),
+//!     "<?xml version='1.0'?><hello planet='Earth'/>"
+//! );
+//! ```
+//!
 //! ### Potential options to support
 //!
 //! - Space before `/>`
 //! - Single vs double quotes
-//! - Fixed ordering of attributes
 
 use std::{
-    borrow::ToOwned,
+    borrow::{Cow, ToOwned},
+    fmt,
     io::{self, Write},
-    slice,
+    slice, str,
 };
 
 use self::Content::*;
 
 use super::{
+    str::{XmlChar, XmlStr},
     str_ext::{SplitKeepingDelimiterExt, SplitType},
     QName,
 };
@@ -153,8 +169,22 @@ impl<'d> PrefixMapping<'d> {
         self.scopes.last().unwrap().defined_prefixes.iter()
     }
 
-    fn populate_scope(&mut self, element: &dom::Element<'d>, attributes: &[dom::Attribute<'d>]) {
-        self.scopes.last_mut().unwrap().default_namespace_uri = element.default_namespace_uri();
+    /// When `minimize` is set, only namespaces the element or its
+    /// attributes use are declared, and a default namespace is not
+    /// redeclared with the URI it already has.
+    fn populate_scope(
+        &mut self,
+        element: &dom::Element<'d>,
+        attributes: &[dom::Attribute<'d>],
+        minimize: bool,
+    ) {
+        let default_namespace_uri = element.default_namespace_uri();
+        let redundant = default_namespace_uri == self.active_default_namespace_uri();
+        self.scopes.last_mut().unwrap().default_namespace_uri = if minimize && redundant {
+            None
+        } else {
+            default_namespace_uri
+        };
 
         if let Some(prefix) = element.preferred_prefix() {
             let name = element.name();
@@ -172,6 +202,19 @@ impl<'d> PrefixMapping<'d> {
             }
         }
 
+        // Prefixes registered on the element are declared there, even
+        // when only descendants use them, as a parsed document would
+        // have done. The xml prefix never needs declaring.
+        if !minimize {
+            let mut registered = element.prefixes_declared_here();
+            registered.sort_by_key(|ns| ns.prefix());
+            for ns in registered {
+                if ns.prefix() != crate::XML_NS_PREFIX {
+                    self.set_prefix(ns.prefix(), ns.uri());
+                }
+            }
+        }
+
         let name = element.name();
         if let Some(uri) = name.namespace_uri {
             self.generate_prefix(uri);
@@ -273,6 +316,151 @@ impl<'d> PrefixMapping<'d> {
     }
 }
 
+/// Tracks, for each open element, whether its children are each
+/// placed on their own indented line.
+struct Layout {
+    indented: Vec<bool>,
+}
+
+impl Layout {
+    fn new() -> Layout {
+        Layout {
+            indented: Vec::new(),
+        }
+    }
+
+    fn depth(&self) -> usize {
+        self.indented.len()
+    }
+
+    fn is_indented(&self) -> bool {
+        self.indented.last().cloned().unwrap_or(false)
+    }
+
+    fn can_indent(&self) -> bool {
+        self.indented.last().cloned().unwrap_or(true)
+    }
+}
+
+/// The whitespace used for each level of nesting when pretty-printing.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Indent {
+    Spaces(usize),
+    Tabs(usize),
+}
+
+/// Two spaces.
+impl Default for Indent {
+    fn default() -> Self {
+        Indent::Spaces(2)
+    }
+}
+
+/// The line ending used when pretty-printing.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Newline {
+    /// `\n`
+    Lf,
+    /// `\r\n`
+    CrLf,
+}
+
+impl Newline {
+    fn as_str(self) -> &'static str {
+        match self {
+            Newline::Lf => "\n",
+            Newline::CrLf => "\r\n",
+        }
+    }
+}
+
+/// How an element without children is written.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EmptyElementStyle {
+    /// `<a/>`
+    SelfClosing,
+    /// `<a></a>`
+    EndTag,
+    /// However the element was written in the parsed input, as told
+    /// by `Element::was_self_closing`. Elements that were not parsed
+    /// are self-closing.
+    AsParsed,
+}
+
+impl EmptyElementStyle {
+    fn self_closing(self, was_self_closing: Option<bool>) -> bool {
+        match self {
+            EmptyElementStyle::SelfClosing => true,
+            EmptyElementStyle::EndTag => false,
+            EmptyElementStyle::AsParsed => was_self_closing.unwrap_or(true),
+        }
+    }
+}
+
+/// Controls which characters are written as references in text and
+/// attribute values.
+///
+/// `<` and `&` are always escaped. The default policy additionally
+/// escapes `>` everywhere, both quote characters in attribute values
+/// and whitespace that a parser would otherwise normalize away.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EscapePolicy {
+    greater_than: bool,
+    all_quotes: bool,
+    ascii_only: bool,
+    whitespace: bool,
+}
+
+impl Default for EscapePolicy {
+    fn default() -> Self {
+        EscapePolicy {
+            greater_than: true,
+            all_quotes: true,
+            ascii_only: false,
+            whitespace: true,
+        }
+    }
+}
+
+impl EscapePolicy {
+    /// Create a new `EscapePolicy` with default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set whether `>` is always escaped. When `false`, it is only
+    /// escaped where required, following `]]` in text.
+    pub fn set_escape_greater_than(mut self, greater_than: bool) -> Self {
+        self.greater_than = greater_than;
+        self
+    }
+
+    /// Set whether both quote characters are escaped in attribute
+    /// values. When `false`, only the quote delimiting the value is.
+    pub fn set_escape_all_quotes(mut self, all_quotes: bool) -> Self {
+        self.all_quotes = all_quotes;
+        self
+    }
+
+    /// Set whether characters outside of ASCII are written as
+    /// hexadecimal character references.
+    pub fn set_ascii_only(mut self, ascii_only: bool) -> Self {
+        self.ascii_only = ascii_only;
+        self
+    }
+
+    /// Set whether whitespace that would not survive being parsed
+    /// again is written as character references: carriage returns in
+    /// text, and tabs, line feeds and carriage returns in attribute
+    /// values. CDATA sections containing a carriage return are written
+    /// as text. Comments and processing instructions cannot contain
+    /// references and are always written as is.
+    pub fn set_escape_whitespace(mut self, whitespace: bool) -> Self {
+        self.whitespace = whitespace;
+        self
+    }
+}
+
 enum Content<'d> {
     Element(dom::Element<'d>),
     ElementEnd(dom::Element<'d>),
@@ -306,14 +494,34 @@ enum Content<'d> {
 /// ```
 pub struct Writer {
     single_quotes: bool,
+    write_declaration: bool,
     write_encoding: bool,
+    version: Option<String>,
+    encoding: Option<String>,
+    standalone: Option<bool>,
+    indent: Option<Indent>,
+    newline: Newline,
+    escape_policy: EscapePolicy,
+    sort_attributes: bool,
+    empty_element_style: EmptyElementStyle,
+    canonical: bool,
 }
 
 impl Default for Writer {
     fn default() -> Self {
         Self {
             single_quotes: true,
+            write_declaration: true,
             write_encoding: false,
+            version: None,
+            encoding: None,
+            standalone: None,
+            indent: None,
+            newline: Newline::Lf,
+            escape_policy: EscapePolicy::default(),
+            sort_attributes: false,
+            empty_element_style: EmptyElementStyle::SelfClosing,
+            canonical: false,
         }
     }
 }
@@ -330,12 +538,163 @@ impl Writer {
         self
     }
 
+    /// Set whether the XML declaration should be written. Defaults to
+    /// `true`.
+    pub fn set_write_declaration(mut self, write_declaration: bool) -> Self {
+        self.write_declaration = write_declaration;
+        self
+    }
+
     /// Set whether the encoding should be specified in the output document header.
     pub fn set_write_encoding(mut self, write_encoding: bool) -> Self {
         self.write_encoding = write_encoding;
         self
     }
 
+    /// Set the XML version named in the output document header. When
+    /// `None`, the default, `1.0` is written. The document is written
+    /// the same way whatever the version.
+    ///
+    /// Formatting fails if the version is not a valid
+    /// [VersionNum](https://www.w3.org/TR/xml/#NT-VersionNum).
+    ///
+    /// ```
+    /// use sxd_document::{parser, writer::Writer};
+    ///
+    /// let package = parser::parse("<?xml version='1.1'?><a/>").expect("Failed to parse");
+    /// let doc = package.as_document();
+    ///
+    /// let mut output = Vec::new();
+    /// Writer::new()
+    ///     .set_version(doc.version())
+    ///     .format_document(&doc, &mut output)
+    ///     .expect("unable to output XML");
+    ///
+    /// let output_string = String::from_utf8(output).unwrap();
+    /// assert_eq!(output_string, "<?xml version='1.1'?><a/>");
+    /// ```
+    pub fn set_version(mut self, version: Option<&str>) -> Self {
+        self.version = version.map(Into::into);
+        self
+    }
+
+    /// Set the encoding named in the output document header. The name
+    /// is written verbatim; it does not change how the document is
+    /// encoded, which is always UTF-8.
+    ///
+    /// Formatting fails if the name is not a valid
+    /// [EncName](https://www.w3.org/TR/xml/#NT-EncName).
+    ///
+    /// ```
+    /// use sxd_document::{parser, writer::Writer};
+    ///
+    /// let package = parser::parse("<?xml version='1.0' encoding='utf-8' standalone='yes'?><a/>")
+    ///     .expect("Failed to parse");
+    /// let doc = package.as_document();
+    ///
+    /// let mut output = Vec::new();
+    /// Writer::new()
+    ///     .set_encoding(doc.encoding())
+    ///     .set_standalone(doc.standalone())
+    ///     .format_document(&doc, &mut output)
+    ///     .expect("unable to output XML");
+    ///
+    /// let output_string = String::from_utf8(output).unwrap();
+    /// assert_eq!(output_string, "<?xml version='1.0' encoding='utf-8' standalone='yes'?><a/>");
+    /// ```
+    pub fn set_encoding(mut self, encoding: Option<&str>) -> Self {
+        self.encoding = encoding.map(Into::into);
+        self
+    }
+
+    /// Set the standalone declaration in the output document header.
+    /// When `None`, the default, none is written.
+    pub fn set_standalone(mut self, standalone: Option<bool>) -> Self {
+        self.standalone = standalone;
+        self
+    }
+
+    /// Set the indentation used to pretty-print the document. When
+    /// `None`, the default, no whitespace is added.
+    ///
+    /// Elements containing text other than whitespace are written
+    /// unchanged, as adding whitespace would alter their content.
+    /// Whitespace-only text in other elements is replaced by the
+    /// indentation.
+    ///
+    /// ```
+    /// use sxd_document::{Package, writer::{Indent, Writer}};
+    ///
+    /// let p = Package::new();
+    /// let doc = p.as_document();
+    /// let hello = doc.create_element("hello");
+    /// let world = doc.create_element("world");
+    /// hello.append_child(world);
+    /// doc.root().append_child(hello);
+    ///
+    /// let mut output = Vec::new();
+    /// Writer::new()
+    ///     .set_indent(Some(Indent::Spaces(2)))
+    ///     .format_document(&doc, &mut output)
+    ///     .expect("unable to output XML");
+    ///
+    /// let output_string = String::from_utf8(output).unwrap();
+    /// assert_eq!(output_string, "<?xml version='1.0'?>\n<hello>\n  <world/>\n</hello>");
+    /// ```
+    pub fn set_indent(mut self, indent: Option<Indent>) -> Self {
+        self.indent = indent;
+        self
+    }
+
+    /// Set the line ending used when pretty-printing.
+    pub fn set_newline(mut self, newline: Newline) -> Self {
+        self.newline = newline;
+        self
+    }
+
+    /// Set which characters are escaped in text and attribute values.
+    pub fn set_escape_policy(mut self, escape_policy: EscapePolicy) -> Self {
+        self.escape_policy = escape_policy;
+        self
+    }
+
+    /// Set whether attributes are written sorted by namespace URI and
+    /// then local name, with namespace declarations sorted by prefix
+    /// after them. Defaults to `false`, which writes attributes in the
+    /// order they were added to the element.
+    pub fn set_sort_attributes(mut self, sort_attributes: bool) -> Self {
+        self.sort_attributes = sort_attributes;
+        self
+    }
+
+    /// Set how elements without children are written. Defaults to
+    /// `EmptyElementStyle::SelfClosing`. An element containing only a
+    /// comment or processing instruction, or an empty text node, has
+    /// children and always gets an end tag.
+    ///
+    /// `EmptyElementStyle::AsParsed` keeps the form used in the
+    /// parsed input, so that `<a></a>` is not rewritten as `<a/>`.
+    pub fn set_empty_element_style(mut self, empty_element_style: EmptyElementStyle) -> Self {
+        self.empty_element_style = empty_element_style;
+        self
+    }
+
+    /// The settings used by `format_document_c14n`.
+    fn canonical() -> Self {
+        Self {
+            single_quotes: false,
+            write_declaration: false,
+            escape_policy: EscapePolicy::new()
+                .set_escape_greater_than(false)
+                .set_escape_all_quotes(false)
+                .set_escape_whitespace(true),
+            sort_attributes: true,
+            empty_element_style: EmptyElementStyle::EndTag,
+            canonical: true,
+            ..Self::default()
+        }
+    }
+
     fn quote_char(&self) -> &'static str {
         if self.single_quotes {
             "'"
@@ -380,9 +739,7 @@ impl Writer {
     where
         W: Write,
     {
-        for item in value
-            .split_keeping_delimiter(|c| c == '<' || c == '>' || c == '&' || c == '\'' || c == '"')
-        {
+        for item in value.split_keeping_delimiter(|c| self.escapes_in_attribute_value(c)) {
             match item {
                 SplitType::Match(t) => writer.write_str(t)?,
                 SplitType::Delimiter("<") => writer.write_str("&lt;")?,
@@ -390,25 +747,59 @@ impl Writer {
                 SplitType::Delimiter("&") => writer.write_str("&amp;")?,
                 SplitType::Delimiter("'") => writer.write_str("&apos;")?,
                 SplitType::Delimiter("\"") => writer.write_str("&quot;")?,
-                SplitType::Delimiter(..) => unreachable!(),
+                SplitType::Delimiter(c) => self.format_char_reference(c, writer)?,
             }
         }
         Ok(())
     }
 
+    fn escapes_in_attribute_value(&self, c: char) -> bool {
+        let policy = &self.escape_policy;
+
+        c == '<'
+            || c == '&'
+            || (c == '>' && policy.greater_than)
+            || ((c == '\'' || c == '"') && (policy.all_quotes || self.quote_char().starts_with(c)))
+            || (!c.is_ascii() && policy.ascii_only)
+            || ((c == '\t' || c == '\n' || c == '\r') && policy.whitespace)
+    }
+
+    fn format_namespace_uri<W: ?Sized>(&self, namespace_uri: &str, writer: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        writer.write_str(self.quote_char())?;
+        self.format_attribute_value(namespace_uri, writer)?;
+        writer.write_str(self.quote_char())
+    }
+
+    fn format_char_reference<W: ?Sized>(&self, c: &str, writer: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        for c in c.chars() {
+            write!(writer, "&#x{:X};", c as u32)?;
+        }
+        Ok(())
+    }
+
     fn format_element<'d, W: ?Sized>(
         &self,
         element: dom::Element<'d>,
         todo: &mut Vec<Content<'d>>,
         mapping: &mut PrefixMapping<'d>,
+        layout: &mut Layout,
         writer: &mut W,
     ) -> io::Result<()>
     where
         W: Write,
     {
-        let attrs = element.attributes();
+        let mut attrs = element.attributes();
+        if self.sort_attributes {
+            attrs.sort_by_key(|a| (a.name().namespace_uri(), a.name().local_part()));
+        }
 
-        mapping.populate_scope(&element, &attrs);
+        mapping.populate_scope(&element, &attrs, self.canonical);
 
         writer.write_str("<")?;
         self.format_qname(
@@ -419,6 +810,11 @@ impl Writer {
             writer,
         )?;
 
+        // Canonical XML places namespace declarations first
+        if self.canonical {
+            self.format_namespace_declarations(mapping, writer)?;
+        }
+
         for attr in &attrs {
             writer.write_str(" ")?;
             self.format_qname(attr.name(), mapping, attr.preferred_prefix(), true, writer)?;
@@ -428,26 +824,31 @@ impl Writer {
             write!(writer, "{}", self.quote_char())?;
         }
 
-        if let Some(ns_uri) = mapping.default_namespace_uri_in_current_scope() {
-            writer.write_str(" xmlns='")?;
-            writer.write_str(ns_uri)?;
-            writer.write_str("'")?;
-        }
-
-        for &(ref prefix, ref ns_uri) in mapping.prefixes_in_current_scope() {
-            writer.write_str(" xmlns:")?;
-            writer.write_str(prefix)?;
-            write!(writer, "='{}'", ns_uri)?;
+        if !self.canonical {
+            self.format_namespace_declarations(mapping, writer)?;
         }
 
         let mut children = element.children();
-        if children.is_empty() {
+        let self_closing = self
+            .empty_element_style
+            .self_closing(element.was_self_closing());
+        if children.is_empty() && self_closing {
             writer.write_str("/>")?;
             mapping.pop_scope();
             Ok(())
         } else {
             writer.write_str(">")?;
 
+            let has_text = children.iter().any(|c| match *c {
+                ChildOfElement::Text(t) => {
+                    t.is_cdata() || !t.text().chars().all(|c| c.is_space_char())
+                }
+                _ => false,
+            });
+            layout.indented.push(
+                self.indent.is_some() && layout.can_indent() && !has_text && !children.is_empty(),
+            );
+
             todo.push(ElementEnd(element));
             children.reverse();
             let x = children.into_iter().map(|c| match c {
@@ -462,6 +863,34 @@ impl Writer {
         }
     }
 
+    fn format_namespace_declarations<W: ?Sized>(
+        &self,
+        mapping: &PrefixMapping<'_>,
+        writer: &mut W,
+    ) -> io::Result<()>
+    where
+        W: Write,
+    {
+        if let Some(ns_uri) = mapping.default_namespace_uri_in_current_scope() {
+            writer.write_str(" xmlns=")?;
+            self.format_namespace_uri(ns_uri, writer)?;
+        }
+
+        let mut prefixes: Vec<_> = mapping.prefixes_in_current_scope().collect();
+        if self.sort_attributes {
+            prefixes.sort();
+        }
+
+        for (prefix, ns_uri) in prefixes {
+            writer.write_str(" xmlns:")?;
+            writer.write_str(prefix)?;
+            writer.write_str("=")?;
+            self.format_namespace_uri(ns_uri, writer)?;
+        }
+
+        Ok(())
+    }
+
     fn format_element_end<'d, W: ?Sized>(
         &self,
         element: dom::Element<'d>,
@@ -486,17 +915,93 @@ impl Writer {
     where
         W: Write,
     {
-        for item in text
-            .text()
-            .split_keeping_delimiter(|c| c == '<' || c == '>' || c == '&')
+        let policy = &self.escape_policy;
+
+        if text.is_cdata() && !self.canonical && !(policy.whitespace && text.text().contains('\r'))
         {
+            return self.format_cdata(text, writer);
+        }
+
+        self.format_escaped_text(text.text(), writer)
+    }
+
+    fn format_escaped_text<W: ?Sized>(&self, text: &str, writer: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        let policy = &self.escape_policy;
+        let mut after_brackets = false;
+
+        for item in text.split_keeping_delimiter(|c| self.escapes_in_text(c)) {
             match item {
                 SplitType::Match(t) => writer.write_str(t)?,
                 SplitType::Delimiter("<") => writer.write_str("&lt;")?,
-                SplitType::Delimiter(">") => writer.write_str("&gt;")?,
+                SplitType::Delimiter(">")
+                    if policy.greater_than || after_brackets || self.canonical =>
+                {
+                    // `]]>` may not appear in text
+                    writer.write_str("&gt;")?
+                }
+                SplitType::Delimiter(">") => writer.write_str(">")?,
                 SplitType::Delimiter("&") => writer.write_str("&amp;")?,
-                SplitType::Delimiter(..) => unreachable!(),
+                SplitType::Delimiter(c) => self.format_char_reference(c, writer)?,
             }
+
+            after_brackets = match item {
+                SplitType::Match(t) => t.ends_with("]]"),
+                SplitType::Delimiter(_) => false,
+            };
+        }
+        Ok(())
+    }
+
+    /// `>` is only escaped where the policy asks for it.
+    fn escapes_in_text(&self, c: char) -> bool {
+        let policy = &self.escape_policy;
+
+        c == '<'
+            || c == '>'
+            || c == '&'
+            || (!c.is_ascii() && policy.ascii_only)
+            || (c == '\r' && policy.whitespace)
+    }
+
+    fn format_cdata<W: ?Sized>(&self, text: dom::Text<'_>, writer: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        writer.write_str("<![CDATA[")?;
+        // A CDATA section cannot contain its own terminator, so split
+        // it across two sections.
+        writer.write_str(&text.text().replace("]]>", "]]]]><![CDATA[>"))?;
+        writer.write_str("]]>")
+    }
+
+    fn format_newline<W: ?Sized>(&self, depth: usize, writer: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        writer.write_str(self.newline.as_str())?;
+
+        let (c, width) = match self.indent {
+            Some(Indent::Spaces(width)) => (" ", width),
+            Some(Indent::Tabs(width)) => ("\t", width),
+            None => return Ok(()),
+        };
+
+        for _ in 0..depth * width {
+            writer.write_str(c)?;
+        }
+
+        Ok(())
+    }
+
+    fn format_indent<W: ?Sized>(&self, layout: &Layout, writer: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        if layout.is_indented() {
+            self.format_newline(layout.depth(), writer)?;
         }
         Ok(())
     }
@@ -522,11 +1027,53 @@ impl Writer {
         }
     }
 
+    fn format_document_type<W: ?Sized>(
+        &self,
+        document_type: dom::DocumentType<'_>,
+        writer: &mut W,
+    ) -> io::Result<()>
+    where
+        W: Write,
+    {
+        // A literal may contain either quote, but not both
+        fn quoted(literal: &str) -> io::Result<String> {
+            let quote = if literal.contains('"') { '\'' } else { '"' };
+            if literal.contains(quote) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Identifier '{}' contains both quotes", literal),
+                ));
+            }
+            Ok(format!("{}{}{}", quote, literal, quote))
+        }
+
+        write!(writer, "<!DOCTYPE {}", document_type.name())?;
+
+        if let Some(system_id) = document_type.system_id() {
+            match document_type.public_id() {
+                Some(public_id) => write!(
+                    writer,
+                    " PUBLIC {} {}",
+                    quoted(public_id)?,
+                    quoted(system_id)?
+                )?,
+                None => write!(writer, " SYSTEM {}", quoted(system_id)?)?,
+            }
+        }
+
+        if let Some(subset) = document_type.internal_subset() {
+            write!(writer, " [{}]", subset)?;
+        }
+
+        writer.write_str(">")
+    }
+
     fn format_one<'d, W: ?Sized>(
         &self,
         content: Content<'d>,
         todo: &mut Vec<Content<'d>>,
         mapping: &mut PrefixMapping<'d>,
+        layout: &mut Layout,
         writer: &mut W,
     ) -> io::Result<()>
     where
@@ -534,17 +1081,34 @@ impl Writer {
     {
         match content {
             Element(e) => {
+                self.format_indent(layout, writer)?;
                 mapping.push_scope();
-                self.format_element(e, todo, mapping, writer)
+                self.format_element(e, todo, mapping, layout, writer)
             }
             ElementEnd(e) => {
+                if layout.indented.pop().unwrap_or(false) {
+                    self.format_newline(layout.depth(), writer)?;
+                }
                 let r = self.format_element_end(e, mapping, writer);
                 mapping.pop_scope();
                 r
             }
-            Text(t) => self.format_text(t, writer),
-            Comment(c) => self.format_comment(c, writer),
-            ProcessingInstruction(p) => self.format_processing_instruction(p, writer),
+            Text(t) => {
+                if layout.is_indented() {
+                    // Only whitespace, which the indentation replaces
+                    Ok(())
+                } else {
+                    self.format_text(t, writer)
+                }
+            }
+            Comment(c) => {
+                self.format_indent(layout, writer)?;
+                self.format_comment(c, writer)
+            }
+            ProcessingInstruction(p) => {
+                self.format_indent(layout, writer)?;
+                self.format_processing_instruction(p, writer)
+            }
         }
     }
 
@@ -554,9 +1118,16 @@ impl Writer {
     {
         let mut todo = vec![Element(element)];
         let mut mapping = PrefixMapping::new();
+        let mut layout = Layout::new();
 
         while !todo.is_empty() {
-            self.format_one(todo.pop().unwrap(), &mut todo, &mut mapping, writer)?;
+            self.format_one(
+                todo.pop().unwrap(),
+                &mut todo,
+                &mut mapping,
+                &mut layout,
+                writer,
+            )?;
         }
 
         Ok(())
@@ -566,18 +1137,60 @@ impl Writer {
     where
         W: Write,
     {
+        let version = match self.version {
+            Some(ref version) => &version[..],
+            None => "1.0",
+        };
+
+        let minor = if version.starts_with("1.") {
+            &version[2..]
+        } else {
+            ""
+        };
+        if minor.end_of_decimal_chars() != Some(minor.len()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid version number '{}'", version),
+            ));
+        }
+
         write!(
             writer,
-            "<?xml version={}1.0{}",
+            "<?xml version={}{}{}",
             self.quote_char(),
+            version,
             self.quote_char()
         )?;
 
-        if self.write_encoding {
+        let encoding = match self.encoding {
+            Some(ref encoding) => Some(&encoding[..]),
+            None if self.write_encoding => Some("UTF-8"),
+            None => None,
+        };
+
+        if let Some(encoding) = encoding {
+            if encoding.end_of_encoding() != Some(encoding.len()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid encoding name '{}'", encoding),
+                ));
+            }
+
+            write!(
+                writer,
+                " encoding={}{}{}",
+                self.quote_char(),
+                encoding,
+                self.quote_char()
+            )?;
+        }
+
+        if let Some(standalone) = self.standalone {
             write!(
                 writer,
-                " encoding={}UTF-8{}",
+                " standalone={}{}{}",
                 self.quote_char(),
+                if standalone { "yes" } else { "no" },
                 self.quote_char()
             )?;
         }
@@ -587,7 +1200,12 @@ impl Writer {
         Ok(())
     }
 
-    /// Formats a document into a Write
+    /// Formats a document into a Write.
+    ///
+    /// The output is written piece by piece as the document is
+    /// traversed, never collected in memory, so it is best to supply
+    /// a buffered writer such as `io::BufWriter`. The first error
+    /// returned by the writer stops formatting and is returned.
     pub fn format_document<'d, W: ?Sized>(
         &self,
         doc: &'d dom::Document<'d>,
@@ -596,23 +1214,69 @@ impl Writer {
     where
         W: Write,
     {
-        self.format_declaration(writer)?;
+        if self.write_declaration {
+            self.format_declaration(writer)?;
+        }
+
+        let mut wrote_prolog = self.write_declaration;
+        let children = doc.root().children();
+
+        // Canonical XML removes the document type declaration
+        let document_type = doc.document_type().filter(|_| !self.canonical);
+        // The declaration may not follow the root element
+        let document_type_position = children
+            .iter()
+            .position(|c| c.element().is_some())
+            .unwrap_or(children.len())
+            .min(doc.document_type_position());
+
+        let mut after_element = false;
+
+        for i in 0..=children.len() {
+            if let Some(document_type) = document_type.filter(|_| i == document_type_position) {
+                if self.indent.is_some() && (wrote_prolog || i > 0) {
+                    self.format_newline(0, writer)?;
+                }
+                self.format_document_type(document_type, writer)?;
+                wrote_prolog = true;
+            }
+
+            let child = match children.get(i) {
+                Some(&child) => child,
+                None => break,
+            };
+
+            if self.indent.is_some() && (wrote_prolog || i > 0) {
+                self.format_newline(0, writer)?;
+            }
+
+            // Canonical XML separates nodes outside the document
+            // element from it with a line feed
+            let is_element = child.element().is_some();
+            if self.canonical && after_element {
+                writer.write_str("\n")?;
+            }
 
-        for child in doc.root().children().into_iter() {
             match child {
                 ChildOfRoot::Element(e) => self.format_body(e, writer),
                 ChildOfRoot::Comment(c) => self.format_comment(c, writer),
                 ChildOfRoot::ProcessingInstruction(p) => {
                     self.format_processing_instruction(p, writer)
                 }
-            }?
+            }?;
+
+            after_element |= is_element;
+            if self.canonical && !after_element {
+                writer.write_str("\n")?;
+            }
         }
 
         Ok(())
     }
 }
 
-/// Formats a document into a `Write` using the default `Writer`
+/// Formats a document into a `Write` using the default `Writer`. See
+/// `Writer::format_document`.
 pub fn format_document<'d, W: ?Sized>(doc: &'d dom::Document<'d>, writer: &mut W) -> io::Result<()>
 where
     W: Write,
@@ -620,23 +1284,620 @@ where
     Writer::default().format_document(doc, writer)
 }
 
-#[cfg(test)]
-mod test {
-    use super::{
-        super::{dom, Package},
-        Writer,
-    };
-
-    fn format_xml<'d>(doc: &'d dom::Document<'d>) -> String {
-        format_xml_writer(Writer::default(), doc)
-    }
+/// Formats a document as [Canonical XML](https://www.w3.org/TR/xml-c14n),
+/// so that logically equivalent documents produce the same bytes.
+/// This is useful for comparing or hashing documents.
+///
+/// The following rules of Canonical XML 1.0 are applied:
+///
+/// - The output is UTF-8 and there is no XML or document type
+///   declaration.
+/// - Empty elements are written as a start tag and an end tag.
+/// - Namespace declarations come before attributes, the default
+///   namespace first and the rest sorted by prefix. Attributes are
+///   sorted by namespace URI and then local name.
+/// - Attribute values are delimited by double quotes. `&`, `<`, `"`,
+///   tab, line feed and carriage return are escaped in them.
+/// - `&`, `<`, `>` and carriage return are escaped in text.
+/// - CDATA sections are written as escaped text.
+/// - Comments and processing instructions outside the document
+///   element are separated from it by a line feed.
+///
+/// The following differ from the specification:
+///
+/// - Namespaces are declared on the outermost element that uses them,
+///   as in Exclusive Canonical XML, rather than everywhere they are in
+///   scope, and the prefixes are those the writer would choose.
+/// - Comments are kept, as in the "with comments" variant.
+/// - Text and attribute values are written as stored, and no DTD is
+///   used to add default attributes. Line endings and attribute value
+///   whitespace are normalized by the parser, but not in values added
+///   programmatically.
+/// - Whitespace in processing instructions is not normalized.
+///
+/// ### Example
+///
+/// ```
+/// use sxd_document::{parser, writer::format_document_c14n};
+///
+/// let package = parser::parse("<a b='2' a='1'><c/></a>").expect("Failed to parse");
+///
+/// let mut output = Vec::new();
+/// format_document_c14n(&package.as_document(), &mut output).expect("unable to output XML");
+///
+/// assert_eq!(String::from_utf8(output).unwrap(), r#"<a a="1" b="2"><c></c></a>"#);
+/// ```
+pub fn format_document_c14n<'d, W: ?Sized>(
+    doc: &'d dom::Document<'d>,
+    writer: &mut W,
+) -> io::Result<()>
+where
+    W: Write,
+{
+    Writer::canonical().format_document(doc, writer)
+}
 
-    fn format_xml_writer(writer: Writer, doc: &dom::Document<'_>) -> String {
+/// Escapes a string for use as text content, as the default `Writer`
+/// would write it. Only allocates when a character needs escaping.
+///
+/// ```
+/// use sxd_document::writer::escape_text;
+///
+/// assert_eq!(escape_text("1 < 2 & 3"), "1 &lt; 2 &amp; 3");
+/// ```
+pub fn escape_text(text: &str) -> Cow<'_, str> {
+    let writer = Writer::default();
+    if !text.chars().any(|c| writer.escapes_in_text(c)) {
+        return Cow::Borrowed(text);
+    }
+
+    let mut output = Vec::with_capacity(text.len());
+    writer
+        .format_escaped_text(text, &mut output)
+        .expect("Writing to a Vec cannot fail");
+    Cow::Owned(String::from_utf8(output).expect("Escaped text is UTF-8"))
+}
+
+/// Escapes a string for use as an attribute value delimited by
+/// `quote`, as the default `Writer` would write it, except that the
+/// other quote character is left as is. Only allocates when a
+/// character needs escaping.
+///
+/// # Panics
+///
+/// If `quote` is neither `'` nor `"`.
+///
+/// ```
+/// use sxd_document::writer::escape_attribute;
+///
+/// assert_eq!(escape_attribute("it's \"a\"", '"'), "it's &quot;a&quot;");
+/// ```
+pub fn escape_attribute(value: &str, quote: char) -> Cow<'_, str> {
+    assert!(
+        quote == '\'' || quote == '"',
+        "An attribute value must be quoted with ' or \""
+    );
+
+    let writer = Writer::new()
+        .set_single_quotes(quote == '\'')
+        .set_escape_policy(EscapePolicy::new().set_escape_all_quotes(false));
+    if !value.chars().any(|c| writer.escapes_in_attribute_value(c)) {
+        return Cow::Borrowed(value);
+    }
+
+    let mut output = Vec::with_capacity(value.len());
+    writer
+        .format_attribute_value(value, &mut output)
+        .expect("Writing to a Vec cannot fail");
+    Cow::Owned(String::from_utf8(output).expect("Escaped text is UTF-8"))
+}
+
+/// A name that has not been given a prefix yet, as a namespace URI
+/// and local part.
+type EventName = (Option<String>, String);
+
+/// A namespace declaration, as a prefix and URI. The default
+/// namespace has no prefix.
+type EventNamespace = (Option<String>, String);
+
+struct StartTag {
+    name: EventName,
+    attributes: Vec<(EventName, String)>,
+    namespaces: Vec<EventNamespace>,
+}
+
+struct OpenElement {
+    name: String,
+    namespaces: Vec<EventNamespace>,
+}
+
+/// Writes XML from a sequence of events, without building a DOM.
+/// This allows generating documents too large to hold in memory.
+///
+/// Each start tag is held until the next event, so that attributes
+/// and namespace declarations can still be added to it. An element
+/// that is ended before anything is written inside it is written as
+/// an empty-element tag, unless the `Writer` asks for end tags.
+///
+/// Names with a namespace are written with a prefix declared by
+/// `namespace`, or with a generated one when none is in scope.
+/// Elements without a namespace undeclare the default namespace when
+/// one is in scope, unless the default namespace was declared on the
+/// element itself.
+///
+/// Escaping, quoting and the XML declaration follow the `Writer` the
+/// event writer was created with. Indentation is not applied. Events
+/// that would produce a malformed tag, such as an attribute outside
+/// of a start tag or an end without a matching start, fail with an
+/// error of kind `InvalidInput`. An attribute added twice to the same
+/// element is also rejected. The order of content at the top level is
+/// not checked.
+///
+/// ### Example
+///
+/// ```
+/// use sxd_document::writer::EventWriter;
+///
+/// let mut events = EventWriter::new(Vec::new());
+/// events.start_element("hello").unwrap();
+/// events.attribute("planet", "Earth").unwrap();
+/// events.text("Hi & bye").unwrap();
+/// events.start_element("empty").unwrap();
+/// let output = events.finish().unwrap();
+///
+/// assert_eq!(
+///     String::from_utf8(output).unwrap(),
+///     "<?xml version='1.0'?><hello planet='Earth'>Hi &amp; bye<empty/></hello>"
+/// );
+/// ```
+pub struct EventWriter<W> {
+    options: Writer,
+    output: W,
+    started: bool,
+    start_tag: Option<StartTag>,
+    open: Vec<OpenElement>,
+    generated_prefix_count: usize,
+}
+
+impl<W> EventWriter<W>
+where
+    W: Write,
+{
+    /// Create an event writer with the default `Writer` settings.
+    pub fn new(output: W) -> Self {
+        EventWriter::with_options(Writer::default(), output)
+    }
+
+    /// Create an event writer using the settings of `options`.
+    pub fn with_options(options: Writer, output: W) -> Self {
+        EventWriter {
+            options,
+            output,
+            started: false,
+            start_tag: None,
+            open: Vec::new(),
+            generated_prefix_count: 0,
+        }
+    }
+
+    /// Starts an element, which stays open until `end_element`.
+    pub fn start_element<'n, N>(&mut self, name: N) -> io::Result<()>
+    where
+        N: Into<QName<'n>>,
+    {
+        self.write_start_tag(false)?;
+
+        let name = name.into();
+        self.start_tag = Some(StartTag {
+            name: (name.namespace_uri.map(Into::into), name.local_part.into()),
+            attributes: Vec::new(),
+            namespaces: Vec::new(),
+        });
+        Ok(())
+    }
+
+    /// Adds an attribute to the element just started.
+    pub fn attribute<'n, N>(&mut self, name: N, value: &str) -> io::Result<()>
+    where
+        N: Into<QName<'n>>,
+    {
+        let start_tag = self
+            .start_tag
+            .as_mut()
+            .ok_or_else(|| invalid_event("An attribute must follow a start tag"))?;
+
+        let name = name.into();
+        let duplicate = start_tag
+            .attributes
+            .iter()
+            .any(|((namespace_uri, local_part), _)| {
+                namespace_uri.as_ref().map(String::as_str) == name.namespace_uri
+                    && local_part == name.local_part
+            });
+        if duplicate {
+            return Err(invalid_event("An attribute cannot be added twice"));
+        }
+
+        start_tag.attributes.push((
+            (name.namespace_uri.map(Into::into), name.local_part.into()),
+            value.into(),
+        ));
+        Ok(())
+    }
+
+    /// Declares a namespace on the element just started. A prefix of
+    /// `None` declares the default namespace, which an empty URI
+    /// undeclares.
+    pub fn namespace(&mut self, prefix: Option<&str>, namespace_uri: &str) -> io::Result<()> {
+        let start_tag = self
+            .start_tag
+            .as_mut()
+            .ok_or_else(|| invalid_event("A namespace must follow a start tag"))?;
+
+        if let Some(prefix) = prefix {
+            if namespace_uri.is_empty() {
+                return Err(invalid_event(
+                    "A prefix cannot be bound to an empty namespace",
+                ));
+            }
+            if prefix == crate::XML_NS_PREFIX || prefix == "xmlns" {
+                return Err(invalid_event("The xml and xmlns prefixes are reserved"));
+            }
+        }
+
+        start_tag
+            .namespaces
+            .retain(|(p, _)| p.as_ref().map(String::as_str) != prefix);
+        start_tag
+            .namespaces
+            .push((prefix.map(Into::into), namespace_uri.into()));
+        Ok(())
+    }
+
+    /// Writes text inside the current element.
+    pub fn text(&mut self, text: &str) -> io::Result<()> {
+        self.write_start_tag(false)?;
+        self.options.format_escaped_text(text, &mut self.output)
+    }
+
+    /// Writes a comment. The text is written as is.
+    pub fn comment(&mut self, text: &str) -> io::Result<()> {
+        self.write_start_tag(false)?;
+        write!(self.output, "<!--{}-->", text)
+    }
+
+    /// Writes a processing instruction. The value is written as is.
+    pub fn processing_instruction(&mut self, target: &str, value: Option<&str>) -> io::Result<()> {
+        self.write_start_tag(false)?;
+        match value {
+            None => write!(self.output, "<?{}?>", target),
+            Some(v) => write!(self.output, "<?{} {}?>", target, v),
+        }
+    }
+
+    /// Ends the innermost open element.
+    pub fn end_element(&mut self) -> io::Result<()> {
+        if self.start_tag.is_some() {
+            // There is no parsed element to consult
+            let self_closing = self.options.empty_element_style.self_closing(None);
+            self.write_start_tag(self_closing)?;
+            if self_closing {
+                return Ok(());
+            }
+        }
+
+        let element = self
+            .open
+            .pop()
+            .ok_or_else(|| invalid_event("There is no open element to end"))?;
+        write!(self.output, "</{}>", element.name)
+    }
+
+    /// Ends every element that is still open and returns the output.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_declaration()?;
+        while self.start_tag.is_some() || !self.open.is_empty() {
+            self.end_element()?;
+        }
+        Ok(self.output)
+    }
+
+    fn write_declaration(&mut self) -> io::Result<()> {
+        if !self.started {
+            self.started = true;
+            if self.options.write_declaration {
+                self.options.format_declaration(&mut self.output)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the pending start tag, if any. An empty element is
+    /// closed immediately.
+    fn write_start_tag(&mut self, empty: bool) -> io::Result<()> {
+        self.write_declaration()?;
+
+        let StartTag {
+            name,
+            attributes,
+            mut namespaces,
+        } = match self.start_tag.take() {
+            Some(start_tag) => start_tag,
+            None => return Ok(()),
+        };
+
+        let name = self.qualified_name(name, &mut namespaces, true);
+        let attributes: Vec<_> = attributes
+            .into_iter()
+            .map(|(n, v)| (self.qualified_name(n, &mut namespaces, false), v))
+            .collect();
+
+        let options = &self.options;
+        let output = &mut self.output;
+        let quote = options.quote_char();
+
+        write!(output, "<{}", name)?;
+        for (prefix, namespace_uri) in &namespaces {
+            match prefix {
+                Some(prefix) => write!(output, " xmlns:{}={}", prefix, quote)?,
+                None => write!(output, " xmlns={}", quote)?,
+            }
+            options.format_attribute_value(namespace_uri, output)?;
+            output.write_str(quote)?;
+        }
+        for (name, value) in attributes {
+            write!(output, " {}={}", name, quote)?;
+            options.format_attribute_value(&value, output)?;
+            output.write_str(quote)?;
+        }
+
+        if empty {
+            output.write_str("/>")
+        } else {
+            output.write_str(">")?;
+            self.open.push(OpenElement { name, namespaces });
+            Ok(())
+        }
+    }
+
+    /// The name as written, declaring a prefix or undeclaring the
+    /// default namespace in `namespaces` when needed. A default
+    /// namespace declared on the start tag itself is never undeclared.
+    /// Attributes are never in the default namespace.
+    fn qualified_name(
+        &mut self,
+        name: EventName,
+        namespaces: &mut Vec<EventNamespace>,
+        element: bool,
+    ) -> String {
+        let (namespace_uri, local_part) = name;
+
+        let namespace_uri = match namespace_uri {
+            Some(namespace_uri) => namespace_uri,
+            None => {
+                let declared_here = namespaces.iter().any(|(p, _)| p.is_none());
+                if element && !declared_here && self.namespace_uri_for(namespaces, None).is_some() {
+                    namespaces.push((None, String::new()));
+                }
+                return local_part;
+            }
+        };
+
+        if namespace_uri == crate::XML_NS_URI {
+            return format!("{}:{}", crate::XML_NS_PREFIX, local_part);
+        }
+
+        if element && self.namespace_uri_for(namespaces, None) == Some(&namespace_uri[..]) {
+            return local_part;
+        }
+
+        let prefix = match self.prefix_for(namespaces, &namespace_uri) {
+            Some(prefix) => prefix,
+            None => {
+                let prefix = loop {
+                    let prefix = format!("autons{}", self.generated_prefix_count);
+                    self.generated_prefix_count += 1;
+                    if self.namespace_uri_for(namespaces, Some(&prefix)).is_none() {
+                        break prefix;
+                    }
+                };
+                namespaces.push((Some(prefix.clone()), namespace_uri));
+                prefix
+            }
+        };
+
+        format!("{}:{}", prefix, local_part)
+    }
+
+    /// The namespaces in scope, innermost first, including those
+    /// declared on the start tag being written.
+    fn scope<'a>(
+        &'a self,
+        namespaces: &'a [EventNamespace],
+    ) -> impl Iterator<Item = &'a EventNamespace> + 'a {
+        namespaces.iter().rev().chain(
+            self.open
+                .iter()
+                .rev()
+                .flat_map(|e| e.namespaces.iter().rev()),
+        )
+    }
+
+    fn namespace_uri_for<'a>(
+        &'a self,
+        namespaces: &'a [EventNamespace],
+        prefix: Option<&str>,
+    ) -> Option<&'a str> {
+        self.scope(namespaces)
+            .find(|(p, _)| p.as_ref().map(String::as_str) == prefix)
+            .map(|(_, uri)| &uri[..])
+            .filter(|uri| !uri.is_empty())
+    }
+
+    fn prefix_for(&self, namespaces: &[EventNamespace], namespace_uri: &str) -> Option<String> {
+        self.scope(namespaces)
+            .filter_map(|(p, uri)| match p {
+                Some(p) if uri == namespace_uri => Some(p),
+                _ => None,
+            })
+            .find(|p| self.namespace_uri_for(namespaces, Some(p)) == Some(namespace_uri))
+            .cloned()
+    }
+}
+
+fn invalid_event(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Lets the writer format into a `fmt::Write`. Every chunk the writer
+/// produces is a complete string, so no bytes are ever split across
+/// calls.
+struct FmtWriter<'a, W: ?Sized>(&'a mut W);
+
+impl<'a, W: ?Sized> Write for FmtWriter<'a, W>
+where
+    W: fmt::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let s = str::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.0
+            .write_str(s)
+            .map_err(|_| io::Error::from(io::ErrorKind::Other))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Formats the document using the default `Writer`.
+impl<'d> fmt::Display for dom::Document<'d> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Writer::default()
+            .format_document(self, &mut FmtWriter(f))
+            .map_err(|_| fmt::Error)
+    }
+}
+
+/// Formats the element and its descendants using the default
+/// `Writer`, without an XML declaration.
+impl<'d> fmt::Display for dom::Element<'d> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Writer::default()
+            .format_body(*self, &mut FmtWriter(f))
+            .map_err(|_| fmt::Error)
+    }
+}
+
+impl<'d> dom::Document<'d> {
+    /// Formats the document as a string with the given indentation;
+    /// `Indent::default()` is two spaces. Otherwise the default
+    /// `Writer` is used; `to_string` gives the same output without
+    /// indentation. Use a `Writer` directly for other options.
+    ///
+    /// ```
+    /// use sxd_document::{parser, writer::Indent};
+    ///
+    /// let package = parser::parse("<a><b><c/></b><d/></a>").expect("Failed to parse");
+    /// let doc = package.as_document();
+    ///
+    /// assert_eq!(
+    ///     doc.to_string_pretty(Indent::default()),
+    ///     "<?xml version='1.0'?>\n<a>\n  <b>\n    <c/>\n  </b>\n  <d/>\n</a>"
+    /// );
+    /// assert_eq!(
+    ///     doc.to_string_pretty(Indent::Tabs(1)),
+    ///     "<?xml version='1.0'?>\n<a>\n\t<b>\n\t\t<c/>\n\t</b>\n\t<d/>\n</a>"
+    /// );
+    /// assert_eq!(
+    ///     doc.to_string(),
+    ///     "<?xml version='1.0'?><a><b><c/></b><d/></a>"
+    /// );
+    /// ```
+    pub fn to_string_pretty(&self, indent: Indent) -> String {
+        let mut output = String::new();
+        Writer::new()
+            .set_indent(Some(indent))
+            .format_document(self, &mut FmtWriter(&mut output))
+            .expect("Formatting to a String cannot fail");
+        output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        super::{dom, parser, Package, QName},
+        EmptyElementStyle, EscapePolicy, EventWriter, Indent, Newline, Writer,
+    };
+    use std::{borrow::Cow, io};
+
+    fn format_xml<'d>(doc: &'d dom::Document<'d>) -> String {
+        format_xml_writer(Writer::default(), doc)
+    }
+
+    fn format_xml_writer(writer: Writer, doc: &dom::Document<'_>) -> String {
         let mut w = Vec::new();
         writer.format_document(doc, &mut w).expect("Not formatted");
         String::from_utf8(w).expect("Not a string")
     }
 
+    #[test]
+    fn to_string_pretty_keeps_mixed_content() {
+        let p = parser::parse("<a><p>Hello <b>world</b></p></a>").expect("Failed to parse");
+        let d = p.as_document();
+
+        assert_eq!(
+            d.to_string_pretty(Indent::default()),
+            "<?xml version='1.0'?>\n<a>\n  <p>Hello <b>world</b></p>\n</a>"
+        );
+    }
+
+    #[test]
+    fn format_document_writes_bytes() {
+        let p = parser::parse("<a b='\u{E9}'>caf\u{E9}</a>").expect("Failed to parse");
+        let d = p.as_document();
+
+        let mut output = Vec::new();
+        super::format_document(&d, &mut output).expect("Not formatted");
+
+        assert_eq!(
+            output,
+            b"<?xml version='1.0'?><a b='\xC3\xA9'>caf\xC3\xA9</a>".to_vec()
+        );
+    }
+
+    struct FailingWriter {
+        remaining: usize,
+    }
+
+    impl io::Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"));
+            }
+            let n = buf.len().min(self.remaining);
+            self.remaining -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn format_document_returns_writer_errors() {
+        let p = parser::parse("<a><b/><c>text</c></a>").expect("Failed to parse");
+        let d = p.as_document();
+
+        let mut output = FailingWriter { remaining: 25 };
+        let err = super::format_document(&d, &mut output).expect_err("Should fail");
+
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
     #[test]
     fn top_element() {
         let p = Package::new();
@@ -890,6 +2151,164 @@ mod test {
         );
     }
 
+    #[test]
+    fn prefixed_namespaces_round_trip() {
+        let input = "<?xml version='1.0'?><ns:a xmlns:ns='u'><ns:b/></ns:a>";
+        let p = parser::parse(input).expect("Failed to parse");
+        let d = p.as_document();
+
+        let xml = format_xml(&d);
+        assert_eq!(xml, input);
+    }
+
+    #[test]
+    fn registered_prefixes_are_declared_where_registered() {
+        let input = "<?xml version='1.0'?><a xmlns:ns='u'><ns:b/><c><ns:d ns:e='f'/></c></a>";
+        let p = parser::parse(input).expect("Failed to parse");
+        let d = p.as_document();
+
+        let xml = format_xml(&d);
+        assert_eq!(xml, input);
+    }
+
+    #[test]
+    fn registered_prefixes_already_in_scope_are_not_redeclared() {
+        let p = Package::new();
+        let d = p.as_document();
+        let hello = d.create_element("hello");
+        hello.register_prefix("p", "namespace");
+        let world = d.create_element(("namespace", "world"));
+        world.register_prefix("p", "namespace");
+        hello.append_child(world);
+        d.root().append_child(hello);
+
+        let xml = format_xml(&d);
+        assert_eq!(
+            xml,
+            "<?xml version='1.0'?><hello xmlns:p='namespace'><p:world/></hello>"
+        );
+    }
+
+    #[test]
+    fn namespace_declarations_use_the_quote_style() {
+        let p = Package::new();
+        let d = p.as_document();
+        let hello = d.create_element(("a\"b", "hello"));
+        hello.set_default_namespace_uri(Some("a\"b"));
+        hello.register_prefix("p", "x&y");
+        d.root().append_child(hello);
+
+        let xml = format_xml_writer(Writer::new().set_single_quotes(false), &d);
+        assert_eq!(
+            xml,
+            r#"<?xml version="1.0"?><hello xmlns="a&quot;b" xmlns:p="x&amp;y"/>"#
+        );
+    }
+
+    #[test]
+    fn attributes_in_insertion_order() {
+        let p = Package::new();
+        let d = p.as_document();
+        let hello = d.create_element("hello");
+        hello.set_attribute_value("b", "1");
+        hello.set_attribute_value("a", "2");
+        d.root().append_child(hello);
+
+        let xml = format_xml(&d);
+        assert_eq!(xml, "<?xml version='1.0'?><hello b='1' a='2'/>");
+    }
+
+    #[test]
+    fn sorted_attributes() {
+        let p = Package::new();
+        let d = p.as_document();
+        let hello = d.create_element("hello");
+        hello
+            .set_attribute_value(("urn:z", "a"), "1")
+            .set_preferred_prefix(Some("a"));
+        hello.set_attribute_value("c", "2");
+        hello
+            .set_attribute_value(("urn:y", "b"), "3")
+            .set_preferred_prefix(Some("z"));
+        hello.set_attribute_value("a", "4");
+        hello
+            .set_attribute_value(("urn:y", "a"), "5")
+            .set_preferred_prefix(Some("z"));
+        d.root().append_child(hello);
+
+        let xml = format_xml_writer(Writer::new().set_sort_attributes(true), &d);
+        assert_eq!(
+            xml,
+            "<?xml version='1.0'?><hello a='4' c='2' z:a='5' z:b='3' a:a='1' xmlns:a='urn:z' xmlns:z='urn:y'/>"
+        );
+    }
+
+    #[test]
+    fn empty_element_styles() {
+        let p = Package::new();
+        let d = p.as_document();
+        let hello = d.create_element("hello");
+        hello.set_attribute_value("a", "b");
+        d.root().append_child(hello);
+
+        let writer = Writer::new().set_empty_element_style(EmptyElementStyle::SelfClosing);
+        let xml = format_xml_writer(writer, &d);
+        assert_eq!(xml, "<?xml version='1.0'?><hello a='b'/>");
+
+        let writer = Writer::new().set_empty_element_style(EmptyElementStyle::EndTag);
+        let xml = format_xml_writer(writer, &d);
+        assert_eq!(xml, "<?xml version='1.0'?><hello a='b'></hello>");
+    }
+
+    #[test]
+    fn empty_elements_written_as_parsed() {
+        let p = parser::parse("<a><b/><c></c></a>").expect("Failed to parse");
+        let d = p.as_document();
+        let a = d.root().children()[0].element().unwrap();
+        a.append_child(d.create_element("created"));
+
+        let writer = Writer::new()
+            .set_write_declaration(false)
+            .set_empty_element_style(EmptyElementStyle::AsParsed);
+        let xml = format_xml_writer(writer, &d);
+
+        assert_eq!(xml, "<a><b/><c></c><created/></a>");
+    }
+
+    #[test]
+    fn empty_elements_with_end_tags_are_not_indented_inside() {
+        let p = parser::parse("<a><b/><c></c></a>").expect("Failed to parse");
+        let d = p.as_document();
+
+        let writer = Writer::new()
+            .set_write_declaration(false)
+            .set_indent(Some(Indent::Spaces(2)))
+            .set_empty_element_style(EmptyElementStyle::EndTag);
+        let xml = format_xml_writer(writer, &d);
+        assert_eq!(xml, "<a>\n  <b></b>\n  <c></c>\n</a>");
+
+        let writer = Writer::new()
+            .set_write_declaration(false)
+            .set_indent(Some(Indent::Spaces(2)))
+            .set_empty_element_style(EmptyElementStyle::AsParsed);
+        let xml = format_xml_writer(writer, &d);
+        assert_eq!(xml, "<a>\n  <b/>\n  <c></c>\n</a>");
+    }
+
+    #[test]
+    fn element_with_only_a_comment_is_not_empty() {
+        let p = Package::new();
+        let d = p.as_document();
+        let hello = d.create_element("hello");
+        hello.append_child(d.create_comment("c"));
+        d.root().append_child(hello);
+
+        for &style in &[EmptyElementStyle::SelfClosing, EmptyElementStyle::EndTag] {
+            let xml = format_xml_writer(Writer::new().set_empty_element_style(style), &d);
+            assert_eq!(xml, "<?xml version='1.0'?><hello><!--c--></hello>");
+        }
+    }
+
     #[test]
     fn nested_text() {
         let p = Package::new();
@@ -967,6 +2386,14 @@ mod test {
         );
     }
 
+    #[test]
+    fn processing_instruction_value_with_leading_whitespace_round_trips() {
+        let xml = "<?xml version='1.0'?><hello><?display  screen ?></hello>";
+        let p = parser::parse(xml).expect("Failed to parse");
+
+        assert_eq!(format_xml(&p.as_document()), xml);
+    }
+
     #[test]
     fn top_level_comment() {
         let p = Package::new();
@@ -1015,4 +2442,790 @@ mod test {
         );
         assert_eq!(xml, r#"<?xml version="1.0" encoding="UTF-8"?><hello/>"#);
     }
+
+    #[test]
+    fn pretty_nested_elements() {
+        let p = Package::new();
+        let d = p.as_document();
+        let a = d.create_element("a");
+        let b = d.create_element("b");
+        let c = d.create_element("c");
+        let e = d.create_element("e");
+        b.append_child(c);
+        a.append_child(b);
+        a.append_child(e);
+        d.root().append_child(a);
+
+        let xml = format_xml_writer(Writer::new().set_indent(Some(Indent::Spaces(2))), &d);
+        assert_eq!(
+            xml,
+            "<?xml version='1.0'?>\n<a>\n  <b>\n    <c/>\n  </b>\n  <e/>\n</a>"
+        );
+    }
+
+    #[test]
+    fn pretty_with_tabs_and_crlf() {
+        let p = Package::new();
+        let d = p.as_document();
+        let a = d.create_element("a");
+        let b = d.create_element("b");
+        a.append_child(b);
+        d.root().append_child(a);
+
+        let xml = format_xml_writer(
+            Writer::new()
+                .set_indent(Some(Indent::Tabs(1)))
+                .set_newline(Newline::CrLf),
+            &d,
+        );
+        assert_eq!(xml, "<?xml version='1.0'?>\r\n<a>\r\n\t<b/>\r\n</a>");
+    }
+
+    #[test]
+    fn pretty_leaves_mixed_content_untouched() {
+        let p = Package::new();
+        let d = p.as_document();
+        let a = d.create_element("a");
+        let para = d.create_element("p");
+        let b = d.create_element("b");
+        let i = d.create_element("i");
+        para.append_child(d.create_text("Hello "));
+        b.append_child(i);
+        para.append_child(b);
+        a.append_child(para);
+        d.root().append_child(a);
+
+        let xml = format_xml_writer(Writer::new().set_indent(Some(Indent::Spaces(2))), &d);
+        assert_eq!(
+            xml,
+            "<?xml version='1.0'?>\n<a>\n  <p>Hello <b><i/></b></p>\n</a>"
+        );
+    }
+
+    #[test]
+    fn pretty_replaces_whitespace_only_text() {
+        let p = Package::new();
+        let d = p.as_document();
+        let a = d.create_element("a");
+        let b = d.create_element("b");
+        a.append_child(d.create_text("\n    "));
+        a.append_child(b);
+        a.append_child(d.create_text("\n"));
+        d.root().append_child(a);
+
+        let xml = format_xml_writer(Writer::new().set_indent(Some(Indent::Spaces(2))), &d);
+        assert_eq!(xml, "<?xml version='1.0'?>\n<a>\n  <b/>\n</a>");
+    }
+
+    #[test]
+    fn pretty_comments_and_processing_instructions() {
+        let p = Package::new();
+        let d = p.as_document();
+        let a = d.create_element("a");
+        a.append_child(d.create_comment("inside"));
+        a.append_child(d.create_processing_instruction("pi", None));
+        d.root().append_child(d.create_comment("before"));
+        d.root().append_child(a);
+
+        let xml = format_xml_writer(Writer::new().set_indent(Some(Indent::Spaces(1))), &d);
+        assert_eq!(
+            xml,
+            "<?xml version='1.0'?>\n<!--before-->\n<a>\n <!--inside-->\n <?pi?>\n</a>"
+        );
+    }
+
+    #[test]
+    fn declaration_with_version() {
+        let p = Package::new();
+        let d = p.as_document();
+        d.root().append_child(d.create_element("hello"));
+
+        let xml = format_xml_writer(Writer::new().set_version(Some("1.1")), &d);
+        assert_eq!(xml, "<?xml version='1.1'?><hello/>");
+    }
+
+    #[test]
+    fn declaration_with_invalid_version_fails() {
+        let p = Package::new();
+        let d = p.as_document();
+        d.root().append_child(d.create_element("hello"));
+
+        let mut w = Vec::new();
+        let r = Writer::new()
+            .set_version(Some("2.0"))
+            .format_document(&d, &mut w);
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn declaration_with_named_encoding() {
+        let p = Package::new();
+        let d = p.as_document();
+        let e = d.create_element("hello");
+        d.root().append_child(e);
+
+        let xml = format_xml_writer(Writer::new().set_encoding(Some("ISO-8859-1")), &d);
+        assert_eq!(xml, "<?xml version='1.0' encoding='ISO-8859-1'?><hello/>");
+    }
+
+    #[test]
+    fn declaration_with_invalid_encoding_fails() {
+        let p = Package::new();
+        let d = p.as_document();
+        let e = d.create_element("hello");
+        d.root().append_child(e);
+
+        let mut w = Vec::new();
+        let r = Writer::new()
+            .set_encoding(Some("8bit encoding"))
+            .format_document(&d, &mut w);
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn declaration_with_standalone() {
+        let p = Package::new();
+        let d = p.as_document();
+        let e = d.create_element("hello");
+        d.root().append_child(e);
+
+        let xml = format_xml_writer(
+            Writer::new()
+                .set_write_encoding(true)
+                .set_standalone(Some(false))
+                .set_single_quotes(false),
+            &d,
+        );
+        assert_eq!(
+            xml,
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="no"?><hello/>"#
+        );
+    }
+
+    #[test]
+    fn without_declaration() {
+        let p = Package::new();
+        let d = p.as_document();
+        let e = d.create_element("hello");
+        d.root().append_child(e);
+
+        let xml = format_xml_writer(Writer::new().set_write_declaration(false), &d);
+        assert_eq!(xml, "<hello/>");
+    }
+
+    #[test]
+    fn pretty_without_declaration() {
+        let p = Package::new();
+        let d = p.as_document();
+        d.root().append_child(d.create_comment("c"));
+        d.root().append_child(d.create_element("hello"));
+
+        let xml = format_xml_writer(
+            Writer::new()
+                .set_write_declaration(false)
+                .set_indent(Some(Indent::Spaces(2))),
+            &d,
+        );
+        assert_eq!(xml, "<!--c-->\n<hello/>");
+    }
+
+    #[test]
+    fn minimal_escaping_in_attribute_values() {
+        let p = Package::new();
+        let d = p.as_document();
+        let e = d.create_element("hello");
+        e.set_attribute_value("name", r#"<&>"'"#);
+        d.root().append_child(e);
+
+        let policy = EscapePolicy::new()
+            .set_escape_greater_than(false)
+            .set_escape_all_quotes(false);
+        let xml = format_xml_writer(Writer::new().set_escape_policy(policy), &d);
+        assert_eq!(
+            xml,
+            r#"<?xml version='1.0'?><hello name='&lt;&amp;>"&apos;'/>"#
+        );
+    }
+
+    #[test]
+    fn minimal_escaping_in_attribute_values_with_double_quotes() {
+        let p = Package::new();
+        let d = p.as_document();
+        let e = d.create_element("hello");
+        e.set_attribute_value("name", r#""'"#);
+        d.root().append_child(e);
+
+        let policy = EscapePolicy::new().set_escape_all_quotes(false);
+        let xml = format_xml_writer(
+            Writer::new()
+                .set_single_quotes(false)
+                .set_escape_policy(policy),
+            &d,
+        );
+        assert_eq!(xml, r#"<?xml version="1.0"?><hello name="&quot;'"/>"#);
+    }
+
+    #[test]
+    fn minimal_escaping_in_text_still_escapes_cdata_end() {
+        let p = Package::new();
+        let d = p.as_document();
+        let hello = d.create_element("hello");
+        hello.append_child(d.create_text("a > b ]]> c"));
+        d.root().append_child(hello);
+
+        let policy = EscapePolicy::new().set_escape_greater_than(false);
+        let xml = format_xml_writer(Writer::new().set_escape_policy(policy), &d);
+        assert_eq!(xml, "<?xml version='1.0'?><hello>a > b ]]&gt; c</hello>");
+    }
+
+    #[test]
+    fn ascii_only_escaping() {
+        let p = Package::new();
+        let d = p.as_document();
+        let hello = d.create_element("hello");
+        hello.set_attribute_value("name", "caf\u{e9}");
+        hello.append_child(d.create_text("\u{1F600} & \u{e9}t\u{e9}"));
+        d.root().append_child(hello);
+
+        let policy = EscapePolicy::new().set_ascii_only(true);
+        let xml = format_xml_writer(Writer::new().set_escape_policy(policy), &d);
+        assert_eq!(
+            xml,
+            "<?xml version='1.0'?><hello name='caf&#xE9;'>&#x1F600; &amp; &#xE9;t&#xE9;</hello>"
+        );
+    }
+
+    #[test]
+    fn whitespace_that_parsing_normalizes_is_escaped() {
+        let p = Package::new();
+        let d = p.as_document();
+        let hello = d.create_element("hello");
+        hello.set_attribute_value("name", "a\tb\nc\rd");
+        hello.append_child(d.create_text("one\r\ntwo\tthree"));
+        d.root().append_child(hello);
+
+        let xml = format_xml(&d);
+        assert_eq!(
+            xml,
+            "<?xml version='1.0'?><hello name='a&#x9;b&#xA;c&#xD;d'>one&#xD;\ntwo\tthree</hello>"
+        );
+    }
+
+    #[test]
+    fn escaped_whitespace_survives_a_round_trip() {
+        let p = Package::new();
+        let d = p.as_document();
+        let hello = d.create_element("hello");
+        hello.set_attribute_value("name", "a\tb\r\nc");
+        hello.append_child(d.create_text("one\rtwo"));
+        d.root().append_child(hello);
+
+        let xml = format_xml(&d);
+        let p = parser::parse(&xml).expect("Failed to parse");
+        let hello = p.as_document().root_element().unwrap();
+
+        assert_eq!(hello.attribute_value("name"), Some("a\tb\r\nc"));
+        assert_eq!(hello.string_value(), "one\rtwo");
+    }
+
+    #[test]
+    fn cdata_containing_a_carriage_return_is_written_as_text() {
+        let p = Package::new();
+        let d = p.as_document();
+        let hello = d.create_element("hello");
+        let text = d.create_text("<one\rtwo>");
+        text.set_cdata(true);
+        hello.append_child(text);
+        d.root().append_child(hello);
+
+        let xml = format_xml(&d);
+        assert_eq!(
+            xml,
+            "<?xml version='1.0'?><hello>&lt;one&#xD;two&gt;</hello>"
+        );
+    }
+
+    #[test]
+    fn whitespace_escaping_can_be_disabled() {
+        let p = Package::new();
+        let d = p.as_document();
+        let hello = d.create_element("hello");
+        hello.set_attribute_value("name", "a\nb");
+        let text = d.create_text("one\rtwo");
+        text.set_cdata(true);
+        hello.append_child(text);
+        d.root().append_child(hello);
+
+        let policy = EscapePolicy::new().set_escape_whitespace(false);
+        let xml = format_xml_writer(Writer::new().set_escape_policy(policy), &d);
+        assert_eq!(
+            xml,
+            "<?xml version='1.0'?><hello name='a\nb'><![CDATA[one\rtwo]]></hello>"
+        );
+    }
+
+    #[test]
+    fn nested_cdata() {
+        let p = Package::new();
+        let d = p.as_document();
+        let hello = d.create_element("hello");
+        let text = d.create_text("if (a < b && c) {}");
+        text.set_cdata(true);
+        hello.append_child(text);
+        d.root().append_child(hello);
+
+        let xml = format_xml(&d);
+        assert_eq!(
+            xml,
+            "<?xml version='1.0'?><hello><![CDATA[if (a < b && c) {}]]></hello>"
+        );
+    }
+
+    #[test]
+    fn cdata_containing_its_terminator_is_split() {
+        let p = Package::new();
+        let d = p.as_document();
+        let hello = d.create_element("hello");
+        let text = d.create_text("a]]>b");
+        text.set_cdata(true);
+        hello.append_child(text);
+        d.root().append_child(hello);
+
+        let xml = format_xml(&d);
+        assert_eq!(
+            xml,
+            "<?xml version='1.0'?><hello><![CDATA[a]]]]><![CDATA[>b]]></hello>"
+        );
+    }
+
+    #[test]
+    fn created_cdata() {
+        let p = Package::new();
+        let d = p.as_document();
+        let hello = d.create_element("hello");
+        hello.append_child(d.create_cdata("<b>]]></b>"));
+        d.root().append_child(hello);
+
+        let xml = format_xml(&d);
+        assert_eq!(
+            xml,
+            "<?xml version='1.0'?><hello><![CDATA[<b>]]]]><![CDATA[></b>]]></hello>"
+        );
+    }
+
+    #[test]
+    fn parsed_attributes_are_written_in_document_order() {
+        let input = "<?xml version='1.0'?><a z='1' a='2' m='3'/>";
+        let p = parser::parse(input).expect("Failed to parse");
+        let d = p.as_document();
+
+        assert_eq!(format_xml(&d), input);
+    }
+
+    #[test]
+    fn parsed_document_type_round_trips() {
+        let input = r#"<!DOCTYPE html SYSTEM "about:legacy-compat"><html/>"#;
+        let p = parser::parse(input).expect("Failed to parse");
+        let d = p.as_document();
+
+        let xml = format_xml_writer(Writer::new().set_write_declaration(false), &d);
+        assert_eq!(xml, input);
+    }
+
+    #[test]
+    fn parsed_document_type_keeps_its_position() {
+        let input = "<!--c--><!DOCTYPE a><a/>";
+        let p = parser::parse(input).expect("Failed to parse");
+        let d = p.as_document();
+
+        let xml = format_xml_writer(Writer::new().set_write_declaration(false), &d);
+        assert_eq!(xml, input);
+    }
+
+    #[test]
+    fn document_type_is_written_before_the_root_element() {
+        let p = Package::new();
+        let d = p.as_document();
+        d.set_document_type(Some(d.create_document_type("a")));
+        d.set_document_type_position(5);
+        d.root().append_child(d.create_element("a"));
+        d.root().append_child(d.create_comment("c"));
+
+        let xml = format_xml_writer(Writer::new().set_write_declaration(false), &d);
+        assert_eq!(xml, "<!DOCTYPE a><a/><!--c-->");
+    }
+
+    #[test]
+    fn document_type_identifier_with_both_quotes_is_an_error() {
+        let p = Package::new();
+        let d = p.as_document();
+        let document_type = d.create_document_type("a");
+        document_type.set_system_id(Some(r#"it's "x".dtd"#));
+        d.set_document_type(Some(document_type));
+        d.root().append_child(d.create_element("a"));
+
+        let mut w = Vec::new();
+        let err = Writer::new().format_document(&d, &mut w).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn document_type_with_public_id_and_internal_subset() {
+        let p = Package::new();
+        let d = p.as_document();
+        let document_type = d.create_document_type("a");
+        document_type.set_public_id(Some("-//X//it's//EN"));
+        document_type.set_system_id(Some(r#"say "hi".dtd"#));
+        document_type.set_internal_subset(Some("<!ENTITY e 'x'>"));
+        d.set_document_type(Some(document_type));
+        d.root().append_child(d.create_element("a"));
+
+        let xml = format_xml(&d);
+        assert_eq!(
+            xml,
+            r#"<?xml version='1.0'?><!DOCTYPE a PUBLIC "-//X//it's//EN" 'say "hi".dtd' [<!ENTITY e 'x'>]><a/>"#
+        );
+    }
+
+    #[test]
+    fn pretty_document_type() {
+        let p = Package::new();
+        let d = p.as_document();
+        d.set_document_type(Some(d.create_document_type("a")));
+        d.root().append_child(d.create_element("a"));
+
+        let xml = format_xml_writer(Writer::new().set_indent(Some(Indent::Spaces(2))), &d);
+        assert_eq!(xml, "<?xml version='1.0'?>\n<!DOCTYPE a>\n<a/>");
+    }
+
+    fn format_c14n(input: &str) -> String {
+        let p = parser::parse(input).expect("Failed to parse");
+        let mut w = Vec::new();
+        super::format_document_c14n(&p.as_document(), &mut w).expect("Not formatted");
+        String::from_utf8(w).expect("Not a string")
+    }
+
+    #[test]
+    fn c14n_sorts_attributes() {
+        let xml = format_c14n(
+            "<a xmlns:z='urn:z' xmlns:b='urn:b' z:x='1' b='2' b:y='3' a='4' xmlns='urn:d'/>",
+        );
+        assert_eq!(
+            xml,
+            r#"<a xmlns="urn:d" xmlns:b="urn:b" xmlns:z="urn:z" a="4" b="2" b:y="3" z:x="1"></a>"#
+        );
+    }
+
+    #[test]
+    fn c14n_omits_the_document_type() {
+        let xml = format_c14n("<!DOCTYPE a [<!ENTITY e 'x'>]><a>&e;</a>");
+        assert_eq!(xml, "<a>x</a>");
+    }
+
+    #[test]
+    fn c14n_expands_empty_elements() {
+        let xml = format_c14n("<a><b/><c></c><d x='1'/></a>");
+        assert_eq!(xml, r#"<a><b></b><c></c><d x="1"></d></a>"#);
+    }
+
+    #[test]
+    fn c14n_escapes_text_and_attributes() {
+        let xml =
+            format_c14n("<a x='&quot;&apos;&gt;&#9;&#10;&#13;'>&gt;&#13;'\"<![CDATA[<&]]></a>");
+        assert_eq!(
+            xml,
+            "<a x=\"&quot;'>&#x9;&#xA;&#xD;\">&gt;&#xD;'\"&lt;&amp;</a>"
+        );
+    }
+
+    #[test]
+    fn c14n_omits_unused_and_redundant_namespace_declarations() {
+        let xml = format_c14n(
+            "<a xmlns='urn:d' xmlns:x='urn:x' xmlns:unused='urn:u'><b xmlns='urn:d'><x:c/></b></a>",
+        );
+        assert_eq!(
+            xml,
+            r#"<a xmlns="urn:d"><b><x:c xmlns:x="urn:x"></x:c></b></a>"#
+        );
+    }
+
+    #[test]
+    fn c14n_separates_nodes_outside_the_document_element() {
+        let xml = format_c14n("<?xml version='1.0'?>\n<?pi?>\n<!--c-->\n<a/>\n<!--d-->");
+        assert_eq!(xml, "<?pi?>\n<!--c-->\n<a></a>\n<!--d-->");
+    }
+
+    #[test]
+    fn display_document() {
+        let p = parser::parse("<a x='1'><!--c-->&lt;b&gt;</a>").expect("Failed to parse");
+        let d = p.as_document();
+
+        assert_eq!(d.to_string(), format_xml(&d));
+    }
+
+    #[test]
+    fn display_element() {
+        let p = parser::parse("<a xmlns:x='urn:x'><x:b>text</x:b></a>").expect("Failed to parse");
+        let d = p.as_document();
+        let a = d.root().children()[0].element().unwrap();
+        let b = a.children()[0].element().unwrap();
+
+        assert_eq!(b.to_string(), "<x:b xmlns:x='urn:x'>text</x:b>");
+    }
+
+    #[test]
+    fn cdata_round_trips() {
+        let input = "<?xml version='1.0'?><script>x<![CDATA[if (a < b) { c && d }]]></script>";
+        let p = parser::parse(input).expect("Failed to parse");
+        let d = p.as_document();
+
+        let xml = format_xml(&d);
+        assert_eq!(xml, input);
+    }
+
+    fn format_events<F>(writer: Writer, events: F) -> String
+    where
+        F: FnOnce(&mut EventWriter<Vec<u8>>) -> io::Result<()>,
+    {
+        let mut w = EventWriter::with_options(writer, Vec::new());
+        events(&mut w).expect("Not formatted");
+        String::from_utf8(w.finish().expect("Not formatted")).expect("Not a string")
+    }
+
+    #[test]
+    fn events_are_written_in_order() {
+        let xml = format_events(Writer::new(), |w| {
+            w.comment(" start ")?;
+            w.start_element("a")?;
+            w.attribute("x", "1 < 2")?;
+            w.attribute("y", "'")?;
+            w.text("one & two")?;
+            w.start_element("b")?;
+            w.end_element()?;
+            w.processing_instruction("pi", Some("value"))?;
+            w.start_element("c")?;
+            w.text("three")?;
+            w.end_element()?;
+            w.end_element()
+        });
+
+        assert_eq!(
+            xml,
+            "<?xml version='1.0'?><!-- start --><a x='1 &lt; 2' y='&apos;'>one &amp; two<b/><?pi value?><c>three</c></a>"
+        );
+    }
+
+    #[test]
+    fn events_close_open_elements_when_finished() {
+        let xml = format_events(Writer::new().set_write_declaration(false), |w| {
+            w.start_element("a")?;
+            w.start_element("b")?;
+            w.text("text")?;
+            w.start_element("c")
+        });
+
+        assert_eq!(xml, "<a><b>text<c/></b></a>");
+    }
+
+    #[test]
+    fn events_follow_the_writer_options() {
+        let writer = Writer::new()
+            .set_single_quotes(false)
+            .set_encoding(Some("UTF-8"));
+        let xml = format_events(writer, |w| {
+            w.start_element("a")?;
+            w.attribute("x", "\"")
+        });
+
+        assert_eq!(
+            xml,
+            r#"<?xml version="1.0" encoding="UTF-8"?><a x="&quot;"/>"#
+        );
+    }
+
+    #[test]
+    fn events_follow_the_empty_element_style() {
+        let writer = Writer::new()
+            .set_write_declaration(false)
+            .set_empty_element_style(EmptyElementStyle::EndTag);
+        let xml = format_events(writer, |w| {
+            w.start_element("a")?;
+            w.start_element("b")?;
+            w.end_element()?;
+            w.start_element("c")
+        });
+
+        assert_eq!(xml, "<a><b></b><c></c></a>");
+    }
+
+    #[test]
+    fn events_declare_namespaces() {
+        let xml = format_events(Writer::new().set_write_declaration(false), |w| {
+            w.start_element(("urn:a", "a"))?;
+            w.namespace(Some("x"), "urn:a")?;
+            w.namespace(None, "urn:d")?;
+            w.attribute(("urn:a", "attr"), "1")?;
+            w.start_element(("urn:d", "b"))?;
+            w.start_element(("urn:a", "c"))?;
+            w.end_element()?;
+            w.end_element()?;
+            w.start_element("d")?;
+            w.attribute(("urn:d", "attr"), "2")?;
+            w.end_element()
+        });
+
+        assert_eq!(
+            xml,
+            "<x:a xmlns:x='urn:a' xmlns='urn:d' x:attr='1'><b><x:c/></b><d xmlns='' xmlns:autons0='urn:d' autons0:attr='2'/></x:a>"
+        );
+    }
+
+    #[test]
+    fn events_generate_prefixes_for_undeclared_namespaces() {
+        let xml = format_events(Writer::new().set_write_declaration(false), |w| {
+            w.start_element(("urn:a", "a"))?;
+            w.attribute((crate::XML_NS_URI, "lang"), "en")?;
+            w.start_element(("urn:a", "b"))?;
+            w.start_element(("urn:b", "c"))
+        });
+
+        assert_eq!(
+            xml,
+            "<autons0:a xmlns:autons0='urn:a' xml:lang='en'><autons0:b><autons1:c xmlns:autons1='urn:b'/></autons0:b></autons0:a>"
+        );
+    }
+
+    #[test]
+    fn events_output_can_be_parsed() {
+        let xml = format_events(Writer::new(), |w| {
+            w.start_element(("urn:a", "a"))?;
+            w.namespace(Some("p"), "urn:p")?;
+            w.attribute(("urn:p", "x"), "a\tb")?;
+            w.start_element("b")?;
+            w.text("text")
+        });
+
+        let p = parser::parse(&xml).expect("Failed to parse");
+        let d = p.as_document();
+        let a = d.root_element().unwrap();
+        let b = a.children()[0].element().unwrap();
+
+        assert_eq!(a.name(), QName::with_namespace_uri(Some("urn:a"), "a"));
+        assert_eq!(a.attribute_value(("urn:p", "x")), Some("a\tb"));
+        assert_eq!(b.name(), QName::new("b"));
+        assert_eq!(b.string_value(), "text");
+    }
+
+    #[test]
+    fn events_keep_a_default_namespace_declared_on_the_element() {
+        let xml = format_events(Writer::new(), |w| {
+            w.start_element("a")?;
+            w.namespace(None, "urn:d")?;
+            w.start_element("b")
+        });
+
+        assert_eq!(
+            xml,
+            "<?xml version='1.0'?><a xmlns='urn:d'><b xmlns=''/></a>"
+        );
+
+        let p = parser::parse(&xml).expect("Failed to parse");
+        let d = p.as_document();
+        let a = d.root_element().unwrap();
+        let b = a.children()[0].element().unwrap();
+
+        assert_eq!(a.name(), QName::with_namespace_uri(Some("urn:d"), "a"));
+        assert_eq!(b.name(), QName::new("b"));
+    }
+
+    #[test]
+    fn events_reject_duplicate_attributes() {
+        let mut w = EventWriter::new(Vec::new());
+        w.start_element("a").unwrap();
+        w.attribute("x", "1").unwrap();
+        w.attribute(("urn:x", "x"), "2").unwrap();
+
+        let err = w.attribute("x", "3").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = w.attribute(("urn:x", "x"), "4").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let output = String::from_utf8(w.finish().unwrap()).unwrap();
+        let p = parser::parse(&output).expect("Failed to parse");
+        let a = p.as_document().root_element().unwrap();
+
+        assert_eq!(a.attribute_value("x"), Some("1"));
+        assert_eq!(a.attribute_value(("urn:x", "x")), Some("2"));
+    }
+
+    #[test]
+    fn events_out_of_place_are_rejected() {
+        let mut w = EventWriter::new(Vec::new());
+
+        let err = w.attribute("x", "1").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let err = w.end_element().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        w.start_element("a").unwrap();
+        let err = w.namespace(Some("xmlns"), "urn:x").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        w.text("text").unwrap();
+        let err = w.namespace(Some("x"), "urn:x").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn escape_text_borrows_plain_text() {
+        let text = "plain 'text' \"here\"\n";
+
+        match super::escape_text(text) {
+            Cow::Borrowed(t) => assert_eq!(t, text),
+            other => panic!("Expected borrowed text, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn escape_text_escapes_markup() {
+        assert_eq!(
+            super::escape_text("<a> & ]]>\r"),
+            "&lt;a&gt; &amp; ]]&gt;&#xD;"
+        );
+    }
+
+    #[test]
+    fn escape_attribute_borrows_plain_values() {
+        let value = "it's plain";
+
+        match super::escape_attribute(value, '"') {
+            Cow::Borrowed(v) => assert_eq!(v, value),
+            other => panic!("Expected a borrowed value, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn escape_attribute_escapes_the_delimiting_quote() {
+        let value = "<'&\"\t>";
+
+        assert_eq!(
+            super::escape_attribute(value, '\''),
+            "&lt;&apos;&amp;\"&#x9;&gt;"
+        );
+        assert_eq!(
+            super::escape_attribute(value, '"'),
+            "&lt;'&amp;&quot;&#x9;&gt;"
+        );
+    }
+
+    #[test]
+    fn escaped_text_round_trips_through_unescape() {
+        let text = "a < b & 'c' > \"d\"\r";
+
+        let escaped = super::escape_attribute(text, '"');
+        assert_eq!(parser::unescape(&escaped).unwrap(), text);
+        let escaped = super::escape_text(text);
+        assert_eq!(parser::unescape(&escaped).unwrap(), text);
+    }
 }